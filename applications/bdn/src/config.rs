@@ -0,0 +1,212 @@
+use serde::Deserialize;
+
+use yulong::config::{Config, invalid};
+use yulong::error::ConfigError;
+
+use crate::configs::{DEFAULT_BDN_PORT, MSG_MAXLEN};
+
+
+/// Tunables of a BDN node. All durations are in ms.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BdnConfig {
+    pub listen_port: u16,
+
+    // read buffer size of an ingress stream, no more than configs::MSG_MAXLEN
+    pub msg_maxlen: usize,
+
+    pub heartbeat_inv: u64,
+    pub measure_inv: u64,
+
+    // how often the event loop checks its timers when idle
+    pub tick_inv: u64,
+
+    // max relay links kept by RouteTable
+    pub max_link: u32,
+    // # of delegates
+    pub del_num: u32,
+
+    pub mlbt: MlbtConfig,
+
+    pub conn: ConnConfig,
+}
+
+
+/// Timeouts of the MLBT wait states, see route_inner::impls::mlbt_wait.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MlbtConfig {
+    // consecutive relay failures before a child is detached
+    pub relay_fail_max: u32,
+
+    pub join_wait_to: u64,
+    pub join_pre_to: u64,
+    pub merge_wait_to: u64,
+    pub merge_pre_to: u64,
+    pub merge_check_to: u64,
+    pub grant_wait_to: u64,
+    pub grant_join_to: u64,
+    pub retract_wait_to: u64,
+    pub retract_join_to: u64,
+    pub grant_recv_to: u64,
+    pub grant_total_to: u64,
+    pub retract_recv_to: u64,
+    pub retract_total_to: u64,
+}
+
+
+/// Outgoing connection management, see conn::ConnManager.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnConfig {
+    // max outgoing streams, the least recently used one is closed to make room
+    pub max_conn: usize,
+
+    // streams unused for this long are closed
+    pub idle_to: u64,
+
+    // a write taking longer drops the stream, the peer stopped reading
+    pub write_to: u64,
+
+    // reconnect delay doubles from backoff_base up to backoff_max
+    pub backoff_base: u64,
+    pub backoff_max: u64,
+
+    // consecutive failures before a peer is reported unreachable
+    pub max_retries: u32,
+}
+
+
+impl Default for BdnConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: DEFAULT_BDN_PORT,
+            msg_maxlen: MSG_MAXLEN,
+            heartbeat_inv: 5000,
+            measure_inv: 30000,
+            tick_inv: 100,
+            max_link: 128,
+            del_num: 1,
+            mlbt: MlbtConfig::default(),
+            conn: ConnConfig::default(),
+        }
+    }
+}
+
+
+impl Default for ConnConfig {
+    fn default() -> Self {
+        Self {
+            max_conn: 128,
+            idle_to: 60000,
+            write_to: 5000,
+            backoff_base: 500,
+            backoff_max: 30000,
+            max_retries: 8,
+        }
+    }
+}
+
+
+impl Default for MlbtConfig {
+    fn default() -> Self {
+        Self {
+            relay_fail_max: 3,
+            join_wait_to: 2000,
+            join_pre_to: 2000,
+            merge_wait_to: 2000,
+            merge_pre_to: 4000,
+            merge_check_to: 2000,
+            grant_wait_to: 2000,
+            grant_join_to: 2000,
+            retract_wait_to: 2000,
+            retract_join_to: 2000,
+            grant_recv_to: 2000,
+            grant_total_to: 2000,
+            retract_recv_to: 2000,
+            retract_total_to: 2000,
+        }
+    }
+}
+
+
+impl Config for BdnConfig {
+
+    const ENV_PREFIX: &'static str = "YULONG_BDN";
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        // 4 bytes length prefix
+        if self.msg_maxlen <= 4 || self.msg_maxlen > MSG_MAXLEN {
+            return Err(invalid(format!("msg_maxlen should be in (4, {}]", MSG_MAXLEN)));
+        }
+        if self.heartbeat_inv == 0 || self.measure_inv == 0 || self.tick_inv == 0 {
+            return Err(invalid("heartbeat_inv, measure_inv and tick_inv should be positive"));
+        }
+        if self.max_link == 0 {
+            return Err(invalid("max_link should be positive"));
+        }
+        self.mlbt.validate()?;
+        self.conn.validate()
+    }
+}
+
+
+impl ConnConfig {
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_conn == 0 || self.idle_to == 0 || self.write_to == 0 {
+            return Err(invalid("conn.max_conn, conn.idle_to and conn.write_to should be positive"));
+        }
+        if self.backoff_base == 0 || self.backoff_base > self.backoff_max {
+            return Err(invalid("conn.backoff_base should be in (0, backoff_max]"));
+        }
+        Ok(())
+    }
+}
+
+
+impl MlbtConfig {
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let all = [
+            self.join_wait_to, self.join_pre_to,
+            self.merge_wait_to, self.merge_pre_to, self.merge_check_to,
+            self.grant_wait_to, self.grant_join_to, self.grant_recv_to, self.grant_total_to,
+            self.retract_wait_to, self.retract_join_to, self.retract_recv_to, self.retract_total_to,
+        ];
+        if all.iter().any(|to| *to == 0) {
+            return Err(invalid("mlbt timeouts should be positive"));
+        }
+        if self.relay_fail_max == 0 {
+            return Err(invalid("mlbt.relay_fail_max should be positive"));
+        }
+
+        // the merge requirer waits for the target to wait and check
+        if self.merge_pre_to < self.merge_check_to + self.merge_wait_to {
+            return Err(invalid("mlbt.merge_pre_to should be at least merge_check_to + merge_wait_to"));
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        assert!(BdnConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn merge_timeouts() {
+        let env = vec![("YULONG_BDN_MLBT__MERGE_PRE_TO".to_owned(), "3999".to_owned())];
+        assert!(BdnConfig::from_sources("", env).is_err());
+
+        let c = BdnConfig::from_sources("listen_port = 10451\n[mlbt]\nmerge_pre_to = 5000", vec![]).unwrap();
+        assert_eq!(c.listen_port, 10451);
+        assert_eq!(c.mlbt.merge_pre_to, 5000);
+        assert_eq!(c.mlbt.merge_wait_to, 2000);
+    }
+}
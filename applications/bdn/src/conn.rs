@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::AsyncWriteExt;
+use log::{debug, info, warn};
+
+use yulong::error::DumbError;
+use yulong::utils::clock;
+use yulong_network::identity::{Me, Peer};
+use yulong_network::transport::Transport;
+
+use crate::config::ConnConfig;
+use crate::error::ConnError;
+
+
+/// Connection state of a peer as seen by ConnManager, changes are reported
+/// through ConnManager::take_events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    // an outgoing stream is open
+    Connected,
+
+    // the last n attempts failed, wait before the next one
+    Backoff(u32),
+
+    // failed more than max_retries times in a row
+    Unreachable,
+
+    // closed on purpose, because of idleness or the connection cap
+    Closed,
+}
+
+
+struct ConnEntry<S> {
+    stream: Option<S>,
+    state: ConnState,
+    last_used: Instant,
+
+    // consecutive failures
+    failures: u32,
+
+    // no connection attempt before this
+    retry_at: Option<Instant>,
+}
+
+
+/// Outgoing streams of a BDN node.
+///
+/// A stream that fails to write, or takes longer than write_to, is dropped
+/// and the peer is retried with exponential backoff, streams left idle for
+/// idle_to are closed, and no
+/// more than max_conn streams are kept open, the least recently used one
+/// makes room for a new one.
+pub struct ConnManager<T: Transport> {
+    conns: HashMap<Peer, ConnEntry<T::Stream>>,
+
+    config: ConnConfig,
+
+    events: Vec<(Peer, ConnState)>,
+}
+
+
+impl<T: Transport> ConnManager<T> {
+
+    pub fn new(config: ConnConfig) -> Self {
+        Self {
+            conns: HashMap::new(),
+            config,
+            events: Vec::new(),
+        }
+    }
+
+
+    pub fn state(&self, peer: &Peer) -> Option<ConnState> {
+        self.conns.get(peer).map(|entry| entry.state)
+    }
+
+
+    pub fn is_connected(&self, peer: &Peer) -> bool {
+        self.conns.get(peer).map_or(false, |entry| entry.stream.is_some())
+    }
+
+
+    pub fn connected_count(&self) -> usize {
+        self.conns.values().filter(|entry| entry.stream.is_some()).count()
+    }
+
+
+    /// State changes since the last call, in the order they happened.
+    pub fn take_events(&mut self) -> Vec<(Peer, ConnState)> {
+        std::mem::take(&mut self.events)
+    }
+
+
+    /// Write bytes to dst, connecting to addr first if there is no open
+    /// stream. Fails without connecting while dst is backing off.
+    pub async fn send(&mut self, dst: &Peer, addr: Option<SocketAddr>, local: &Me, bytes: &[u8])
+        -> Result<(), ConnError>
+    {
+        if !self.is_connected(dst) {
+            if let Some(retry_at) = self.conns.get(dst).and_then(|entry| entry.retry_at) {
+                if clock::now() < retry_at {
+                    return Err(ConnError::new(format!("{} is backing off", dst), DumbError));
+                }
+            }
+
+            let addr = addr.ok_or_else(|| {
+                ConnError::new(format!("unknown address of {}", dst), DumbError)
+            })?;
+
+            self.open(dst, addr, local).await?;
+        }
+
+        // open succeeded or the stream was there, safe unwrap
+        let write_to = self.config.write_to;
+        let entry = self.conns.get_mut(dst).unwrap();
+        let stream = entry.stream.as_mut().unwrap();
+
+        // io::Error is Send, but keep the same shape as connect errors
+        let result = match clock::timeout(Duration::from_millis(write_to), stream.write_all(bytes)).await {
+            Ok(written) => written.map_err(|e| e.to_string()),
+            Err(_) => Err(format!("stalled for {}ms", write_to)),
+        };
+
+        match result {
+            Ok(_) => {
+                entry.last_used = clock::now();
+                Ok(())
+            }
+            Err(error) => {
+                warn!("ConnManager::send write to {} failed: {}", dst, error);
+                self.fail(dst);
+                Err(ConnError::new(format!("write to {}: {}", dst, error), DumbError))
+            }
+        }
+    }
+
+
+    /// Connect to dst at addr, dropping any stream already kept for dst.
+    /// The peer answering at addr must be dst.
+    pub async fn open(&mut self, dst: &Peer, addr: SocketAddr, local: &Me) -> Result<(), ConnError> {
+        // keep the entry, a failure below counts on top of earlier ones
+        if let Some(entry) = self.conns.get_mut(dst) {
+            entry.stream = None;
+        }
+
+        // TransportError is not Send, turn it into a String before any other await
+        let result = T::connect(&addr, local).await.map_err(|e| e.to_string());
+
+        match result {
+            Ok((_, remote_peer)) if remote_peer != *dst => {
+                warn!("ConnManager::open {} answered as {}", addr, remote_peer);
+                self.fail(dst);
+                Err(ConnError::new(format!("{} answered as {}", addr, remote_peer), DumbError))
+            }
+
+            Ok((stream, _)) => {
+                debug!("ConnManager::open connected {} at {}", dst, addr);
+                self.make_room();
+                self.conns.insert(dst.to_owned(), ConnEntry {
+                    stream: Some(stream),
+                    state: ConnState::Connected,
+                    last_used: clock::now(),
+                    failures: 0,
+                    retry_at: None,
+                });
+                self.events.push((dst.to_owned(), ConnState::Connected));
+                Ok(())
+            }
+
+            Err(error) => {
+                warn!("ConnManager::open failed to connect {}: {}", addr, error);
+                self.fail(dst);
+                Err(ConnError::new(format!("connect {}: {}", addr, error), DumbError))
+            }
+        }
+    }
+
+
+    /// Close the stream to peer, a later send reconnects.
+    pub fn close(&mut self, peer: &Peer) {
+        if let Some(entry) = self.conns.get_mut(peer) {
+            if entry.stream.take().is_some() {
+                entry.state = ConnState::Closed;
+                self.events.push((peer.to_owned(), ConnState::Closed));
+            }
+        }
+    }
+
+
+    /// Close streams not used for idle_to.
+    pub fn evict_idle(&mut self) {
+        let idle_to = Duration::from_millis(self.config.idle_to);
+
+        let idle: Vec<Peer> = self.conns.iter()
+            .filter(|(_, entry)| entry.stream.is_some() && clock::elapsed(entry.last_used) >= idle_to)
+            .map(|(peer, _)| peer.to_owned())
+            .collect();
+
+        for peer in idle {
+            debug!("ConnManager::evict_idle close {}", peer);
+            self.close(&peer);
+        }
+    }
+
+
+    /// Peers backing off whose retry time has come. Unreachable peers are
+    /// left alone until someone sends to them again.
+    pub fn due(&self) -> Vec<Peer> {
+        let now = clock::now();
+        self.conns.iter()
+            .filter(|(_, entry)| matches!(entry.state, ConnState::Backoff(_)))
+            .filter(|(_, entry)| entry.retry_at.map_or(true, |at| at <= now))
+            .map(|(peer, _)| peer.to_owned())
+            .collect()
+    }
+
+
+    // drop the stream and schedule the next attempt
+    fn fail(&mut self, peer: &Peer) {
+        let config = &self.config;
+        let entry = self.conns.entry(peer.to_owned()).or_insert_with(|| ConnEntry {
+            stream: None,
+            state: ConnState::Closed,
+            last_used: clock::now(),
+            failures: 0,
+            retry_at: None,
+        });
+
+        entry.stream = None;
+        entry.failures += 1;
+
+        if entry.failures > config.max_retries {
+            entry.state = ConnState::Unreachable;
+            entry.retry_at = Some(clock::now() + Duration::from_millis(config.backoff_max));
+        }
+        else {
+            entry.state = ConnState::Backoff(entry.failures);
+            entry.retry_at = Some(clock::now() + Self::backoff(config, entry.failures));
+        }
+
+        // report the first time a peer becomes unreachable only
+        if entry.state != ConnState::Unreachable || entry.failures == config.max_retries + 1 {
+            info!("ConnManager::fail {} is now {:?}", peer, entry.state);
+            self.events.push((peer.to_owned(), entry.state));
+        }
+    }
+
+
+    fn backoff(config: &ConnConfig, failures: u32) -> Duration {
+        let shift = failures.saturating_sub(1).min(31);
+        let delay = config.backoff_base.saturating_mul(1 << shift);
+        Duration::from_millis(delay.min(config.backoff_max))
+    }
+
+
+    // close the least recently used streams until a new one fits
+    fn make_room(&mut self) {
+        while self.connected_count() >= self.config.max_conn {
+            let lru = self.conns.iter()
+                .filter(|(_, entry)| entry.stream.is_some())
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(peer, _)| peer.to_owned());
+
+            match lru {
+                Some(peer) => {
+                    debug!("ConnManager::make_room close {}", peer);
+                    self.close(&peer);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use async_trait::async_trait;
+    use futures::{AsyncRead, AsyncWrite};
+    use yulong_network::error::TransportError;
+    use yulong_network::transport::IngressStream;
+    use yulong_sim::{seed_from_env, SimContext, SimNetwork};
+
+    const PORT: u16 = 9000;
+
+    // a transport to a peer that never reads, it answers as whoever connects
+    #[derive(Clone, Copy)]
+    struct Stalled;
+
+    #[derive(Debug)]
+    struct StalledStream;
+
+    impl AsyncRead for StalledStream {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut [u8]) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for StalledStream {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[async_trait]
+    impl Transport for Stalled {
+        type Stream = StalledStream;
+        type Listener = ();
+        type Incoming = ();
+
+        async fn listen(_: &SocketAddr) -> Result<(), TransportError> {
+            Ok(())
+        }
+
+        async fn connect(_: &SocketAddr, local: &Me) -> Result<(StalledStream, Peer), TransportError> {
+            Ok((StalledStream, local.peer().to_owned()))
+        }
+
+        async fn accept(_: &mut (), _: &Me) -> Result<(), TransportError> {
+            Err(TransportError::new("Stalled::accept", DumbError))
+        }
+
+        async fn upgrade(_: (), _: &Me) -> Result<IngressStream<StalledStream>, TransportError> {
+            Err(TransportError::new("Stalled::upgrade", DumbError))
+        }
+    }
+
+    // a node of net, with the address it would listen at
+    fn node(net: &SimNetwork) -> (Me, SocketAddr) {
+        let id = net.identity();
+        let addr = SocketAddr::new(net.add_node(&id), PORT);
+        (id, addr)
+    }
+
+    // accept and hold every stream, as a silent peer
+    async fn serve(net: &SimNetwork, id: Me) {
+        let peer = id.peer().to_owned();
+        net.spawn(async move {
+            let mut listener = SimContext::listen(&SocketAddr::from(([0, 0, 0, 0], PORT))).await.unwrap();
+            let mut held = Vec::new();
+            while let Ok(incoming) = SimContext::accept(&mut listener, &id).await {
+                if let Ok(istream) = SimContext::upgrade(incoming, &id).await {
+                    held.push(istream.stream);
+                }
+            }
+        });
+
+        while !net.listening(&peer) {
+            async_std::task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let net = SimNetwork::new(seed_from_env());
+        net.block_on(async {
+            let (me, _) = node(&net);
+            let (dst, addr) = node(&net);
+            let mut conns = ConnManager::<SimContext>::new(ConnConfig {
+                backoff_base: 200, backoff_max: 400, max_retries: 1, ..ConnConfig::default()
+            });
+
+            // dst does not listen
+            assert!(conns.send(dst.peer(), Some(addr), &me, &[1]).await.is_err());
+            assert_eq!(conns.state(dst.peer()), Some(ConnState::Backoff(1)));
+            assert!(conns.due().is_empty());
+
+            // within backoff, fail without trying
+            net.advance(Duration::from_millis(199));
+            assert!(conns.send(dst.peer(), Some(addr), &me, &[1]).await.is_err());
+            assert_eq!(conns.state(dst.peer()), Some(ConnState::Backoff(1)));
+            assert!(conns.due().is_empty());
+
+            net.advance(Duration::from_millis(1));
+            assert_eq!(conns.due(), vec![dst.peer().to_owned()]);
+
+            assert!(conns.open(dst.peer(), addr, &me).await.is_err());
+            assert_eq!(conns.state(dst.peer()), Some(ConnState::Unreachable));
+            assert!(conns.due().is_empty());
+
+            assert_eq!(conns.take_events(), vec![
+                (dst.peer().to_owned(), ConnState::Backoff(1)),
+                (dst.peer().to_owned(), ConnState::Unreachable),
+            ]);
+        });
+    }
+
+    #[test]
+    fn cap_and_idle() {
+        let net = SimNetwork::new(seed_from_env());
+        net.block_on(async {
+            let (me, _) = node(&net);
+            let (a, a_addr) = node(&net);
+            let (b, b_addr) = node(&net);
+            serve(&net, a.clone()).await;
+            serve(&net, b.clone()).await;
+
+            let mut conns = ConnManager::<SimContext>::new(ConnConfig {
+                max_conn: 1, idle_to: 100, ..ConnConfig::default()
+            });
+
+            conns.send(a.peer(), Some(a_addr), &me, &[1]).await.unwrap();
+            assert!(conns.is_connected(a.peer()));
+
+            // the cap closes a to make room for b
+            conns.send(b.peer(), Some(b_addr), &me, &[1]).await.unwrap();
+            assert_eq!(conns.state(a.peer()), Some(ConnState::Closed));
+            assert_eq!(conns.state(b.peer()), Some(ConnState::Connected));
+            assert_eq!(conns.connected_count(), 1);
+
+            // a wrong identity at an address is a failure
+            assert!(conns.open(a.peer(), b_addr, &me).await.is_err());
+            assert_eq!(conns.state(a.peer()), Some(ConnState::Backoff(1)));
+
+            net.advance(Duration::from_millis(99));
+            conns.evict_idle();
+            assert_eq!(conns.connected_count(), 1);
+
+            net.advance(Duration::from_millis(1));
+            conns.evict_idle();
+            assert_eq!(conns.connected_count(), 0);
+            assert_eq!(conns.state(b.peer()), Some(ConnState::Closed));
+        });
+    }
+
+    #[test]
+    fn stalled_write() {
+        let net = SimNetwork::new(seed_from_env());
+        net.block_on(async {
+            let (me, addr) = node(&net);
+            let mut conns = ConnManager::<Stalled>::new(ConnConfig { write_to: 100, ..ConnConfig::default() });
+
+            let start = clock::now();
+            assert!(conns.send(me.peer(), Some(addr), &me, &[1]).await.is_err());
+            assert_eq!(clock::elapsed(start), Duration::from_millis(100));
+            assert!(!conns.is_connected(me.peer()));
+            assert_eq!(conns.take_events(), vec![
+                (me.peer().to_owned(), ConnState::Connected),
+                (me.peer().to_owned(), ConnState::Backoff(1)),
+            ]);
+        });
+    }
+}
@@ -30,4 +30,35 @@ impl Display for BadFieldError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Bad field error: {}", self.describe)
     }
-}
\ No newline at end of file
+}
+
+/// Errors happened sending to a peer through ConnManager
+#[derive(Debug)]
+pub struct ConnError {
+    describe: String,
+    boxed_error: Box<dyn Error>
+}
+
+
+impl ConnError {
+    pub fn new<S: ToString>(des: S, err: impl Error + 'static) -> Self {
+        Self {
+            describe: des.to_string(),
+            boxed_error: Box::new(err)
+        }
+    }
+}
+
+
+impl Error for ConnError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+
+impl Display for ConnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Connection error: {}", self.describe)
+    }
+}
@@ -0,0 +1,224 @@
+use std::time::Duration;
+
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use futures::channel::oneshot;
+use futures::future;
+use futures::stream::{self, StreamExt};
+use log::{debug, warn};
+
+use yulong::utils::{clock, runtime};
+use yulong_network::identity::Peer;
+use yulong_network::transport::Transport;
+
+use crate::common::MessageWithIp;
+use crate::message::OverlayMessage;
+use crate::overlay::BDN;
+use crate::route_inner::RelayCtl;
+
+
+/// Payload messages delivered to this node, in arrival order. It ends when
+/// the event loop stops.
+pub type PayloadStream = Receiver<OverlayMessage>;
+
+
+// payloads not taken yet beyond this are dropped, protocols on top resend
+const PAYLOAD_QUEUE: usize = 1024;
+
+// commands waiting for the event loop, senders wait beyond this
+const COMMAND_QUEUE: usize = 256;
+
+
+// requests from BdnHandle, the oneshot fires once the message is sent
+enum BdnCommand {
+    SendTo(Peer, OverlayMessage, oneshot::Sender<()>),
+    SendToIndirect(Peer, OverlayMessage, oneshot::Sender<()>),
+    Broadcast(OverlayMessage, oneshot::Sender<()>),
+}
+
+
+enum Event {
+    Ingress(MessageWithIp),
+    Command(BdnCommand),
+    Tick,
+    Shutdown,
+}
+
+
+/// Cloneable sending side of a spawned BDN. The event loop stops once every
+/// handle is dropped.
+#[derive(Clone)]
+pub struct BdnHandle {
+    cmd_sender: Sender<BdnCommand>,
+}
+
+
+impl BdnHandle {
+
+    pub async fn send_to(&self, dst: &Peer, msg: OverlayMessage) {
+        self.call(|done| BdnCommand::SendTo(dst.to_owned(), msg, done)).await
+    }
+
+
+    pub async fn send_to_indirect(&self, dst: &Peer, msg: OverlayMessage) {
+        self.call(|done| BdnCommand::SendToIndirect(dst.to_owned(), msg, done)).await
+    }
+
+
+    pub async fn broadcast(&self, msg: OverlayMessage) {
+        self.call(|done| BdnCommand::Broadcast(msg, done)).await
+    }
+
+
+    async fn call<F>(&self, build: F)
+        where F: FnOnce(oneshot::Sender<()>) -> BdnCommand
+    {
+        let (done, wait) = oneshot::channel();
+
+        if self.cmd_sender.send(build(done)).await.is_err() {
+            warn!("BdnHandle::call event loop is closed");
+            return;
+        }
+
+        // cancelled only if the event loop stops before sending
+        wait.await.unwrap_or_else(|_| {
+            warn!("BdnHandle::call event loop stopped before sending");
+        });
+    }
+}
+
+
+impl<T: Transport, R: RelayCtl + 'static> BDN<T, R> {
+
+    /// Move the node into a background event loop. Incoming messages are
+    /// processed as they arrive, heartbeat and measure timers are checked
+    /// every tick_inv regardless of traffic.
+    ///
+    /// The event loop never waits for the payload stream, payloads arriving
+    /// while PAYLOAD_QUEUE of them are not taken yet are dropped.
+    ///
+    /// Listening is still started separately with BDN::listen, using a clone
+    /// of msg_sender taken before spawning.
+    pub fn spawn(self) -> (BdnHandle, PayloadStream) {
+        let (cmd_sender, cmd_receiver) = channel::bounded(COMMAND_QUEUE);
+        let (payload_sender, payload_receiver) = channel::bounded(PAYLOAD_QUEUE);
+
+        runtime::spawn(self.run(cmd_receiver, payload_sender));
+
+        (BdnHandle { cmd_sender }, payload_receiver)
+    }
+
+
+    async fn run(mut self, commands: Receiver<BdnCommand>, payloads: Sender<OverlayMessage>) {
+        let ingress = self.msg_receiver.clone().map(Event::Ingress);
+
+        let ticks = clock::interval(Duration::from_millis(self.config().tick_inv))
+            .map(|_| Event::Tick);
+
+        // the command stream ends when the last handle is dropped
+        let commands = commands
+            .map(Event::Command)
+            .chain(stream::once(future::ready(Event::Shutdown)));
+
+        let mut events = stream::select(stream::select(ingress, ticks), commands);
+
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Ingress(msg) => {
+                    if let Some(payload) = self.process(msg).await {
+                        match payloads.try_send(payload) {
+                            Ok(()) => {}
+                            Err(TrySendError::Full(_)) => {
+                                warn!("BDN::run payload stream is full, discard payload");
+                            }
+                            Err(TrySendError::Closed(_)) => {
+                                debug!("BDN::run payload stream dropped, discard payload");
+                            }
+                        }
+                    }
+                }
+
+                Event::Tick => self.tick().await,
+
+                Event::Command(cmd) => self.execute(cmd).await,
+
+                Event::Shutdown => break,
+            }
+        }
+
+        debug!("BDN::run event loop stopped");
+    }
+
+
+    async fn execute(&mut self, cmd: BdnCommand) {
+        // the caller may have given up waiting, ignore it
+        match cmd {
+            BdnCommand::SendTo(dst, mut msg, done) => {
+                let _ = self.send_to(&dst, &mut msg).await;
+                let _ = done.send(());
+            }
+
+            BdnCommand::SendToIndirect(dst, mut msg, done) => {
+                self.send_to_indirect(&dst, &mut msg).await;
+                let _ = done.send(());
+            }
+
+            BdnCommand::Broadcast(mut msg, done) => {
+                self.broadcast(&mut msg).await;
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::config::BdnConfig;
+    use crate::msg_header::{MsgHeader, MsgTypeKind, RelayMethodKind};
+    use crate::route_inner::impls::mlbt::MlbtRelayCtlContext;
+    use yulong_sim::{seed_from_env, SimContext, SimNetwork};
+
+    type Node = BDN<SimContext, MlbtRelayCtlContext>;
+
+    #[test]
+    fn handle_and_stream() {
+        let net = SimNetwork::new(seed_from_env());
+        net.block_on(async {
+            let (a_id, b_id) = (net.identity(), net.identity());
+            let config = BdnConfig::default();
+            let a_addr = SocketAddr::new(net.add_node(&a_id), config.listen_port);
+            net.add_node(&b_id);
+
+            let a = Node::new(a_id.clone(), config.clone());
+            runtime::spawn(Node::listen(config.clone(), a.msg_sender.clone(), a_id.clone()));
+            let (_a_handle, a_payloads) = a.spawn();
+
+            let mut b = Node::new(b_id.clone(), config);
+            b.add_peer(a_id.peer(), a_addr);
+            let (b_handle, _b_payloads) = b.spawn();
+
+            while !net.listening(a_id.peer()) {
+                async_std::task::yield_now().await;
+            }
+
+            let msg = OverlayMessage::new(
+                MsgHeader::build(MsgTypeKind::PAYLOAD_MSG, false, RelayMethodKind::ALL, 0, 0).unwrap(),
+                b_id.peer(),
+                b_id.peer(),
+                a_id.peer(),
+                &[1, 2, 3]
+            );
+            b_handle.send_to(a_id.peer(), msg).await;
+
+            // the payload is on its way once send_to returns
+            let got = clock::timeout(Duration::from_secs(5), a_payloads.recv()).await
+                .expect("payload never arrived")
+                .unwrap();
+            assert_eq!(got.payload(), vec![1, 2, 3]);
+            assert_eq!(got.from(), *b_id.peer());
+        });
+    }
+}
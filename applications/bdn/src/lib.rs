@@ -15,6 +15,13 @@ mod measure;
 pub mod route_inner;
 
 pub mod overlay;
+pub mod handle;
+pub mod conn;
+
+pub mod config;
+
+#[cfg(feature = "sim")]
+pub mod sim;
 
 mod bdn_message {
     include!(concat!(env!("OUT_DIR"), "/bdn.rs"));
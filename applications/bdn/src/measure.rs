@@ -1,57 +1,211 @@
 use std::net::SocketAddr;
 use std::collections::HashMap;
+use std::time::Instant;
+
 use yulong_network::identity::Peer;
-use async_trait::async_trait;
-use log::warn;
+use yulong::utils::{clock, AsBytes};
+use yulong::error::{DumbError, SerializeError, DeserializeError};
+
+use log::{debug, warn};
+use prost::Message;
+use num_traits::{FromPrimitive, ToPrimitive};
+
+use crate::bdn_message::NetMeasure;
 
 // test & update net stat
-// update all net stat
+// update net stat of known peers, a few at a time
 // query net stat
-#[async_trait]
+//
+// measurement is message driven: update & update_all return probes to be sent,
+// measure_callback consumes incoming probes and returns replies, arrival is
+// when the probe was read off the stream
 pub trait NetPref {
 
     fn latency(&self, to: &Peer) -> Option<u64>;
 
     fn bandwidth(&self, to: &Peer) -> Option<u64>;
 
-    async fn update(&mut self, target: &Peer);
+    fn update(&mut self, target: &Peer) -> Vec<(Peer, Vec<u8>)>;
 
-    async fn update_all(&mut self);
+    fn update_all(&mut self) -> Vec<(Peer, Vec<u8>)>;
+
+    fn measure_callback(&mut self, sender: &Peer, msg: &[u8], arrival: Instant) -> Vec<(Peer, Vec<u8>)>;
 }
 
 
-// setup known netstat for test simplicity 
+// setup known netstat for test simplicity
 pub trait NetStatDebug {
     fn set(&mut self, target: &Peer, addr: Option<SocketAddr>, lat: Option<u64>, bw: Option<u64>);
 }
 
 
+#[allow(non_camel_case_types)]
+#[derive(FromPrimitive, ToPrimitive, Clone, Copy, Debug, PartialEq)]
+enum MeasureMsgKind {
+    PING = 0,
+    PONG = 1,
+    TRAIN = 2,
+    TRAIN_REPORT = 3,
+}
+
+
+#[derive(Debug)]
+struct MeasureMessage {
+    kind: MeasureMsgKind,
+    seq: u64,
+    train_idx: u32,
+    train_len: u32,
+    value: u64,
+    padding_len: usize,
+}
+
+
+impl AsBytes for MeasureMessage {
+
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        let protobuf_msg = NetMeasure {
+            kind: ToPrimitive::to_u32(&self.kind).unwrap(),
+            seq: self.seq,
+            train_idx: self.train_idx,
+            train_len: self.train_len,
+            value: self.value,
+            padding: vec![0; self.padding_len],
+        };
+
+        let mut buf: Vec<u8> = Vec::with_capacity(protobuf_msg.encoded_len());
+        match protobuf_msg.encode(&mut buf) {
+            Ok(_) => Ok(buf),
+            Err(error) => Err(SerializeError::new("MeasureMessage::into_bytes", error))
+        }
+    }
+
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        match NetMeasure::decode(buf) {
+            Ok(msg) => {
+                let kind: Option<MeasureMsgKind> = FromPrimitive::from_u32(msg.kind);
+                if kind.is_none() {
+                    warn!("MeasureMessage::from_bytes decode msg kind error");
+                    return Err(DeserializeError::new("decode msg kind error", DumbError));
+                }
+
+                Ok(Self {
+                    kind: kind.unwrap(),
+                    seq: msg.seq,
+                    train_idx: msg.train_idx,
+                    train_len: msg.train_len,
+                    value: msg.value,
+                    padding_len: msg.padding.len(),
+                })
+            }
+            Err(error) => {
+                warn!("MeasureMessage::from_bytes decode error {}", error);
+                Err(DeserializeError::new("decode error", error))
+            }
+        }
+    }
+}
+
+
+impl MeasureMessage {
+    fn new(kind: MeasureMsgKind, seq: u64) -> Self {
+        Self {
+            kind,
+            seq,
+            train_idx: 0,
+            train_len: 0,
+            value: 0,
+            padding_len: 0,
+        }
+    }
+}
+
+
 #[derive(Clone, Copy)]
 struct NetStatEntry {
-    addr: SocketAddr,
+    addr: Option<SocketAddr>,
     latency: u64,   // ms
     bandwidth: u64, // bps
+    latency_sampled: bool,
+    bandwidth_sampled: bool,
 }
 
 
 impl NetStatEntry {
-    pub fn new(addr: SocketAddr) -> Self {
+    pub fn new(addr: Option<SocketAddr>) -> Self {
         Self {
             addr,
             latency: 0,
             bandwidth: 0,
+            latency_sampled: false,
+            bandwidth_sampled: false,
+        }
+    }
+
+
+    fn sample_latency(&mut self, rtt: u64) {
+        if self.latency_sampled {
+            self.latency = smooth(self.latency, rtt, NetStat::LATENCY_GAIN);
+        }
+        else {
+            self.latency = rtt;
+            self.latency_sampled = true;
+        }
+    }
+
+
+    fn sample_bandwidth(&mut self, bw: u64) {
+        if self.bandwidth_sampled {
+            self.bandwidth = smooth(self.bandwidth, bw, NetStat::BANDWIDTH_GAIN);
+        }
+        else {
+            self.bandwidth = bw;
+            self.bandwidth_sampled = true;
         }
     }
 }
 
 
+// exponentially weighted moving average, gain = 1 / 2^shift
+fn smooth(prev: u64, sample: u64, shift: u32) -> u64 {
+    let prev = prev as i128;
+    let sample = sample as i128;
+    (prev + ((sample - prev) >> shift)) as u64
+}
+
+
+// packet train being received
+#[derive(Clone)]
+struct TrainRecord {
+    first_arrival: Instant,
+    last_arrival: Instant,
+    received: u32,
+    bytes_after_first: u64,
+    // train_idx of the probes received, one bit each
+    seen: u32,
+}
+
+
 #[derive(Clone)]
 pub struct NetStat {
     stat_by_peer: HashMap<Peer, NetStatEntry>,
+
+    seq: u64,
+
+    // seq -> (target, send time)
+    pending_ping: HashMap<u64, (Peer, Instant)>,
+
+    // seq -> (target, send time)
+    pending_train: HashMap<u64, (Peer, Instant)>,
+
+    // when probes last went to each peer
+    probed: HashMap<Peer, Instant>,
+
+    // trains sent by others
+    recv_train: HashMap<(Peer, u64), TrainRecord>,
 }
 
 
-#[async_trait]
 impl NetPref for NetStat {
 
     fn latency(&self, to: &Peer) -> Option<u64> {
@@ -76,13 +230,75 @@ impl NetPref for NetStat {
     }
 
 
-    async fn update(&mut self, target: &Peer) {
-        todo!()
+    // one ping for rtt and one packet train for bandwidth
+    fn update(&mut self, target: &Peer) -> Vec<(Peer, Vec<u8>)> {
+        self.expire_pending();
+
+        let mut ret = Vec::new();
+        let now = clock::now();
+        self.probed.insert(target.to_owned(), now);
+
+        let ping_seq = self.next_seq();
+        self.pending_ping.insert(ping_seq, (target.to_owned(), now));
+        ret.push((
+            target.to_owned(),
+            MeasureMessage::new(MeasureMsgKind::PING, ping_seq).into_bytes().unwrap()
+        ));
+
+        let train_seq = self.next_seq();
+        self.pending_train.insert(train_seq, (target.to_owned(), now));
+        for idx in 0..Self::TRAIN_LEN {
+            let mut probe = MeasureMessage::new(MeasureMsgKind::TRAIN, train_seq);
+            probe.train_idx = idx;
+            probe.train_len = Self::TRAIN_LEN;
+            probe.padding_len = Self::TRAIN_PKT_SIZE;
+            ret.push((target.to_owned(), probe.into_bytes().unwrap()));
+        }
+
+        ret
+    }
+
+
+    fn update_all(&mut self) -> Vec<(Peer, Vec<u8>)> {
+        let known: Vec<Peer> = self.stat_by_peer.keys().cloned().collect();
+
+        let mut ret = Vec::new();
+        for target in self.pick(&known) {
+            ret.append(&mut self.update(&target));
+        }
+        ret
     }
 
 
-    async fn update_all(&mut self, ) {
-        todo!()
+    fn measure_callback(&mut self, sender: &Peer, msg: &[u8], arrival: Instant) -> Vec<(Peer, Vec<u8>)> {
+        let size = msg.len();
+        let msg = MeasureMessage::from_bytes(msg);
+        if msg.is_err() {
+            warn!("NetStat::measure_callback {}", msg.unwrap_err());
+            return vec![];
+        }
+        let msg = msg.unwrap();
+
+        match msg.kind {
+            MeasureMsgKind::PING => {
+                let pong = MeasureMessage::new(MeasureMsgKind::PONG, msg.seq);
+                vec![(sender.to_owned(), pong.into_bytes().unwrap())]
+            }
+
+            MeasureMsgKind::PONG => {
+                self.pong_callback(sender, &msg, arrival);
+                vec![]
+            }
+
+            MeasureMsgKind::TRAIN => {
+                self.train_callback(sender, &msg, size, arrival)
+            }
+
+            MeasureMsgKind::TRAIN_REPORT => {
+                self.train_report_callback(sender, &msg);
+                vec![]
+            }
+        }
     }
 
 }
@@ -91,16 +307,16 @@ impl NetPref for NetStat {
 impl NetStatDebug for NetStat {
 
     fn set(&mut self, target: &Peer, addr: Option<SocketAddr>, lat: Option<u64>, bw: Option<u64>) {
-        
+
         let mut handle = self.stat_by_peer.get_mut(target);
-        
+
         if handle.is_none() {
             if addr.is_none() {
                 return;
             }
             self.stat_by_peer.insert(
-                target.to_owned(), 
-                NetStatEntry::new(addr.unwrap())
+                target.to_owned(),
+                NetStatEntry::new(addr)
             );
             handle = self.stat_by_peer.get_mut(target);
         }
@@ -110,10 +326,12 @@ impl NetStatDebug for NetStat {
 
         if let Some(lat) = lat {
             handle.latency = lat;
+            handle.latency_sampled = true;
         }
 
         if let Some(bw) = bw {
             handle.bandwidth = bw;
+            handle.bandwidth_sampled = true;
         }
     }
 
@@ -121,9 +339,321 @@ impl NetStatDebug for NetStat {
 
 
 impl NetStat {
+
+    // probes unanswered for this long are dropped, ms
+    const PROBE_TO: u128 = 10000;
+
+    // at most 32, one bit each in TrainRecord::seen
+    const TRAIN_LEN: u32 = 8;
+    const TRAIN_PKT_SIZE: usize = 8 * 1024;
+
+    // peers probed by a measure round
+    const PROBED_PER_ROUND: usize = 4;
+
+    // trains received at once, from one peer and in total
+    const TRAIN_PER_PEER: usize = 2;
+    const TRAIN_MAX: usize = 64;
+
+    // ewma gain 1/8 for rtt as tcp does, 1/4 for bandwidth
+    const LATENCY_GAIN: u32 = 3;
+    const BANDWIDTH_GAIN: u32 = 2;
+
     pub fn new() -> Self {
         Self {
             stat_by_peer: HashMap::new(),
+            seq: 0,
+            pending_ping: HashMap::new(),
+            pending_train: HashMap::new(),
+            recv_train: HashMap::new(),
+            probed: HashMap::new(),
         }
     }
-}
\ No newline at end of file
+
+
+    /// At most PROBED_PER_ROUND of targets, those probed longest ago or never
+    /// first. A round then costs the same however many peers are known, and
+    /// each of them has its turn.
+    pub fn pick(&self, targets: &[Peer]) -> Vec<Peer> {
+        let mut targets: Vec<(Option<Instant>, &Peer)> = targets.iter()
+            .map(|peer| (self.probed.get(peer).copied(), peer))
+            .collect();
+        targets.sort_by_key(|(probed, _)| *probed);
+
+        targets.into_iter()
+            .take(Self::PROBED_PER_ROUND)
+            .map(|(_, peer)| peer.to_owned())
+            .collect()
+    }
+
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+
+    fn entry_mut(&mut self, peer: &Peer) -> &mut NetStatEntry {
+        self.stat_by_peer.entry(peer.to_owned())
+            .or_insert_with(|| NetStatEntry::new(None))
+    }
+
+
+    fn expire_pending(&mut self) {
+        let fresh = |since: &Instant| clock::elapsed(*since).as_millis() < Self::PROBE_TO;
+        self.pending_ping.retain(|_, (_, sent)| fresh(sent));
+        self.pending_train.retain(|_, (_, sent)| fresh(sent));
+        self.recv_train.retain(|_, r| fresh(&r.first_arrival));
+    }
+
+
+    fn pong_callback(&mut self, sender: &Peer, msg: &MeasureMessage, arrival: Instant) {
+        match self.pending_ping.remove(&msg.seq) {
+            Some((target, sent)) if target == *sender => {
+                let rtt = arrival.saturating_duration_since(sent).as_millis() as u64;
+                debug!("NetStat::pong_callback rtt to {} is {} ms", sender, rtt);
+                self.entry_mut(sender).sample_latency(rtt);
+            }
+            _ => {
+                warn!("NetStat::pong_callback unexpected pong {} from {}", msg.seq, sender);
+            }
+        }
+    }
+
+
+    // the receiver measures the dispersion of the train and reports it back,
+    // size is what the probe took on the wire
+    fn train_callback(&mut self, sender: &Peer, msg: &MeasureMessage, size: usize, arrival: Instant)
+        -> Vec<(Peer, Vec<u8>)>
+    {
+        if msg.train_len > Self::TRAIN_LEN || msg.train_idx >= msg.train_len {
+            warn!("NetStat::train_callback bad train {} of {} from {}", msg.train_idx, msg.train_len, sender);
+            return vec![];
+        }
+
+        let key = (sender.to_owned(), msg.seq);
+        if !self.recv_train.contains_key(&key) {
+            self.expire_pending();
+            let from_sender = self.recv_train.keys().filter(|(peer, _)| peer == sender).count();
+            if from_sender >= Self::TRAIN_PER_PEER || self.recv_train.len() >= Self::TRAIN_MAX {
+                warn!("NetStat::train_callback too many trains, drop train {} from {}", msg.seq, sender);
+                return vec![];
+            }
+        }
+
+        let record = self.recv_train.entry(key.clone()).or_insert(TrainRecord {
+            first_arrival: arrival,
+            last_arrival: arrival,
+            received: 0,
+            bytes_after_first: 0,
+            seen: 0,
+        });
+
+        // a repeated probe is counted once
+        if record.seen & (1 << msg.train_idx) != 0 {
+            return vec![];
+        }
+        record.seen |= 1 << msg.train_idx;
+
+        if record.received > 0 {
+            record.bytes_after_first += size as u64;
+        }
+        record.received += 1;
+        record.last_arrival = arrival;
+
+        if msg.train_idx + 1 < msg.train_len {
+            return vec![];
+        }
+
+        // last probe of the train, lost probes simply shorten the sample
+        let record = self.recv_train.remove(&key).unwrap();
+        if record.received < 2 {
+            return vec![];
+        }
+
+        let dispersion = record.last_arrival.duration_since(record.first_arrival)
+            .as_micros()
+            .max(1);
+        let bw = (record.bytes_after_first as u128 * 8 * 1_000_000 / dispersion) as u64;
+
+        let mut report = MeasureMessage::new(MeasureMsgKind::TRAIN_REPORT, msg.seq);
+        report.value = bw;
+        vec![(sender.to_owned(), report.into_bytes().unwrap())]
+    }
+
+
+    fn train_report_callback(&mut self, sender: &Peer, msg: &MeasureMessage) {
+        match self.pending_train.remove(&msg.seq) {
+            Some((target, _)) if target == *sender => {
+                debug!("NetStat::train_report_callback bw to {} is {} bps", sender, msg.value);
+                self.entry_mut(sender).sample_bandwidth(msg.value);
+            }
+            _ => {
+                warn!("NetStat::train_report_callback unexpected report {} from {}", msg.seq, sender);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    // exchange all messages between a and b until quiet
+    fn exchange(a: &mut NetStat, pa: &Peer, b: &mut NetStat, pb: &Peer, init: Vec<(Peer, Vec<u8>)>) {
+        let mut in_flight: Vec<(Peer, Peer, Vec<u8>)> = init.into_iter()
+            .map(|(dst, msg)| (pa.to_owned(), dst, msg))
+            .collect();
+
+        while let Some((from, dst, msg)) = in_flight.pop() {
+            let replies = if dst == *pb {
+                b.measure_callback(&from, &msg, clock::now())
+            }
+            else {
+                a.measure_callback(&from, &msg, clock::now())
+            };
+            for (next, reply) in replies {
+                in_flight.insert(0, (dst.to_owned(), next, reply));
+            }
+        }
+    }
+
+    #[test]
+    fn ping_and_train() {
+        let pa = Peer::try_from_id(&[1; 32]).unwrap();
+        let pb = Peer::try_from_id(&[2; 32]).unwrap();
+
+        let mut a = NetStat::new();
+        let mut b = NetStat::new();
+
+        assert!(a.latency(&pb).is_none());
+
+        // probes are ordered, keep the train in order when popping from the back
+        let mut probes = a.update(&pb);
+        probes.reverse();
+        exchange(&mut a, &pa, &mut b, &pb, probes);
+
+        assert!(a.latency(&pb).is_some());
+        assert!(a.bandwidth(&pb).unwrap() > 0);
+        assert!(a.pending_ping.is_empty());
+        assert!(a.pending_train.is_empty());
+        assert!(b.recv_train.is_empty());
+    }
+
+    #[test]
+    fn smoothing() {
+        let mut entry = NetStatEntry::new(None);
+        entry.sample_latency(100);
+        assert_eq!(entry.latency, 100);
+        entry.sample_latency(180);
+        assert_eq!(entry.latency, 110);
+        entry.sample_latency(30);
+        assert_eq!(entry.latency, 100);
+    }
+
+    #[test]
+    fn unexpected_pong() {
+        let pb = Peer::try_from_id(&[2; 32]).unwrap();
+        let pc = Peer::try_from_id(&[3; 32]).unwrap();
+        let mut a = NetStat::new();
+
+        let probes = a.update(&pb);
+        let seq = *a.pending_ping.keys().next().unwrap();
+        assert!(!probes.is_empty());
+
+        // pong for the right seq but from the wrong peer is ignored
+        let pong = MeasureMessage::new(MeasureMsgKind::PONG, seq).into_bytes().unwrap();
+        a.measure_callback(&pc, &pong, clock::now());
+        assert!(a.latency(&pc).is_none());
+    }
+
+    #[test]
+    fn train_dispersion() {
+        let pa = Peer::try_from_id(&[1; 32]).unwrap();
+        let pb = Peer::try_from_id(&[2; 32]).unwrap();
+        let mut a = NetStat::new();
+        let mut b = NetStat::new();
+
+        // probes arrive 1 ms apart, the first one only starts the clock
+        let probes: Vec<Vec<u8>> = a.update(&pb).into_iter().skip(1).map(|(_, probe)| probe).collect();
+        let start = clock::now();
+        let mut reports = vec![];
+        for (n, probe) in probes.iter().enumerate() {
+            let arrival = start + Duration::from_millis(n as u64);
+            reports.extend(b.measure_callback(&pa, probe, arrival));
+        }
+
+        let after_first: usize = probes[1..].iter().map(|probe| probe.len()).sum();
+        let expected = after_first as u64 * 8 * 1000 / (probes.len() as u64 - 1);
+        assert_eq!(reports.len(), 1);
+        let report = MeasureMessage::from_bytes(&reports[0].1).unwrap();
+        assert_eq!(report.value, expected);
+
+        a.measure_callback(&pb, &reports[0].1, clock::now());
+        assert_eq!(a.bandwidth(&pb), Some(expected));
+    }
+
+    #[test]
+    fn train_bounds() {
+        let pa = Peer::try_from_id(&[1; 32]).unwrap();
+        let mut b = NetStat::new();
+
+        let probe = |seq: u64, idx: u32, len: u32| {
+            let mut probe = MeasureMessage::new(MeasureMsgKind::TRAIN, seq);
+            probe.train_idx = idx;
+            probe.train_len = len;
+            probe.into_bytes().unwrap()
+        };
+
+        // longer trains than any sent, or probes past their end, are dropped
+        b.measure_callback(&pa, &probe(1, 0, NetStat::TRAIN_LEN + 1), clock::now());
+        b.measure_callback(&pa, &probe(1, 4, 4), clock::now());
+        assert!(b.recv_train.is_empty());
+
+        // one peer cannot open trains without end
+        for seq in 0..10 {
+            b.measure_callback(&pa, &probe(seq, 0, 4), clock::now());
+        }
+        assert_eq!(b.recv_train.len(), NetStat::TRAIN_PER_PEER);
+
+        // nor can many peers together
+        for id in 100..=100 + NetStat::TRAIN_MAX as u8 {
+            let peer = Peer::try_from_id(&[id; 32]).unwrap();
+            b.measure_callback(&peer, &probe(0, 0, 4), clock::now());
+        }
+        assert_eq!(b.recv_train.len(), NetStat::TRAIN_MAX);
+
+        // a probe repeated within a train counts once
+        let pc = Peer::try_from_id(&[1; 32]).unwrap();
+        let mut c = NetStat::new();
+        for _ in 0..10 {
+            c.measure_callback(&pc, &probe(1, 0, 4), clock::now());
+        }
+        assert_eq!(c.recv_train[&(pc, 1)].received, 1);
+    }
+
+    #[test]
+    fn probe_in_turn() {
+        let peers: Vec<Peer> = (1..=6).map(|i| Peer::try_from_id(&[i; 32]).unwrap()).collect();
+        let mut a = NetStat::new();
+
+        let first = a.pick(&peers);
+        assert_eq!(first, peers[..4]);
+        for peer in first.iter() {
+            a.update(peer);
+        }
+
+        // the two left out come first next time
+        let second = a.pick(&peers);
+        assert_eq!(second.len(), 4);
+        assert_eq!(second[..2], peers[4..]);
+
+        for peer in peers.iter() {
+            a.set(peer, Some(SocketAddr::from(([127, 0, 0, 1], 9000))), None, None);
+        }
+        let mut targets: Vec<Peer> = a.update_all().into_iter().map(|(peer, _)| peer).collect();
+        targets.dedup();
+        assert_eq!(targets.len(), 4);
+    }
+}
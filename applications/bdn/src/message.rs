@@ -1,10 +1,14 @@
+use std::collections::VecDeque;
 use std::fmt::Display;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{convert::TryInto, mem::size_of};
 
 use log::warn;
 
-use yulong::utils::AsBytes;
+use yulong::utils::{clock, AsBytes};
 
 use yulong::error::{
     DeserializeError,
@@ -22,7 +26,7 @@ use yulong_network::{
 use prost::Message;
 use crate::bdn_message::BdnMessage;
 use async_std::io::BufReader;
-use futures::{AsyncReadExt};
+use futures::{ready, AsyncRead, AsyncReadExt};
 
 use crate::configs::MSG_MAXLEN;
 use crate::msg_header;
@@ -39,6 +43,9 @@ pub struct OverlayMessage {
     dst_id: Peer,
 
     payload: Vec<u8>,
+
+    // when it was read off the stream, local and not on the wire
+    arrival: Option<Instant>,
 }
 
 impl OverlayMessage {
@@ -58,6 +65,7 @@ impl OverlayMessage {
             from_id: from_id.to_owned(),
             dst_id: dst_id.to_owned(),
             payload: payload.to_vec(),
+            arrival: None,
         }
     }
 
@@ -87,6 +95,16 @@ impl OverlayMessage {
     }
 
 
+    pub fn arrival(&self) -> Option<Instant> {
+        self.arrival
+    }
+
+
+    pub(crate) fn set_arrival(&mut self, arrival: Instant) {
+        self.arrival = Some(arrival)
+    }
+
+
     pub fn src(&self) -> Peer {
         self.src_id.clone()
     }
@@ -233,6 +251,7 @@ impl OverlayMessage {
                     from_id: from_peer.unwrap(),
                     dst_id: dst_peer.unwrap(),
                     payload: m.payload,
+                    arrival: None,
                 })
             }
 
@@ -308,14 +327,24 @@ impl AsBytes for OverlayMessage {
 }
 
 
+/// Reads messages off an ingress stream through a buffer of capacity bytes.
+///
+/// The arrival of a message is when its last byte came off the stream, not
+/// when it is taken out of the buffer, one read may bring in several.
 pub struct MessageReader<T: Transport>  {
-    inner: BufReader<<T as Transport>::Stream>,
+    inner: BufReader<Stamped<<T as Transport>::Stream>>,
+
+    // bytes taken out of inner
+    consumed: u64,
 }
 
 
 impl<T: Transport> MessageReader<T> {
-    pub fn new(inner_reader: BufReader<<T as Transport>::Stream>) -> Self {
-        Self {inner: inner_reader}
+    pub fn new(stream: <T as Transport>::Stream, capacity: usize) -> Self {
+        Self {
+            inner: BufReader::with_capacity(capacity, Stamped::new(stream)),
+            consumed: 0,
+        }
     }
 
     pub async fn read_message(&mut self) -> Result<Option<OverlayMessage>, DeserializeError> {
@@ -339,7 +368,58 @@ impl<T: Transport> MessageReader<T> {
             ));
         }
 
-        OverlayMessage::der_protobf_payload(&payload_buf).map(|m| Some(m))
+        self.consumed += (len_buf.len() + len) as u64;
+        let arrival = self.inner.get_mut().arrival(self.consumed);
+
+        OverlayMessage::der_protobf_payload(&payload_buf).map(|mut m| {
+            m.set_arrival(arrival);
+            Some(m)
+        })
+    }
+}
+
+
+// a stream that notes when each read of it returned, and how many bytes it
+// had given out by then
+struct Stamped<S> {
+    inner: S,
+    read: u64,
+
+    // (bytes read in total, when), oldest first
+    stamps: VecDeque<(u64, Instant)>,
+}
+
+
+impl<S> Stamped<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            read: 0,
+            stamps: VecDeque::new(),
+        }
+    }
+
+    // when the n-th byte came off the stream, earlier reads are forgotten
+    fn arrival(&mut self, n: u64) -> Instant {
+        while matches!(self.stamps.front(), Some((read, _)) if *read < n) {
+            self.stamps.pop_front();
+        }
+
+        // the byte was read, so a read covers it, safe unwrap
+        self.stamps.front().unwrap().1
+    }
+}
+
+
+impl<S: AsyncRead + Unpin> AsyncRead for Stamped<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if n > 0 {
+            self.read += n as u64;
+            let read = self.read;
+            self.stamps.push_back((read, clock::now()));
+        }
+        Poll::Ready(Ok(n))
     }
 }
 
@@ -411,9 +491,12 @@ impl MsgWithPriority {
 mod test {
     
     use super::*;
+    use std::net::SocketAddr;
     use crate::msg_header::{MsgHeader, MsgTypeKind, RelayMethodKind};
+    use futures::AsyncWriteExt;
     use log::debug;
     use yulong::log::setup_logger;
+    use yulong_sim::{seed_from_env, SimContext, SimNetwork};
 
     #[test]
     fn message_serde() {
@@ -526,4 +609,38 @@ mod test {
         assert_eq!(msg.get_fanout(), 1);
         assert_eq!(msg.get_ttl(), 15);
     }
-}
\ No newline at end of file
+
+    // messages read in at once arrived together, however late they are
+    // taken out of the buffer
+    #[test]
+    fn reader_stamps_arrival() {
+        let net = SimNetwork::new(seed_from_env());
+        net.block_on(async {
+            let (a, b) = (net.identity(), net.identity());
+            let addr = SocketAddr::new(net.add_node(&a), 9000);
+            net.add_node(&b);
+
+            let mut listener = SimContext::listen(&SocketAddr::from(([0, 0, 0, 0], 9000))).await.unwrap();
+            let accept = async {
+                let incoming = SimContext::accept(&mut listener, &a).await.unwrap();
+                SimContext::upgrade(incoming, &a).await.unwrap()
+            };
+            let (istream, opened) = futures::join!(accept, SimContext::connect(&addr, &b));
+            let (mut out, _) = opened.unwrap();
+            let mut reader = MessageReader::<SimContext>::new(istream.stream, 1024);
+
+            let frame = |payload: &[u8]| OverlayMessage::new(0, b.peer(), b.peer(), a.peer(), payload).into_bytes().unwrap();
+            out.write_all(&[frame(b"one"), frame(b"two")].concat()).await.unwrap();
+
+            let one = reader.read_message().await.unwrap().unwrap();
+            clock::sleep(Duration::from_millis(50)).await;
+            let two = reader.read_message().await.unwrap().unwrap();
+            assert_eq!(two.payload(), b"two");
+            assert_eq!(two.arrival(), one.arrival());
+
+            out.write_all(&frame(b"three")).await.unwrap();
+            let three = reader.read_message().await.unwrap().unwrap();
+            assert!(three.arrival().unwrap() >= one.arrival().unwrap() + Duration::from_millis(50));
+        });
+    }
+}
@@ -1,22 +1,19 @@
-use futures::AsyncWriteExt;
-
-use yulong::utils::{bidirct_hashmap::BidirctHashmap, AsBytes, CasualTimer};
+use yulong::error::DumbError;
+use yulong::utils::{bidirct_hashmap::BidirctHashmap, clock, runtime, AsBytes, CasualTimer};
 
 use yulong_network::{identity::Me, identity::Peer, transport::Transport};
 
 use std::{
-    collections::HashMap,
     collections::BinaryHeap,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::mpsc,
-    time::{Duration, SystemTime, Instant},
+    time::{Duration, SystemTime},
 };
 
-use async_std::io::BufReader;
+use async_std::channel;
+
 use log::{debug, info, warn};
 
 use crate::common::{MessageWithIp, SocketAddrBi};
-use crate::configs::{DEFAULT_BDN_PORT, MSG_MAXLEN};
 use crate::{
     message::{self, OverlayMessage, MsgWithPriority},
     msg_header::MsgTypeKind,
@@ -27,6 +24,10 @@ use crate::{
 use bytes::{Bytes};
 
 use crate::route_inner::RelayCtl;
+use crate::config::BdnConfig;
+use crate::conn::{ConnManager, ConnState};
+use crate::error::ConnError;
+use crate::route_inner::RelayReceipt;
 
 // todo: interface is not done, so make pub for now, change it back later
 pub struct BDN<T: Transport, R: RelayCtl> {
@@ -35,10 +36,10 @@ pub struct BDN<T: Transport, R: RelayCtl> {
     // peer's listening socket
     pub address_book: BidirctHashmap<Peer, SocketAddrBi>,
 
-    w_stream: HashMap<Peer, <T as Transport>::Stream>,
+    conns: ConnManager<T>,
 
-    pub msg_sender: mpsc::Sender<MessageWithIp>,
-    msg_receiver: mpsc::Receiver<MessageWithIp>,
+    pub msg_sender: channel::Sender<MessageWithIp>,
+    pub(crate) msg_receiver: channel::Receiver<MessageWithIp>,
 
     use_send_buffer: bool,
     use_zero_copy: bool,
@@ -48,40 +49,60 @@ pub struct BDN<T: Transport, R: RelayCtl> {
     pub route: Route<R>,
 
     heartbeat_timer: CasualTimer,
-}
 
-impl<T: Transport, R: RelayCtl> BDN<T, R> {
+    measure_timer: CasualTimer,
 
-    const HEARTBEAT_INV: u128 = 5000; // ms
+    config: BdnConfig,
+}
 
-    pub fn new() -> Self {
-        let (sender, receiver) = mpsc::channel::<MessageWithIp>();
+impl<T: Transport, R: RelayCtl> BDN<T, R> {
 
-        // todo: read from config or generate new
-        let id = Me::new();
+    /// Create a BDN node running as id. Use Me::load_or_generate to keep
+    /// the same Peer id across restarts.
+    pub fn new(id: Me, config: BdnConfig) -> Self {
+        let (sender, receiver) = channel::unbounded::<MessageWithIp>();
 
-        let mut timer = CasualTimer::new(Self::HEARTBEAT_INV);
+        let mut timer = CasualTimer::new(config.heartbeat_inv as u128);
         timer.set_now();
 
+        let mut measure_timer = CasualTimer::new(config.measure_inv as u128);
+        measure_timer.set_now();
+
         Self {
             local_identity: id.clone(),
 
             address_book: BidirctHashmap::<Peer, SocketAddrBi>::new(),
 
-            w_stream: HashMap::<Peer, <T as Transport>::Stream>::new(),
+            conns: ConnManager::new(config.conn.clone()),
             msg_sender: sender,
             msg_receiver: receiver,
             use_send_buffer: true,
             use_zero_copy: false,
             send_buffer: BinaryHeap::new(),
-            route: Route::new(&id.peer()),
+            route: Route::new(&id.peer(), &config),
             heartbeat_timer: timer,
+            measure_timer,
+            config,
         }
     }
 
 
+    pub fn config(&self) -> &BdnConfig {
+        &self.config
+    }
+
+
+    /// Outgoing connection state of peer, None if never contacted.
+    pub fn conn_state(&self, peer: &Peer) -> Option<ConnState> {
+        self.conns.state(peer)
+    }
+
+
     // accept incoming connections and spawn tasks to serve them
-    pub async fn listen(listen_port: u16, msg_sender: mpsc::Sender<MessageWithIp>) {
+    // every stream is authenticated as its remote peer on its own task, a
+    // peer stalling the handshake holds up no other one
+    pub async fn listen(config: BdnConfig, msg_sender: channel::Sender<MessageWithIp>, local_identity: Me) {
+        let listen_port = config.listen_port;
         let mut listener = T::listen(&SocketAddr::new(
             IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
             listen_port,
@@ -92,18 +113,30 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
 
         info!("BDN listening on {}", listen_port);
 
-        while match T::accept(&mut listener).await {
-            Ok(istream) => {
+        while match T::accept(&mut listener, &local_identity).await {
+            Ok(incoming) => {
                 // a new incoming connection
 
-                let ip = istream.remote_addr.ip();
-                let incoming_port = istream.remote_addr.port();
-                let socket = SocketAddrBi::new(ip, DEFAULT_BDN_PORT, Some(incoming_port));
-
                 let sender = msg_sender.clone();
-
-                async_std::task::spawn(async move {
-                    Self::handle_ingress(istream.stream, sender, socket).await;
+                let msg_maxlen = config.msg_maxlen;
+                let local_identity = local_identity.clone();
+
+                runtime::spawn(async move {
+                    // TransportError is not Send, log it before any other await
+                    let istream = match T::upgrade(incoming, &local_identity).await {
+                        Ok(istream) => istream,
+                        Err(error) => {
+                            warn!("BDN::listen: {}", error);
+                            return;
+                        }
+                    };
+
+                    let ip = istream.remote_addr.ip();
+                    let incoming_port = istream.remote_addr.port();
+                    // nodes of a deployment share the same listening port
+                    let socket = SocketAddrBi::new(ip, listen_port, Some(incoming_port));
+
+                    Self::handle_ingress(istream.stream, sender, socket, istream.remote_peer, msg_maxlen).await;
                 });
 
                 true
@@ -117,21 +150,22 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
     }
 
 
-    pub async fn connect(&mut self) {
-        for (peer, addr) in self.address_book.iter() {
-            if self.w_stream.contains_key(peer) {
-                continue;
-            }
+    /// Remember that peer listens on addr, e.g. as known from a config file.
+    /// It is dialed on BDN::connect or once something is sent to it.
+    pub fn add_peer(&mut self, peer: &Peer, addr: SocketAddr) {
+        self.address_book.insert(peer, &SocketAddrBi::new(addr.ip(), addr.port(), None));
+    }
 
-            let con_socket = SocketAddr::new(addr.ip(), addr.listen_port());
 
-            match T::connect(&con_socket).await {
-                Ok(stream) => {
-                    self.w_stream.insert(peer.clone(), stream);
-                }
-                Err(error) => {
-                    warn!("BDN::connect: {}", error);
-                }
+    pub async fn connect(&mut self) {
+        let targets: Vec<(Peer, SocketAddr)> = self.address_book.iter()
+            .filter(|(peer, _)| !self.conns.is_connected(peer))
+            .map(|(peer, addr)| (peer.to_owned(), SocketAddr::new(addr.ip(), addr.listen_port())))
+            .collect();
+
+        for (peer, addr) in targets {
+            if let Err(error) = self.conns.open(&peer, addr, &self.local_identity).await {
+                warn!("BDN::connect: {}", error);
             }
         }
     }
@@ -143,7 +177,8 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
             self.send_to_buffered(dst, msg, pri.unwrap())
         }
         else {
-            async_std::task::block_on(
+            // failures are logged by send_to
+            let _ = runtime::block_on(
                 self.send_to(dst, msg)
             );
         }
@@ -156,96 +191,43 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
     }
 
 
-    pub async fn send_to_raw_message(&mut self, dst: &Peer, msg_bytes: &[u8]) {
-        if let Some(wstream) = self.w_stream.get_mut(&dst) {
-            // use existing connection to dst
-            wstream.write_all(msg_bytes).await.unwrap_or_else(|err| {
-                warn!("BDN::send_to write error: {}", err);
-            });
-            return;
-        }
-
-        // no established stream, connect and send
-        let addr = self.address_book.get_by_key(&dst);
-
-        if addr.is_none() {
-            warn!("BDN::send_to unknown dst: {:?}", &dst.get_id());
-            return;
-        }
-
-        let addr = addr.unwrap();
-        let con_socket = SocketAddr::new(addr.ip(), addr.listen_port());
-
-        match T::connect(&con_socket).await {
-            Ok(mut wstream) => {
-                wstream.write_all(msg_bytes).await.unwrap_or_else(|err| {
-                    warn!("BDN::send_to write error: {}", err);
-                });
-                self.w_stream.insert(dst.clone(), wstream);
-            }
-
-            Err(error) => {
-                warn!(
-                    "BDN::send_to encounter an error when connecting {}. Error: {}",
-                    con_socket, error
-                );
-            }
-        }
+    pub async fn send_to_raw_message(&mut self, dst: &Peer, msg_bytes: &[u8]) -> Result<(), ConnError> {
+        self.send_bytes(dst, msg_bytes).await
     }
 
 
-    // async send
-    pub async fn send_to(&mut self, dst: &Peer, msg: &mut message::OverlayMessage) {
+    // async send, failures are logged before being returned
+    pub async fn send_to(&mut self, dst: &Peer, msg: &mut message::OverlayMessage) -> Result<(), ConnError> {
         // allow fail
         msg.set_timestamp_now();
 
         // todo: msg is cloned here, which may not be efficient
-        let msg_bytes = msg.into_bytes();
-
-        if msg_bytes.is_err() {
-            warn!("BDN::send_to: {}", msg_bytes.unwrap_err());
-            return;
-        }
-        let msg_bytes = msg_bytes.unwrap();
-
-        // send through an existing stream
+        // SerializeError is not Send, do not keep it across the awaits below
+        let msg_bytes = match msg.into_bytes() {
+            Ok(msg_bytes) => msg_bytes,
+            Err(error) => {
+                warn!("BDN::send_to: {}", error);
+                return Err(ConnError::new(format!("serialize: {}", error), DumbError));
+            }
+        };
 
         debug!("BDN::send_to: {} bytes", msg_bytes.len());
 
-        if let Some(wstream) = self.w_stream.get_mut(&dst) {
-            // use existing connection to dst
-            wstream.write_all(&msg_bytes).await.unwrap_or_else(|err| {
-                warn!("BDN::send_to write error: {}", err);
-            });
-            return;
-        }
-
-        // no established stream, connect and send
-        let addr = self.address_book.get_by_key(&dst);
-
-        if addr.is_none() {
-            warn!("BDN::send_to unknown dst: {:?}", &dst.get_id());
-            return;
-        }
+        self.send_bytes(dst, &msg_bytes).await
+    }
 
-        let addr = addr.unwrap();
-        let con_socket = SocketAddr::new(addr.ip(), addr.listen_port());
 
-        match T::connect(&con_socket).await {
-            Ok(mut wstream) => {
-                wstream.write_all(&msg_bytes).await.unwrap_or_else(|err| {
-                    warn!("BDN::send_to write error: {}", err);
-                });
-                self.w_stream.insert(dst.clone(), wstream);
-            }
+    // write through the connection manager, which reconnects when needed
+    async fn send_bytes(&mut self, dst: &Peer, msg_bytes: &[u8]) -> Result<(), ConnError> {
+        let addr = self.address_book.get_by_key(dst)
+            .map(|addr| SocketAddr::new(addr.ip(), addr.listen_port()));
 
-            Err(error) => {
-                warn!(
-                    "BDN::send_to encounter an error when connecting {}. Error: {}",
-                    con_socket, error
-                );
-            }
-        }
+        // ConnError is not Send, callers must not keep it across an await
+        self.conns.send(dst, addr, &self.local_identity, msg_bytes).await
+            .map_err(|error| {
+                warn!("BDN::send_to {}", error);
+                error
+            })
     }
 
 
@@ -258,7 +240,7 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
     // send one buffered msg
     pub fn send_buffered_once(&mut self) {
         if let Some(send_task) = self.send_buffer.pop() {
-            async_std::task::block_on(
+            let _ = runtime::block_on(
                 self.send_to(send_task.dst(), &mut send_task.msg().to_owned())
             );
         }
@@ -268,28 +250,39 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
     // flush send buffer
     pub async fn flush_send_buffer(&mut self) {
         while let Some(send_task) = self.send_buffer.pop() {
-            self.send_to(send_task.dst(), &mut send_task.msg().to_owned()).await;
+            let _ = self.send_to(send_task.dst(), &mut send_task.msg().to_owned()).await;
         }
     }
 
 
+    /// Relay msg to the children of src and report the outcome of each send
+    /// to the relay module.
     pub async fn relay_on(&mut self, src: &Peer, msg: &mut message::OverlayMessage) {
         let relay_list = self.route.get_relay(&src);
+        let mut receipts = Vec::with_capacity(relay_list.len());
 
-        if self.use_zero_copy {
+        let raw_msg = match self.use_zero_copy {
+            true => Some(Bytes::from(Self::prepare_msg_bytes(msg))),
+            false => None,
+        };
 
-            
-            let raw_msg = Bytes::from(Self::prepare_msg_bytes(msg));
+        // send in sequence, the latency of a child covers its own send only
+        for peer in relay_list {
+            let start = clock::now();
 
-            for peer in relay_list {
-                self.send_to_raw_message(&peer, &raw_msg).await
-            }
-        }
-        else {
-            for peer in relay_list {
-                self.send_to(&peer, msg).await
-            }
+            let success = match &raw_msg {
+                Some(raw_msg) => self.send_to_raw_message(&peer, raw_msg).await.is_ok(),
+                None => self.send_to(&peer, msg).await.is_ok(),
+            };
+
+            receipts.push(RelayReceipt {
+                child: peer,
+                success,
+                latency: clock::elapsed(start).as_millis() as u64,
+            });
         }
+
+        self.route.relay_receipt(src, &receipts);
     }
 
    
@@ -308,7 +301,7 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
             msg.set_dst(&Peer::BROADCAST_ID);
 
             // todo: short path for src is self
-            self.send_to(&src, msg).await;
+            let _ = self.send_to(&src, msg).await;
         } else {
             warn!("BDN::broadcast failed because it cannot find a feasible root");
         }
@@ -322,13 +315,15 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
             warn!("Send to {} failed: No route.", &dst);
             return;
         }
-        self.send_to(&next.unwrap(), msg).await;
+        let _ = self.send_to(&next.unwrap(), msg).await;
     }
 
     pub async fn handle_ingress(
         s: <T as Transport>::Stream,
-        sender: mpsc::Sender<MessageWithIp>,
+        sender: channel::Sender<MessageWithIp>,
         remote_sock: SocketAddrBi,
+        remote_peer: Peer,
+        msg_maxlen: usize,
     ) {
         // incoming stream obviously has an incoming port, safe unwrap
         info!(
@@ -338,108 +333,134 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
         );
 
         // create a message reader with an inner buffered reader
-        let mut msg_reader =
-            message::MessageReader::<T>::new(BufReader::with_capacity(MSG_MAXLEN, s));
+        let mut msg_reader = message::MessageReader::<T>::new(s, msg_maxlen);
 
         loop {
             // read one message at a time, including deserialization
-            let msg = msg_reader.read_message().await;
+            // the error is not Send, so it must not live across an await
+            let mut overlay_msg = match msg_reader.read_message().await {
+                Ok(Some(msg)) => msg,
 
-            // encounter an ill-formed message
-            if msg.is_err() {
-                warn!("BDN::handle_ingress: {}", msg.unwrap_err());
-                continue;
-            }
+                // EOF, end this processing task
+                Ok(None) => break,
 
-            // EOF, end this processing task
-            let msg = msg.unwrap();
-            if msg.is_none() {
-                break;
-            }
+                // encounter an ill-formed message
+                Err(error) => {
+                    warn!("BDN::handle_ingress: {}", error);
+                    continue;
+                }
+            };
 
-            let overlay_msg = msg.unwrap();
+            // the stream is bound to remote_peer by the transport handshake,
+            // a different carried identity is a forgery
+            if overlay_msg.from().common() && overlay_msg.from() != remote_peer {
+                warn!(
+                    "BDN::handle_ingress: {} claims to be {}, drop",
+                    remote_peer,
+                    overlay_msg.from()
+                );
+                continue;
+            }
+            overlay_msg.set_from(&remote_peer);
 
             debug!(
                 "BDN::handle_ingress: receive {} bytes payload",
                 overlay_msg.payload().len()
             );
 
-            match sender.send((remote_sock, overlay_msg.clone())) {
-                Ok(_) => {}
-                Err(error) => {
-                    warn!("BDN::handle_ingress: {}", error);
-                    // log the error and continue, only EOF will shutdown the listening thread
-                }
-            };
+            if sender.send((remote_sock, overlay_msg)).await.is_err() {
+                // the event loop is gone, nobody will read from this stream
+                warn!("BDN::handle_ingress: event loop closed, stop serving {}", remote_sock);
+                break;
+            }
         }
     }
 }
 
-/// Main Event Loop for BDN
-/// poll it to activate BDN
+/// Blocking event loop for sync callers, each call runs due timers and
+/// processes at most one incoming message.
 ///
-/// BDN will not actually process incoming messages but only store it until
-/// you poll it.
+/// None means no payload message was delivered this round, e.g. nothing
+/// arrived within tick_inv or the message was a control message, it does not
+/// mean the end of the stream. Async callers should use BDN::spawn instead.
 impl<T: Transport, R: RelayCtl> Iterator for BDN<T, R> {
     type Item = OverlayMessage;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // check heartbeat timer
-        self.check_heartbeat();
+        runtime::block_on(self.poll_once())
+    }
+}
+
+// inner method for main event loop
+impl<T: Transport, R: RelayCtl> BDN<T, R> {
+
+    async fn poll_once(&mut self) -> Option<OverlayMessage> {
+        self.tick().await;
 
-        // get a message from the receiver queue
-        let msg = self.msg_receiver.recv();
+        let wait = Duration::from_millis(self.config.tick_inv);
+        match clock::timeout(wait, self.msg_receiver.recv()).await {
+            Ok(Ok(msg)) => self.process(msg).await,
 
-        // no more messages in the receiver queue
-        if msg.is_err() {
-            return None;
+            // timeout, or every sender is gone
+            _ => None,
         }
+    }
+
+
+    /// Run timers that are due, independent of incoming traffic.
+    pub(crate) async fn tick(&mut self) {
+        // check heartbeat timer
+        self.check_heartbeat().await;
+
+        // check net measure timer
+        self.check_measure().await;
+
+        // close idle streams, retry broken ones and report changes
+        self.check_conn().await;
+    }
+
+
+    /// Handle one incoming message, payload messages are returned to the
+    /// caller, control messages are consumed.
+    pub(crate) async fn process(&mut self, msg: MessageWithIp) -> Option<OverlayMessage> {
 
         // update the identity-address map of incoming node
         // return None if cannot figure out the identity of incoming node
-        let incoming_msg = self.from_id_handler(msg.unwrap());
-        if incoming_msg.is_none() {
-            return None;
-        }
-        let incoming_msg = incoming_msg.unwrap();
-
+        let incoming_msg = self.from_id_handler(msg)?;
 
-        
         // relay module will take a clone in case it changes the message before relaying it
-        self.relay_handler(incoming_msg.clone());
+        self.relay_handler(incoming_msg.clone()).await;
 
         // todo: flush policy
-        async_std::task::block_on(
-            self.flush_send_buffer()
-        );
+        self.flush_send_buffer().await;
+
+        // parse errors are not Send, keep them out of the awaits below
+        let msg_type = incoming_msg.get_type()
+            .map_err(|error| warn!("BDN::process bad msg_type {}", error))
+            .ok();
 
         // dispatch messages
-        match incoming_msg.get_type() {
+        match msg_type {
             // payload_msg is returned to the caller
-            Ok(MsgTypeKind::PAYLOAD_MSG) => Some(incoming_msg),
+            Some(MsgTypeKind::PAYLOAD_MSG) => Some(incoming_msg),
 
-            Ok(MsgTypeKind::ROUTE_MSG) => {
+            Some(MsgTypeKind::ROUTE_MSG) => {
                 // hand it to route module
-                self.route_message_dispatcher(incoming_msg);
+                self.route_message_dispatcher(incoming_msg).await;
                 None
             }
 
-            Ok(MsgTypeKind::NET_MEASURE_MSG) => {
-                // Todo net measure
+            Some(MsgTypeKind::NET_MEASURE_MSG) => {
+                // hand it to netstat
+                self.measure_message_dispatcher(incoming_msg).await;
                 None
             }
 
-            Err(error) => {
-                // cannot parse msg_type from msg header, skip this message
-                warn!("BDN::next bad msg_type {}", error);
-                None
-            }
+            // cannot parse msg_type from msg header, skip this message
+            None => None
         }
     }
-}
 
-// inner method for main event loop
-impl<T: Transport, R: RelayCtl> BDN<T, R> {
     fn from_id_handler(&mut self, msg: (SocketAddrBi, OverlayMessage)) -> Option<OverlayMessage> {
         // clone for modification
         let (from_addr, mut incoming_msg) = msg.to_owned();
@@ -459,7 +480,8 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
             }
             incoming_msg.set_from(stored_peer.unwrap());
         }
-        // contains from peer id, believe carried identity (todo: check sign aforehead)
+        // contains from peer id, which has been checked against the authenticated
+        // stream identity in handle_ingress
         else {
             // if carried peer is unknown, add it to address book
             // else update it
@@ -482,31 +504,23 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
         Some(incoming_msg)
     }
 
-    fn relay_handler(&mut self, mut incoming_msg: OverlayMessage) {
+    async fn relay_handler(&mut self, mut incoming_msg: OverlayMessage) {
         // relay messages
         // handle relay messages in sequence
         if incoming_msg.is_relay() {
 
-            let relay_start = Instant::now();
+            let relay_start = clock::now();
 
-            let src = &incoming_msg.src();
+            let src = incoming_msg.src();
             incoming_msg.set_from(&self.local_identity.peer());
 
-            let relay_list = self.route.get_relay(src);
-            for next_node in relay_list {
-                // send it in sequence
-                async_std::task::block_on(self.send_to(&next_node, &mut incoming_msg));
-            }
-
-            // todo: now send_to intercept all errors so we cannot pass on send failures
-            // to route module
-            self.route.relay_receipt(true);
+            self.relay_on(&src, &mut incoming_msg).await;
 
-            debug!("Relay time consumption: {}", relay_start.elapsed().as_millis());
+            debug!("Relay time consumption: {}", clock::elapsed(relay_start).as_millis());
         }
     }
 
-    fn route_message_dispatcher(&mut self, incoming_msg: OverlayMessage) {
+    async fn route_message_dispatcher(&mut self, incoming_msg: OverlayMessage) {
         // pass it to route module
         let reply_list = self.route.handle_route_message(&incoming_msg);
 
@@ -515,16 +529,74 @@ impl<T: Transport, R: RelayCtl> BDN<T, R> {
             msg.set_src(&self.local_identity.peer());
             msg.set_from(&self.local_identity.peer());
 
-            async_std::task::block_on(self.send_to(&msg.dst(), &mut msg));
+            let _ = self.send_to(&msg.dst(), &mut msg).await;
+        }
+    }
+
+    async fn measure_message_dispatcher(&mut self, incoming_msg: OverlayMessage) {
+        let reply_list = self.route.handle_measure_message(&incoming_msg);
+
+        for mut msg in reply_list {
+            msg.set_src(&self.local_identity.peer());
+            msg.set_from(&self.local_identity.peer());
+
+            let _ = self.send_to(&msg.dst(), &mut msg).await;
+        }
+    }
+
+    async fn check_measure(&mut self) {
+        if self.measure_timer.is_timeout() {
+            let targets: Vec<Peer> = self.address_book.iter()
+                .map(|(peer, _)| peer.to_owned())
+                .collect();
+
+            let probe_list = self.route.invoke_measure(&targets);
+
+            // probes of a packet train must leave back-to-back
+            for mut msg in probe_list {
+                msg.set_src(&self.local_identity.peer());
+                msg.set_from(&self.local_identity.peer());
+
+                let _ = self.send_to(&msg.dst(), &mut msg).await;
+            }
+
+            self.measure_timer.set_now();
+        }
+    }
+
+    async fn check_conn(&mut self) {
+        self.conns.evict_idle();
+
+        for peer in self.conns.due() {
+            let addr = match self.address_book.get_by_key(&peer) {
+                Some(addr) => SocketAddr::new(addr.ip(), addr.listen_port()),
+                None => continue,
+            };
+
+            if let Err(error) = self.conns.open(&peer, addr, &self.local_identity).await {
+                debug!("BDN::check_conn retry failed: {}", error);
+            }
+        }
+
+        let events = self.conns.take_events();
+        if events.is_empty() {
+            return;
+        }
+
+        for mut msg in self.route.handle_conn_events(events) {
+            msg.set_src(&self.local_identity.peer());
+            msg.set_from(&self.local_identity.peer());
+
+            let _ = self.send_to(&msg.dst(), &mut msg).await;
         }
     }
 
-    fn check_heartbeat(&mut self) {
+    async fn check_heartbeat(&mut self) {
         if self.heartbeat_timer.is_timeout() {
             let send_list = self.route.invoke_heartbeat();
 
             for mut msg in send_list {
-                async_std::task::block_on(self.send_to(&msg.dst(), &mut msg));
+                let _ = self.send_to(&msg.dst(), &mut msg).await;
             }
 
             self.heartbeat_timer.set_now();
@@ -557,155 +629,3 @@ impl<T: Transport, R: RelayCtl> AppLayerRouteUser for BDN<T, R> {
         self.route.get_relay(src)
     }
 }
-
-#[cfg(test)]
-mod test {
-    use std::{net::IpAddr, str::FromStr};
-
-    use crate::{message, overlay::SocketAddrBi, route::AppLayerRouteUser};
-
-    use super::BDN;
-    use crate::route_inner::impls::mlbt::MlbtRelayCtlContext;
-    use ::log::debug;
-    use yulong_quic::QuicContext;
-    use yulong_tcp::TcpContext;
-
-    use async_std::{self};
-    use yulong::log;
-    use yulong_network::identity::Peer;
-
-    #[async_std::test]
-    async fn bdn_1() {
-        log::setup_logger("bdn_test1").unwrap();
-
-        let mut bdn = BDN::<QuicContext, MlbtRelayCtlContext>::new();
-
-        let peer = Peer::from_random();
-        let socket = SocketAddrBi::new(IpAddr::from_str("127.0.0.1").unwrap(), 9002_u16, None);
-
-        println!(
-            "New BDN client at: {:?}",
-            bdn.local_identity.peer().get_id()
-        );
-        bdn.address_book.insert(&peer, &socket);
-
-        let payload = [42_u8; 1900];
-
-        let server = async_std::task::spawn(BDN::<QuicContext, MlbtRelayCtlContext>::listen(
-            9001,
-            bdn.msg_sender.clone(),
-        ));
-
-        let mut m1 = message::OverlayMessage::new(
-            0b00110000000000000000000000000000,
-            &peer,
-            &peer,
-            &peer,
-            &[1, 2, 3],
-        );
-
-        let mut m2 = message::OverlayMessage::new(
-            0b00110000000000000000000000000000,
-            &peer,
-            &peer,
-            &peer,
-            &[1, 2, 3, 4, 5, 6],
-        );
-
-        let mut m3 = message::OverlayMessage::new(
-            0b00110000000000000000000000000000,
-            &peer,
-            &peer,
-            &peer,
-            &payload,
-        );
-
-        let mut m4 = message::OverlayMessage::new(
-            0b00110000000000000000000000000000,
-            &peer,
-            &peer,
-            &peer,
-            &[1, 2, 3],
-        );
-
-        bdn.connect().await;
-        bdn.send_to(&peer, &mut m1).await;
-        bdn.send_to(&peer, &mut m2).await;
-        bdn.send_to(&peer, &mut m3).await;
-        bdn.send_to(&peer, &mut m4).await;
-
-        loop {
-            if let Some(msg) = bdn.next() {
-                debug!("recv:\n{}", &msg);
-            }
-        }
-    }
-
-    #[async_std::test]
-    async fn bdn_2() {
-        log::setup_logger("bdn_test2").unwrap();
-
-        let mut bdn = BDN::<QuicContext, MlbtRelayCtlContext>::new();
-
-        let peer = Peer::from_random();
-        let socket = SocketAddrBi::new(IpAddr::from_str("127.0.0.1").unwrap(), 9001_u16, None);
-
-        println!(
-            "New BDN client at: {:?}",
-            bdn.local_identity.peer().get_id()
-        );
-        bdn.address_book.insert(&peer, &socket);
-
-        let payload = [42_u8; 1900];
-
-        let server = async_std::task::spawn(BDN::<QuicContext, MlbtRelayCtlContext>::listen(
-            9002,
-            bdn.msg_sender.clone(),
-        ));
-
-        let mut m1 = message::OverlayMessage::new(
-            0b00110000000000000000000000000000,
-            &peer,
-            &peer,
-            &peer,
-            &[1, 2, 3],
-        );
-
-        let mut m2 = message::OverlayMessage::new(
-            0b00110000000000000000000000000000,
-            &peer,
-            &peer,
-            &peer,
-            &[1, 2, 3, 4, 5, 6],
-        );
-
-        let mut m3 = message::OverlayMessage::new(
-            0b00110000000000000000000000000000,
-            &peer,
-            &peer,
-            &peer,
-            &payload,
-        );
-
-        let mut m4 = message::OverlayMessage::new(
-            0b00110000000000000000000000000000,
-            &peer,
-            &peer,
-            &peer,
-            &[1, 2, 3],
-        );
-
-        bdn.connect().await;
-        
-        bdn.send_to(&peer, &mut m1).await;
-        bdn.send_to(&peer, &mut m2).await;
-        bdn.send_to(&peer, &mut m3).await;
-        bdn.send_to(&peer, &mut m4).await;
-
-        loop {
-            if let Some(msg) = bdn.next() {
-                debug!("recv:\n{}", &msg);
-            }
-        }
-    }
-}
@@ -1,20 +1,22 @@
 use log::{info, warn, debug};
 use num_traits::ToPrimitive;
-use yulong::utils::bidirct_hashmap::BidirctHashmap;
+use yulong::utils::{bidirct_hashmap::BidirctHashmap, clock};
 use yulong_network::{identity::Peer};
 use std::hash::Hash;
 use std::{collections::HashMap, fmt::Debug};
 use std::collections::BinaryHeap;
 use std::net::SocketAddr;
-
-use async_trait::async_trait;
+use std::time::Instant;
 
 use crate::msg_header::{MsgHeader, MsgType, MsgTypeKind, RelayMethodKind};
+use crate::config::BdnConfig;
+use crate::conn::ConnState;
 
 use crate::{
     common::MessageWithIp,
     message::OverlayMessage,
     route_inner::RelayCtl,
+    route_inner::RelayReceipt,
     measure::NetStat,
     measure::NetStatDebug,
     measure::NetPref,
@@ -120,16 +122,16 @@ pub struct RouteTable {
 
     relay_counter: u32,
     relay_ct_per_tree: HashMap<Peer, u32>,
-}
 
-impl RouteTable {
-
-    pub const MAX_LINK: u32 = 128;
+    max_link: u32,
 
     // # of delegates
-    const DEL_NUM: u32 = 1;
+    del_num: u32,
+}
 
-    pub fn new(local: &Peer) -> Self {
+impl RouteTable {
+
+    pub fn new(local: &Peer, max_link: u32, del_num: u32) -> Self {
         Self {
 
             local_id: local.to_owned(),
@@ -146,24 +148,42 @@ impl RouteTable {
             
             relay_counter: 0,
             relay_ct_per_tree: HashMap::new(),
+
+            max_link,
+            del_num,
         }
     }
 
 
+    pub fn max_link(&self) -> u32 {
+        self.max_link
+    }
+
+
+    pub fn del_num(&self) -> u32 {
+        self.del_num
+    }
+
+
     pub fn local_id(&self) -> Peer {
         self.local_id.clone()
     }
+
+
+    pub fn contains_src(&self, src: &Peer) -> bool {
+        self.roots.iter().any(|p| p.peer == *src)
+    }
 }
 
 impl<R: RelayCtl> Route<R> {
 
-    pub fn new(local: &Peer) -> Self {
+    pub fn new(local: &Peer, config: &BdnConfig) -> Self {
         
-        let route_table = RouteTable::new(local);
+        let route_table = RouteTable::new(local, config.max_link, config.del_num);
 
         Self {
 
-            relay_mod: R::new(&route_table),
+            relay_mod: R::new(&route_table, config),
             route_table,
             netstat: NetStat::new(),
         }
@@ -236,8 +256,96 @@ impl<R: RelayCtl> Route<R> {
     }
 
 
-    pub fn relay_receipt(&mut self, all_success: bool) {
-        self.relay_mod.relay_receipt(&mut self.route_table, all_success);
+    // accept a net measure message, update netstat and return replies
+    pub fn handle_measure_message(&mut self, msg: &OverlayMessage) -> Vec<OverlayMessage> {
+        let sender = msg.from();
+        let arrival = msg.arrival().unwrap_or_else(clock::now);
+        let replies = self.netstat.measure_callback(&sender, &msg.payload(), arrival);
+
+        // keep the rtt of a known src in line with measurement
+        if self.route_table.contains_src(&sender) {
+            if let Some(rtt) = self.netstat.latency(&sender) {
+                self.route_table.remove_src(&sender);
+                self.route_table.insert_src(&sender, rtt);
+            }
+        }
+
+        replies.into_iter()
+            .map(|(peer, payload)| Self::pack_measure_message(&peer, &payload))
+            .collect()
+    }
+
+
+    // start probing some of targets, invoked temporally
+    pub fn invoke_measure(&mut self, targets: &[Peer]) -> Vec<OverlayMessage> {
+        let mut probes = Vec::new();
+        for target in self.netstat.pick(targets) {
+            probes.append(&mut self.netstat.update(&target));
+        }
+
+        probes.into_iter()
+            .map(|(peer, payload)| Self::pack_measure_message(&peer, &payload))
+            .collect()
+    }
+
+
+    fn pack_measure_message(dst: &Peer, payload: &[u8]) -> OverlayMessage {
+        OverlayMessage::new(
+            MsgHeader::build(
+                MsgTypeKind::NET_MEASURE_MSG,
+                false,
+                RelayMethodKind::LOOKUP_TABLE_1,
+                0,
+                0
+            ).unwrap(),
+
+            // to be filled by caller
+            &Peer::BROADCAST_ID,
+
+            // to be filled by caller
+            &Peer::BROADCAST_ID,
+
+            dst,
+
+            payload
+        )
+    }
+
+
+    // let the relay module react to connection state changes
+    pub fn handle_conn_events(&mut self, events: Vec<(Peer, ConnState)>) -> Vec<OverlayMessage> {
+        let mut ret = Vec::<OverlayMessage>::new();
+
+        for (peer, state) in events {
+            let ctl_msgs = self.relay_mod.conn_state_change(&mut self.route_table, &peer, state);
+            for (dst, payload) in ctl_msgs {
+                ret.push(OverlayMessage::new(
+                    MsgHeader::build(
+                        MsgTypeKind::ROUTE_MSG,
+                        false,
+                        RelayMethodKind::LOOKUP_TABLE_1,
+                        0,
+                        0
+                    ).unwrap(),
+
+                    // to be filled by caller
+                    &Peer::BROADCAST_ID,
+
+                    // to be filled by caller
+                    &Peer::BROADCAST_ID,
+
+                    &dst,
+
+                    &payload
+                ));
+            }
+        }
+        ret
+    }
+
+
+    pub fn relay_receipt(&mut self, src: &Peer, receipts: &[RelayReceipt]) {
+        self.relay_mod.relay_receipt(&mut self.route_table, src, receipts);
     }
 
 }
@@ -520,7 +628,6 @@ impl<R: RelayCtl> AppLayerRouteInner for Route<R> {
 
 
 // wrap it for calling convenient
-#[async_trait]
 impl<R: RelayCtl> NetPref for Route<R> {
 
     fn latency(&self, to: &Peer) -> Option<u64> {
@@ -533,13 +640,18 @@ impl<R: RelayCtl> NetPref for Route<R> {
     }
 
 
-    async fn update(&mut self, target: &Peer) {
-        self.netstat.update(target).await;
+    fn update(&mut self, target: &Peer) -> Vec<(Peer, Vec<u8>)> {
+        self.netstat.update(target)
+    }
+
+
+    fn update_all(&mut self) -> Vec<(Peer, Vec<u8>)> {
+        self.netstat.update_all()
     }
 
 
-    async fn update_all(&mut self) {
-        self.netstat.update_all().await;
+    fn measure_callback(&mut self, sender: &Peer, msg: &[u8], arrival: Instant) -> Vec<(Peer, Vec<u8>)> {
+        self.netstat.measure_callback(sender, msg, arrival)
     }
 }
 
@@ -569,7 +681,7 @@ mod test {
         let p3 = Peer::from_bytes(&[3]);
         let p4 = Peer::from_bytes(&[4]);
 
-        let mut route = Route::<MlbtRelayCtlContext>::new(&p1);
+        let mut route = Route::<MlbtRelayCtlContext>::new(&p1, &BdnConfig::default());
 
         route.insert_relay(&p1, &p3);
         route.insert_relay(&p1, &p4);
@@ -584,6 +696,44 @@ mod test {
     }
 
 
+    #[test]
+    fn relay_receipt_detach() {
+        let p1 = Peer::from_bytes(&[1]);
+        let p2 = Peer::from_bytes(&[2]);
+        let p3 = Peer::from_bytes(&[3]);
+        let p4 = Peer::from_bytes(&[4]);
+
+        let mut route = Route::<MlbtRelayCtlContext>::new(&p1, &BdnConfig::default());
+        route.insert_src(&p2, 10);
+        route.insert_relay(&p2, &p3);
+        route.insert_relay(&p2, &p4);
+
+        let receipts = vec![
+            RelayReceipt { child: p3.clone(), success: true, latency: 5 },
+            RelayReceipt { child: p4.clone(), success: false, latency: 1 },
+        ];
+
+        // sends to a child in backoff are not tried and do not count
+        route.handle_conn_events(vec![(p4.clone(), ConnState::Backoff(1))]);
+        for _ in 0..5 {
+            route.relay_receipt(&p2, &receipts);
+        }
+        assert_eq!(route.get_relay(&p2), vec![p3.clone(), p4.clone()]);
+        route.handle_conn_events(vec![(p4.clone(), ConnState::Connected)]);
+
+        // detached after relay_fail_max consecutive failures
+        route.relay_receipt(&p2, &receipts);
+        route.relay_receipt(&p2, &receipts);
+        assert_eq!(route.get_relay(&p2), vec![p3.clone(), p4.clone()]);
+        route.relay_receipt(&p2, &receipts);
+        assert_eq!(route.get_relay(&p2), vec![p3.clone()]);
+
+        // an unreachable peer is dropped from every tree at once
+        route.handle_conn_events(vec![(p3.clone(), ConnState::Unreachable)]);
+        assert!(route.get_relay(&p2).is_empty());
+    }
+
+
     #[test]
     fn insert_remove_route() {
         log::setup_logger("route_test").unwrap();
@@ -593,7 +743,7 @@ mod test {
         let p3 = Peer::from_bytes(&[3]);
         let p4 = Peer::from_bytes(&[4]);
 
-        let mut route = Route::<MlbtRelayCtlContext>::new(&p1);
+        let mut route = Route::<MlbtRelayCtlContext>::new(&p1, &BdnConfig::default());
 
         route.insert_path(&p4, &p3);
         route.insert_path(&p3, &p3);
@@ -1,6 +1,7 @@
 use std::cmp::min;
 use std::collections::{
     HashMap,
+    HashSet,
     BinaryHeap
 };
 use std::hash::Hash;
@@ -10,11 +11,14 @@ use crate::msg_header::MsgTypeKind;
 use crate::msg_header::RelayMethodKind;
 
 use crate::route::{AppLayerRouteUser, RouteTable, AppLayerRouteInner};
+use crate::config::BdnConfig;
 
 use crate::route_inner::impls::mlbt_message::RelayMsgGrantInfo;
 use crate::route_inner::impls::mlbt_stat::MlbtStatDebug;
+use crate::conn::ConnState;
 use crate::route_inner::{
     RelayCtl,
+    RelayReceipt,
     impls::{
         mlbt_message::RelayMsgKind,
         mlbt_stat::MlbtStatList,
@@ -45,6 +49,15 @@ pub struct MlbtRelayCtlContext {
     mlbt_stat: MlbtStatList,
 
     grant_prev: bool,
+
+    // consecutive relay failures by (src, child)
+    relay_fail: HashMap<(Peer, Peer), u32>,
+
+    relay_fail_max: u32,
+
+    // peers the connection manager waits to retry, sends to them fail
+    // without being tried
+    backoff: HashSet<Peer>,
 }
 
 
@@ -136,17 +149,23 @@ impl MlbtTermList {
 
 impl RelayCtl for MlbtRelayCtlContext {
 
-    fn new(route_ctl: &RouteTable) -> Self {
+    fn new(route_ctl: &RouteTable, config: &BdnConfig) -> Self {
         Self {
             local_id: 0,
             
             state: MlbtTermList::new(route_ctl),
 
-            wait_list: WaitList::new(),
+            wait_list: WaitList::new(config.mlbt.clone()),
 
             mlbt_stat: MlbtStatList::new(),
 
             grant_prev: false,
+
+            relay_fail: HashMap::new(),
+
+            relay_fail_max: config.mlbt.relay_fail_max,
+
+            backoff: HashSet::new(),
         }
     }
 
@@ -309,12 +328,56 @@ impl RelayCtl for MlbtRelayCtlContext {
         ret
     }
 
-    fn relay_receipt(&mut self, route_ctl: &mut RouteTable, all_success: bool) {
-        
-        // relay finish time
-        // can be used to update self relay time consumption
+    fn relay_receipt(&mut self, route_ctl: &mut RouteTable, src: &Peer, receipts: &[RelayReceipt]) {
 
-        // todo
+        // a child in backoff was not tried, it tells nothing about the relay
+        // and the connection manager already deals with it
+        let tried: Vec<&RelayReceipt> = receipts.iter()
+            .filter(|r| !self.backoff.contains(&r.child))
+            .collect();
+
+        // children are sent to in sequence, so the relay finish time
+        // is the sum of per child latency
+        if receipts.is_empty() || !tried.is_empty() {
+            let relay_time = tried.iter().map(|r| r.latency).sum();
+            self.mlbt_stat.roll_update_relay_inv(src, relay_time);
+        }
+
+        for receipt in tried {
+            let key = (src.to_owned(), receipt.child.to_owned());
+
+            if receipt.success {
+                self.relay_fail.remove(&key);
+                continue;
+            }
+
+            let fails = self.relay_fail.entry(key).or_insert(0);
+            *fails += 1;
+
+            if *fails >= self.relay_fail_max {
+                warn!("MlbtRelayCtlContext::relay_receipt child {} of {} failed {} times, detach",
+                    receipt.child, src, fails);
+                self.detach(route_ctl, src, &receipt.child);
+            }
+        }
+    }
+
+
+    fn conn_state_change(&mut self, route_ctl: &mut RouteTable, peer: &Peer, state: ConnState)
+        -> Vec<(Peer, Vec<u8>)>
+    {
+        match state {
+            ConnState::Backoff(_) => self.backoff.insert(peer.to_owned()),
+            _ => self.backoff.remove(peer),
+        };
+
+        // the connection manager has given up on peer, drop it from every tree
+        if state == ConnState::Unreachable {
+            for src in route_ctl.get_src_list() {
+                self.detach(route_ctl, &src, peer);
+            }
+        }
+        vec![]
     }
 
 
@@ -330,6 +393,17 @@ impl MlbtRelayCtlContext {
     }
 
 
+    // stop relaying tree src to an unreachable child
+    fn detach(&mut self, route_ctl: &mut RouteTable, src: &Peer, child: &Peer) {
+        self.relay_fail.remove(&(src.to_owned(), child.to_owned()));
+
+        if route_ctl.get_relay(src).contains(child) {
+            info!("MlbtRelayCtlContext::detach {} from tree {}", child, src);
+            route_ctl.remove_relay(src, child);
+        }
+    }
+
+
     // reset timeouted timers and recover states
     fn check_timers(&mut self, route_ctl: &mut RouteTable) {
 
@@ -424,7 +498,7 @@ impl MlbtRelayCtlContext {
         let join_msg = join_msg.unwrap();
 
         // already have too many links, reject new ones
-        if route_ctl.get_relay_count() >= RouteTable::MAX_LINK {
+        if route_ctl.get_relay_count() >= route_ctl.max_link() {
             return Some((
                 sender.to_owned(),
                 msg.reject(self.seq())
@@ -25,6 +25,9 @@ pub trait MlbtStatMaintainer {
 
 
     fn roll_update_delay_ts(&mut self, peer: &Peer, new_delay: u64);
+
+    // moving average of local relay time in tree tr
+    fn roll_update_relay_inv(&mut self, tr: &Peer, new_inv: u64);
      
     // todo: 
     
@@ -58,6 +61,7 @@ struct MlbtStat {
     src_inv: u64,
     relay_inv: u64,
     merge_thrd: u64,
+    relay_sampled: bool,
 }
 
 
@@ -89,6 +93,7 @@ impl MlbtStat {
             src_inv: 0,
             relay_inv: 0,
             merge_thrd: 500,    // never set to zero
+            relay_sampled: false,
         }
     }
 
@@ -171,6 +176,21 @@ impl MlbtStatMaintainer for MlbtStatList {
         }
     }
 
+
+    fn roll_update_relay_inv(&mut self, tr: &Peer, new_inv: u64) {
+        let stat = self.inner_list.entry(tr.to_owned()).or_insert_with(MlbtStat::new);
+
+        // the first sample is taken as is
+        if !stat.relay_sampled {
+            stat.relay_inv = new_inv;
+            stat.relay_sampled = true;
+        }
+        else {
+            stat.relay_inv = (stat.relay_inv * (DELAY_AVERAGE_WD as u64 - 1)
+                + new_inv) / DELAY_AVERAGE_WD as u64;
+        }
+    }
+
 }
 
 
@@ -191,6 +211,7 @@ impl MlbtStatDebug for MlbtStatList {
         match self.inner_list.get_mut(tr) {
             Some(stat) => {
                 stat.relay_inv = new_value;
+                stat.relay_sampled = true;
                 Some(())
             }
             None => {
@@ -250,4 +271,20 @@ impl MlbtStatDebug for MlbtStatList {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn relay_inv_first_sample() {
+        let tr = Peer::from_bytes(&[1]);
+        let mut stats = MlbtStatList::new();
+
+        // a relay to no child takes no time, it is still a sample
+        stats.roll_update_relay_inv(&tr, 0);
+        stats.roll_update_relay_inv(&tr, 100);
+        assert_eq!(stats.relay_inv(&tr), Some(10));
+    }
+}
@@ -4,6 +4,8 @@ use log::warn;
 use yulong_network::identity::Peer;
 use yulong::utils::CasualTimer;
 
+use crate::config::MlbtConfig;
+
 #[derive(Clone)]
 pub enum WaitStateData {
     JoinWait((Peer, Peer, u64)),   // src, waitfor, require msg id
@@ -46,23 +48,6 @@ pub enum WaitStateType {
     RetractTotal,
 }
 
-// todo: initial from config 
-// note for reconstruct:
-// MERGE_PRE_TO should exceed MERGE_CHECK_TO + MERGE_WAIT_TO
-static JOIN_WAIT_TO: u128 = 2000; // ms
-static JOIN_PRE_TO: u128 = 2000; // ms
-static MERGE_WAIT_TO: u128 = 2000; // ms
-static MERGE_PRE_TO: u128 = 4000; // ms
-static MERGE_CHECK_TO: u128 = 2000; // ms
-static GRANT_WAIT_TO: u128 = 2000; // ms
-static GRANT_JOIN_TO: u128 = 2000; // ms
-static RETRACT_WAIT_TO: u128 = 2000; // ms
-static RETRACT_JOIN_TO: u128 = 2000; // ms
-static GRANT_RECV_TO: u128 = 2000; // ms
-static GRANT_TOTAL_TO: u128 = 2000; // ms
-static RETRACT_RECV_TO: u128 = 2000; // ms
-static RETRACT_TOTAL_TO: u128 = 2000; // ms
-
 trait TimedStatesSingle {
 
     // get associated data of a type if any
@@ -72,7 +57,7 @@ trait TimedStatesSingle {
     fn check(&self, state_type: WaitStateType) -> Option<WaitStateData>;
 
     // set state data and start the timer now
-    fn set(&mut self, state: WaitStateData);
+    fn set(&mut self, state: WaitStateData, timeouts: &MlbtConfig);
 
     // clear the timed state by type
     fn clear(&mut self, state_type: WaitStateType);
@@ -114,97 +99,97 @@ impl WaitState {
 
     // create a timed state and start the timer, the state is considered stale after timeout
     // todo use macros to be generic over types ?
-    fn new(kind: WaitStateData) -> Self {
+    fn new(kind: WaitStateData, timeouts: &MlbtConfig) -> Self {
         let mut ret: Self;
 
         match kind {
             WaitStateData::JoinWait(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(JOIN_WAIT_TO),
+                    wait_timer: CasualTimer::new(timeouts.join_wait_to as u128),
                     inner: WaitStateData::JoinWait(data),
                 };
             }
 
             WaitStateData::JoinPre(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(JOIN_PRE_TO),
+                    wait_timer: CasualTimer::new(timeouts.join_pre_to as u128),
                     inner: WaitStateData::JoinPre(data),
                 };
             },
             
             WaitStateData::MergeWait(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(MERGE_WAIT_TO),
+                    wait_timer: CasualTimer::new(timeouts.merge_wait_to as u128),
                     inner: WaitStateData::MergeWait(data),
                 };
             },
             
             WaitStateData::MergePre(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(MERGE_PRE_TO),
+                    wait_timer: CasualTimer::new(timeouts.merge_pre_to as u128),
                     inner: WaitStateData::MergePre(data),
                 };
             },
             
             WaitStateData::MergeCheck(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(MERGE_CHECK_TO),
+                    wait_timer: CasualTimer::new(timeouts.merge_check_to as u128),
                     inner: WaitStateData::MergeCheck(data),
                 };
             },
 
             WaitStateData::GrantWait(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(GRANT_WAIT_TO),
+                    wait_timer: CasualTimer::new(timeouts.grant_wait_to as u128),
                     inner: WaitStateData::GrantWait(data),
                 };
             },
 
             WaitStateData::GrantJoin(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(GRANT_JOIN_TO),
+                    wait_timer: CasualTimer::new(timeouts.grant_join_to as u128),
                     inner: WaitStateData::GrantJoin(data),
                 };
             },
 
             WaitStateData::RetractWait(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(RETRACT_WAIT_TO),
+                    wait_timer: CasualTimer::new(timeouts.retract_wait_to as u128),
                     inner: WaitStateData::RetractWait(data),
                 };
             },
             
             WaitStateData::RetractJoin(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(RETRACT_JOIN_TO),
+                    wait_timer: CasualTimer::new(timeouts.retract_join_to as u128),
                     inner: WaitStateData::RetractJoin(data),
                 };
             },
             
             WaitStateData::GrantRecv(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(GRANT_RECV_TO),
+                    wait_timer: CasualTimer::new(timeouts.grant_recv_to as u128),
                     inner: WaitStateData::GrantRecv(data),
                 };
             },
             
             WaitStateData::GrantTotal(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(GRANT_TOTAL_TO),
+                    wait_timer: CasualTimer::new(timeouts.grant_total_to as u128),
                     inner: WaitStateData::GrantTotal(data),
                 };
             },
             
             WaitStateData::RetractRecv(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(RETRACT_RECV_TO),
+                    wait_timer: CasualTimer::new(timeouts.retract_recv_to as u128),
                     inner: WaitStateData::RetractRecv(data),
                 };
             },
             
             WaitStateData::RetractTotal(data) => {
                 ret = Self {
-                    wait_timer: CasualTimer::new(RETRACT_TOTAL_TO),
+                    wait_timer: CasualTimer::new(timeouts.retract_total_to as u128),
                     inner: WaitStateData::RetractTotal(data),
                 };
             },
@@ -550,58 +535,58 @@ impl TimedStatesSingle for WaitStats {
     }
 
 
-    fn set(&mut self, state: WaitStateData) {
+    fn set(&mut self, state: WaitStateData, timeouts: &MlbtConfig) {
         match state {
             WaitStateData::JoinWait(_) => {
-                self.join_wait = Some(WaitState::new(state))
+                self.join_wait = Some(WaitState::new(state, timeouts))
             }
 
             WaitStateData::JoinPre(_) => {
-                self.join_pre = Some(WaitState::new(state))
+                self.join_pre = Some(WaitState::new(state, timeouts))
             }
 
             WaitStateData::MergeWait(_) => {
-                self.merge_wait = Some(WaitState::new(state))
+                self.merge_wait = Some(WaitState::new(state, timeouts))
             }
 
             WaitStateData::MergePre(_) => {
-                self.merge_pre = Some(WaitState::new(state))
+                self.merge_pre = Some(WaitState::new(state, timeouts))
             }
 
             WaitStateData::MergeCheck(_) => {
-                self.merge_check = Some(WaitState::new(state))
+                self.merge_check = Some(WaitState::new(state, timeouts))
             }
 
             WaitStateData::GrantWait(_) => {
-                self.grant_wait = Some(WaitState::new(state))
+                self.grant_wait = Some(WaitState::new(state, timeouts))
             }
 
             WaitStateData::GrantJoin(_) => {
-                self.grant_join = Some(WaitState::new(state))
+                self.grant_join = Some(WaitState::new(state, timeouts))
             }
             
             WaitStateData::RetractWait(_) => {
-                self.retract_wait = Some(WaitState::new(state))
+                self.retract_wait = Some(WaitState::new(state, timeouts))
             }
             
             WaitStateData::RetractJoin(_) => {
-                self.retract_join = Some(WaitState::new(state))
+                self.retract_join = Some(WaitState::new(state, timeouts))
             }
             
             WaitStateData::GrantRecv(_) => {
-                self.grant_recv = Some(WaitState::new(state))
+                self.grant_recv = Some(WaitState::new(state, timeouts))
             }
             
             WaitStateData::GrantTotal(_) => {
-                self.grant_total = Some(WaitState::new(state))
+                self.grant_total = Some(WaitState::new(state, timeouts))
             }
             
             WaitStateData::RetractRecv(_) => {
-                self.retract_recv = Some(WaitState::new(state))
+                self.retract_recv = Some(WaitState::new(state, timeouts))
             }
             
             WaitStateData::RetractTotal(_) => {
-                self.retract_total = Some(WaitState::new(state))
+                self.retract_total = Some(WaitState::new(state, timeouts))
             }
             
             
@@ -730,14 +715,16 @@ impl TimedStatesSingle for WaitStats {
 
 pub struct WaitList {
     inner_list: HashMap<Peer, WaitStats>,
+    timeouts: MlbtConfig,
 }
 
 
 impl WaitList {
 
-    pub fn new() -> Self {
+    pub fn new(timeouts: MlbtConfig) -> Self {
         Self {
             inner_list: HashMap::new(),
+            timeouts,
         } 
     }
 
@@ -788,7 +775,7 @@ impl TimedStates for WaitList {
     fn set(&mut self, peer: &Peer, state: WaitStateData) {
         match self.inner_list.get_mut(peer) {
             Some(states) => {
-                states.set(state)
+                states.set(state, &self.timeouts)
             }
             None => {
                 // todo log it or throw errors up?
@@ -1,10 +1,23 @@
 pub mod impls;
 
 use crate::route::RouteTable;
+use crate::config::BdnConfig;
+use crate::conn::ConnState;
 
 use yulong_network::identity::Peer;
 use crate::msg_header::RelayMethodKind;
 
+/// Outcome of relaying one message to one child.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayReceipt {
+    pub child: Peer,
+    pub success: bool,
+
+    // ms spent sending to this child
+    pub latency: u64,
+}
+
+
 /// Relay method should provide a callback interface to handle its messages,
 /// and a bootstrap function to generate initial messages according to initial
 /// RouteTable configurations.
@@ -12,7 +25,8 @@ use crate::msg_header::RelayMethodKind;
 /// message is defined by concrete implementations, thus declared as bytes.
 pub trait RelayCtl: Send + Sync {
 
-    fn new(route_ctl: &RouteTable) -> Self;
+    // pick its own section from config
+    fn new(route_ctl: &RouteTable, config: &BdnConfig) -> Self;
 
     fn get_relay_method(&self) -> RelayMethodKind;
 
@@ -24,8 +38,15 @@ pub trait RelayCtl: Send + Sync {
     fn relay_ctl_callback(&mut self, route_ctl: &mut RouteTable, sender: &Peer, msg: &[u8])
         -> Vec<(Peer, Vec<u8>)>;
     
-    // call after finish send list
-    fn relay_receipt(&mut self, route_ctl: &mut RouteTable, all_success: bool);
+    // call after finish send list of tree src, one receipt per child in send order
+    fn relay_receipt(&mut self, route_ctl: &mut RouteTable, src: &Peer, receipts: &[RelayReceipt]);
+
+    // connection to peer changed, see conn::ConnManager
+    fn conn_state_change(&mut self, _route_ctl: &mut RouteTable, _peer: &Peer, _state: ConnState)
+        -> Vec<(Peer, Vec<u8>)>
+    {
+        vec![]
+    }
 }
 
 
@@ -0,0 +1,95 @@
+//! BDN nodes over a SimNetwork, for tests of the protocols built on it.
+//!
+//! Everything here runs on the virtual clock of the network, call it from
+//! within SimNetwork::block_on.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use async_std::channel::Sender;
+use futures::future::{self, Either};
+
+use yulong::utils::{clock, runtime};
+use yulong_network::identity::Me;
+use yulong_sim::{SimContext, SimNetwork};
+
+use crate::config::BdnConfig;
+use crate::overlay::BDN;
+use crate::route::AppLayerRouteInner;
+use crate::route_inner::impls::mlbt::MlbtRelayCtlContext;
+
+
+pub type SimNode = BDN<SimContext, MlbtRelayCtlContext>;
+
+
+/// A member taking its turn, with the inbox of its BDN.
+pub type Turn<M> = (Sender<M>, Box<dyn FnMut()>);
+
+
+/// Virtual time between two rounds of turns.
+pub const TICK: Duration = Duration::from_millis(1);
+
+/// Virtual time a run may take at most.
+pub const TIME_LIMIT: Duration = Duration::from_secs(60);
+
+
+/// Listening BDN of id on net knowing everyone, broadcasts go up to the
+/// first of members and down to every other one. It waits a tick at most
+/// for a message.
+pub fn sim_node(net: &SimNetwork, id: &Me, members: &[Me], everyone: &[Me]) -> SimNode {
+    let config = BdnConfig { tick_inv: 1, ..BdnConfig::default() };
+    let root = members[0].peer();
+
+    let mut bdn = SimNode::new(id.clone(), config.clone());
+    for other in everyone {
+        // the root reaches itself the way the others reach it
+        if other.peer() != id.peer() || id.peer() == root {
+            bdn.add_peer(other.peer(), SocketAddr::new(net.add_node(other), config.listen_port));
+        }
+    }
+
+    bdn.route.insert_src(root, 0);
+    if id.peer() == root {
+        for member in &members[1..] {
+            bdn.route.insert_relay(root, member.peer());
+        }
+    }
+
+    runtime::spawn(SimNode::listen(config, bdn.msg_sender.clone(), id.clone()));
+    bdn
+}
+
+
+/// Wait for every node of ids to listen.
+pub async fn listening(net: &SimNetwork, ids: &[Me]) {
+    while !ids.iter().all(|id| net.listening(id.peer())) {
+        async_std::task::yield_now().await;
+    }
+}
+
+
+/// Members take turns, each handling what it has received, and time moves
+/// on by a tick after each round, until client is done.
+///
+/// Panics once TIME_LIMIT has passed.
+pub async fn drive<M, F: Future>(net: &SimNetwork, members: &mut [Turn<M>], client: F) -> F::Output {
+    let turns = async {
+        loop {
+            for (inbox, step) in members.iter_mut() {
+                step();
+                while !inbox.is_empty() {
+                    step();
+                }
+            }
+
+            assert!(net.now() < TIME_LIMIT, "no progress, seed {}", net.seed());
+            clock::sleep(TICK).await;
+        }
+    };
+
+    match future::select(Box::pin(client), Box::pin(turns)).await {
+        Either::Left((output, _)) => output,
+        Either::Right(_) => unreachable!(),
+    }
+}
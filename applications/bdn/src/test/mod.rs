@@ -1,234 +1,114 @@
 #[cfg(test)]
 mod test {
 
-    use std::{net::IpAddr, str::FromStr};
+    use std::net::SocketAddr;
+    use std::time::Duration;
 
-    use crate::{message, common::SocketAddrBi, route::AppLayerRouteUser};
+    use crate::message;
     use crate::route::AppLayerRouteInner;
     use crate::msg_header::MsgHeader;
     use crate::msg_header::MsgTypeKind;
     use crate::msg_header::RelayMethodKind;
 
     use crate::overlay::BDN;
+    use crate::config::BdnConfig;
+    use crate::handle::{BdnHandle, PayloadStream};
     use crate::route_inner::impls::mlbt::MlbtRelayCtlContext;
-    use ::log::debug;
-    use yulong_quic::QuicContext;
-    use yulong_tcp::TcpContext;
 
-    use async_std::{self};
-    use yulong::log;
-    use yulong_network::identity::Peer;
-
-    #[async_std::test]
-    async fn node1() {
-        log::setup_logger("bdn_test1").unwrap();
-
-        let mut bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new();
-        let self_id = Peer::from_bytes(&[1]);
-        bdn.local_identity.set_peer(self_id.clone());
-
-        let peer = Peer::from_bytes(&[2]);
-        let socket = SocketAddrBi::new(IpAddr::from_str("127.0.0.1").unwrap(), 9002_u16, None);
-
-        println!(
-            "New BDN client at: {:?}",
-            bdn.local_identity.peer().get_id()
-        );
-
-        bdn.address_book.insert(&peer, &socket);
+    use yulong::utils::{clock, runtime};
+    use yulong_network::identity::{Me, Peer};
+    use yulong_sim::{seed_from_env, LinkConfig, SimContext, SimNetwork};
+
+    type SimNode = BDN<SimContext, MlbtRelayCtlContext>;
+
+    // n listening nodes of net that know each other, broadcasts are relayed
+    // down a binary tree rooted at the first one
+    async fn sim_nodes(net: &SimNetwork, n: usize) -> Vec<(Me, BdnHandle, PayloadStream)> {
+        let ids: Vec<Me> = (0..n).map(|_| net.identity()).collect();
+        let config = BdnConfig::default();
+        let addrs: Vec<SocketAddr> = ids.iter()
+            .map(|id| SocketAddr::new(net.add_node(id), config.listen_port))
+            .collect();
+        let root = ids[0].peer().to_owned();
+
+        let mut nodes = Vec::with_capacity(n);
+        for (i, id) in ids.iter().enumerate() {
+            let mut bdn = SimNode::new(id.clone(), config.clone());
+            for (j, other) in ids.iter().enumerate().filter(|(j, _)| *j != i) {
+                bdn.add_peer(other.peer(), addrs[j]);
+            }
 
-        let payload = vec![42_u8; 10 * 1024 * 1024 - 200];
+            bdn.route.insert_src(&root, 0);
+            for child in (2 * i + 1..=2 * i + 2).filter(|child| *child < n) {
+                bdn.route.insert_relay(&root, ids[child].peer());
+            }
 
-        bdn.connect().await;
+            runtime::spawn(SimNode::listen(config.clone(), bdn.msg_sender.clone(), id.clone()));
+            let (handle, payloads) = bdn.spawn();
+            nodes.push((id.clone(), handle, payloads));
+        }
 
-        let header = MsgHeader::build(
-            MsgTypeKind::PAYLOAD_MSG,
-            true,
-            RelayMethodKind::LOOKUP_TABLE_1,
-            1,
-            15).unwrap();
+        while !ids.iter().all(|id| net.listening(id.peer())) {
+            async_std::task::yield_now().await;
+        }
+        nodes
+    }
 
-        let mut m = message::OverlayMessage::new(
-            header,
-            &self_id,
-            &self_id,
+    fn payload_msg(from: &Me, payload: &[u8]) -> message::OverlayMessage {
+        message::OverlayMessage::new(
+            MsgHeader::build(MsgTypeKind::PAYLOAD_MSG, true, RelayMethodKind::LOOKUP_TABLE_1, 1, 15).unwrap(),
+            from.peer(),
+            from.peer(),
             &Peer::BROADCAST_ID,
-            &payload,
-        );
-
-        bdn.send_to(&peer, &mut m).await;
+            payload,
+        )
     }
 
-    #[async_std::test]
-    async fn node2() {
-
-        log::setup_logger("bdn_test2").unwrap();
-
-        let mut bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new();
-        let self_id = Peer::from_bytes(&[2]);
-        bdn.local_identity.set_peer(self_id);
-
-        let peer3 = Peer::from_bytes(&[3]);
-        let socket3 = SocketAddrBi::new(IpAddr::from_str("121.36.95.93").unwrap(), 9003_u16, None);
-
-        let peer4 = Peer::from_bytes(&[4]);
-        let socket4 = SocketAddrBi::new(IpAddr::from_str("121.36.95.93").unwrap(), 9004_u16, None);
-
-        let peer5 = Peer::from_bytes(&[5]);
-        let socket5 = SocketAddrBi::new(IpAddr::from_str("121.36.95.93").unwrap(), 9005_u16, None);
-
-        let peer6 = Peer::from_bytes(&[6]);
-        let socket6 = SocketAddrBi::new(IpAddr::from_str("121.36.95.93").unwrap(), 9006_u16, None);
-
-        let peer7 = Peer::from_bytes(&[7]);
-        let socket7 = SocketAddrBi::new(IpAddr::from_str("121.36.95.93").unwrap(), 9007_u16, None);
-
-        let peer8 = Peer::from_bytes(&[8]);
-        let socket8 = SocketAddrBi::new(IpAddr::from_str("121.36.95.93").unwrap(), 9008_u16, None);
-
-        let peer9 = Peer::from_bytes(&[9]);
-        let socket9 = SocketAddrBi::new(IpAddr::from_str("121.36.95.93").unwrap(), 9009_u16, None);
-
-        let peer10 = Peer::from_bytes(&[10]);
-        let socket10 = SocketAddrBi::new(IpAddr::from_str("121.36.95.93").unwrap(), 9010_u16, None);
-
-        let peer1 = Peer::from_bytes(&[1]);
-
-        bdn.address_book.insert(&peer3, &socket3);
-        bdn.address_book.insert(&peer4, &socket4);
-        bdn.address_book.insert(&peer5, &socket5);
-        bdn.address_book.insert(&peer6, &socket6);
-        bdn.address_book.insert(&peer7, &socket7);
-        bdn.address_book.insert(&peer8, &socket8);
-        bdn.address_book.insert(&peer9, &socket9);
-        bdn.address_book.insert(&peer10, &socket10);
-
-        bdn.route.insert_relay(&peer1, &peer3);
-        bdn.route.insert_relay(&peer1, &peer4);
-        bdn.route.insert_relay(&peer1, &peer5);
-        bdn.route.insert_relay(&peer1, &peer6);
-        bdn.route.insert_relay(&peer1, &peer7);
-        bdn.route.insert_relay(&peer1, &peer8);
-        bdn.route.insert_relay(&peer1, &peer9);
-        bdn.route.insert_relay(&peer1, &peer10);
-
-        bdn.connect().await;
-
-        let server = async_std::task::spawn(BDN::<TcpContext, MlbtRelayCtlContext>::listen(
-            9002,
-            bdn.msg_sender.clone(),
-        ));
-
-        println!(
-            "New BDN client at: {:?}",
-            bdn.local_identity.peer().get_id()
-        );
-
-        loop {
-            if let Some(msg) = bdn.next() {
-                // debug!("recv:\n{}", &msg);
-            }
-        }
+    // next payload of a node within wait milliseconds of virtual time
+    async fn recv(payloads: &PayloadStream, wait: u64) -> Option<Vec<u8>> {
+        clock::timeout(Duration::from_millis(wait), payloads.recv()).await
+            .ok()
+            .and_then(Result::ok)
+            .map(|msg| msg.payload())
     }
 
-    #[async_std::test]
-    async fn node3() {
-        log::setup_logger("bdn_test3").unwrap();
-
-        let mut bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new();
-
-        bdn.connect().await;
-
-        let server = async_std::task::spawn(BDN::<TcpContext, MlbtRelayCtlContext>::listen(
-            9003,
-            bdn.msg_sender.clone(),
-        ));
-
-        println!(
-            "New BDN client at: {:?}",
-            bdn.local_identity.peer().get_id()
-        );
-
-        loop {
-            if let Some(msg) = bdn.next() {
-                // debug!("recv:\n{}", &msg);
+    #[test]
+    fn sim_mlbt_broadcast() {
+        let net = SimNetwork::new(seed_from_env());
+        net.set_default_link(LinkConfig {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+            bandwidth: 10_000_000,
+            ..LinkConfig::default()
+        });
+
+        net.block_on(async {
+            let nodes = sim_nodes(&net, 7).await;
+
+            // from a leaf up to the root and down the whole tree, itself included
+            let (leaf, handle, _) = &nodes[6];
+            handle.broadcast(payload_msg(leaf, b"first")).await;
+            for (_, _, payloads) in nodes.iter() {
+                assert_eq!(recv(payloads, 5000).await.as_deref(), Some(&b"first"[..]));
             }
-        }
-    }
-
-    #[async_std::test]
-    async fn node4() {
-        log::setup_logger("bdn_test4").unwrap();
 
-        let mut bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new();
-
-        bdn.connect().await;
-
-        let server = async_std::task::spawn(BDN::<TcpContext, MlbtRelayCtlContext>::listen(
-            9004,
-            bdn.msg_sender.clone(),
-        ));
-
-        println!(
-            "New BDN client at: {:?}",
-            bdn.local_identity.peer().get_id()
-        );
-
-        loop {
-            if let Some(msg) = bdn.next() {
-                // debug!("recv:\n{}", &msg);
+            // node 2 is cut off, and so is its subtree
+            net.partition(&[vec![nodes[2].0.peer().to_owned()]]);
+            let (sender, handle, _) = &nodes[3];
+            handle.broadcast(payload_msg(sender, b"second")).await;
+            for i in [0, 1, 3, 4] {
+                assert_eq!(recv(&nodes[i].2, 5000).await.as_deref(), Some(&b"second"[..]));
             }
-        }
-    }
-
-    #[async_std::test]
-    async fn node5() {
-        log::setup_logger("bdn_test5").unwrap();
-
-        let mut bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new();
-
-        bdn.connect().await;
-
-        let server = async_std::task::spawn(BDN::<TcpContext, MlbtRelayCtlContext>::listen(
-            9005,
-            bdn.msg_sender.clone(),
-        ));
-
-        println!(
-            "New BDN client at: {:?}",
-            bdn.local_identity.peer().get_id()
-        );
-
-        loop {
-            if let Some(msg) = bdn.next() {
-                // debug!("recv:\n{}", &msg);
+            for i in [2, 5, 6] {
+                assert_eq!(recv(&nodes[i].2, 200).await, None);
             }
-        }
-    }
+            assert!(net.link_stats(nodes[0].0.peer(), nodes[2].0.peer()).dropped > 0);
 
-    #[async_std::test]
-    async fn node6() {
-        log::setup_logger("bdn_test6").unwrap();
-
-        let mut bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new();
-
-        bdn.connect().await;
-
-        let server = async_std::task::spawn(BDN::<TcpContext, MlbtRelayCtlContext>::listen(
-            9006,
-            bdn.msg_sender.clone(),
-        ));
-
-        println!(
-            "New BDN client at: {:?}",
-            bdn.local_identity.peer().get_id()
-        );
-
-        loop {
-            if let Some(msg) = bdn.next() {
-                // debug!("recv:\n{}", &msg);
+            net.heal();
+            handle.broadcast(payload_msg(sender, b"third")).await;
+            for (_, _, payloads) in nodes.iter() {
+                assert_eq!(recv(payloads, 5000).await.as_deref(), Some(&b"third"[..]));
             }
-        }
+        });
     }
-
-}
\ No newline at end of file
+}
@@ -0,0 +1,102 @@
+//! Runs one PBFT replica.
+//!
+//!     pbft-node <keystore> <cluster file> [pbft config] [bdn config]
+//!         run the replica of keystore, the cluster file lists every replica
+//!         as described in pbft::cluster::load
+//!     pbft-node <keystore> --entry <ip:port>
+//!         print the cluster file lines of keystore listening on ip:port
+//!
+//! A missing keystore is generated, YULONG_KEYSTORE_PASSWORD is its password
+//! if set. The configs are toml files overridden by YULONG_PBFT_* and
+//! YULONG_BDN_* variables, the listening port is the one in the cluster file.
+
+use std::env;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::process;
+
+use log::info;
+
+use yulong::config::Config;
+use yulong::log::setup_logger;
+use yulong_bdn::config::BdnConfig;
+use yulong_bdn::overlay::BDN;
+use yulong_bdn::route_inner::impls::mlbt::MlbtRelayCtlContext;
+use yulong_network::identity::Me;
+use yulong_network::identity::crypto::{GenericSigner, PublicKey};
+use yulong_network::identity::crypto::ed25519_signer::Ed25519Signer;
+use yulong_network::identity::crypto::secp256k1_signer::Secp256k1Signer;
+use yulong_network::identity::crypto::sm_signer::SmSigner;
+use yulong_tcp::TcpContext;
+
+use pbft::builder::PbftBuilder;
+use pbft::cluster;
+use pbft::config::PbftConfig;
+
+
+type Node = BDN<TcpContext, MlbtRelayCtlContext>;
+
+
+const USAGE: &str = "usage: pbft-node <keystore> <cluster file> [pbft config] [bdn config]
+       pbft-node <keystore> --entry <ip:port>";
+
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let password = env::var("YULONG_KEYSTORE_PASSWORD").ok();
+    let me = Me::load_or_generate(&args[0], password.as_deref().map(str::as_bytes))?;
+
+    if args[1] == "--entry" {
+        let address: SocketAddr = args.get(2).ok_or(USAGE)?.parse()?;
+        print!("{}", cluster::entry(me.peer(), address));
+        return Ok(());
+    }
+
+    setup_logger("pbft-node")?;
+
+    // the replicas sign with the scheme of their keys
+    match me.public_key() {
+        PublicKey::SM2(_) => run(me, SmSigner::new(), &args),
+        PublicKey::Ed25519(_) => run(me, Ed25519Signer::new(), &args),
+        PublicKey::Secp256k1(_) => run(me, Secp256k1Signer::new(), &args),
+        PublicKey::NoKey => Err("Keystore holds no key".into()),
+    }
+}
+
+
+fn run<S: GenericSigner>(me: Me, signer: S, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let replicas = cluster::load(&args[1])?;
+    let pbft_config = PbftConfig::load(args.get(2))?;
+    let mut bdn_config = BdnConfig::load(args.get(3))?;
+
+    let local = replicas.iter()
+        .find(|replica| replica.peer == *me.peer())
+        .ok_or("Keystore identity is not in the cluster file")?;
+    bdn_config.listen_port = local.address.port();
+
+    let mut bdn = Node::new(me.clone(), bdn_config.clone());
+    for replica in replicas.iter().filter(|replica| replica.peer != *me.peer()) {
+        bdn.add_peer(&replica.peer, replica.address);
+    }
+
+    async_std::task::spawn(Node::listen(bdn_config, bdn.msg_sender.clone(), me.clone()));
+    async_std::task::block_on(bdn.connect());
+
+    let mut pbft = PbftBuilder::new(replicas.into_iter().map(|replica| replica.peer).collect())
+        .local_id(me)
+        .network(bdn)
+        .signer(signer)
+        .config(pbft_config)
+        .build()?;
+
+    info!("pbft-node started, quorum {}, primary {}", pbft.quorum_size(), pbft.primary());
+
+    loop {
+        pbft.heartbeat();
+    }
+}
@@ -0,0 +1,165 @@
+use yulong::config::invalid;
+use yulong::error::ConfigError;
+
+use yulong_network::identity::{Me, Peer};
+use yulong_network::identity::crypto::GenericSigner;
+use yulong_network::transport::Transport;
+
+use yulong_bdn::overlay::BDN;
+use yulong_bdn::route_inner::RelayCtl;
+
+use crate::config::PbftConfig;
+use crate::pbft::{Executor, PbftContext, Restore, TakeSnapshot};
+
+
+/// Assembles a PbftContext and checks that its parts agree before the
+/// replica starts.
+///
+/// participants is the full replica set in the order every replica uses,
+/// local_id included, e.g. as read by cluster::load. Out of n >= 3f + 1
+/// replicas f may be faulty, decisions take n - f of them.
+pub struct PbftBuilder<S, T, R>
+    where
+        S: GenericSigner,
+        T: Transport,
+        R: RelayCtl
+{
+    participants: Vec<Peer>,
+    local_id: Option<Me>,
+    network_handle: Option<BDN<T, R>>,
+    signer: Option<S>,
+    executor: Option<Executor>,
+    snapshot: Option<(TakeSnapshot, Restore)>,
+    config: PbftConfig,
+}
+
+
+impl<S, T, R> PbftBuilder<S, T, R>
+    where
+        S: GenericSigner,
+        T: Transport,
+        R: RelayCtl
+{
+
+    pub fn new(participants: Vec<Peer>) -> Self {
+        Self {
+            participants,
+            local_id: None,
+            network_handle: None,
+            signer: None,
+            executor: None,
+            snapshot: None,
+            config: PbftConfig::default(),
+        }
+    }
+
+
+    pub fn local_id(mut self, local_id: Me) -> Self {
+        self.local_id = Some(local_id);
+        self
+    }
+
+
+    /// BDN running as local_id, it is polled by PbftContext::heartbeat.
+    pub fn network(mut self, network_handle: BDN<T, R>) -> Self {
+        self.network_handle = Some(network_handle);
+        self
+    }
+
+
+    pub fn signer(mut self, signer: S) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+
+    /// See PbftContext::set_executor.
+    pub fn executor<F>(mut self, executor: F) -> Self
+        where F: FnMut(&[u8]) -> Vec<u8> + Send + 'static
+    {
+        self.executor = Some(Box::new(executor));
+        self
+    }
+
+
+    /// See PbftContext::set_snapshot.
+    pub fn snapshot<F, G>(mut self, snapshot: F, restore: G) -> Self
+        where
+            F: FnMut() -> Vec<u8> + Send + 'static,
+            G: FnMut(&[u8]) + Send + 'static
+    {
+        self.snapshot = Some((Box::new(snapshot), Box::new(restore)));
+        self
+    }
+
+
+    pub fn config(mut self, config: PbftConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+
+    pub fn build(self) -> Result<PbftContext<S, T, R>, ConfigError> {
+        let local_id = self.local_id.ok_or_else(|| invalid("PbftBuilder needs local_id"))?;
+        let network_handle = self.network_handle.ok_or_else(|| invalid("PbftBuilder needs network"))?;
+        let signer = self.signer.ok_or_else(|| invalid("PbftBuilder needs signer"))?;
+
+        let mut pbft = PbftContext::new(network_handle, signer, local_id, self.participants, self.config)?;
+        if let Some(executor) = self.executor {
+            pbft.set_executor(executor);
+        }
+        if let Some((snapshot, restore)) = self.snapshot {
+            pbft.set_snapshot(snapshot, restore);
+        }
+        Ok(pbft)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::identity;
+    use yulong_bdn::config::BdnConfig;
+    use yulong_bdn::route_inner::impls::mlbt::MlbtRelayCtlContext;
+    use yulong_network::identity::crypto::ed25519_signer::Ed25519Signer;
+    use yulong_tcp::TcpContext;
+
+    type Builder = PbftBuilder<Ed25519Signer, TcpContext, MlbtRelayCtlContext>;
+
+    fn builder(participants: Vec<Peer>, me: &Me) -> Builder {
+        PbftBuilder::new(participants)
+            .local_id(me.clone())
+            .network(BDN::new(me.clone(), BdnConfig::default()))
+            .signer(Ed25519Signer::new())
+    }
+
+    #[test]
+    fn builder_checks_parts() {
+        let replicas: Vec<Me> = (0..7).map(|_| identity()).collect();
+        let participants: Vec<Peer> = replicas.iter().map(|me| me.peer().to_owned()).collect();
+
+        // f = 2 out of 7
+        let pbft = builder(participants.clone(), &replicas[3]).build().unwrap();
+        assert_eq!(pbft.quorum_size(), 5);
+        assert_eq!(pbft.primary(), replicas[0].peer());
+
+        // f = 1 out of 5, two quorums of 3 could meet at the faulty one
+        let pbft = builder(participants[..5].to_vec(), &replicas[3]).build().unwrap();
+        assert_eq!(pbft.quorum_size(), 4);
+
+        assert!(Builder::new(participants.clone()).local_id(replicas[0].clone()).build().is_err());
+        assert!(builder(participants[1..].to_vec(), &replicas[0]).build().is_err());
+        assert!(builder([&participants[..], &participants[..1]].concat(), &replicas[0]).build().is_err());
+
+        let keyless = participants.iter().map(|peer| Peer::try_from_id(&peer.get_id()).unwrap()).collect();
+        assert!(builder(keyless, &replicas[0]).build().is_err());
+
+        let other = builder(participants.clone(), &replicas[0])
+            .network(BDN::new(replicas[1].clone(), BdnConfig::default()));
+        assert!(other.build().is_err());
+
+        let config = PbftConfig { batch_max: 0, ..PbftConfig::default() };
+        assert!(builder(participants, &replicas[0]).config(config).build().is_err());
+    }
+}
@@ -0,0 +1,343 @@
+//! Misbehaving replicas and a safety check over the honest ones, to test
+//! that up to f faulty replicas out of 3f + 1 cannot split the others.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use yulong::utils::clock;
+use yulong_network::identity::Peer;
+use yulong_network::identity::crypto::GenericSigner;
+use yulong_network::transport::Transport;
+use yulong_bdn::route_inner::RelayCtl;
+
+use crate::message::{digest, pack_batch, unpack_batch, PbftMessage};
+use crate::pbft::PbftContext;
+
+pub use crate::message::PbftMsgKind;
+
+
+// request added to a batch, or hashed into a vote, to make it another one
+const CONFLICT: &[u8] = b"conflict";
+
+
+/// What a ByzantineReplica does to the messages it sends.
+#[derive(Clone)]
+pub enum Fault {
+    /// Replicas in to get a PRE-PREPARE for another batch than the rest
+    /// for the same round.
+    ConflictingPrePrepare { to: Vec<Peer> },
+
+    /// Messages of kinds to replicas in to carry a made-up payload, a vote
+    /// is for a request nobody proposed.
+    Equivocate { kinds: Vec<PbftMsgKind>, to: Vec<Peer> },
+
+    /// Messages of these kinds are never sent.
+    Withhold(Vec<PbftMsgKind>),
+
+    /// Messages of kinds claim signer_id as their signer, they are signed
+    /// with the own key all the same.
+    Forge { kinds: Vec<PbftMsgKind>, signer_id: Box<Peer> },
+
+    /// Messages of kinds go out delay later.
+    Delay { kinds: Vec<PbftMsgKind>, delay: Duration },
+}
+
+
+impl Fault {
+
+    // the replicas may not all get the same message of kind
+    fn splits(&self, kind: PbftMsgKind) -> bool {
+        match self {
+            Fault::ConflictingPrePrepare { .. } => kind == PbftMsgKind::PRE_PREPARE,
+            Fault::Equivocate { kinds, .. } => kinds.contains(&kind),
+            _ => false,
+        }
+    }
+}
+
+
+/// A replica that follows the protocol except for its faults, which
+/// apply in turn to every message it sends.
+pub struct ByzantineReplica<S, T, R>
+    where
+        S: GenericSigner,
+        T: Transport,
+        R: RelayCtl
+{
+    pbft: PbftContext<S, T, R>,
+    faults: Vec<Fault>,
+
+    // delayed messages with when they are due
+    delayed: Vec<(Instant, PbftMessage, Option<Peer>)>,
+}
+
+
+impl<S, T, R> ByzantineReplica<S, T, R>
+    where
+        S: GenericSigner,
+        T: Transport,
+        R: RelayCtl
+{
+
+    pub fn new(mut pbft: PbftContext<S, T, R>, faults: Vec<Fault>) -> Self {
+        pbft.hold();
+        Self {
+            pbft,
+            faults,
+            delayed: Vec::new(),
+        }
+    }
+
+
+    pub fn pbft(&self) -> &PbftContext<S, T, R> {
+        &self.pbft
+    }
+
+
+    /// PbftContext::heartbeat, then send what it gave out as tampered with
+    /// and the delayed messages now due.
+    pub fn heartbeat(&mut self) {
+        self.pbft.heartbeat();
+
+        for (msg, target) in self.pbft.take_held() {
+            for (msg, target) in self.tamper(msg, target) {
+                match self.delay(msg.msg_type()) {
+                    Some(delay) => self.delayed.push((clock::now() + delay, msg, target)),
+                    None => self.pbft.release(msg, target.as_ref()),
+                }
+            }
+        }
+
+        let now = clock::now();
+        let (due, later) = std::mem::take(&mut self.delayed).into_iter()
+            .partition::<Vec<_>, _>(|(at, _, _)| *at <= now);
+        self.delayed = later;
+        for (_, msg, target) in due {
+            self.pbft.release(msg, target.as_ref());
+        }
+    }
+
+
+    fn delay(&self, kind: PbftMsgKind) -> Option<Duration> {
+        self.faults.iter().find_map(|fault| match fault {
+            Fault::Delay { kinds, delay } if kinds.contains(&kind) => Some(*delay),
+            _ => None,
+        })
+    }
+
+
+    // the messages that go out for msg to target, None being every replica
+    fn tamper(&self, msg: PbftMessage, target: Option<Peer>) -> Vec<(PbftMessage, Option<Peer>)> {
+        let kind = msg.msg_type();
+        if self.faults.iter().any(|fault| matches!(fault, Fault::Withhold(kinds) if kinds.contains(&kind))) {
+            debug!("ByzantineReplica withhold {:?} of round {}", kind, msg.round());
+            return vec![];
+        }
+
+        // a broadcast some see differently goes to each replica on its own
+        let local = self.pbft.local_peer();
+        let targets = match target {
+            None if self.faults.iter().any(|fault| fault.splits(kind)) => self.pbft.replicas().into_iter()
+                .filter(|peer| peer != local)
+                .map(Some)
+                .collect(),
+            target => vec![target],
+        };
+
+        targets.into_iter().map(|target| {
+            let mut msg = msg.clone();
+            let mut changed = false;
+
+            for fault in &self.faults {
+                match fault {
+                    Fault::ConflictingPrePrepare { to } if kind == PbftMsgKind::PRE_PREPARE && reaches(to, &target) => {
+                        let mut batch = unpack_batch(msg.payload()).unwrap_or_default();
+                        batch.push(CONFLICT.to_vec());
+                        msg.set_payload(pack_batch(&batch));
+                        changed = true;
+                    }
+
+                    Fault::Equivocate { kinds, to } if kinds.contains(&kind) && reaches(to, &target) => {
+                        let payload = digest(&[msg.payload(), CONFLICT].concat()).to_vec();
+                        msg.set_payload(payload);
+                        changed = true;
+                    }
+
+                    Fault::Forge { kinds, signer_id } if kinds.contains(&kind) => {
+                        msg.set_signer_id(signer_id.as_ref().to_owned());
+                        changed = true;
+                    }
+
+                    _ => {}
+                }
+            }
+
+            if changed {
+                self.pbft.sign_as_self(&mut msg);
+            }
+            (msg, target)
+        }).collect()
+    }
+}
+
+
+fn reaches(to: &[Peer], target: &Option<Peer>) -> bool {
+    matches!(target, Some(target) if to.contains(target))
+}
+
+
+/// Two honest replicas committed different requests in round.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub round: u32,
+    pub first: Peer,
+    pub second: Peer,
+}
+
+
+/// Collects what honest replicas commit, round by round as they execute it,
+/// and tells when two of them disagree. Clones share what is collected, one
+/// goes to the thread of each replica.
+#[derive(Clone, Default)]
+pub struct SafetyChecker {
+    commits: Arc<Mutex<Commits>>,
+}
+
+
+#[derive(Default)]
+struct Commits {
+    // the first replica to commit each round and its requests
+    by_round: HashMap<u32, (Peer, Vec<u8>)>,
+    violations: Vec<Violation>,
+}
+
+
+impl SafetyChecker {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+
+    /// Take in each round replica executes from now on, through its commit
+    /// hook. Only honest replicas are to be watched.
+    pub fn watch<S, T, R>(&self, replica: &mut PbftContext<S, T, R>)
+        where
+            S: GenericSigner,
+            T: Transport,
+            R: RelayCtl
+    {
+        let (checker, peer) = (self.clone(), replica.local_peer().to_owned());
+        replica.set_commit_hook(move |round, requests| checker.record(&peer, round, requests));
+    }
+
+
+    fn record(&self, replica: &Peer, round: u32, requests: &[u8]) {
+        let mut commits = self.commits.lock().unwrap();
+
+        match commits.by_round.get(&round) {
+            Some((first, agreed)) if agreed != requests => {
+                let violation = Violation { round, first: first.to_owned(), second: replica.to_owned() };
+                commits.violations.push(violation);
+            }
+            Some(_) => {}
+            None => {
+                commits.by_round.insert(round, (replica.to_owned(), requests.to_vec()));
+            }
+        }
+    }
+
+
+    /// Rounds committed by at least one replica observed.
+    pub fn rounds(&self) -> usize {
+        self.commits.lock().unwrap().by_round.len()
+    }
+
+
+    pub fn violations(&self) -> Vec<Violation> {
+        self.commits.lock().unwrap().violations.clone()
+    }
+
+
+    /// Panic if two replicas committed different requests in a round.
+    pub fn assert_safe(&self) {
+        let violations = self.violations();
+        assert!(violations.is_empty(), "honest replicas disagree: {:?}", violations);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{identity, signed};
+    use crate::builder::PbftBuilder;
+    use yulong_bdn::config::BdnConfig;
+    use yulong_bdn::overlay::BDN;
+    use yulong_bdn::route_inner::impls::mlbt::MlbtRelayCtlContext;
+    use yulong_network::identity::Me;
+    use yulong_network::identity::crypto::ed25519_signer::Ed25519Signer;
+    use yulong_tcp::TcpContext;
+
+    #[test]
+    fn faults_tamper_with_messages() {
+        let replicas: Vec<Me> = (0..4).map(|_| identity()).collect();
+        let peers: Vec<Peer> = replicas.iter().map(|me| me.peer().to_owned()).collect();
+        let me = &replicas[0];
+
+        let pbft = PbftBuilder::new(peers.clone())
+            .local_id(me.clone())
+            .network(BDN::<TcpContext, MlbtRelayCtlContext>::new(me.clone(), BdnConfig::default()))
+            .signer(Ed25519Signer::new())
+            .build()
+            .unwrap();
+        let replica = ByzantineReplica::new(pbft, vec![
+            Fault::ConflictingPrePrepare { to: vec![peers[3].clone()] },
+            Fault::Equivocate { kinds: vec![PbftMsgKind::PREPARE], to: vec![peers[2].clone()] },
+            Fault::Forge { kinds: vec![PbftMsgKind::COMMIT], signer_id: Box::new(peers[1].clone()) },
+            Fault::Withhold(vec![PbftMsgKind::CHECKPOINT]),
+        ]);
+        let verify = |msg: &PbftMessage| msg.verify(&Ed25519Signer::new());
+
+        // the broadcast goes to each other replica, the last one orders more
+        let batch = pack_batch(&[b"req".to_vec()]);
+        let sent = replica.tamper(signed(me, 0, 0, PbftMsgKind::PRE_PREPARE, batch.clone()), None);
+        let targets: Vec<Option<Peer>> = sent.iter().map(|(_, target)| target.clone()).collect();
+        assert_eq!(targets, peers[1..].iter().cloned().map(Some).collect::<Vec<_>>());
+        assert_eq!(sent[0].0.payload(), batch.as_slice());
+        assert_eq!(sent[1].0.payload(), batch.as_slice());
+        assert_eq!(unpack_batch(sent[2].0.payload()).unwrap(), vec![b"req".to_vec(), CONFLICT.to_vec()]);
+        assert!(sent.iter().all(|(msg, _)| verify(msg)));
+
+        let vote = digest(&batch).to_vec();
+        let sent = replica.tamper(signed(me, 0, 0, PbftMsgKind::PREPARE, vote.clone()), None);
+        let votes: Vec<bool> = sent.iter().map(|(msg, _)| msg.payload() == vote.as_slice()).collect();
+        assert_eq!(votes, [true, false, true]);
+
+        // left to one target, it stays so
+        let sent = replica.tamper(signed(me, 0, 0, PbftMsgKind::COMMIT, vote), Some(peers[2].clone()));
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].0.signer_id(), sent[0].1.as_ref()), (&peers[1], Some(&peers[2])));
+        assert!(!verify(&sent[0].0));
+
+        assert!(replica.tamper(signed(me, 0, 1, PbftMsgKind::CHECKPOINT, vec![0; 32]), None).is_empty());
+    }
+
+    #[test]
+    fn checker_catches_disagreement() {
+        let peers: Vec<Peer> = (0..3).map(|_| identity().peer().to_owned()).collect();
+        let checker = SafetyChecker::new();
+
+        checker.record(&peers[0], 0, b"a");
+        checker.clone().record(&peers[1], 0, b"a");
+        checker.record(&peers[1], 1, b"b");
+        assert_eq!(checker.rounds(), 2);
+        checker.assert_safe();
+
+        checker.record(&peers[2], 1, b"c");
+        assert_eq!(checker.violations(), vec![Violation { round: 1, first: peers[1].clone(), second: peers[2].clone() }]);
+    }
+}
@@ -0,0 +1,186 @@
+use prost::Message;
+
+use yulong::error::{DeserializeError, DumbError, SerializeError};
+use yulong::utils::AsBytes;
+use yulong_network::identity::Peer;
+use yulong_network::identity::crypto::PublicKey;
+
+use crate::message::PbftMessage;
+use crate::pbft_message::{ProtoCachedReply, ProtoCheckpointCert, ProtoSnapshot, ProtoState};
+
+
+/// Proof that a checkpoint is stable: CHECKPOINT messages of 2f + 1
+/// replicas on the same round and state digest. The rounds up to it are
+/// settled and their requests can be dropped.
+#[derive(Debug, Clone)]
+pub(crate) struct CheckpointCert {
+    votes: Vec<PbftMessage>,
+}
+
+
+impl CheckpointCert {
+
+    pub(crate) fn new(votes: Vec<PbftMessage>) -> Self {
+        Self { votes }
+    }
+
+
+    pub(crate) fn round(&self) -> u32 {
+        self.votes[0].round()
+    }
+
+
+    pub(crate) fn state(&self) -> [u8; 32] {
+        let mut state = [0; 32];
+        // checked by Verifier::checkpoint before use
+        if self.votes[0].payload().len() == state.len() {
+            state.copy_from_slice(self.votes[0].payload());
+        }
+        state
+    }
+
+
+    pub(crate) fn votes_mut(&mut self) -> &mut [PbftMessage] {
+        &mut self.votes
+    }
+
+
+    pub(crate) fn signers(&self) -> impl Iterator<Item = &Peer> {
+        self.votes.iter().map(|vote| vote.signer_id())
+    }
+
+
+    pub(crate) fn to_proto(&self) -> Result<ProtoCheckpointCert, SerializeError> {
+        let votes = self.votes.iter()
+            .map(|vote| vote.into_bytes())
+            .collect::<Result<Vec<Vec<u8>>, SerializeError>>()?;
+        Ok(ProtoCheckpointCert { votes })
+    }
+
+
+    pub(crate) fn from_proto(m: ProtoCheckpointCert) -> Result<Self, DeserializeError> {
+        let votes = m.votes.iter()
+            .map(|vote| PbftMessage::from_bytes(vote))
+            .collect::<Result<Vec<PbftMessage>, DeserializeError>>()?;
+
+        if votes.is_empty() {
+            return Err(DeserializeError::new("Empty CheckpointCert", DumbError));
+        }
+        Ok(Self { votes })
+    }
+
+}
+
+
+/// Last reply to a client: the timestamp of the request it answers, the
+/// round the request was executed in and the result.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CachedReply {
+    pub(crate) client: Peer,
+    pub(crate) timestamp: u64,
+    pub(crate) round: u32,
+    pub(crate) result: Vec<u8>,
+}
+
+
+/// What a checkpoint vouches for, the CHECKPOINT payload being its digest:
+/// the requests committed up to the round as chained by the Store, the
+/// application state and the last reply to each client. A replica that
+/// fell behind a stable checkpoint installs it in place of the rounds it
+/// missed, so replicas have to agree on it byte for byte.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Snapshot {
+    pub(crate) chain: [u8; 32],
+    pub(crate) app: Vec<u8>,
+    pub(crate) replies: Vec<CachedReply>,
+}
+
+
+impl AsBytes for Snapshot {
+
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        let mut replies = self.replies.iter()
+            .map(|reply| Ok(ProtoCachedReply {
+                client_pk: reply.client.pubkey().into_bytes()?,
+                timestamp: reply.timestamp,
+                round: reply.round,
+                result: reply.result.clone(),
+            }))
+            .collect::<Result<Vec<ProtoCachedReply>, SerializeError>>()?;
+        // the same replies in any order make the same snapshot
+        replies.sort_by(|a, b| a.client_pk.cmp(&b.client_pk));
+
+        let proto_message = ProtoSnapshot {
+            chain: self.chain.to_vec(),
+            app: self.app.clone(),
+            replies,
+        };
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        let m = ProtoSnapshot::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize Snapshot", e))?;
+
+        let mut chain = [0; 32];
+        if m.chain.len() != chain.len() {
+            return Err(DeserializeError::new("Bad chain in Snapshot", DumbError));
+        }
+        chain.copy_from_slice(&m.chain);
+
+        let replies = m.replies.into_iter()
+            .map(|reply| {
+                let pk = PublicKey::from_bytes(&reply.client_pk)
+                    .map_err(|e| DeserializeError::new("Bad client key in Snapshot", e))?;
+                Ok(CachedReply {
+                    client: Peer::from_public_key(&pk),
+                    timestamp: reply.timestamp,
+                    round: reply.round,
+                    result: reply.result,
+                })
+            })
+            .collect::<Result<Vec<CachedReply>, DeserializeError>>()?;
+
+        Ok(Self { chain, app: m.app, replies })
+    }
+}
+
+
+/// STATE payload, a stable checkpoint and the encoded Snapshot it
+/// vouches for.
+pub(crate) struct StateTransfer {
+    pub(crate) cert: CheckpointCert,
+    pub(crate) snapshot: Vec<u8>,
+}
+
+
+impl StateTransfer {
+
+    pub(crate) fn into_payload(&self) -> Result<Vec<u8>, SerializeError> {
+        let proto_message = ProtoState {
+            checkpoint: Some(self.cert.to_proto()?),
+            snapshot: self.snapshot.clone(),
+        };
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+
+    pub(crate) fn from_payload(buf: &[u8]) -> Result<Self, DeserializeError> {
+        let m = ProtoState::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize StateTransfer", e))?;
+
+        let cert = m.checkpoint
+            .ok_or_else(|| DeserializeError::new("StateTransfer without checkpoint", DumbError))?;
+
+        Ok(Self {
+            cert: CheckpointCert::from_proto(cert)?,
+            snapshot: m.snapshot,
+        })
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use yulong::config::invalid;
+use yulong::error::ConfigError;
+use yulong::utils::AsBytes;
+use yulong_network::identity::Peer;
+use yulong_network::identity::crypto::PublicKey;
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClusterFile {
+    replica: Vec<ReplicaEntry>,
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReplicaEntry {
+    id: String,
+    pubkey: String,
+    address: String,
+}
+
+
+/// A replica as listed in a cluster file.
+#[derive(Debug, Clone)]
+pub struct Replica {
+    pub peer: Peer,
+    // where it listens
+    pub address: SocketAddr,
+}
+
+
+/// Read the replicas of a cluster file, a toml list of
+///
+/// ```toml
+/// [[replica]]
+/// id = "<peer id, hex>"
+/// pubkey = "<encoded public key, hex>"
+/// address = "<ip>:<port>"
+/// ```
+///
+/// in the order every replica of the cluster uses, replica v % n is the
+/// primary of view v. entry gives the lines of a replica.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Replica>, ConfigError> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| ConfigError::new("Read cluster file", e))?;
+    parse(&text)
+}
+
+
+/// Replicas of cluster file text, see load.
+pub fn parse(text: &str) -> Result<Vec<Replica>, ConfigError> {
+    let file: ClusterFile = toml::from_str(text)
+        .map_err(|e| ConfigError::new(format!("Invalid cluster file: {}", e), e))?;
+
+    if file.replica.is_empty() {
+        return Err(invalid("Cluster file lists no replica"));
+    }
+
+    let mut seen = HashSet::new();
+    let mut replicas = Vec::with_capacity(file.replica.len());
+
+    for entry in file.replica {
+        let pubkey = from_hex(&entry.pubkey)
+            .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+            .ok_or_else(|| invalid(format!("Bad public key of replica {}", entry.id)))?;
+
+        // ids are derived from the key, a mismatch is a typo or a forgery
+        let peer = Peer::from_public_key(&pubkey);
+        if from_hex(&entry.id).as_deref() != Some(&peer.get_id()[..]) {
+            return Err(invalid(format!("Replica {} does not match its public key", entry.id)));
+        }
+
+        let address = entry.address.parse()
+            .map_err(|e| ConfigError::new(format!("Bad address of replica {}", entry.id), e))?;
+
+        if !seen.insert(peer.clone()) {
+            return Err(invalid(format!("Replica {} is listed twice", entry.id)));
+        }
+        replicas.push(Replica { peer, address });
+    }
+
+    Ok(replicas)
+}
+
+
+/// Cluster file lines of peer listening on address.
+pub fn entry(peer: &Peer, address: SocketAddr) -> String {
+    // PublicKey::into_bytes do not throw error, safe unwrap
+    format!(
+        "[[replica]]\nid = \"{}\"\npubkey = \"{}\"\naddress = \"{}\"\n",
+        to_hex(&peer.get_id()),
+        to_hex(&peer.pubkey().into_bytes().unwrap()),
+        address
+    )
+}
+
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::identity;
+    use yulong_network::identity::Me;
+
+    #[test]
+    fn cluster_file_round_trip() {
+        let me: Vec<Me> = (0..2).map(|_| identity()).collect();
+        let address: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+
+        let text = entry(me[0].peer(), address) + &entry(me[1].peer(), address);
+        let replicas = parse(&text).unwrap();
+        assert_eq!(replicas.len(), 2);
+        assert_eq!(&replicas[1].peer, me[1].peer());
+        assert_eq!(replicas[1].peer.pubkey().into_bytes().unwrap(), me[1].public_key().into_bytes().unwrap());
+        assert_eq!(replicas[0].address, address);
+
+        // listed twice
+        let text = entry(me[0].peer(), address).repeat(2);
+        assert!(parse(&text).is_err());
+
+        // the id of one key with the public key of another
+        let mut lines: Vec<String> = entry(me[0].peer(), address).lines().map(String::from).collect();
+        lines[2] = entry(me[1].peer(), address).lines().nth(2).unwrap().to_owned();
+        assert!(parse(&lines.join("\n")).is_err());
+
+        assert!(parse(&entry(me[0].peer(), address).replace("127.0.0.1:9001", "localhost")).is_err());
+        assert!(parse("").is_err());
+    }
+}
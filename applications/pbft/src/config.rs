@@ -0,0 +1,80 @@
+use serde::Deserialize;
+
+use yulong::config::{Config, invalid};
+use yulong::error::ConfigError;
+
+
+/// Tunables of a PBFT replica or client. All durations are in ms.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PbftConfig {
+    // size of generated test requests, bytes
+    pub payload_max: usize,
+
+    pub request_to: u64,
+    pub preprepare_to: u64,
+    pub prepare_to: u64,
+    // wait for NEW-VIEW before moving on to the next view
+    pub view_change_to: u64,
+
+    // rounds between two checkpoints
+    pub checkpoint_period: u32,
+    // rounds accepted past the last stable checkpoint
+    pub watermark_window: u32,
+
+    // requests the primary puts in one PRE-PREPARE
+    pub batch_max: usize,
+    // a batch that is not full waits this long for more requests
+    pub batch_delay: u64,
+    // rounds in flight at once
+    pub pipeline_window: u32,
+
+    // a client waits this long for f + 1 matching replies before sending
+    // the request to every replica, client_retry times at most
+    pub client_timeout: u64,
+    pub client_retry: u32,
+}
+
+
+impl Default for PbftConfig {
+    fn default() -> Self {
+        Self {
+            payload_max: 500,
+            request_to: 5000,
+            preprepare_to: 5000,
+            prepare_to: 5000,
+            view_change_to: 5000,
+            checkpoint_period: 100,
+            watermark_window: 200,
+            batch_max: 64,
+            batch_delay: 10,
+            pipeline_window: 16,
+            client_timeout: 2000,
+            client_retry: 5,
+        }
+    }
+}
+
+
+impl Config for PbftConfig {
+
+    const ENV_PREFIX: &'static str = "YULONG_PBFT";
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.request_to == 0 || self.preprepare_to == 0 || self.prepare_to == 0 ||
+            self.view_change_to == 0
+        {
+            return Err(invalid("pbft timeouts should be positive"));
+        }
+        if self.checkpoint_period == 0 || self.watermark_window < self.checkpoint_period {
+            return Err(invalid("checkpoint_period should be positive and within watermark_window"));
+        }
+        if self.batch_max == 0 || self.pipeline_window == 0 {
+            return Err(invalid("batch_max and pipeline_window should be positive"));
+        }
+        if self.client_timeout == 0 || self.client_retry == 0 {
+            return Err(invalid("client_timeout and client_retry should be positive"));
+        }
+        Ok(())
+    }
+}
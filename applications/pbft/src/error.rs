@@ -0,0 +1,34 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+
+
+/// A client request got no f + 1 matching replies
+#[derive(Debug)]
+pub struct ClientError {
+    describe: String,
+    boxed_error: Box<dyn Error>
+}
+
+
+impl ClientError {
+    pub fn new<S: ToString>(des: S, err: impl Error + 'static) -> Self {
+        Self {
+            describe: des.to_string(),
+            boxed_error: Box::new(err)
+        }
+    }
+}
+
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Client error: {}", self.describe)
+    }
+}
@@ -4,8 +4,18 @@ mod pbft_message {
 }
 mod quorum;
 mod participants;
+mod checkpoint;
+mod view_change;
 
 mod store;
 mod test;
+#[cfg(test)]
+mod test_util;
 
 pub mod pbft;
+pub mod pbft_client;
+pub mod builder;
+pub mod cluster;
+pub mod config;
+pub mod error;
+pub mod byzantine;
@@ -1,14 +1,19 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
 use prost::Message;
 
-use yulong::error::{DeserializeError, SerializeError};
+use yulong::error::{DeserializeError, DumbError, SerializeError};
+use log::warn;
+
 use yulong::utils::AsBytes;
 use yulong_network::identity::crypto::{GenericSigner, PublicKey, PrivateKey};
+use yulong_network::identity::crypto::sm_cipher::{sm3_hash, SM3_HASH_SIZE};
 
 use yulong_network::identity::Peer;
 
-use crate::pbft_message::ProtoPbftMessage;
+use crate::pbft_message::{ProtoBatch, ProtoClientReply, ProtoClientRequest, ProtoPbftMessage};
 
 
 #[derive(FromPrimitive, ToPrimitive, PartialEq, Debug, Clone, Copy)]
@@ -19,14 +24,50 @@ pub enum PbftMsgKind {
     COMMIT = 3,
     REPLY = 4,
 
-    BLAME = 5,
-    NEW_EPOCH = 6,
-    CONFIRM_EPOCH = 7,
+    VIEW_CHANGE = 5,
+    NEW_VIEW = 6,
+
+    CHECKPOINT = 7,
+    FETCH_STATE = 8,
+    STATE = 9,
+}
+
+
+/// PREPARE and COMMIT carry the digest of the request instead of the request.
+pub fn digest(request: &[u8]) -> [u8; SM3_HASH_SIZE] {
+    sm3_hash(request)
+}
+
+
+/// PRE-PREPARE payload made of the requests ordered in one round.
+pub fn pack_batch(requests: &[Vec<u8>]) -> Vec<u8> {
+    let proto_message = ProtoBatch { requests: requests.to_vec() };
+
+    let mut buf = Vec::with_capacity(proto_message.encoded_len());
+    proto_message.encode(&mut buf).unwrap();
+    buf
 }
 
 
-#[derive(Debug)]
+pub fn unpack_batch(payload: &[u8]) -> Result<Vec<Vec<u8>>, DeserializeError> {
+    ProtoBatch::decode(payload)
+        .map(|m| m.requests)
+        .map_err(|e| DeserializeError::new("Deserialize batch", e))
+}
+
+
+/// Timestamp of the next request of a client, the clock in ms unless it
+/// has not moved past last.
+pub fn next_timestamp(last: u64) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    now.max(last + 1)
+}
+
+
+#[derive(Debug, Clone)]
 pub struct PbftMessage {
+    view: u32,
     round: u32,
     
     msg_no: u32,
@@ -46,6 +87,7 @@ impl AsBytes for PbftMessage {
             round: self.round,
             msg_no: self.msg_no,
             msg_type: ToPrimitive::to_u32(&self.msg_type).unwrap(),
+            view: self.view,
             signer_id: self.signer_id.get_id().to_vec(),
             proof: self.proof.clone(),
             payload: self.payload.clone(),
@@ -57,18 +99,24 @@ impl AsBytes for PbftMessage {
     }
 
     fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
-        ProtoPbftMessage::decode(buf)
-            .map_err(|e| DeserializeError::new("Deserialize PbftMessage", e))
-            .map(|m| Self {
-                round: m.round,
-                msg_no: m.msg_no,
-                msg_type: FromPrimitive::from_u32(m.msg_type).unwrap(),
- 
-                signer_id: Peer::try_from_id(&m.signer_id).expect("Wrong ID size."),
-
-                proof: m.proof,
-                payload: m.payload,
-            })
+        let m = ProtoPbftMessage::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize PbftMessage", e))?;
+
+        let msg_type = FromPrimitive::from_u32(m.msg_type).ok_or_else(|| DeserializeError::new(
+            format!("Unknown PbftMessage type {}", m.msg_type), DumbError))?;
+
+        let signer_id = Peer::try_from_id(&m.signer_id)
+            .map_err(|e| DeserializeError::new("Bad signer id in PbftMessage", e))?;
+
+        Ok(Self {
+            view: m.view,
+            round: m.round,
+            msg_no: m.msg_no,
+            msg_type,
+            signer_id,
+            proof: m.proof,
+            payload: m.payload,
+        })
     }
 }
 
@@ -77,10 +125,11 @@ impl PbftMessage {
 
 
     // do not sign
-    pub fn new(round: u32, msg_no: u32, msg_type: PbftMsgKind, signer_id: Peer,
+    pub fn new(view: u32, round: u32, msg_no: u32, msg_type: PbftMsgKind, signer_id: Peer,
         payload: Vec<u8>) -> Self 
     { 
         Self {
+            view,
             round,
             msg_no,
             msg_type,
@@ -91,15 +140,59 @@ impl PbftMessage {
     }
 
 
-    pub fn sign<S: GenericSigner>(&mut self,s: &S, sk: &PrivateKey, pk: &PublicKey) {
-        let sig = GenericSigner::sign(s, &self.into_bytes().unwrap(), sk, pk);
-        self.proof = sig.into_bytes().unwrap();
+    // the signed content is the message encoded with an empty proof
+    fn signing_bytes(&self) -> Vec<u8> {
+        let proto_message = ProtoPbftMessage {
+            round: self.round,
+            msg_no: self.msg_no,
+            msg_type: ToPrimitive::to_u32(&self.msg_type).unwrap(),
+            view: self.view,
+            signer_id: self.signer_id.get_id().to_vec(),
+            proof: vec![],
+            payload: self.payload.clone(),
+        };
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        buf
     }
 
 
-    pub fn verify(&self) -> bool {
-        //todo: check if proof is valid
-        true
+    pub fn sign<S: GenericSigner>(&mut self,s: &S, sk: &PrivateKey, pk: &PublicKey) -> Option<()> {
+        let sig = GenericSigner::sign(s, &self.signing_bytes(), sk, pk);
+        if sig.is_none() {
+            warn!("PbftMessage::sign key type does not match the signer");
+            return None;
+        }
+
+        self.proof = sig.unwrap().into_bytes().unwrap();
+        Some(())
+    }
+
+
+    /// Check proof against the public key carried by signer_id, fill it with
+    /// ParticipantsStore::query_pk first.
+    pub fn verify<S: GenericSigner>(&self, s: &S) -> bool {
+        if self.proof.is_empty() {
+            return false;
+        }
+
+        match S::SIG::from_bytes(&self.proof) {
+            Ok(sig) => {
+                GenericSigner::verify(s, &self.signing_bytes(), self.signer_id.pubkey(), &sig)
+            }
+            Err(_) => false
+        }
+    }
+
+    /// Get the pbft message's view.
+    pub fn view(&self) -> u32 {
+        self.view
+    }
+
+    /// Set the pbft message's view.
+    pub fn set_view(&mut self, view: u32) {
+        self.view = view;
     }
 
     /// Get a reference to the pbft message's round.
@@ -169,6 +262,178 @@ impl PbftMessage {
 
 }
 
+/// An operation signed by its client, who is known by its public key.
+#[derive(Debug, Clone)]
+pub struct ClientRequest {
+    client: Peer,
+    timestamp: u64,
+    operation: Vec<u8>,
+    proof: Vec<u8>,
+}
+
+
+impl AsBytes for ClientRequest {
+
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        let proto_message = self.to_proto(self.proof.clone())?;
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        let m = ProtoClientRequest::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize ClientRequest", e))?;
+
+        let pk = PublicKey::from_bytes(&m.client_pk)
+            .map_err(|e| DeserializeError::new("Bad client key in ClientRequest", e))?;
+
+        Ok(Self {
+            client: Peer::from_public_key(&pk),
+            timestamp: m.timestamp,
+            operation: m.operation,
+            proof: m.proof,
+        })
+    }
+}
+
+
+impl ClientRequest {
+
+    // do not sign, client carries the public key
+    pub fn new(client: Peer, timestamp: u64, operation: Vec<u8>) -> Self {
+        Self {
+            client,
+            timestamp,
+            operation,
+            proof: vec![],
+        }
+    }
+
+
+    fn to_proto(&self, proof: Vec<u8>) -> Result<ProtoClientRequest, SerializeError> {
+        Ok(ProtoClientRequest {
+            client_pk: self.client.pubkey().into_bytes()?,
+            timestamp: self.timestamp,
+            operation: self.operation.clone(),
+            proof,
+        })
+    }
+
+
+    // the signed content is the request encoded with an empty proof
+    fn signing_bytes(&self) -> Option<Vec<u8>> {
+        let proto_message = self.to_proto(vec![]).ok()?;
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Some(buf)
+    }
+
+
+    pub fn sign<S: GenericSigner>(&mut self, s: &S, sk: &PrivateKey) -> Option<()> {
+        let sig = GenericSigner::sign(s, &self.signing_bytes()?, sk, self.client.pubkey());
+        if sig.is_none() {
+            warn!("ClientRequest::sign key type does not match the signer");
+            return None;
+        }
+
+        self.proof = sig.unwrap().into_bytes().unwrap();
+        Some(())
+    }
+
+
+    pub fn verify<S: GenericSigner>(&self, s: &S) -> bool {
+        let signing_bytes = match self.signing_bytes() {
+            Some(signing_bytes) if !self.proof.is_empty() => signing_bytes,
+            _ => return false,
+        };
+
+        match S::SIG::from_bytes(&self.proof) {
+            Ok(sig) => GenericSigner::verify(s, &signing_bytes, self.client.pubkey(), &sig),
+            Err(_) => false
+        }
+    }
+
+    /// Get a reference to the client request's client.
+    pub fn client(&self) -> &Peer {
+        &self.client
+    }
+
+    /// Get the client request's timestamp.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Get a reference to the client request's operation.
+    pub fn operation(&self) -> &[u8] {
+        self.operation.as_ref()
+    }
+}
+
+
+/// What a replica answers a client with once the request is executed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientReply {
+    client_id: Peer,
+    timestamp: u64,
+    result: Vec<u8>,
+}
+
+
+impl AsBytes for ClientReply {
+
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        let proto_message = ProtoClientReply {
+            client_id: self.client_id.get_id().to_vec(),
+            timestamp: self.timestamp,
+            result: self.result.clone(),
+        };
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        let m = ProtoClientReply::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize ClientReply", e))?;
+
+        let client_id = Peer::try_from_id(&m.client_id)
+            .map_err(|e| DeserializeError::new("Bad client id in ClientReply", e))?;
+
+        Ok(Self {
+            client_id,
+            timestamp: m.timestamp,
+            result: m.result,
+        })
+    }
+}
+
+
+impl ClientReply {
+
+    pub fn new(client_id: Peer, timestamp: u64, result: Vec<u8>) -> Self {
+        Self { client_id, timestamp, result }
+    }
+
+    /// Get a reference to the client reply's client id.
+    pub fn client_id(&self) -> &Peer {
+        &self.client_id
+    }
+
+    /// Get the client reply's timestamp.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Get a reference to the client reply's result.
+    pub fn result(&self) -> &[u8] {
+        self.result.as_ref()
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -180,6 +445,7 @@ mod test {
         crypto::{
             Signer,
             sm_signer::SmSigner,
+            ed25519_signer::Ed25519Signer,
             PublicKey,
         }
     };
@@ -193,6 +459,7 @@ mod test {
         let (pk, sk) = signer.keygen();
 
         let msg = message::PbftMessage {
+            view: 3,
             round: 10,
     
             msg_no: 11278,
@@ -209,6 +476,7 @@ mod test {
         // println!("Serialized msg: {:?}", msg_bytes);
 
         let dse_msg = message::PbftMessage::from_bytes(&msg_bytes).ok().unwrap();
+        assert_eq!(msg.view, dse_msg.view);
         assert_eq!(msg.round, dse_msg.round);
         assert_eq!(msg.msg_no, dse_msg.msg_no);
         assert_eq!(msg.msg_type, dse_msg.msg_type);
@@ -218,4 +486,71 @@ mod test {
         assert_eq!(msg.signer_id.get_id(), dse_msg.signer_id.get_id());
     }
 
-}
\ No newline at end of file
+
+    #[test]
+    fn pbft_message_sign_verify() {
+
+        let signer = SmSigner::new();
+        let (pk, sk) = signer.keygen();
+        let pk = PublicKey::SM2(pk);
+        let sk = PrivateKey::SM2(sk);
+
+        let signer_id = message::Peer::from_public_key(&pk);
+
+        let mut msg = message::PbftMessage::new(
+            0, 1, 2, PbftMsgKind::PREPARE, signer_id.clone(), vec![1, 2, 3]);
+        msg.sign(&signer, &sk, &pk).unwrap();
+        assert!(msg.verify(&signer));
+
+        // proof survives serialization, pk has to be filled again
+        let mut dse_msg = message::PbftMessage::from_bytes(&msg.into_bytes().unwrap()).unwrap();
+        assert!(!dse_msg.verify(&signer));
+        dse_msg.set_signer_id(signer_id.clone());
+        assert!(dse_msg.verify(&signer));
+
+        // any change in signed fields breaks the proof
+        dse_msg.set_view(1);
+        assert!(!dse_msg.verify(&signer));
+        dse_msg.set_view(0);
+        dse_msg.set_payload(vec![1, 2, 4]);
+        assert!(!dse_msg.verify(&signer));
+
+        // unsigned message never verifies
+        let unsigned = message::PbftMessage::new(
+            0, 1, 2, PbftMsgKind::PREPARE, signer_id, vec![1, 2, 3]);
+        assert!(!unsigned.verify(&signer));
+    }
+
+
+    #[test]
+    fn client_request_sign_verify() {
+
+        let signer = Ed25519Signer::new();
+        let (pk, sk) = signer.keygen();
+        let client = message::Peer::from_public_key(&PublicKey::Ed25519(pk));
+
+        let mut request = ClientRequest::new(client.clone(), next_timestamp(0), b"op".to_vec());
+        assert!(!request.verify(&signer));
+        request.sign(&signer, &PrivateKey::Ed25519(sk)).unwrap();
+        assert!(request.verify(&signer));
+
+        // the key travels with the request, nothing has to be filled
+        let dse_request = ClientRequest::from_bytes(&request.into_bytes().unwrap()).unwrap();
+        assert!(dse_request.verify(&signer));
+        assert_eq!(dse_request.client(), &client);
+        assert_eq!(dse_request.timestamp(), request.timestamp());
+        assert_eq!(dse_request.operation(), b"op");
+
+        let mut forged = dse_request.clone();
+        forged.timestamp += 1;
+        assert!(!forged.verify(&signer));
+
+        let reply = ClientReply::new(client, 7, b"ok".to_vec());
+        assert_eq!(ClientReply::from_bytes(&reply.into_bytes().unwrap()).unwrap(), reply);
+
+        // a request right after the last still gets a later timestamp
+        let last = request.timestamp();
+        assert!(next_timestamp(last) > last);
+    }
+
+}
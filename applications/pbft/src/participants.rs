@@ -14,6 +14,16 @@ pub trait ParticipantsStore: PartialEq {
     fn nth(&self, n: u32) -> Option<&Peer>;
 
     fn get_idx(&self, p: &Peer) -> Option<u32>;
+
+    fn len(&self) -> u32;
+
+    /// Replicas take turns being the primary, one view each.
+    fn primary(&self, view: u32) -> Option<&Peer> {
+        match self.len() {
+            0 => None,
+            n => self.nth(view % n),
+        }
+    }
 }
 
 
@@ -62,4 +72,8 @@ impl ParticipantsStore for Participants {
     fn get_idx(&self, p: &Peer) -> Option<u32> {
         self.indexed_peer.get_by_value(p).map(|p| p.to_owned())
     }
+
+    fn len(&self) -> u32 {
+        self.indexed_peer.iter().count() as u32
+    }
 }
\ No newline at end of file
@@ -1,21 +1,35 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::vec;
 use rand;
 use crate::message::{
+    digest,
+    next_timestamp,
+    pack_batch,
+    unpack_batch,
+    ClientReply,
+    ClientRequest,
     PbftMessage,
     PbftMsgKind
 };
 use crate::participants::{Participants, ParticipantsStore};
 use crate::quorum::{QuorumCollector, VoteBox, VoteBoxes, VoteResult};
 use crate::store::{Store, StoreService};
+use crate::checkpoint::{CachedReply, CheckpointCert, Snapshot, StateTransfer};
+use crate::view_change::{latest_checkpoint, reissue, NewView, PreparedCert, Verifier, ViewChange};
+use crate::config::PbftConfig;
 
 use log::{debug, info, warn};
+use rand::seq::IteratorRandom;
 
 use yulong_network::identity::Peer;
 use yulong_network::identity::Me;
-use yulong_network::identity::crypto::GenericSigner;
+use yulong_network::identity::crypto::{GenericSigner, PublicKey};
 
+use yulong::config::{Config, invalid};
+use yulong::error::ConfigError;
 use yulong::utils::AsBytes;
 use yulong::utils::CasualTimer;
+use yulong::utils::runtime;
 
 use yulong_bdn::overlay::BDN;
 use yulong_bdn::message::OverlayMessage;
@@ -30,18 +44,91 @@ use yulong_bdn::route_inner::RelayCtl;
 enum PbftStage {
     IDLE,
 
-    REQUEST,
     PRE_PREPARE,
     PREPARE,
     COMMIT,
-    REPLY,
 
-    BLAME,
-    NEW_EPOCH,
-    CONFIRM_EPOCH,
+    VIEW_CHANGE,
+}
+
+
+// hooks of the application, see set_executor and set_snapshot
+pub(crate) type Executor = Box<dyn FnMut(&[u8]) -> Vec<u8> + Send>;
+pub(crate) type TakeSnapshot = Box<dyn FnMut() -> Vec<u8> + Send>;
+pub(crate) type Restore = Box<dyn FnMut(&[u8]) + Send>;
+pub(crate) type CommitHook = Box<dyn FnMut(u32, &[u8]) + Send>;
+
+
+// a round in flight
+struct Slot {
+    stage: PbftStage,
+
+    // PRE-PREPARE of the round and the signed votes on it, votes arriving
+    // before the PRE-PREPARE are kept until it does
+    pre_prepare: Option<PbftMessage>,
+    prepares: HashMap<Peer, PbftMessage>,
+    commits: HashMap<Peer, PbftMessage>,
+
+    prepare_vote_box: VoteBox,
+    commit_vote_box: VoteBox,
+}
+
+
+impl Slot {
+
+    fn new(voter: usize, quorum: usize) -> Self {
+        Self {
+            stage: PbftStage::IDLE,
+            pre_prepare: None,
+            prepares: HashMap::new(),
+            commits: HashMap::new(),
+            prepare_vote_box: VoteBox::new(voter, quorum),
+            commit_vote_box: VoteBox::new(voter, quorum),
+        }
+    }
+
+
+    // count the votes on the request of the PRE-PREPARE, the PRE-PREPARE
+    // being the primary's
+    fn tally(&mut self) {
+        if let Some(pre_prepare) = &self.pre_prepare {
+            let request = digest(pre_prepare.payload());
+
+            self.prepare_vote_box.vote(pre_prepare.signer_id(), true);
+            for (peer, prepare) in &self.prepares {
+                if prepare.payload() == request {
+                    self.prepare_vote_box.vote(peer, true);
+                }
+            }
+
+            for (peer, commit) in &self.commits {
+                if commit.payload() == request {
+                    self.commit_vote_box.vote(peer, true);
+                }
+            }
+        }
+    }
+
+
+    fn cert(&self) -> Option<PreparedCert> {
+        let pre_prepare = self.pre_prepare.clone()?;
+        let request = digest(pre_prepare.payload());
+
+        let prepares = self.prepares.values()
+            .filter(|p| p.payload() == request && p.signer_id() != pre_prepare.signer_id())
+            .cloned()
+            .collect();
+
+        Some(PreparedCert::new(pre_prepare, prepares))
+    }
+
+
+    // replicas that voted in the round, on whatever request
+    fn voters(&self) -> usize {
+        self.prepares.keys().chain(self.commits.keys()).collect::<HashSet<&Peer>>().len()
+    }
 }
 
-const PAYLOAD_MAX: usize = 500; // bytes
 
 pub struct PbftContext<S, T, R> 
     where
@@ -56,6 +143,8 @@ pub struct PbftContext<S, T, R>
 
     seq: u32,
 
+    view: u32,
+    // next round to execute, the rounds before are committed here
     round: u32,
     stage: PbftStage,
 
@@ -68,18 +157,56 @@ pub struct PbftContext<S, T, R>
 
     primary_id: Peer,
 
-    prepare_vote_box: VoteBox,
-    commit_vote_box: VoteBox,
-    blame_vote_box: VoteBox,
-    new_epoch_vote_box: VoteBox,
+    // rounds from round on with a PRE-PREPARE or votes
+    slots: BTreeMap<u32, Slot>,
     reply_vote_boxes: VoteBoxes,
 
+    // at the primary, requests waiting for a batch and the round it goes to
+    queue: Vec<Vec<u8>>,
+    next_round: u32,
+
+    // certificates of the requests prepared here, by round, carried by
+    // VIEW-CHANGE
+    prepared: BTreeMap<u32, PreparedCert>,
+
+    // CHECKPOINTs received, by round then sender, until one is stable
+    checkpoints: BTreeMap<u32, HashMap<Peer, PbftMessage>>,
+    stable_cert: Option<CheckpointCert>,
+    // snapshots of the checkpoints taken or installed here from the stable
+    // one on, replicas behind get that one
+    snapshots: BTreeMap<u32, Vec<u8>>,
+
+    // view to move to while in VIEW_CHANGE stage
+    next_view: u32,
+    view_changes: BTreeMap<u32, HashMap<Peer, (PbftMessage, ViewChange)>>,
+
+    // requests this replica waits to see executed, its own and those it
+    // relayed for clients
+    awaited_requests: Vec<Vec<u8>>,
+    // timestamp of the last request sent from here
+    timestamp: u64,
+
+    // last reply to each client with the timestamp it answers, sent again
+    // for a retried request
+    replies: HashMap<Peer, (u64, PbftMessage)>,
+    executor: Option<Executor>,
+    take_snapshot: Option<TakeSnapshot>,
+    restore: Option<Restore>,
+    commit_hook: Option<CommitHook>,
+
     request_timer: CasualTimer,
     preprepare_timer: CasualTimer,
     prepare_timer: CasualTimer,
+    view_change_timer: CasualTimer,
+    batch_timer: CasualTimer,
 
     test: bool,
 
+    // messages from here are held for byzantine::ByzantineReplica instead
+    // of sent, each with its target or None for a broadcast
+    held: Option<Vec<(PbftMessage, Option<Peer>)>>,
+
+    config: PbftConfig,
 }
 
 
@@ -90,6 +217,135 @@ impl<S, T, R> PbftContext<S, T, R>
         R: RelayCtl
 {
 
+    /// participants is the full replica set in a fixed order shared by every
+    /// replica, local_id included. Replica v % n is the primary of view v.
+    /// network_handle must run as local_id, and participants carry their
+    /// public keys, each listed once.
+    pub fn new(
+        network_handle: BDN<T, R>,
+        signer: S,
+        local_id: Me,
+        participants: Vec<Peer>,
+        config: PbftConfig
+    ) -> Result<Self, ConfigError> {
+
+        config.validate()?;
+
+        if participants.iter().collect::<HashSet<&Peer>>().len() != participants.len() {
+            return Err(invalid("A participant is listed twice"));
+        }
+        if !participants.contains(local_id.peer()) {
+            return Err(invalid("local_id is not a participant"));
+        }
+        // messages are verified against them
+        if participants.iter().any(|peer| matches!(peer.pubkey(), PublicKey::NoKey)) {
+            return Err(invalid("Participants should come with their public keys"));
+        }
+        if network_handle.local_identity.peer() != local_id.peer() {
+            return Err(invalid("network runs as another identity than local_id"));
+        }
+
+        let total_node = participants.len() as u32;
+
+        // tolerate f faulty replicas out of n >= 3f + 1, any two quorums of
+        // n - f share f + 1 replicas, an honest one among them
+        let faulty = total_node.saturating_sub(1) / 3;
+        let quorum_size = total_node - faulty;
+
+        let total_node_set = Participants::new(participants);
+        let primary_id = total_node_set.primary(0).cloned().unwrap_or(Peer::BROADCAST_ID);
+
+        Ok(Self {
+            network_handle,
+            signer,
+            local_id,
+            seq: 0,
+            view: 0,
+            round: 0,
+            stage: PbftStage::IDLE,
+            total_node,
+            total_node_set,
+            commit_log: Store::new(),
+            quorum_size,
+            primary_id,
+            slots: BTreeMap::new(),
+            reply_vote_boxes: VoteBoxes::new(total_node as usize, quorum_size as usize),
+            queue: Vec::new(),
+            next_round: 0,
+            prepared: BTreeMap::new(),
+            checkpoints: BTreeMap::new(),
+            stable_cert: None,
+            snapshots: BTreeMap::new(),
+            next_view: 0,
+            view_changes: BTreeMap::new(),
+            awaited_requests: Vec::new(),
+            timestamp: 0,
+            replies: HashMap::new(),
+            executor: None,
+            take_snapshot: None,
+            restore: None,
+            commit_hook: None,
+            request_timer: CasualTimer::new(config.request_to as u128),
+            preprepare_timer: CasualTimer::new(config.preprepare_to as u128),
+            prepare_timer: CasualTimer::new(config.prepare_to as u128),
+            view_change_timer: CasualTimer::new(config.view_change_to as u128),
+            batch_timer: CasualTimer::new(config.batch_delay as u128),
+            test: false,
+            held: None,
+            config,
+        })
+    }
+
+
+    /// Run the operations of committed requests through executor, its
+    /// output is the result their clients get. Without one the result is
+    /// empty, the reply only tells the request is ordered.
+    ///
+    /// A replica that falls behind a stable checkpoint does not execute the
+    /// rounds it missed, it needs set_snapshot to catch up.
+    pub fn set_executor<F>(&mut self, executor: F)
+        where F: FnMut(&[u8]) -> Vec<u8> + Send + 'static
+    {
+        self.executor = Some(Box::new(executor));
+    }
+
+
+    /// Let checkpoints carry the state of the executor: snapshot takes it
+    /// after the last round of a checkpoint and restore brings it back at a
+    /// replica behind. Every replica must take the same bytes for the same
+    /// requests executed, the checkpoint is agreed on them.
+    pub fn set_snapshot<F, G>(&mut self, snapshot: F, restore: G)
+        where
+            F: FnMut() -> Vec<u8> + Send + 'static,
+            G: FnMut(&[u8]) + Send + 'static
+    {
+        self.take_snapshot = Some(Box::new(snapshot));
+        self.restore = Some(Box::new(restore));
+    }
+
+
+    /// Call hook with each round this replica executes and the batch of
+    /// requests committed for it, rounds skipped by a state transfer are
+    /// not seen.
+    pub fn set_commit_hook<F>(&mut self, hook: F)
+        where F: FnMut(u32, &[u8]) + Send + 'static
+    {
+        self.commit_hook = Some(Box::new(hook));
+    }
+
+
+    /// Replicas a decision takes, n - f out of n, 2f + 1 out of 3f + 1.
+    pub fn quorum_size(&self) -> u32 {
+        self.quorum_size
+    }
+
+
+    /// Primary of the current view.
+    pub fn primary(&self) -> &Peer {
+        &self.primary_id
+    }
+
+
     // call this every tick
     pub fn heartbeat(&mut self) {
 
@@ -118,26 +374,65 @@ impl<S, T, R> PbftContext<S, T, R>
         self.request_timer.reset();
         self.preprepare_timer.reset();
         self.prepare_timer.reset();
+        self.view_change_timer.reset();
     }
 
 
     fn check_all_timer(&mut self) {
         
-        if self.request_timer.is_timeout() {
+        // timers are armed only while something is awaited
+        let due = |timer: &CasualTimer| timer.is_set() && timer.is_timeout();
+
+        if due(&self.request_timer) {
             self.request_timer.reset();
             self.request_timeout_cb();
         }
 
-        if self.preprepare_timer.is_timeout() {
+        if due(&self.preprepare_timer) {
             self.preprepare_timer.reset();
             self.preprepare_timeout_cb();
         }
 
-        if self.prepare_timer.is_timeout() {
+        if due(&self.prepare_timer) {
             self.prepare_timer.reset();
             self.prepare_timeout_cb();
         }
 
+        if self.stage == PbftStage::VIEW_CHANGE && self.view_change_timer.is_timeout() {
+            self.view_change_timer.reset();
+            self.view_change_timeout_cb();
+        }
+
+        if !self.queue.is_empty() && self.batch_timer.is_timeout() {
+            self.flush();
+        }
+
+    }
+
+
+    // timers run while something is awaited, an accepted round to execute
+    // or a PRE-PREPARE missing. One is missing once f + 1 replicas vote in
+    // its round, an honest one among them, or a request waits here, a
+    // faulty replica voting alone cannot force a view change.
+    fn rearm(&mut self) {
+        if self.stage == PbftStage::VIEW_CHANGE {
+            return;
+        }
+
+        let faulty = (self.total_node - self.quorum_size) as usize;
+        let waiting = !self.awaited_requests.is_empty();
+        let orphan = self.slots.values()
+            .any(|slot| slot.pre_prepare.is_none() && (waiting || slot.voters() > faulty));
+        let in_flight = self.slots.values().any(|slot| slot.pre_prepare.is_some());
+
+        for (timer, awaited) in [(&mut self.preprepare_timer, orphan), (&mut self.prepare_timer, in_flight)] {
+            if !awaited {
+                timer.reset();
+            }
+            else if !timer.is_set() {
+                timer.set_now();
+            }
+        }
     }
 
 
@@ -146,16 +441,17 @@ impl<S, T, R> PbftContext<S, T, R>
         let peer_id = msg.signer_id_mut();
         self.total_node_set.query_pk(peer_id);
 
-        if !msg.verify() {
-            warn!("PbftContext::pbft_msg_cb fail to verify signature.");
-            // return;
+        // a request is signed by its client, whoever relays it
+        if msg.msg_type() != PbftMsgKind::REQUEST && !msg.verify(&self.signer) {
+            warn!("PbftContext::pbft_msg_cb fail to verify signature, drop.");
+            return;
         }
 
         match msg.msg_type() {
 
             PbftMsgKind::REQUEST => self.request_cb(msg),
             
-            PbftMsgKind::PRE_PREPARE => self.prepare_cb(msg),
+            PbftMsgKind::PRE_PREPARE => self.preprepare_cb(msg),
             
             PbftMsgKind::PREPARE => self.prepare_cb(msg),
             
@@ -163,224 +459,327 @@ impl<S, T, R> PbftContext<S, T, R>
             
             PbftMsgKind::REPLY => self.replay_cb(msg),
             
-            PbftMsgKind::BLAME => self.blame_cb(msg),
+            PbftMsgKind::VIEW_CHANGE => self.view_change_cb(msg),
             
-            PbftMsgKind::NEW_EPOCH => self.new_epoch_cb(msg),
-            
-            PbftMsgKind::CONFIRM_EPOCH => self.confirm_epoch_cb(msg),
+            PbftMsgKind::NEW_VIEW => self.new_view_cb(msg),
+
+            PbftMsgKind::CHECKPOINT => self.checkpoint_cb(msg),
+
+            PbftMsgKind::FETCH_STATE => self.fetch_state_cb(msg),
+
+            PbftMsgKind::STATE => self.state_cb(msg),
         
         }
+
+        self.rearm();
     }
 
 
     fn request_cb(&mut self, msg: PbftMessage) {
-        if *self.local_id.peer() == self.primary_id && self.stage == PbftStage::IDLE {
+        let request = match ClientRequest::from_bytes(msg.payload()) {
+            Ok(request) if request.verify(&self.signer) => request,
+            _ => {
+                warn!("PbftContext::request_cb invalid request from {}", msg.signer_id());
+                return;
+            }
+        };
 
-            self.commit_log.pending(self.round, msg.payload());
+        // executed already, the client may have missed the reply
+        if let Some((timestamp, reply)) = self.replies.get(request.client()).cloned() {
+            if timestamp == request.timestamp() {
+                runtime::block_on(
+                    self.send_to_direct(reply, request.client())
+                );
+            }
+            if timestamp >= request.timestamp() {
+                return;
+            }
+        }
 
-            let mut reply_msg = PbftMessage::new(
-                self.round,
-                self.seq(),
-                PbftMsgKind::PRE_PREPARE,
-                self.local_id.peer().clone(),
-                msg.payload().to_vec()
-            );
+        let request = msg.payload().to_vec();
+        if !self.is_primary() {
+            // a client that gets no answer sends to every replica
+            self.relay(request);
+        }
+        else if self.stage != PbftStage::VIEW_CHANGE && !self.queue.contains(&request) {
+            self.enqueue(request);
+        }
+    }
 
-            reply_msg.sign(
-                &self.signer, 
-                self.local_id.private_key(), 
-                self.local_id.peer().pubkey()
-            );
 
-            async_std::task::block_on(
-                self.broadcast(reply_msg)
+    fn enqueue(&mut self, request: Vec<u8>) {
+        if self.queue.is_empty() {
+            self.batch_timer.set_now();
+        }
+        self.queue.push(request);
+
+        self.flush();
+    }
+
+
+    // the primary orders queued requests in one PRE-PREPARE once there are
+    // batch_max of them or the oldest waited batch_delay, as long as the
+    // round is within the pipeline window and the water marks
+    fn flush(&mut self) {
+        while self.is_primary() && self.stage != PbftStage::VIEW_CHANGE && !self.queue.is_empty() {
+
+            let due = self.queue.len() >= self.config.batch_max || self.batch_timer.is_timeout();
+            let room = self.next_round < self.round + self.config.pipeline_window &&
+                self.next_round < self.high_mark();
+            if !due || !room {
+                return;
+            }
+
+            let n = self.queue.len().min(self.config.batch_max);
+            let batch: Vec<Vec<u8>> = self.queue.drain(..n).collect();
+
+            let round = self.next_round;
+            self.next_round += 1;
+
+            let pre_prepare = self.signed(self.view, round, PbftMsgKind::PRE_PREPARE, pack_batch(&batch));
+
+            runtime::block_on(
+                self.broadcast(pre_prepare.clone())
             );
-            
-            self.stage = PbftStage::PRE_PREPARE;
 
-            self.reset_all_timer();
-            self.preprepare_timer.set_now();
+            self.propose(pre_prepare);
+
+            self.batch_timer.reset();
+            if !self.queue.is_empty() {
+                self.batch_timer.set_now();
+            }
         }
     }
 
 
-    fn preprepare_cb(&mut self, msg: PbftMessage) {
-        if *msg.signer_id() == self.primary_id {
-            if msg.round() == self.round {
-                if self.stage == PbftStage::IDLE || 
-                    self.stage == PbftStage::REQUEST 
-                {
-                    // everything is right
-
-                    self.commit_log.pending(self.round, msg.payload());
-
-                    // do not need to include the payload since it has been
-                    //  broadcasted with PRE_PREPARE msg
-                    let mut reply_msg = PbftMessage::new(
-                        self.round,
-                        self.seq(),
-                        PbftMsgKind::PREPARE,
-                        self.local_id.peer().clone(),
-                        vec![]
-                    );
-
-                    reply_msg.sign(
-                        &self.signer, 
-                        self.local_id.private_key(), 
-                        self.local_id.peer().pubkey()
-                    );
-                    
-                    async_std::task::block_on(
-                        self.broadcast(reply_msg)
-                    );
-                    
-                    self.stage = PbftStage::PRE_PREPARE;
-
-                    self.reset_all_timer();
-                    self.preprepare_timer.set_now();
-
-                    // in case message received is quite out-of-order
-                    self.prepared(false);
-                }
-                else {
-                    debug!("PbftContext::preprepareCb duplicated preprepare");
-                }
-            }
-            else if msg.round() > self.round {
-                // perhaps lag back
-                // believe the primary
+    fn slot(&mut self, round: u32) -> &mut Slot {
+        let (voter, quorum) = (self.total_node as usize, self.quorum_size as usize);
+        self.slots.entry(round).or_insert_with(|| Slot::new(voter, quorum))
+    }
 
-                // give up stale states
-                self.discard_round();
 
-                self.round = msg.round();
+    // rounds this replica takes messages for
+    fn in_window(&self, round: u32) -> bool {
+        round >= self.round && round < self.high_mark()
+    }
 
-                let mut reply_msg = PbftMessage::new(
-                    self.round,
-                    self.seq(),
-                    PbftMsgKind::PREPARE,
-                    self.local_id.peer().clone(),
-                    vec![]
-                );
 
-                reply_msg.sign(
-                    &self.signer, 
-                    self.local_id.private_key(), 
-                    self.local_id.peer().pubkey()
-                );
-                
-                async_std::task::block_on(
-                    self.broadcast(reply_msg)
-                );
+    // rounds this replica takes votes for, those ahead of any PRE-PREPARE
+    // only within the pipeline window, further ones cannot be ordered yet
+    fn takes_vote(&self, round: u32) -> bool {
+        self.in_window(round) &&
+            (self.slots.contains_key(&round) || round < self.round + self.config.pipeline_window)
+    }
 
-                self.stage = PbftStage::PRE_PREPARE;
 
-                self.reset_all_timer();
-                self.preprepare_timer.set_now();
+    // the primary's side of a PRE-PREPARE it sent
+    fn propose(&mut self, pre_prepare: PbftMessage) {
+        let round = pre_prepare.round();
+        self.commit_log.pending(round, pre_prepare.payload());
 
-                // in case message received is quite out-of-order
-                self.prepared(false);
-            }
-            else {
-                // msg round < node round
-                // either conflict or rather old msg
-                // ignore it
-                debug!("PbftContext::preprepareCb stale preprepare msg");
-            }
+        let slot = self.slot(round);
+        slot.pre_prepare = Some(pre_prepare);
+        slot.stage = PbftStage::PRE_PREPARE;
+
+        // in case message received is quite out-of-order
+        self.prepared(round);
+    }
+
+
+    fn preprepare_cb(&mut self, msg: PbftMessage) {
+        if self.stage == PbftStage::VIEW_CHANGE || msg.view() != self.view {
+            debug!("PbftContext::preprepareCb preprepare out of view");
+            return;
+        }
+
+        if *msg.signer_id() != self.primary_id {
+            return;
+        }
+
+        let round = msg.round();
+        if round >= self.high_mark() {
+            // far behind, the rounds in between are settled by now
+            self.fetch_state(msg.signer_id().to_owned());
+        }
+        else if round < self.round {
+            // either conflict or rather old msg
+            // ignore it
+            debug!("PbftContext::preprepareCb stale preprepare msg");
+        }
+        else if self.slot(round).pre_prepare.is_some() {
+            debug!("PbftContext::preprepareCb duplicated preprepare");
+        }
+        else {
+            // everything is right
+            self.accept(msg);
         }
     }
 
 
-    fn prepared(&mut self, shortcut: bool) {
-        if shortcut || (self.prepare_vote_box.result() == VoteResult::PASS &&
-            self.stage == PbftStage::PRE_PREPARE)
-        {
-            let mut reply_msg = PbftMessage::new(
-                self.round,
-                self.seq(),
-                PbftMsgKind::COMMIT,
-                self.local_id.peer().clone(),
-                vec![]
-            );
+    // a backup's side of the primary's PRE-PREPARE
+    fn accept(&mut self, pre_prepare: PbftMessage) {
+        let round = pre_prepare.round();
+        self.commit_log.pending(round, pre_prepare.payload());
 
-            reply_msg.sign(
-                &self.signer, 
-                self.local_id.private_key(), 
-                self.local_id.peer().pubkey()
-            );
+        // do not need to include the payload since it has been
+        //  broadcasted with PRE_PREPARE msg, its digest binds the vote
+        let prepare = self.signed(
+            self.view, round, PbftMsgKind::PREPARE, digest(pre_prepare.payload()).to_vec());
 
-            async_std::task::block_on(
-                self.broadcast(reply_msg)
-            );
+        let local = self.local_id.peer().to_owned();
+        let slot = self.slot(round);
+        slot.pre_prepare = Some(pre_prepare);
+        slot.prepares.insert(local, prepare.clone());
+        slot.stage = PbftStage::PRE_PREPARE;
+
+        runtime::block_on(
+            self.broadcast(prepare)
+        );
+
+        // in case message received is quite out-of-order
+        self.prepared(round);
+    }
 
-            self.stage = PbftStage::PREPARE;
 
-            self.reset_all_timer();
-            self.prepare_timer.set_now();
+    fn prepared(&mut self, round: u32) {
+        let slot = match self.slots.get_mut(&round) {
+            Some(slot) => slot,
+            None => return,
+        };
 
-            self.committed();
+        slot.tally();
+        if slot.prepare_vote_box.result() != VoteResult::PASS || slot.stage != PbftStage::PRE_PREPARE {
+            return;
         }
+
+        slot.stage = PbftStage::PREPARE;
+        // tally only votes when there is a PRE-PREPARE
+        let cert = slot.cert().unwrap();
+        let request = digest(cert.request());
+        self.prepared.insert(round, cert);
+
+        let commit = self.signed(self.view, round, PbftMsgKind::COMMIT, request.to_vec());
+        let local = self.local_id.peer().to_owned();
+        self.slot(round).commits.insert(local, commit.clone());
+
+        runtime::block_on(
+            self.broadcast(commit)
+        );
+
+        self.committed(round);
     }
 
 
     fn prepare_cb(&mut self, msg: PbftMessage) {
-        if msg.round() == self.round {
-            self.prepare_vote_box.vote(msg.signer_id(), true);
-            if self.prepare_vote_box.result() == VoteResult::PASS &&
-                self.stage == PbftStage::PRE_PREPARE 
-            {
-                self.prepared(true);
-            }
+        if self.stage != PbftStage::VIEW_CHANGE &&
+            msg.view() == self.view &&
+            self.takes_vote(msg.round())
+        {
+            let round = msg.round();
+            self.slot(round).prepares.insert(msg.signer_id().to_owned(), msg);
+            self.prepared(round);
         }
     }
 
 
-    fn committed(&mut self) {
-        if self.commit_vote_box.result() == VoteResult::PASS && self.stage == PbftStage::PREPARE {
-            self.stage = PbftStage::COMMIT;
-            self.reset_all_timer();
+    fn committed(&mut self, round: u32) {
+        let slot = match self.slots.get_mut(&round) {
+            Some(slot) => slot,
+            None => return,
+        };
 
-            self.commit_log.commit(self.round);
-
-            if *self.local_id.peer() != self.primary_id {
-                self.send_reply();
-                self.new_round();
-            }
+        slot.tally();
+        if slot.commit_vote_box.result() == VoteResult::PASS && slot.stage == PbftStage::PREPARE {
+            slot.stage = PbftStage::COMMIT;
+            self.execute();
         }
     }
 
 
     fn commit_cb(&mut self, msg: PbftMessage) {
-        if msg.round() == self.round {
-            self.commit_vote_box.vote(msg.signer_id(), true);
-            if self.commit_vote_box.result() == VoteResult::PASS && self.stage == PbftStage::PREPARE {
-                self.committed();
-            }
+        if self.stage != PbftStage::VIEW_CHANGE &&
+            msg.view() == self.view &&
+            self.takes_vote(msg.round())
+        {
+            let round = msg.round();
+            self.slot(round).commits.insert(msg.signer_id().to_owned(), msg);
+            self.committed(round);
         }
     }
 
 
-    fn blame_cb(&mut self, msg: PbftMessage) {
-        // todo: view change is not fully implemented
-        
-        if msg.round() == self.round {
-            self.blame_vote_box.vote(msg.signer_id(), true);
-            if self.blame_vote_box.result() == VoteResult::PASS {
-                if self.is_backup_primary() {
-                    // claim to be the new primary
-                    self.stage = PbftStage::NEW_EPOCH;
-                    self.send_new_epoch();
-                }
-                else {
-                    // aware of a epoch-changing event
-                    // todo timeout
-                    self.stage = PbftStage::NEW_EPOCH;
+    // rounds take effect in order, one committed early waits for those
+    // before it
+    fn execute(&mut self) {
+        while self.slots.get(&self.round).map_or(false, |slot| slot.stage == PbftStage::COMMIT) {
+            let round = self.round;
+            self.slots.remove(&round);
+            self.round += 1;
+
+            let committed = self.commit_log.commit(round);
+            if let (Some(hook), Some(batch)) = (&mut self.commit_hook, committed) {
+                hook(round, batch);
+            }
+
+            let batch = committed.map_or(vec![], |batch| unpack_batch(batch).unwrap_or_default());
+            for request in &batch {
+                self.apply(round, request);
+            }
+
+            let awaited = self.awaited_requests.len();
+            self.awaited_requests.retain(|request| !batch.contains(request));
+            if self.awaited_requests.len() < awaited {
+                // progress, the requests left get a full timeout
+                self.request_timer.reset();
+                if !self.awaited_requests.is_empty() {
+                    self.request_timer.set_now();
                 }
             }
+
+            self.take_checkpoint(round);
+
+            // progress, the next round gets a full timeout
+            self.prepare_timer.reset();
         }
+
+        self.next_round = self.next_round.max(self.round);
+        self.flush();
     }
 
 
+    // execute a committed request once and answer its client
+    fn apply(&mut self, round: u32, request: &[u8]) {
+        let request = match ClientRequest::from_bytes(request) {
+            Ok(request) if request.verify(&self.signer) => request,
+            // ordered by a faulty primary, every replica skips it alike
+            _ => return,
+        };
+
+        let client = request.client().to_owned();
+        if self.replies.get(&client).map_or(false, |(timestamp, _)| *timestamp >= request.timestamp()) {
+            debug!("PbftContext::apply request of {} ordered again", client);
+            return;
+        }
+
+        let result = match &mut self.executor {
+            Some(executor) => executor(request.operation()),
+            None => vec![],
+        };
+
+        // ClientReply::into_bytes does not fail, safe unwrap
+        let reply = ClientReply::new(client.clone(), request.timestamp(), result).into_bytes().unwrap();
+        let reply = self.signed(self.view, round, PbftMsgKind::REPLY, reply);
+        self.replies.insert(client.clone(), (request.timestamp(), reply.clone()));
+
+        if client != *self.local_id.peer() {
+            runtime::block_on(
+                self.send_to_direct(reply, &client)
+            );
+        }
+    }
+
+
+    // replies to the requests sent from here
     fn replay_cb(&mut self, msg: PbftMessage) {
         
         self.reply_vote_boxes.vote(msg.signer_id(), true, msg.round());
@@ -390,172 +789,555 @@ impl<S, T, R> PbftContext<S, T, R>
             info!("round: {}", msg.round());
         }
 
-        if *self.local_id.peer() == self.primary_id && msg.round() == self.round {
-            if self.reply_vote_boxes.result(self.round) == VoteResult::PASS {
-                info!("Majority Committed");
-                info!("round: {}", self.round);
+        // only on the reply that passes it, later ones change nothing
+        if self.reply_vote_boxes.result(msg.round()) == VoteResult::PASS &&
+            self.reply_vote_boxes.count_pos(msg.round()) == self.quorum_size {
+            info!("Majority Committed");
+            info!("round: {}", msg.round());
 
-                self.new_round();
+            if self.test {
+                self.send_request(&self.test_msg());
+            }
+        }
 
-                if self.test {
-                    self.send_request(&Self::test_msg());
-                }
+    }
+
+
+    fn view_change_cb(&mut self, mut msg: PbftMessage) {
+        if msg.view() <= self.view {
+            debug!("PbftContext::view_change_cb stale view change to {}", msg.view());
+            return;
+        }
+
+        let view_change = match self.verifier().view_change(&mut msg) {
+            Some(view_change) => view_change,
+            None => {
+                warn!("PbftContext::view_change_cb invalid view change from {}", msg.signer_id());
+                return;
             }
+        };
+
+        let view = msg.view();
+        if !self.keep_view_change(view, msg, view_change) {
+            debug!("PbftContext::view_change_cb superseded view change to {}", view);
+            return;
+        }
+
+        // f + 1 replicas leaving the view include an honest one, follow
+        // them to the lowest view they go to
+        let mut ahead = HashSet::new();
+        let mut lowest = None;
+        for (v, senders) in self.view_changes.range(self.target_view() + 1..) {
+            lowest.get_or_insert(*v);
+            ahead.extend(senders.keys());
         }
 
+        if ahead.len() > self.faulty() {
+            if let Some(v) = lowest {
+                self.start_view_change(v);
+            }
+        }
+
+        self.try_new_view(view);
     }
 
 
-    fn new_epoch_cb(&mut self, msg: PbftMessage) {
-        if msg.round() == self.round && *msg.signer_id() == self.get_backup_primary() {
-            let mut reply_msg = PbftMessage::new(
-                self.round,
-                self.seq(),
-                PbftMsgKind::CONFIRM_EPOCH,
-                self.local_id.peer().clone(),
-                vec![]
-            );
+    // only the latest view change of each replica is kept, one that moved
+    // past a view no longer counts for it. False if it was superseded
+    fn keep_view_change(&mut self, view: u32, msg: PbftMessage, view_change: ViewChange) -> bool {
+        let signer = msg.signer_id().to_owned();
+        if self.view_changes.range(view + 1..).any(|(_, senders)| senders.contains_key(&signer)) {
+            return false;
+        }
 
-            reply_msg.sign(
-                &self.signer, 
-                self.local_id.private_key(), 
-                self.local_id.peer().pubkey()
-            );
+        self.view_changes.retain(|_, senders| {
+            senders.remove(&signer);
+            !senders.is_empty()
+        });
+        self.view_changes.entry(view).or_default().insert(signer, (msg, view_change));
+        true
+    }
 
-            self.discard_round();
-            self.primary_id = msg.signer_id().to_owned();
 
-            async_std::task::block_on(
-                self.send_to_primary(reply_msg)
-            );
+    fn new_view_cb(&mut self, msg: PbftMessage) {
+        if msg.view() <= self.view {
+            debug!("PbftContext::new_view_cb stale new view {}", msg.view());
+            return;
+        }
+
+        if self.total_node_set.primary(msg.view()) != Some(msg.signer_id()) {
+            warn!("PbftContext::new_view_cb {} is not the primary of view {}",
+                msg.signer_id(), msg.view());
+            return;
+        }
+
+        let new_view = match NewView::from_payload(msg.payload()) {
+            Ok(new_view) => new_view,
+            Err(error) => {
+                warn!("PbftContext::new_view_cb {}", error);
+                return;
+            }
+        };
+
+        match self.verifier().new_view(msg.view(), new_view) {
+            Some((checkpoint, pre_prepares)) => self.enter_view(msg.view(), checkpoint, pre_prepares),
+            None => warn!("PbftContext::new_view_cb invalid new view from {}", msg.signer_id()),
         }
     }
 
 
-    fn confirm_epoch_cb(&mut self, msg: PbftMessage) {
-        self.new_epoch_vote_box.vote(msg.signer_id(), true);
-        if self.new_epoch_vote_box.result() == VoteResult::PASS {
-            self.discard_round();
-            self.primary_id = msg.signer_id().to_owned();
+    // leave the current view, telling the primary of view what prepared here
+    fn start_view_change(&mut self, view: u32) {
+        info!("PbftContext::start_view_change to view {}", view);
+
+        // rounds not executed yet are either carried by a prepared
+        // certificate or proposed again, queued requests are sent again
+        self.slots.clear();
+        self.queue.clear();
+        self.stage = PbftStage::VIEW_CHANGE;
+        self.next_view = view;
+
+        self.reset_all_timer();
+        self.view_change_timer.set_now();
+
+        // prepared holds nothing below the stable checkpoint
+        let view_change = ViewChange::new(
+            self.stable_cert.clone(), self.prepared.values().cloned().collect());
+
+        let payload = match view_change.into_payload() {
+            Ok(payload) => payload,
+            Err(error) => {
+                warn!("PbftContext::start_view_change {}", error);
+                return;
+            }
+        };
+
+        let msg = self.signed(view, self.round, PbftMsgKind::VIEW_CHANGE, payload);
+        self.keep_view_change(view, msg.clone(), view_change);
+
+        runtime::block_on(
+            self.broadcast(msg)
+        );
+
+        self.try_new_view(view);
+    }
 
             
-            let mut payload: Vec<u8>;
+    // as the primary of view, announce it once 2f + 1 replicas moved to it
+    fn try_new_view(&mut self, view: u32) {
+        if self.stage != PbftStage::VIEW_CHANGE ||
+            self.next_view != view ||
+            self.total_node_set.primary(view) != Some(self.local_id.peer())
+        {
+            return;
+        }
             
-            let saved_req = self.commit_log.get_pending(self.round);
-            if saved_req.is_some() {
-                payload = saved_req.unwrap().to_vec();
+        let (msgs, view_changes): (Vec<PbftMessage>, Vec<ViewChange>) = match self.view_changes.get(&view) {
+            Some(votes) if votes.len() >= self.quorum_size as usize => votes.values().cloned().unzip(),
+            _ => return,
+        };
+
+        let checkpoint = latest_checkpoint(&view_changes);
+        let (low, requests) = reissue(&view_changes);
+
+        let mut pre_prepares = Vec::with_capacity(requests.len());
+        for (n, request) in requests.into_iter().enumerate() {
+            pre_prepares.push(self.signed(view, low + n as u32, PbftMsgKind::PRE_PREPARE, request));
+        }
+
+        let payload = match NewView::new(msgs, pre_prepares.clone()).into_payload() {
+            Ok(payload) => payload,
+            Err(error) => {
+                warn!("PbftContext::try_new_view {}", error);
+                return;
+            }
+        };
+
+        let msg = self.signed(view, self.round, PbftMsgKind::NEW_VIEW, payload);
+        runtime::block_on(
+            self.broadcast(msg)
+        );
+
+        self.enter_view(view, checkpoint, pre_prepares);
+    }
+
+
+    // pre_prepares are those of the NEW-VIEW, for the rounds after checkpoint
+    fn enter_view(&mut self, view: u32, checkpoint: Option<CheckpointCert>, pre_prepares: Vec<PbftMessage>) {
+        info!("PbftContext::enter_view view {}", view);
+
+        self.slots.clear();
+        self.stage = PbftStage::IDLE;
+        self.view = view;
+        // the view is checked against participants, safe unwrap
+        self.primary_id = self.total_node_set.primary(view).unwrap().to_owned();
+        self.view_changes = self.view_changes.split_off(&(view + 1));
+        self.reset_all_timer();
+
+        // a replica behind fetches the state of the settled rounds
+        if let Some(cert) = checkpoint {
+            self.settle(cert, None);
+        }
+
+        let carried: Vec<Vec<u8>> = pre_prepares.iter()
+            .flat_map(|p| unpack_batch(p.payload()).unwrap_or_default())
+            .collect();
+        self.next_round = pre_prepares.last().map_or(self.round, |p| p.round() + 1).max(self.round);
+
+        for pre_prepare in pre_prepares {
+            if pre_prepare.round() < self.round {
+                self.vote_again(pre_prepare);
+            }
+            else if self.is_primary() {
+                self.propose(pre_prepare);
             }
             else {
-                // todo: resume the request
-                // the request is send to original primary
-                // it may or maybe be shared through preprepare
-                // therefore we may need to ak for the request from its original proposal
+                self.accept(pre_prepare);
+            }
+        }
+
+        // requests lost with the old view go to the new primary
+        let lost: Vec<Vec<u8>> = self.awaited_requests.iter()
+            .filter(|request| !carried.contains(request))
+            .cloned()
+            .collect();
+        for request in lost {
+            self.forward(request);
+        }
+        if !self.awaited_requests.is_empty() {
+            self.request_timer.set_now();
+        }
 
-                // use random bytes as a temp placeholder
-                payload = Self::test_msg();
+        self.rearm();
+    }
+
+
+    // a round committed here may still be open at replicas behind, vote on
+    // it again in the new view
+    fn vote_again(&mut self, pre_prepare: PbftMessage) {
+        let round = pre_prepare.round();
+        let request = digest(pre_prepare.payload());
+
+        match self.commit_log.get_pending(round) {
+            Some(done) if digest(done) == request => {}
+            _ => {
+                debug!("PbftContext::vote_again nothing to vote for round {}", round);
+                return;
             }
+        }
 
-            self.send_request(&payload);
+        if !self.is_primary() {
+            let prepare = self.signed(self.view, round, PbftMsgKind::PREPARE, request.to_vec());
+            runtime::block_on(
+                self.broadcast(prepare)
+            );
         }
+
+        let commit = self.signed(self.view, round, PbftMsgKind::COMMIT, request.to_vec());
+        runtime::block_on(
+            self.broadcast(commit)
+        );
     }
 
 
-    fn request_timeout_cb(&mut self) {
-        self.send_blame();
+    // every checkpoint_period rounds, tell the others the state reached
+    fn take_checkpoint(&mut self, round: u32) {
+        if (round + 1) % self.config.checkpoint_period != 0 {
+            return;
+        }
+
+        let chain = match self.commit_log.state(round) {
+            Some(chain) => chain,
+            None => {
+                // rounds were skipped, the others' checkpoint will do
+                debug!("PbftContext::take_checkpoint missing rounds before {}", round);
+                return;
+            }
+        };
+
+        let snapshot = match self.snapshot(chain).into_bytes() {
+            Ok(snapshot) => snapshot,
+            Err(error) => {
+                warn!("PbftContext::take_checkpoint {}", error);
+                return;
+            }
+        };
+        let state = digest(&snapshot);
+        self.snapshots.insert(round, snapshot);
+
+        let msg = self.signed(self.view, round, PbftMsgKind::CHECKPOINT, state.to_vec());
+        self.checkpoints.entry(round).or_default()
+            .insert(self.local_id.peer().to_owned(), msg.clone());
+
+        runtime::block_on(
+            self.broadcast(msg)
+        );
+
+        self.try_stable(round);
     }
 
 
-    fn preprepare_timeout_cb(&mut self) {
-        self.send_blame();
+    fn checkpoint_cb(&mut self, msg: PbftMessage) {
+        let round = msg.round();
+        if round < self.low_mark() || round >= self.high_mark() ||
+            (round + 1) % self.config.checkpoint_period != 0 ||
+            msg.payload().len() != 32
+        {
+            debug!("PbftContext::checkpoint_cb drop checkpoint of round {}", round);
+            return;
+        }
+
+        self.checkpoints.entry(round).or_default()
+            .insert(msg.signer_id().to_owned(), msg);
+        self.try_stable(round);
     }
 
 
-    fn prepare_timeout_cb(&mut self) {
-        self.send_blame();
+    // stable once 2f + 1 replicas agree on the state of round
+    fn try_stable(&mut self, round: u32) {
+        let votes = match self.checkpoints.get(&round) {
+            Some(votes) => votes,
+            None => return,
+        };
+
+        let mut by_state: HashMap<&[u8], Vec<PbftMessage>> = HashMap::new();
+        for vote in votes.values() {
+            by_state.entry(vote.payload()).or_default().push(vote.clone());
+        }
+
+        let quorum = self.quorum_size as usize;
+        if let Some(votes) = by_state.into_values().find(|votes| votes.len() >= quorum) {
+            self.settle(CheckpointCert::new(votes), None);
+        }
     }
 
 
-    fn discard_round(&mut self) {
-        self.stage = PbftStage::IDLE;
+    // the state after round, chain being the Store state up to it
+    fn snapshot(&mut self, chain: [u8; 32]) -> Snapshot {
+        let app = self.take_snapshot.as_mut().map_or(vec![], |take_snapshot| take_snapshot());
 
-        self.prepare_vote_box.reset();
-        self.commit_vote_box.reset();
-        self.new_epoch_vote_box.reset();
+        let replies = self.replies.iter()
+            .filter_map(|(client, (timestamp, reply))| {
+                let result = ClientReply::from_bytes(reply.payload()).ok()?.result().to_vec();
+                Some(CachedReply { client: client.to_owned(), timestamp: *timestamp, round: reply.round(), result })
+            })
+            .collect();
 
-        // todo clear reply vote for this round
+        Snapshot { chain, app, replies }
     }
 
 
-    fn new_round(&mut self) {
-        
-        self.round += 1;
-        self.stage = PbftStage::IDLE;
+    // adopt a stable checkpoint, rounds up to it are dropped. A replica
+    // that did not execute them takes on snapshot in their place, without
+    // it the state is fetched from one of the replicas that vouch for it
+    fn settle(&mut self, cert: CheckpointCert, snapshot: Option<Vec<u8>>) {
+        let round = cert.round();
+        if self.commit_log.stable().map_or(false, |(settled, _)| settled >= round) {
+            return;
+        }
 
-        self.prepare_vote_box.reset();
-        self.commit_vote_box.reset();
-        self.blame_vote_box.reset();
-        self.new_epoch_vote_box.reset();
+        if self.round <= round {
+            match snapshot {
+                Some(snapshot) if digest(&snapshot) == cert.state() && self.install(&snapshot) => {
+                    self.snapshots.insert(round, snapshot);
+                }
+                _ => {
+                    let local = self.local_id.peer().to_owned();
+                    let target = cert.signers().filter(|peer| **peer != local).choose(&mut rand::thread_rng());
+                    if let Some(target) = target.cloned() {
+                        self.fetch_state(target);
+                    }
+                    return;
+                }
+            }
+        }
+
+        info!("PbftContext::settle stable checkpoint at round {}", round);
+
+        if self.snapshots.get(&round).map_or(false, |snapshot| digest(snapshot) != cert.state()) {
+            warn!("PbftContext::settle state of round {} differs from the checkpoint", round);
+        }
+
+        self.commit_log.checkpoint(round, cert.state());
+        self.prepared = self.prepared.split_off(&(round + 1));
+        self.checkpoints = self.checkpoints.split_off(&(round + 1));
+        self.snapshots = self.snapshots.split_off(&round);
+        self.slots = self.slots.split_off(&(round + 1));
+        self.reply_vote_boxes.discard_below(round + 1);
+        self.stable_cert = Some(cert);
+
+        // behind the checkpoint, go on from it
+        if self.round <= round {
+            self.round = round + 1;
+            self.execute();
+        }
     }
 
 
-    pub fn send_request(&mut self, payload: &[u8]) {
-        let mut msg = PbftMessage::new(
-            self.round,
-            self.seq(),
-            PbftMsgKind::REQUEST,
-            self.local_id.peer().clone(),
-            payload.to_vec()
-        );
+    // take on the state of a checkpoint in place of the rounds up to it
+    fn install(&mut self, snapshot: &[u8]) -> bool {
+        let snapshot = match Snapshot::from_bytes(snapshot) {
+            Ok(snapshot) => snapshot,
+            Err(error) => {
+                warn!("PbftContext::install {}", error);
+                return false;
+            }
+        };
 
-        msg.sign(
-            &self.signer, 
-            self.local_id.private_key(), 
-            self.local_id.peer().pubkey()
-        );
+        match &mut self.restore {
+            Some(restore) => restore(&snapshot.app),
+            None if self.executor.is_some() => {
+                warn!("PbftContext::install the executor cannot be restored without set_snapshot");
+                return false;
+            }
+            None => {}
+        }
 
-        async_std::task::block_on(
-            self.send_to_primary(msg)
+        self.replies.clear();
+        for cached in snapshot.replies {
+            // ClientReply::into_bytes does not fail, safe unwrap
+            let reply = ClientReply::new(cached.client.clone(), cached.timestamp, cached.result).into_bytes().unwrap();
+            let reply = self.signed(self.view, cached.round, PbftMsgKind::REPLY, reply);
+            self.replies.insert(cached.client, (cached.timestamp, reply));
+        }
+        true
+    }
+
+
+    // ask target for its stable checkpoint
+    fn fetch_state(&mut self, target: Peer) {
+        let msg = self.signed(self.view, self.round, PbftMsgKind::FETCH_STATE, vec![]);
+        runtime::block_on(
+            self.send_to_direct(msg, &target)
         );
+    }
 
-        self.stage = PbftStage::REQUEST;
-        self.commit_log.pending(self.round, payload);
 
-        self.reset_all_timer();
-        self.request_timer.set_now();
+    fn fetch_state_cb(&mut self, msg: PbftMessage) {
+        let payload = match &self.stable_cert {
+            Some(cert) if cert.round() >= msg.round() && self.snapshots.contains_key(&cert.round()) => {
+                let snapshot = self.snapshots[&cert.round()].clone();
+                StateTransfer { cert: cert.clone(), snapshot }.into_payload()
+            }
+            _ => {
+                debug!("PbftContext::fetch_state_cb no checkpoint at round {} yet", msg.round());
+                return;
+            }
+        };
+
+        match payload {
+            Ok(payload) => {
+                let reply = self.signed(self.view, self.round, PbftMsgKind::STATE, payload);
+                runtime::block_on(
+                    self.send_to_direct(reply, msg.signer_id())
+                );
+            }
+            Err(error) => warn!("PbftContext::fetch_state_cb {}", error),
+        }
     }
 
 
-    fn send_reply(&mut self) {
-        if self.stage == PbftStage::COMMIT {
+    fn state_cb(&mut self, msg: PbftMessage) {
+        let StateTransfer { mut cert, snapshot } = match StateTransfer::from_payload(msg.payload()) {
+            Ok(state) => state,
+            Err(error) => {
+                warn!("PbftContext::state_cb {}", error);
+                return;
+            }
+        };
 
-            let mut msg = PbftMessage::new(
-                self.round,
-                self.seq(),
-                PbftMsgKind::REPLY,
-                self.local_id.peer().clone(),
-                vec![]
-            );
-    
-            msg.sign(
-                &self.signer, 
-                self.local_id.private_key(), 
-                self.local_id.peer().pubkey()
-            );
+        if cert.round() < self.low_mark() {
+            return;
+        }
 
-            async_std::task::block_on(
-                self.send_to_primary(msg)
-            );
+        if self.verifier().checkpoint(&mut cert) {
+            self.settle(cert, Some(snapshot));
+        }
+        else {
+            warn!("PbftContext::state_cb invalid checkpoint from {}", msg.signer_id());
+        }
+    }
+
+
+    fn request_timeout_cb(&mut self) {
+        self.start_view_change(self.view + 1);
+    }
+
+
+    fn preprepare_timeout_cb(&mut self) {
+        self.start_view_change(self.view + 1);
+    }
+
+
+    fn prepare_timeout_cb(&mut self) {
+        self.start_view_change(self.view + 1);
+    }
+
+
+    fn view_change_timeout_cb(&mut self) {
+        // the next primary is silent as well, try the one after
+        self.start_view_change(self.next_view + 1);
+    }
+
+
+    /// Have the cluster execute payload as a request of this replica, it is
+    /// sent again to the next primary if the view changes before it commits.
+    pub fn send_request(&mut self, payload: &[u8]) {
+        self.timestamp = next_timestamp(self.timestamp);
+        let mut request = ClientRequest::new(
+            self.local_id.peer().to_owned(), self.timestamp, payload.to_vec());
+        request.sign(&self.signer, self.local_id.private_key());
+
+        match request.into_bytes() {
+            Ok(request) => self.relay(request),
+            Err(error) => warn!("PbftContext::send_request {}", error),
         }
     }
 
 
-    fn send_blame(&mut self) {
+    // wait for request to be executed, the view changes if it takes
+    // request_to
+    fn relay(&mut self, request: Vec<u8>) {
+        if self.awaited_requests.contains(&request) {
+            return;
+        }
+
+        self.awaited_requests.push(request.clone());
+        if !self.request_timer.is_set() {
+            self.request_timer.set_now();
+        }
+
+        // otherwise it goes to the primary of the next view
+        if self.stage != PbftStage::VIEW_CHANGE {
+            self.forward(request);
+        }
+    }
+
+
+    // hand a request to the primary
+    fn forward(&mut self, request: Vec<u8>) {
+        if self.is_primary() {
+            self.enqueue(request);
+            return;
+        }
+        
+        let msg = self.signed(self.view, self.round, PbftMsgKind::REQUEST, request);
+
+        runtime::block_on(
+            self.send_to_primary(msg)
+        );
+    }
+
+
+    // a message from this replica
+    fn signed(&mut self, view: u32, round: u32, msg_type: PbftMsgKind, payload: Vec<u8>) -> PbftMessage {
         let mut msg = PbftMessage::new(
-            self.round,
+            view,
+            round,
             self.seq(),
-            PbftMsgKind::BLAME,
+            msg_type,
             self.local_id.peer().clone(),
-            vec![]
+            payload
         );
 
         msg.sign(
@@ -564,59 +1346,59 @@ impl<S, T, R> PbftContext<S, T, R>
             self.local_id.peer().pubkey()
         );
 
-        async_std::task::block_on(
-            self.broadcast(msg)
-        );
+        msg
     }
 
 
-    fn send_new_epoch(&mut self) {
-        // todo: get a proof that the leader is faulty, or gather enough blames
+    fn verifier(&self) -> Verifier<'_, S> {
+        Verifier::new(&self.total_node_set, &self.signer, self.quorum_size as usize)
+    }
 
-        if self.is_backup_primary() {
 
-            let mut msg = PbftMessage::new(
-                self.round,
-                self.seq(),
-                PbftMsgKind::NEW_EPOCH,
-                self.local_id.peer().clone(),
-                vec![]
-            );
-    
-            msg.sign(
-                &self.signer, 
-                self.local_id.private_key(), 
-                self.local_id.peer().pubkey()
-            );
+    fn is_primary(&self) -> bool {
+        *self.local_id.peer() == self.primary_id
+    }
 
-            async_std::task::block_on(
-                self.broadcast(msg)
-            );
+        
+    // the view this replica is in, or moving to
+    fn target_view(&self) -> u32 {
+        if self.stage == PbftStage::VIEW_CHANGE {
+            self.next_view
+        }
+        else {
+            self.view
         }
+    }
 
+
+    // first round not settled by a stable checkpoint
+    fn low_mark(&self) -> u32 {
+        self.commit_log.stable().map_or(0, |(settled, _)| settled + 1)
     }
 
 
-    fn is_backup_primary(&mut self) -> bool {
-        let backup_id = self.round % self.total_node;
-        backup_id == self.total_node_set.get_idx(self.local_id.peer()).unwrap()
+    fn high_mark(&self) -> u32 {
+        self.low_mark() + self.config.watermark_window
     }
 
-    fn get_backup_primary(&mut self) -> Peer {
-        // determinately pick a peer from node set using current round
-        
-        let backup_id = self.round % self.total_node;
-        self.total_node_set.nth(backup_id).unwrap().to_owned()
+
+    fn faulty(&self) -> usize {
+        (self.total_node as usize).saturating_sub(1) / 3
     }
 
     async fn broadcast(&mut self, msg: PbftMessage) {
+        if let Some(held) = &mut self.held {
+            held.push((msg, None));
+            return;
+        }
+
         if let Ok(msg_buf) = msg.into_bytes() {
             let header = MsgHeader::build(
                 MsgTypeKind::PAYLOAD_MSG,
                 true,
                 RelayMethodKind::LOOKUP_TABLE_1,
                 1,
-                20
+                15
             ).unwrap();
     
             let mut overlay_msg = OverlayMessage::new(
@@ -636,13 +1418,18 @@ impl<S, T, R> PbftContext<S, T, R>
 
 
     async fn send_to_direct(&mut self, msg: PbftMessage, target: &Peer) {
+        if let Some(held) = &mut self.held {
+            held.push((msg, Some(target.to_owned())));
+            return;
+        }
+
         if let Ok(msg_buf) = msg.into_bytes() {
             let header = MsgHeader::build(
                 MsgTypeKind::PAYLOAD_MSG,
                 false,
                 RelayMethodKind::ALL,
                 1,
-                20
+                15
             ).unwrap();
     
             let mut overlay_msg = OverlayMessage::new(
@@ -652,7 +1439,8 @@ impl<S, T, R> PbftContext<S, T, R>
                 target,
                 &msg_buf);
     
-            self.network_handle.send_to(target, &mut overlay_msg).await;
+            // failures are logged by BDN
+            let _ = self.network_handle.send_to(target, &mut overlay_msg).await;
         }
         else {
             warn!("PbftContext::send_to_direct ill-formed msg: {:?}", msg);
@@ -675,19 +1463,366 @@ impl<S, T, R> PbftContext<S, T, R>
         }
     }
 
+    // what follows lets byzantine::ByzantineReplica tamper with the
+    // messages of this replica
+
+    pub(crate) fn hold(&mut self) {
+        self.held.get_or_insert_with(Vec::new);
+    }
+
+
+    pub(crate) fn take_held(&mut self) -> Vec<(PbftMessage, Option<Peer>)> {
+        self.held.as_mut().map_or(vec![], std::mem::take)
+    }
+
+
+    // send past the hold, to every replica if target is None
+    pub(crate) fn release(&mut self, msg: PbftMessage, target: Option<&Peer>) {
+        let held = self.held.take();
+        match target {
+            Some(target) => runtime::block_on(self.send_to_direct(msg, target)),
+            None => runtime::block_on(self.broadcast(msg)),
+        }
+        self.held = held;
+    }
+
+
+    // sign with the key of this replica, whatever signer_id says
+    pub(crate) fn sign_as_self(&self, msg: &mut PbftMessage) {
+        msg.sign(&self.signer, self.local_id.private_key(), self.local_id.peer().pubkey());
+    }
+
+
+    pub(crate) fn local_peer(&self) -> &Peer {
+        self.local_id.peer()
+    }
+
+
+    pub(crate) fn replicas(&self) -> Vec<Peer> {
+        (0..self.total_node).filter_map(|n| self.total_node_set.nth(n).cloned()).collect()
+    }
+
+
     fn seq(&mut self) -> u32 {
         self.seq += 1;
         self.seq
     }
 
 
-    fn test_msg() -> Vec<u8> {
+    fn test_msg(&self) -> Vec<u8> {
 
         // todo: this is not an efficient way to generate bytes...
         // for byte vec of several special size, you can use rand::gen or rand::fill
         // find better ways
 
-        (0..PAYLOAD_MAX).map(|_| { rand::random::<u8>() }).collect()
+        (0..self.config.payload_max).map(|_| { rand::random::<u8>() }).collect()
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{from, identity};
+    use yulong_bdn::config::BdnConfig;
+    use yulong_bdn::route_inner::impls::mlbt::MlbtRelayCtlContext;
+    use yulong_network::identity::crypto::ed25519_signer::Ed25519Signer;
+    use yulong_tcp::TcpContext;
+    use std::sync::{Arc, Mutex};
+
+    // REQUEST payload of client
+    fn request(client: &Me, timestamp: u64, operation: &[u8]) -> Vec<u8> {
+        let mut request = ClientRequest::new(client.peer().to_owned(), timestamp, operation.to_vec());
+        request.sign(&Ed25519Signer::new(), client.private_key()).unwrap();
+        request.into_bytes().unwrap()
+    }
+
+    #[test]
+    fn prepared_request_survives_view_change() {
+        let replicas: Vec<Me> = (0..4).map(|_| identity()).collect();
+        let participants = replicas.iter().map(|me| me.peer().to_owned()).collect();
+
+        // replica 1 is the primary of view 1, nothing it sends arrives
+        let me = replicas[1].clone();
+        let bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new(me.clone(), BdnConfig::default());
+        let mut pbft = PbftContext::new(bdn, Ed25519Signer::new(), me, participants, PbftConfig::default()).unwrap();
+
+        let request = pack_batch(&[b"req".to_vec()]);
+        let vote = digest(&request).to_vec();
+
+        // the request prepares in view 0, the primary fails before commit
+        pbft.pbft_msg_cb(from(&replicas[0], 0, 0, PbftMsgKind::PRE_PREPARE, request.clone()));
+        pbft.pbft_msg_cb(from(&replicas[2], 0, 0, PbftMsgKind::PREPARE, vec![0; 32]));
+        assert_eq!(pbft.slots[&0].stage, PbftStage::PRE_PREPARE);
+        pbft.pbft_msg_cb(from(&replicas[2], 0, 0, PbftMsgKind::PREPARE, vote.clone()));
+        assert_eq!(pbft.slots[&0].stage, PbftStage::PREPARE);
+        assert!(pbft.prepared.contains_key(&0));
+
+        // one view change is not enough to follow, f + 1 are
+        let view_change = ViewChange::new(None, vec![]).into_payload().unwrap();
+        pbft.pbft_msg_cb(from(&replicas[2], 1, 0, PbftMsgKind::VIEW_CHANGE, view_change.clone()));
+        assert_eq!(pbft.slots[&0].stage, PbftStage::PREPARE);
+        pbft.pbft_msg_cb(from(&replicas[3], 1, 0, PbftMsgKind::VIEW_CHANGE, view_change));
+
+        // with its own view change there are 2f + 1, it takes over and
+        // proposes the prepared request again
+        assert_eq!(pbft.view, 1);
+        assert!(pbft.is_primary());
+        assert_eq!(pbft.slots[&0].stage, PbftStage::PRE_PREPARE);
+        let pre_prepare = pbft.slots[&0].pre_prepare.as_ref().unwrap();
+        assert_eq!((pre_prepare.view(), pre_prepare.round()), (1, 0));
+        assert_eq!(pre_prepare.payload(), request.as_slice());
+
+        // votes of the old view no longer count
+        pbft.pbft_msg_cb(from(&replicas[2], 0, 0, PbftMsgKind::PREPARE, vote.clone()));
+        assert_eq!(pbft.slots[&0].prepare_vote_box.count_pos(), 1);
+
+        for n in [2, 3] {
+            pbft.pbft_msg_cb(from(&replicas[n], 1, 0, PbftMsgKind::PREPARE, vote.clone()));
+        }
+        for n in [2, 3] {
+            pbft.pbft_msg_cb(from(&replicas[n], 1, 0, PbftMsgKind::COMMIT, vote.clone()));
+        }
+        assert_eq!(pbft.round, 1);
+        assert!(pbft.slots.is_empty());
+        assert_eq!(pbft.commit_log.get_pending(0), Some(request.as_slice()));
+
+        // a replica holds one view change, however far ahead it asks for
+        let view_change = ViewChange::new(None, vec![]).into_payload().unwrap();
+        for view in [9, 4, 12] {
+            pbft.pbft_msg_cb(from(&replicas[3], view, 1, PbftMsgKind::VIEW_CHANGE, view_change.clone()));
+        }
+        assert_eq!(pbft.view_changes.keys().collect::<Vec<_>>(), vec![&12]);
+        assert_eq!(pbft.view_changes[&12].len(), 1);
+    }
+
+
+    #[test]
+    fn orphan_votes() {
+        let replicas: Vec<Me> = (0..4).map(|_| identity()).collect();
+        let participants = replicas.iter().map(|me| me.peer().to_owned()).collect();
+
+        let me = replicas[1].clone();
+        let bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new(me.clone(), BdnConfig::default());
+        let config = PbftConfig { pipeline_window: 2, ..PbftConfig::default() };
+        let mut pbft = PbftContext::new(bdn, Ed25519Signer::new(), me, participants, config).unwrap();
+
+        // one replica voting in a round with no PRE-PREPARE may be faulty
+        pbft.pbft_msg_cb(from(&replicas[2], 0, 1, PbftMsgKind::PREPARE, vec![0; 32]));
+        pbft.pbft_msg_cb(from(&replicas[2], 0, 1, PbftMsgKind::COMMIT, vec![0; 32]));
+        assert!(pbft.slots.contains_key(&1));
+        assert!(!pbft.preprepare_timer.is_set());
+
+        // f + 1 cannot all be
+        pbft.pbft_msg_cb(from(&replicas[3], 0, 1, PbftMsgKind::COMMIT, vec![0; 32]));
+        assert!(pbft.preprepare_timer.is_set());
+
+        // no slot past the pipeline window
+        for msg_type in [PbftMsgKind::PREPARE, PbftMsgKind::COMMIT] {
+            pbft.pbft_msg_cb(from(&replicas[2], 0, 2, msg_type, vec![0; 32]));
+        }
+        assert_eq!(pbft.slots.keys().collect::<Vec<_>>(), vec![&1]);
+    }
+
+
+    #[test]
+    fn checkpoint_and_state_transfer() {
+        let replicas: Vec<Me> = (0..4).map(|_| identity()).collect();
+        let participants = replicas.iter().map(|me| me.peer().to_owned()).collect();
+
+        let me = replicas[1].clone();
+        let bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new(me.clone(), BdnConfig::default());
+        let config = PbftConfig { checkpoint_period: 2, watermark_window: 4, ..PbftConfig::default() };
+        let mut pbft = PbftContext::new(bdn, Ed25519Signer::new(), me, participants, config).unwrap();
+
+        // the application is the log of the operations it executed
+        let executed: Arc<Mutex<Vec<Vec<u8>>>> = Arc::default();
+        let (log, taken, restored) = (executed.clone(), executed.clone(), executed.clone());
+        pbft.set_executor(move |operation| {
+            log.lock().unwrap().push(operation.to_vec());
+            vec![]
+        });
+        pbft.set_snapshot(
+            move || pack_batch(&taken.lock().unwrap()),
+            move |app| *restored.lock().unwrap() = unpack_batch(app).unwrap());
+
+        let client = identity();
+        let mut expected = Store::new();
+        for round in 0..2 {
+            let request = pack_batch(&[request(&client, round as u64 + 1, &[round as u8])]);
+            let vote = digest(&request).to_vec();
+            pbft.pbft_msg_cb(from(&replicas[0], 0, round, PbftMsgKind::PRE_PREPARE, request.clone()));
+            pbft.pbft_msg_cb(from(&replicas[2], 0, round, PbftMsgKind::PREPARE, vote.clone()));
+            for n in [0, 2] {
+                pbft.pbft_msg_cb(from(&replicas[n], 0, round, PbftMsgKind::COMMIT, vote.clone()));
+            }
+            expected.pending(round, &request);
+            expected.commit(round);
+        }
+        assert_eq!(pbft.round, 2);
+        assert_eq!(pbft.prepared.len(), 2);
+
+        // round 1 closes a period, the checkpoint vouches for the requests
+        // committed, what they did to the application and the last reply
+        let snapshot = Snapshot::from_bytes(&pbft.snapshots[&1]).unwrap();
+        assert_eq!(snapshot.chain, expected.state(1).unwrap());
+        assert_eq!(snapshot.app, pack_batch(&[vec![0], vec![1]]));
+        assert_eq!((snapshot.replies[0].timestamp, snapshot.replies[0].round), (2, 1));
+
+        // two more agreeing checkpoints make it stable
+        let state = digest(&pbft.snapshots[&1]);
+        pbft.pbft_msg_cb(from(&replicas[0], 0, 1, PbftMsgKind::CHECKPOINT, vec![0; 32]));
+        pbft.pbft_msg_cb(from(&replicas[2], 0, 1, PbftMsgKind::CHECKPOINT, state.to_vec()));
+        assert_eq!(pbft.commit_log.stable(), None);
+        pbft.pbft_msg_cb(from(&replicas[3], 0, 1, PbftMsgKind::CHECKPOINT, state.to_vec()));
+        assert_eq!(pbft.commit_log.stable(), Some((1, state)));
+        assert_eq!(pbft.commit_log.get_pending(0), None);
+        assert!(pbft.prepared.is_empty() && pbft.checkpoints.is_empty());
+
+        // rounds 2 to 5 are within the water marks
+        assert_eq!((pbft.low_mark(), pbft.high_mark()), (2, 6));
+        pbft.pbft_msg_cb(from(&replicas[0], 0, 6, PbftMsgKind::PRE_PREPARE, vec![6]));
+        assert_eq!(pbft.round, 2);
+        assert!(pbft.slots.is_empty());
+        pbft.pbft_msg_cb(from(&replicas[0], 0, 7, PbftMsgKind::CHECKPOINT, state.to_vec()));
+        assert!(pbft.checkpoints.is_empty());
+
+        // the others moved on, a certified state lets it catch up
+        let skipped: Vec<Vec<u8>> = (0..6).map(|n| vec![n]).collect();
+        let other = identity();
+        let snapshot = Snapshot {
+            chain: [5; 32],
+            app: pack_batch(&skipped),
+            replies: vec![CachedReply { client: other.peer().to_owned(), timestamp: 9, round: 4, result: vec![] }],
+        }.into_bytes().unwrap();
+        let state = digest(&snapshot);
+
+        let transfer = |voters: &[usize], snapshot: &[u8]| StateTransfer {
+            cert: CheckpointCert::new(voters.iter()
+                .map(|n| from(&replicas[*n], 0, 5, PbftMsgKind::CHECKPOINT, state.to_vec()))
+                .collect()),
+            snapshot: snapshot.to_vec(),
+        }.into_payload().unwrap();
+
+        pbft.pbft_msg_cb(from(&replicas[2], 0, 2, PbftMsgKind::STATE, transfer(&[0, 2], &snapshot)));
+        assert_eq!(pbft.round, 2);
+        // the checkpoint is fine, the state is not the one it vouches for
+        let forged = [&snapshot[..], &[0]].concat();
+        pbft.pbft_msg_cb(from(&replicas[2], 0, 2, PbftMsgKind::STATE, transfer(&[0, 2, 3], &forged)));
+        assert_eq!(pbft.round, 2);
+        assert_eq!(executed.lock().unwrap().len(), 2);
+
+        pbft.pbft_msg_cb(from(&replicas[2], 0, 2, PbftMsgKind::STATE, transfer(&[0, 2, 3], &snapshot)));
+        assert_eq!(pbft.round, 6);
+        assert_eq!(pbft.commit_log.stable(), Some((5, state)));
+        assert_eq!(*executed.lock().unwrap(), skipped);
+        assert_eq!(pbft.replies[other.peer()].0, 9);
+
+        // it can serve the state on in turn
+        assert_eq!(pbft.snapshots[&5], snapshot);
+    }
+
+
+    #[test]
+    fn batching_and_pipelining() {
+        let replicas: Vec<Me> = (0..4).map(|_| identity()).collect();
+        let participants = replicas.iter().map(|me| me.peer().to_owned()).collect();
+
+        // replica 0 is the primary of view 0
+        let me = replicas[0].clone();
+        let bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new(me.clone(), BdnConfig::default());
+        let config = PbftConfig {
+            batch_max: 2,
+            batch_delay: 60_000,
+            pipeline_window: 2,
+            ..PbftConfig::default()
+        };
+        let mut pbft = PbftContext::new(bdn, Ed25519Signer::new(), me, participants, config).unwrap();
+
+        // full batches go out at once, two rounds fill the window
+        let client = identity();
+        let requests: Vec<Vec<u8>> = (0..5u8).map(|n| request(&client, n as u64 + 1, &[n])).collect();
+        for request in &requests {
+            pbft.pbft_msg_cb(from(&replicas[1], 0, 0, PbftMsgKind::REQUEST, request.clone()));
+        }
+        assert_eq!(pbft.next_round, 2);
+        assert_eq!(pbft.queue, requests[4..]);
+
+        let batch = |round: u32| {
+            unpack_batch(pbft.slots[&round].pre_prepare.as_ref().unwrap().payload()).unwrap()
+        };
+        assert_eq!(batch(0), requests[0..2]);
+        assert_eq!(batch(1), requests[2..4]);
+
+        // each round votes on its own, round 1 commits first and waits
+        let votes = |round: u32, batch: Vec<Vec<u8>>| {
+            let vote = digest(&pack_batch(&batch)).to_vec();
+            let mut msgs = vec![];
+            for msg_type in [PbftMsgKind::PREPARE, PbftMsgKind::COMMIT] {
+                for n in [1, 2] {
+                    msgs.push(from(&replicas[n], 0, round, msg_type, vote.clone()));
+                }
+            }
+            msgs
+        };
+
+        for msg in votes(1, requests[2..4].to_vec()) {
+            pbft.pbft_msg_cb(msg);
+        }
+        assert_eq!(pbft.slots[&1].stage, PbftStage::COMMIT);
+        assert_eq!(pbft.round, 0);
+
+        for msg in votes(0, requests[0..2].to_vec()) {
+            pbft.pbft_msg_cb(msg);
+        }
+        assert_eq!(pbft.round, 2);
+        assert!(pbft.slots.is_empty());
+        assert!(pbft.commit_log.get_pending(1).is_some());
+
+        // the window has room again, the last request waits for a fuller batch
+        assert_eq!(pbft.queue.len(), 1);
+    }
+
+    #[test]
+    fn client_request_executed_once() {
+        let replicas: Vec<Me> = (0..4).map(|_| identity()).collect();
+        let participants = replicas.iter().map(|me| me.peer().to_owned()).collect();
+
+        // replica 0 is the primary of view 0
+        let me = replicas[0].clone();
+        let bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new(me.clone(), BdnConfig::default());
+        let config = PbftConfig { batch_max: 1, ..PbftConfig::default() };
+        let mut pbft = PbftContext::new(bdn, Ed25519Signer::new(), me, participants, config).unwrap();
+        pbft.set_executor(|operation| operation.iter().rev().cloned().collect());
+
+        let client = identity();
+        let request = request(&client, 7, b"abc");
+
+        // the client signs the request, not whoever relays it
+        let mut forged = ClientRequest::from_bytes(&request).unwrap();
+        forged.sign(&Ed25519Signer::new(), replicas[1].private_key());
+        pbft.pbft_msg_cb(from(&replicas[1], 0, 0, PbftMsgKind::REQUEST, forged.into_bytes().unwrap()));
+        assert_eq!(pbft.next_round, 0);
+
+        pbft.pbft_msg_cb(from(&client, 0, 0, PbftMsgKind::REQUEST, request.clone()));
+        assert_eq!(pbft.next_round, 1);
+
+        let vote = digest(&pack_batch(&[request.clone()])).to_vec();
+        for msg_type in [PbftMsgKind::PREPARE, PbftMsgKind::COMMIT] {
+            for n in [1, 2] {
+                pbft.pbft_msg_cb(from(&replicas[n], 0, 0, msg_type, vote.clone()));
+            }
+        }
+        assert_eq!(pbft.round, 1);
+
+        let (timestamp, reply) = pbft.replies[client.peer()].clone();
+        let answer = ClientReply::from_bytes(reply.payload()).unwrap();
+        assert_eq!((timestamp, answer.timestamp()), (7, 7));
+        assert_eq!(answer.result(), b"cba");
+
+        // a retry gets the cached reply and is not ordered again
+        pbft.pbft_msg_cb(from(&client, 0, 0, PbftMsgKind::REQUEST, request));
+        assert_eq!(pbft.next_round, 1);
+        assert!(pbft.queue.is_empty());
+    }
+}
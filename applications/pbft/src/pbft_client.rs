@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use yulong::error::DumbError;
+use yulong::utils::{clock, AsBytes};
+use yulong_bdn::handle::{BdnHandle, PayloadStream};
+use yulong_bdn::msg_header::MsgTypeKind;
+use yulong_bdn::msg_header::RelayMethodKind;
+use yulong_network::identity::{Peer, Me};
+use yulong_network::identity::crypto::GenericSigner;
+use yulong_bdn::msg_header::MsgHeader;
+use yulong_bdn::message::OverlayMessage;
+
+use crate::message::{next_timestamp, ClientReply, ClientRequest, PbftMessage, PbftMsgKind};
+use crate::participants::{Participants, ParticipantsStore};
+
+use crate::config::PbftConfig;
+use crate::error::ClientError;
+
+
+// where waiting for replies ended
+enum Outcome {
+    Done(Vec<u8>),
+    // not enough matching replies in time, send again
+    Retry,
+    Closed,
+}
+
+
+/// Client of a PBFT cluster.
+///
+/// A request goes to the primary of the view last heard of, its result is
+/// the one f + 1 replicas reply with, so at least one correct replica
+/// vouches for it. Without that after client_timeout the request goes to
+/// every replica, backups relay it to the primary and change view if it
+/// is not executed.
+pub struct PbftClientContext<S: GenericSigner> {
+    network: BdnHandle,
+    payloads: PayloadStream,
+    signer: S,
+    local_id: Me,
+    seq: u32,
+
+    // replicas execute each timestamp of a client once, retries included
+    timestamp: u64,
+    view: u32,
+
+    replicas: Participants,
+
+    config: PbftConfig,
+}
+
+
+impl<S: GenericSigner> PbftClientContext<S> {
+
+    /// network and payloads come from BDN::spawn, replies arrive once the
+    /// BDN listens. replicas are in the order the replicas know them by,
+    /// with their public keys.
+    pub fn new(
+        network: BdnHandle,
+        payloads: PayloadStream,
+        signer: S,
+        local_id: Me,
+        replicas: Vec<Peer>,
+        config: PbftConfig
+    ) -> Self {
+        Self {
+            network,
+            payloads,
+            signer,
+            local_id,
+            seq: 0,
+            timestamp: 0,
+            view: 0,
+            replicas: Participants::new(replicas),
+            config,
+        }
+    }
+
+
+    /// Execute operation on the cluster and return its result. It is
+    /// executed once however many times it is sent.
+    pub async fn submit(&mut self, operation: &[u8]) -> Result<Vec<u8>, ClientError> {
+        self.timestamp = next_timestamp(self.timestamp);
+
+        let mut request = ClientRequest::new(
+            self.local_id.peer().to_owned(), self.timestamp, operation.to_vec());
+        if request.sign(&self.signer, self.local_id.private_key()).is_none() {
+            return Err(ClientError::new("Key type does not match the signer", DumbError));
+        }
+        let request = request.into_bytes()
+            .map_err(|e| ClientError::new("Encode request", e))?;
+
+        // a late reply to an earlier attempt counts as well
+        let mut replies = HashMap::new();
+
+        for attempt in 0..self.config.client_retry {
+            let targets: Vec<Peer> = match self.replicas.primary(self.view) {
+                Some(primary) if attempt == 0 => vec![primary.to_owned()],
+                Some(_) => (0..self.replicas.len()).filter_map(|n| self.replicas.nth(n).cloned()).collect(),
+                None => return Err(ClientError::new("No replica to ask", DumbError)),
+            };
+
+            for target in targets {
+                self.send_to(&request, &target).await;
+            }
+
+            match self.wait_replies(&mut replies).await {
+                Outcome::Done(result) => return Ok(result),
+                Outcome::Retry => continue,
+                Outcome::Closed => return Err(ClientError::new("BDN is stopped", DumbError)),
+            }
+        }
+
+        Err(ClientError::new(
+            format!("No f + 1 matching replies after {} attempts", self.config.client_retry),
+            DumbError
+        ))
+    }
+
+
+    /// View of the cluster as last heard.
+    pub fn view(&self) -> u32 {
+        self.view
+    }
+
+
+    async fn wait_replies(&mut self, replies: &mut HashMap<Peer, (u32, Vec<u8>)>) -> Outcome {
+        let deadline = clock::now() + Duration::from_millis(self.config.client_timeout);
+
+        loop {
+            let left = deadline.saturating_duration_since(clock::now());
+
+            let msg = match clock::timeout(left, self.payloads.recv()).await {
+                Ok(Ok(msg)) => msg,
+                Ok(Err(_)) => return Outcome::Closed,
+                Err(_) => {
+                    debug!("PbftClientContext::wait_replies timeout");
+                    return Outcome::Retry;
+                }
+            };
+
+            match PbftMessage::from_bytes(&msg.payload()) {
+                Ok(pbft_msg) if pbft_msg.msg_type() == PbftMsgKind::REPLY => {
+                    if let Some(result) = self.reply_cb(pbft_msg, replies) {
+                        return Outcome::Done(result);
+                    }
+                }
+                Ok(pbft_msg) => {
+                    debug!("PbftClientContext::wait_replies drop message from {}", pbft_msg.signer_id());
+                }
+                Err(error) => {
+                    warn!("PbftClientContext::wait_replies Decode msg error: {}", error);
+                }
+            }
+        }
+    }
+
+
+    // count a reply to the current request, the result once f + 1 replicas
+    // agree on it
+    fn reply_cb(&mut self, mut msg: PbftMessage, replies: &mut HashMap<Peer, (u32, Vec<u8>)>) -> Option<Vec<u8>> {
+        self.replicas.query_pk(msg.signer_id_mut());
+
+        if self.replicas.get_idx(msg.signer_id()).is_none() || !msg.verify(&self.signer) {
+            warn!("PbftClientContext::reply_cb unauthentic reply from {}", msg.signer_id());
+            return None;
+        }
+
+        let reply = match ClientReply::from_bytes(msg.payload()) {
+            Ok(reply) if reply.client_id() == self.local_id.peer() && reply.timestamp() == self.timestamp => reply,
+            _ => {
+                debug!("PbftClientContext::reply_cb stale reply from {}", msg.signer_id());
+                return None;
+            }
+        };
+
+        replies.insert(msg.signer_id().to_owned(), (msg.view(), reply.result().to_vec()));
+
+        let matching = replies.values()
+            .filter(|(_, result)| result == reply.result())
+            .count();
+
+        if matching > self.faulty() {
+            // a view f + 1 replicas report is vouched for by an honest one
+            let mut by_view: HashMap<u32, usize> = HashMap::new();
+            for (view, _) in replies.values() {
+                *by_view.entry(*view).or_default() += 1;
+            }
+            let faulty = self.faulty();
+            let vouched = by_view.into_iter()
+                .filter(|(_, n)| *n > faulty)
+                .map(|(view, _)| view)
+                .max();
+            if let Some(view) = vouched {
+                self.view = view;
+            }
+            Some(reply.result().to_vec())
+        }
+        else {
+            None
+        }
+    }
+
+
+    async fn send_to(&mut self, request: &[u8], target: &Peer) {
+        let mut msg = PbftMessage::new(
+            self.view,
+            0,
+            self.seq(),
+            PbftMsgKind::REQUEST,
+            self.local_id.peer().to_owned(),
+            request.to_vec()
+        );
+        msg.sign(&self.signer, self.local_id.private_key(), self.local_id.peer().pubkey());
+
+        if let Ok(msg_buf) = msg.into_bytes() {
+            let header = MsgHeader::build(
+                MsgTypeKind::PAYLOAD_MSG,
+                false,
+                RelayMethodKind::ALL,
+                1,
+                15
+            ).unwrap();
+
+            let message = OverlayMessage::new(
+                header,
+                self.local_id.peer(),
+                self.local_id.peer(),
+                target,
+                &msg_buf
+            );
+
+            self.network.send_to(target, message).await;
+        }
+    }
+
+
+    // replicas that may be faulty out of 3f + 1
+    fn faulty(&self) -> usize {
+        (self.replicas.len() as usize).saturating_sub(1) / 3
+    }
+
+
+    fn seq(&mut self) -> u32 {
+        self.seq += 1;
+        self.seq
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::identity;
+    use yulong_bdn::config::BdnConfig;
+    use yulong_bdn::overlay::BDN;
+    use yulong_bdn::route_inner::impls::mlbt::MlbtRelayCtlContext;
+    use yulong_network::identity::crypto::ed25519_signer::Ed25519Signer;
+    use yulong_tcp::TcpContext;
+
+    // REPLY of replica as received by the client
+    fn reply(replica: &Me, view: u32, reply: ClientReply) -> PbftMessage {
+        let mut msg = PbftMessage::new(
+            view, 0, 0, PbftMsgKind::REPLY, replica.peer().to_owned(), reply.into_bytes().unwrap());
+        msg.sign(&Ed25519Signer::new(), replica.private_key(), replica.peer().pubkey()).unwrap();
+        PbftMessage::from_bytes(&msg.into_bytes().unwrap()).unwrap()
+    }
+
+    #[async_std::test]
+    async fn client_waits_for_matching_replies() {
+        let me = identity();
+        let bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new(me.clone(), BdnConfig::default());
+        let (network, payloads) = bdn.spawn();
+
+        // replicas have no address, nothing ever answers
+        let replicas: Vec<Me> = (0..4).map(|_| identity()).collect();
+        let peers = replicas.iter().map(|replica| replica.peer().to_owned()).collect();
+        let config = PbftConfig { client_timeout: 20, client_retry: 2, ..PbftConfig::default() };
+        let mut client = PbftClientContext::new(network, payloads, Ed25519Signer::new(), me.clone(), peers, config);
+
+        assert!(client.submit(b"x").await.is_err());
+        let timestamp = client.timestamp;
+        assert!(timestamp > 0);
+
+        let answer = |result: &[u8]| ClientReply::new(me.peer().to_owned(), timestamp, result.to_vec());
+        let mut replies = HashMap::new();
+
+        // one reply may come from a faulty replica
+        assert_eq!(client.reply_cb(reply(&replicas[0], 1, answer(b"ok")), &mut replies), None);
+        assert_eq!(client.reply_cb(reply(&replicas[1], 1, answer(b"bad")), &mut replies), None);
+
+        // neither an outsider nor a reply to another request counts
+        assert_eq!(client.reply_cb(reply(&identity(), 1, answer(b"ok")), &mut replies), None);
+        let stale = ClientReply::new(me.peer().to_owned(), timestamp - 1, b"ok".to_vec());
+        assert_eq!(client.reply_cb(reply(&replicas[2], 1, stale), &mut replies), None);
+
+        // the same replica twice is one vote
+        assert_eq!(client.reply_cb(reply(&replicas[0], 1, answer(b"ok")), &mut replies), None);
+
+        assert_eq!(client.reply_cb(reply(&replicas[3], 1, answer(b"ok")), &mut replies), Some(b"ok".to_vec()));
+        assert_eq!(client.view(), 1);
+
+        // a view a single replica reports may be made up
+        let mut replies = HashMap::new();
+        assert_eq!(client.reply_cb(reply(&replicas[0], 9, answer(b"ok")), &mut replies), None);
+        assert_eq!(client.reply_cb(reply(&replicas[3], 2, answer(b"ok")), &mut replies), Some(b"ok".to_vec()));
+        assert_eq!(client.view(), 1);
+        client.reply_cb(reply(&replicas[1], 2, answer(b"ok")), &mut replies);
+        assert_eq!(client.view(), 2);
+    }
+}
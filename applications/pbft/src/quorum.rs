@@ -116,6 +116,11 @@ impl VoteBoxes {
     }
 
 
+    pub fn discard_below(&mut self, round: u32) {
+        self.boxes_set.retain(|r, _| *r >= round);
+    }
+
+
     pub fn result(&self, round: u32) -> VoteResult {
         if let Some(handle) = self.boxes_set.get(&round) {
             handle.result()
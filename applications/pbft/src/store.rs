@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use crate::message::digest;
+
 pub trait StoreService {
 
     fn new() -> Self;
@@ -10,22 +12,42 @@ pub trait StoreService {
 
     fn get_pending(&self, round: u32) -> Option<&[u8]>;
 
+    /// Requests of round if committed here and not settled yet.
+    fn get_committed(&self, round: u32) -> Option<&[u8]>;
+
+    /// Digest of the requests committed up to round, chained in round order
+    /// from the last checkpoint. None if one of them is not committed here.
+    fn state(&self, round: u32) -> Option<[u8; 32]>;
+
+    /// Settle the rounds up to round, state stands in for their requests
+    /// from now on.
+    fn checkpoint(&mut self, round: u32, state: [u8; 32]);
+
+    /// Last checkpoint round and state.
+    fn stable(&self) -> Option<(u32, [u8; 32])>;
+
 }
 
 
 pub struct Store {
     req_by_round: HashMap::<u32, (Vec<u8>, bool)>,
+    stable: Option<(u32, [u8; 32])>,
 }
 
 
 impl StoreService for Store {
     fn new() -> Self {
         Self {
-            req_by_round: HashMap::new()
+            req_by_round: HashMap::new(),
+            stable: None,
         }
     }
 
     fn pending(&mut self, round: u32, payload: &[u8]) -> bool {
+        if self.stable.map_or(false, |(settled, _)| round <= settled) {
+            return false;
+        }
+
         if let Some((req, committed)) = self.req_by_round.get_mut(&round) {
             if *committed {
                 false
@@ -64,4 +86,78 @@ impl StoreService for Store {
             None
         }
     }
-}
\ No newline at end of file
+
+    fn get_committed(&self, round: u32) -> Option<&[u8]> {
+        match self.req_by_round.get(&round) {
+            Some((req, true)) => Some(req),
+            _ => None,
+        }
+    }
+
+    fn state(&self, round: u32) -> Option<[u8; 32]> {
+        let (first, mut state) = match self.stable {
+            Some((settled, state)) if settled == round => return Some(state),
+            Some((settled, _)) if settled > round => return None,
+            Some((settled, state)) => (settled + 1, state),
+            None => (0, [0; 32]),
+        };
+
+        for n in first..=round {
+            match self.req_by_round.get(&n) {
+                Some((req, true)) => state = digest(&[&state[..], req].concat()),
+                _ => return None,
+            }
+        }
+        Some(state)
+    }
+
+    fn checkpoint(&mut self, round: u32, state: [u8; 32]) {
+        self.req_by_round.retain(|n, _| *n > round);
+        self.stable = Some((round, state));
+    }
+
+    fn stable(&self) -> Option<(u32, [u8; 32])> {
+        self.stable
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn state_chain_and_checkpoint() {
+        let mut store = Store::new();
+        for round in 0..4 {
+            store.pending(round, &[round as u8]);
+        }
+        store.commit(0);
+        store.commit(1);
+        store.commit(3);
+        assert_eq!(store.get_committed(2), None);
+        assert_eq!(store.get_committed(3), Some(&[3][..]));
+
+        let state = store.state(1).unwrap();
+        assert_ne!(store.state(0), Some(state));
+        // round 2 is not committed yet
+        assert_eq!(store.state(3), None);
+
+        store.checkpoint(1, state);
+        assert_eq!(store.stable(), Some((1, state)));
+        assert_eq!(store.get_pending(0), None);
+        assert_eq!(store.state(0), None);
+        assert_eq!(store.state(1), Some(state));
+        assert!(!store.pending(1, &[9]));
+
+        // the chain goes on from the checkpoint
+        store.commit(2);
+        let mut other = Store::new();
+        other.checkpoint(1, state);
+        other.pending(2, &[2]);
+        other.pending(3, &[3]);
+        other.commit(2);
+        other.commit(3);
+        assert_eq!(store.state(3), other.state(3));
+    }
+}
@@ -3,25 +3,201 @@ mod test {
 
     use super::*;
     use yulong::log;
+    use yulong::utils::clock;
+
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use yulong_bdn::sim::{drive, listening, sim_node, Turn, TICK};
+    use yulong_network::identity::{Me, Peer};
+    use yulong_network::identity::crypto::ed25519_signer::Ed25519Signer;
+    use yulong_sim::{seed_from_env, LinkConfig, SimNetwork};
+
+    use crate::builder::PbftBuilder;
+    use crate::byzantine::{ByzantineReplica, Fault, PbftMsgKind, SafetyChecker};
+    use crate::config::PbftConfig;
+    use crate::pbft_client::PbftClientContext;
+
+    // a network of four replicas and a client, the nodes added
+    fn sim_cluster(net: &SimNetwork) -> (Vec<Me>, Me, Vec<Peer>) {
+        net.set_default_link(LinkConfig {
+            latency: Duration::from_millis(2),
+            jitter: Duration::from_millis(3),
+            ..LinkConfig::default()
+        });
+
+        let replicas: Vec<Me> = (0..4).map(|_| net.identity()).collect();
+        let client_id = net.identity();
+        for id in replicas.iter().chain(std::iter::once(&client_id)) {
+            net.add_node(id);
+        }
+        let peers = replicas.iter().map(|id| id.peer().to_owned()).collect();
+        (replicas, client_id, peers)
+    }
+
+    // the client, once every node listens
+    async fn client(net: &SimNetwork, client_id: &Me, replicas: &[Me], peers: &[Peer])
+        -> PbftClientContext<Ed25519Signer>
+    {
+        let everyone = [replicas, std::slice::from_ref(client_id)].concat();
+        let (network, payloads) = sim_node(net, client_id, replicas, &everyone).spawn();
+        let client = PbftClientContext::new(
+            network, payloads, Ed25519Signer::new(), client_id.clone(), peers.to_vec(), PbftConfig::default());
+
+        listening(net, &everyone).await;
+        client
+    }
 
     #[test]
-    fn node1() {
+    fn sim_cluster_commits() {
+        let net = SimNetwork::new(seed_from_env());
+        let (replicas, client_id, peers) = sim_cluster(&net);
+        let everyone = [&replicas[..], std::slice::from_ref(&client_id)].concat();
+
+        // operations each replica executed, in order
+        let executed: Vec<Arc<Mutex<Vec<Vec<u8>>>>> = (0..4).map(|_| Arc::default()).collect();
+
+        net.block_on(async {
+            let mut turns: Vec<Turn<_>> = replicas.iter().zip(executed.iter()).map(|(id, log)| {
+                let bdn = sim_node(&net, id, &replicas, &everyone);
+                let inbox = bdn.msg_sender.clone();
+                let log = log.clone();
+
+                let mut pbft = PbftBuilder::new(peers.clone())
+                    .local_id(id.clone())
+                    .network(bdn)
+                    .signer(Ed25519Signer::new())
+                    .executor(move |op: &[u8]| {
+                        log.lock().unwrap().push(op.to_vec());
+                        op.to_vec()
+                    })
+                    .build()
+                    .unwrap();
+
+                (inbox, Box::new(move || pbft.heartbeat()) as Box<dyn FnMut()>)
+            }).collect();
 
+            let mut client = client(&net, &client_id, &replicas, &peers).await;
+            drive(&net, &mut turns, async {
+                for op in [&b"op-1"[..], b"op-2", b"op-3"] {
+                    assert_eq!(client.submit(op).await.unwrap(), op);
+                }
+            }).await;
+
+            // one replica out of four may fail
+            net.partition(&[vec![peers[3].clone()]]);
+            drive(&net, &mut turns, async {
+                assert_eq!(client.submit(b"op-4").await.unwrap(), b"op-4");
+
+                // f + 1 replies are enough for the client, the third replica may lag
+                while executed[..3].iter().any(|log| log.lock().unwrap().len() < 4) {
+                    clock::sleep(TICK).await;
+                }
+            }).await;
+        });
+
+        let executed: Vec<Vec<Vec<u8>>> = executed.iter().map(|log| log.lock().unwrap().clone()).collect();
+        let ops: Vec<Vec<u8>> = (1..=4).map(|i| format!("op-{}", i).into_bytes()).collect();
+        for log in &executed[..3] {
+            assert_eq!(log, &ops);
+        }
+        assert!(ops.starts_with(&executed[3]));
+    }
+
+    // ops submitted to four replicas, the faulty one running with the faults
+    // made for the replica set, while the others report what they commit
+    fn byzantine_cluster(
+        config: PbftConfig,
+        faulty: usize,
+        faults: fn(&[Peer]) -> Vec<Fault>,
+        ops: &'static [&'static [u8]]
+    ) -> SafetyChecker
+    {
+        let net = SimNetwork::new(seed_from_env());
+        let (replicas, client_id, peers) = sim_cluster(&net);
+        let everyone = [&replicas[..], std::slice::from_ref(&client_id)].concat();
+
+        let faults = faults(&peers);
+        let checker = SafetyChecker::new();
+
+        net.block_on(async {
+            let mut turns: Vec<Turn<_>> = replicas.iter().enumerate().map(|(n, id)| {
+                let bdn = sim_node(&net, id, &replicas, &everyone);
+                let inbox = bdn.msg_sender.clone();
+
+                let mut pbft = PbftBuilder::new(peers.clone())
+                    .local_id(id.clone())
+                    .network(bdn)
+                    .signer(Ed25519Signer::new())
+                    .config(config.clone())
+                    .executor(|op: &[u8]| op.to_vec())
+                    .build()
+                    .unwrap();
+
+                let step: Box<dyn FnMut()> = if n == faulty {
+                    let mut replica = ByzantineReplica::new(pbft, faults.clone());
+                    Box::new(move || replica.heartbeat())
+                }
+                else {
+                    checker.watch(&mut pbft);
+                    Box::new(move || pbft.heartbeat())
+                };
+                (inbox, step)
+            }).collect();
+
+            let mut client = client(&net, &client_id, &replicas, &peers).await;
+            drive(&net, &mut turns, async {
+                for op in ops {
+                    assert_eq!(client.submit(op).await.unwrap(), *op);
+                }
+            }).await;
+        });
+        checker
     }
 
+    const OPS: [&[u8]; 3] = [b"op-1", b"op-2", b"op-3"];
+
+    // a backup votes apart to replica 1, stands for replica 2 in its
+    // checkpoints and never answers clients, the rest commit without it
     #[test]
-    fn node2() {
+    fn byzantine_backup_cannot_split() {
+        let checker = byzantine_cluster(PbftConfig::default(), 3, |peers| vec![
+            Fault::Equivocate { kinds: vec![PbftMsgKind::PREPARE, PbftMsgKind::COMMIT], to: vec![peers[1].clone()] },
+            Fault::Forge { kinds: vec![PbftMsgKind::CHECKPOINT], signer_id: Box::new(peers[2].clone()) },
+            Fault::Delay { kinds: vec![PbftMsgKind::PREPARE], delay: Duration::from_millis(20) },
+            Fault::Withhold(vec![PbftMsgKind::REPLY]),
+        ], &OPS);
 
+        checker.assert_safe();
+        assert_eq!(checker.rounds(), OPS.len());
     }
 
+    // the primary orders another batch to replica 3, which can never
+    // commit it, the others commit the batch they share
     #[test]
-    fn node3() {
+    fn conflicting_pre_prepare_stays_safe() {
+        let checker = byzantine_cluster(PbftConfig::default(), 0, |peers| vec![
+            Fault::ConflictingPrePrepare { to: vec![peers[3].clone()] },
+            Fault::Delay { kinds: vec![PbftMsgKind::COMMIT], delay: Duration::from_millis(20) },
+        ], &OPS);
 
+        checker.assert_safe();
+        assert_eq!(checker.rounds(), OPS.len());
     }
 
+    const MORE_OPS: [&[u8]; 6] = [b"op-1", b"op-2", b"op-3", b"op-4", b"op-5", b"op-6"];
+
+    // checkpoints every other round prune the log as the run goes on, the
+    // rounds pruned are checked all the same
     #[test]
-    fn node4() {
+    fn pruned_rounds_are_checked() {
+        let config = PbftConfig { checkpoint_period: 2, watermark_window: 4, ..PbftConfig::default() };
+        let checker = byzantine_cluster(config, 3, |peers| vec![
+            Fault::Equivocate { kinds: vec![PbftMsgKind::PREPARE, PbftMsgKind::COMMIT], to: vec![peers[1].clone()] },
+        ], &MORE_OPS);
 
+        checker.assert_safe();
+        assert_eq!(checker.rounds(), MORE_OPS.len());
     }
 
 }
\ No newline at end of file
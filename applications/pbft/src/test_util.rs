@@ -0,0 +1,29 @@
+//! Fixtures shared by the tests of this crate.
+
+use yulong::utils::AsBytes;
+use yulong_network::identity::Me;
+use yulong_network::identity::crypto::{PublicKey, PrivateKey, Signer};
+use yulong_network::identity::crypto::ed25519_signer::Ed25519Signer;
+
+use crate::message::{PbftMessage, PbftMsgKind};
+
+
+pub(crate) fn identity() -> Me {
+    let (pk, sk) = Ed25519Signer::new().keygen();
+    Me::from_keypair(PublicKey::Ed25519(pk), PrivateKey::Ed25519(sk))
+}
+
+
+/// A message signed by me, as sent.
+pub(crate) fn signed(me: &Me, view: u32, round: u32, msg_type: PbftMsgKind, payload: Vec<u8>) -> PbftMessage {
+    let mut msg = PbftMessage::new(view, round, 0, msg_type, me.peer().to_owned(), payload);
+    msg.sign(&Ed25519Signer::new(), me.private_key(), me.peer().pubkey()).unwrap();
+    msg
+}
+
+
+/// The same as received from me, the key of the signer is looked up again.
+pub(crate) fn from(me: &Me, view: u32, round: u32, msg_type: PbftMsgKind, payload: Vec<u8>) -> PbftMessage {
+    let msg = signed(me, view, round, msg_type, payload);
+    PbftMessage::from_bytes(&msg.into_bytes().unwrap()).unwrap()
+}
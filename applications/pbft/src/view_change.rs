@@ -0,0 +1,441 @@
+use std::collections::{BTreeMap, HashSet};
+
+use prost::Message;
+
+use yulong::error::{DeserializeError, DumbError, SerializeError};
+use yulong::utils::AsBytes;
+use yulong_network::identity::crypto::GenericSigner;
+
+use crate::checkpoint::CheckpointCert;
+use crate::message::{digest, PbftMessage, PbftMsgKind};
+use crate::participants::{Participants, ParticipantsStore};
+use crate::pbft_message::{ProtoNewView, ProtoPreparedCert, ProtoViewChange};
+
+
+/// Proof that a request prepared: the PRE-PREPARE of a view followed by the
+/// PREPAREs of other replicas on the same view, round and request digest,
+/// 2f + 1 signatures in all.
+#[derive(Debug, Clone)]
+pub(crate) struct PreparedCert {
+    votes: Vec<PbftMessage>,
+}
+
+
+impl PreparedCert {
+
+    pub(crate) fn new(pre_prepare: PbftMessage, prepares: Vec<PbftMessage>) -> Self {
+        let mut votes = vec![pre_prepare];
+        votes.extend(prepares);
+        Self { votes }
+    }
+
+
+    pub(crate) fn view(&self) -> u32 {
+        self.votes[0].view()
+    }
+
+
+    pub(crate) fn round(&self) -> u32 {
+        self.votes[0].round()
+    }
+
+
+    pub(crate) fn request(&self) -> &[u8] {
+        self.votes[0].payload()
+    }
+
+
+    fn to_proto(&self) -> Result<ProtoPreparedCert, SerializeError> {
+        let votes = self.votes.iter()
+            .map(|vote| vote.into_bytes())
+            .collect::<Result<Vec<Vec<u8>>, SerializeError>>()?;
+        Ok(ProtoPreparedCert { votes })
+    }
+
+
+    fn from_proto(m: ProtoPreparedCert) -> Result<Self, DeserializeError> {
+        let votes = m.votes.iter()
+            .map(|vote| PbftMessage::from_bytes(vote))
+            .collect::<Result<Vec<PbftMessage>, DeserializeError>>()?;
+
+        if votes.is_empty() {
+            return Err(DeserializeError::new("Empty PreparedCert", DumbError));
+        }
+        Ok(Self { votes })
+    }
+}
+
+
+/// What a replica tells the next primary when it leaves a view: its last
+/// stable checkpoint and every request it prepared after it.
+#[derive(Debug, Clone)]
+pub(crate) struct ViewChange {
+    checkpoint: Option<CheckpointCert>,
+    prepared: Vec<PreparedCert>,
+}
+
+
+impl ViewChange {
+
+    pub(crate) fn new(checkpoint: Option<CheckpointCert>, prepared: Vec<PreparedCert>) -> Self {
+        Self { checkpoint, prepared }
+    }
+
+
+    // first round not settled by the checkpoint
+    fn low_round(&self) -> u32 {
+        self.checkpoint.as_ref().map_or(0, |cert| cert.round() + 1)
+    }
+
+
+    pub(crate) fn into_payload(&self) -> Result<Vec<u8>, SerializeError> {
+        let proto_message = ProtoViewChange {
+            checkpoint: self.checkpoint.as_ref().map(|cert| cert.to_proto()).transpose()?,
+            prepared: self.prepared.iter()
+                .map(|cert| cert.to_proto())
+                .collect::<Result<Vec<ProtoPreparedCert>, SerializeError>>()?,
+        };
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+
+    pub(crate) fn from_payload(buf: &[u8]) -> Result<Self, DeserializeError> {
+        let m = ProtoViewChange::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize ViewChange", e))?;
+
+        Ok(Self {
+            checkpoint: m.checkpoint.map(CheckpointCert::from_proto).transpose()?,
+            prepared: m.prepared.into_iter()
+                .map(PreparedCert::from_proto)
+                .collect::<Result<Vec<PreparedCert>, DeserializeError>>()?,
+        })
+    }
+}
+
+
+/// The latest stable checkpoint among the view changes, the new view goes
+/// on from there.
+pub(crate) fn latest_checkpoint(view_changes: &[ViewChange]) -> Option<CheckpointCert> {
+    view_changes.iter()
+        .filter_map(|vc| vc.checkpoint.as_ref())
+        .max_by_key(|cert| cert.round())
+        .cloned()
+}
+
+
+/// Requests the new primary proposes again, from the view changes it
+/// gathered: for each round after the latest checkpoint up to the highest
+/// prepared one, the request prepared in the latest view, or an empty
+/// request if none did. Returns the first round and the requests in order.
+pub(crate) fn reissue(view_changes: &[ViewChange]) -> (u32, Vec<Vec<u8>>) {
+    let low = view_changes.iter().map(|vc| vc.low_round()).max().unwrap_or(0);
+
+    let mut latest: BTreeMap<u32, &PreparedCert> = BTreeMap::new();
+    for cert in view_changes.iter().flat_map(|vc| vc.prepared.iter()) {
+        if cert.round() < low {
+            continue;
+        }
+        match latest.get(&cert.round()) {
+            Some(known) if known.view() >= cert.view() => {}
+            _ => { latest.insert(cert.round(), cert); }
+        }
+    }
+
+    let high = match latest.keys().next_back() {
+        Some(high) => *high,
+        None => return (low, vec![]),
+    };
+
+    let requests = (low..=high)
+        .map(|round| latest.get(&round).map(|cert| cert.request().to_vec()).unwrap_or_default())
+        .collect();
+
+    (low, requests)
+}
+
+
+/// NEW-VIEW payload: the view changes that elected the primary and the
+/// PRE-PREPAREs it derived from them.
+pub(crate) struct NewView {
+    view_changes: Vec<PbftMessage>,
+    pre_prepares: Vec<PbftMessage>,
+}
+
+
+impl NewView {
+
+    pub(crate) fn new(view_changes: Vec<PbftMessage>, pre_prepares: Vec<PbftMessage>) -> Self {
+        Self { view_changes, pre_prepares }
+    }
+
+
+    pub(crate) fn into_payload(&self) -> Result<Vec<u8>, SerializeError> {
+        let encode = |msgs: &Vec<PbftMessage>| msgs.iter()
+            .map(|msg| msg.into_bytes())
+            .collect::<Result<Vec<Vec<u8>>, SerializeError>>();
+
+        let proto_message = ProtoNewView {
+            view_changes: encode(&self.view_changes)?,
+            pre_prepares: encode(&self.pre_prepares)?,
+        };
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+
+    pub(crate) fn from_payload(buf: &[u8]) -> Result<Self, DeserializeError> {
+        let m = ProtoNewView::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize NewView", e))?;
+
+        let decode = |msgs: Vec<Vec<u8>>| msgs.iter()
+            .map(|msg| PbftMessage::from_bytes(msg))
+            .collect::<Result<Vec<PbftMessage>, DeserializeError>>();
+
+        Ok(Self {
+            view_changes: decode(m.view_changes)?,
+            pre_prepares: decode(m.pre_prepares)?,
+        })
+    }
+}
+
+
+/// Checks the signed messages carried inside VIEW-CHANGE, NEW-VIEW and
+/// STATE.
+pub(crate) struct Verifier<'a, S: GenericSigner> {
+    participants: &'a Participants,
+    signer: &'a S,
+    quorum_size: usize,
+}
+
+
+impl<'a, S: GenericSigner> Verifier<'a, S> {
+
+    pub(crate) fn new(participants: &'a Participants, signer: &'a S, quorum_size: usize) -> Self {
+        Self { participants, signer, quorum_size }
+    }
+
+
+    // signed by a participant, whose key is filled in first
+    fn authentic(&self, msg: &mut PbftMessage) -> bool {
+        if self.participants.get_idx(msg.signer_id()).is_none() {
+            return false;
+        }
+        self.participants.query_pk(msg.signer_id_mut());
+        msg.verify(self.signer)
+    }
+
+
+    pub(crate) fn prepared(&self, cert: &mut PreparedCert) -> bool {
+        let (view, round) = (cert.view(), cert.round());
+        let primary = match self.participants.primary(view) {
+            Some(primary) => primary.to_owned(),
+            None => return false,
+        };
+
+        let (pre_prepare, prepares) = cert.votes.split_first_mut().unwrap();
+        if pre_prepare.msg_type() != PbftMsgKind::PRE_PREPARE ||
+            *pre_prepare.signer_id() != primary ||
+            !self.authentic(pre_prepare)
+        {
+            return false;
+        }
+
+        let request = digest(pre_prepare.payload());
+        let mut signers = HashSet::new();
+        for prepare in prepares.iter_mut() {
+            if prepare.msg_type() != PbftMsgKind::PREPARE ||
+                prepare.view() != view ||
+                prepare.round() != round ||
+                prepare.payload() != request ||
+                *prepare.signer_id() == primary ||
+                !self.authentic(prepare) ||
+                !signers.insert(prepare.signer_id().to_owned())
+            {
+                return false;
+            }
+        }
+
+        signers.len() + 1 >= self.quorum_size
+    }
+
+
+    pub(crate) fn checkpoint(&self, cert: &mut CheckpointCert) -> bool {
+        let round = cert.round();
+        let state = cert.votes_mut()[0].payload().to_vec();
+        if state.len() != 32 {
+            return false;
+        }
+
+        let mut signers = HashSet::new();
+        for vote in cert.votes_mut() {
+            if vote.msg_type() != PbftMsgKind::CHECKPOINT ||
+                vote.round() != round ||
+                vote.payload() != state ||
+                !self.authentic(vote) ||
+                !signers.insert(vote.signer_id().to_owned())
+            {
+                return false;
+            }
+        }
+
+        signers.len() >= self.quorum_size
+    }
+
+
+    /// A VIEW-CHANGE to msg.view() whose certificates all hold.
+    pub(crate) fn view_change(&self, msg: &mut PbftMessage) -> Option<ViewChange> {
+        if msg.msg_type() != PbftMsgKind::VIEW_CHANGE || !self.authentic(msg) {
+            return None;
+        }
+
+        let mut view_change = ViewChange::from_payload(msg.payload()).ok()?;
+        if !view_change.checkpoint.as_mut().map_or(true, |cert| self.checkpoint(cert)) {
+            return None;
+        }
+
+        let low = view_change.low_round();
+        let sound = view_change.prepared.iter_mut().all(|cert| {
+            cert.view() < msg.view() &&
+            cert.round() >= low &&
+            self.prepared(cert)
+        });
+
+        if sound { Some(view_change) } else { None }
+    }
+
+
+    /// The PRE-PREPAREs a NEW-VIEW to view carries, once checked against
+    /// 2f + 1 view changes, together with the checkpoint they follow.
+    pub(crate) fn new_view(&self, view: u32, new_view: NewView)
+        -> Option<(Option<CheckpointCert>, Vec<PbftMessage>)>
+    {
+        let primary = self.participants.primary(view)?.to_owned();
+
+        let mut signers = HashSet::new();
+        let mut view_changes = Vec::new();
+        for mut msg in new_view.view_changes {
+            if msg.view() != view || !signers.insert(msg.signer_id().to_owned()) {
+                return None;
+            }
+            view_changes.push(self.view_change(&mut msg)?);
+        }
+
+        if view_changes.len() < self.quorum_size {
+            return None;
+        }
+
+        let (low, requests) = reissue(&view_changes);
+        if requests.len() != new_view.pre_prepares.len() {
+            return None;
+        }
+
+        let mut pre_prepares = new_view.pre_prepares;
+        for (n, (pre_prepare, request)) in pre_prepares.iter_mut().zip(requests.iter()).enumerate() {
+            if pre_prepare.msg_type() != PbftMsgKind::PRE_PREPARE ||
+                pre_prepare.view() != view ||
+                pre_prepare.round() != low + n as u32 ||
+                pre_prepare.payload() != request.as_slice() ||
+                *pre_prepare.signer_id() != primary ||
+                !self.authentic(pre_prepare)
+            {
+                return None;
+            }
+        }
+
+        Some((latest_checkpoint(&view_changes), pre_prepares))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{from, identity};
+
+    use yulong_network::identity::Me;
+    use yulong_network::identity::crypto::ed25519_signer::Ed25519Signer;
+
+    fn prepared(replicas: &[Me], view: u32, round: u32, request: &[u8]) -> PreparedCert {
+        let primary = (view as usize) % replicas.len();
+        let pre_prepare = from(&replicas[primary], view, round, PbftMsgKind::PRE_PREPARE, request.to_vec());
+        let prepares = replicas.iter().enumerate()
+            .filter(|(n, _)| *n != primary)
+            .take(2)
+            .map(|(_, me)| from(me, view, round, PbftMsgKind::PREPARE, digest(request).to_vec()))
+            .collect();
+        PreparedCert::new(pre_prepare, prepares)
+    }
+
+    #[test]
+    fn prepared_survives_view_change() {
+        let replicas: Vec<Me> = (0..4).map(|_| identity()).collect();
+        let participants = Participants::new(replicas.iter().map(|me| me.peer().to_owned()).collect());
+        let signer = Ed25519Signer::new();
+        let verifier = Verifier::new(&participants, &signer, 3);
+
+        let mut cert = prepared(&replicas, 0, 2, b"req");
+        assert!(verifier.prepared(&mut cert));
+
+        // a PREPARE on another request does not count
+        let mut forged = cert.clone();
+        forged.votes[2] = from(&replicas[2], 0, 2, PbftMsgKind::PREPARE, digest(b"other").to_vec());
+        assert!(!verifier.prepared(&mut forged));
+        forged.votes.truncate(2);
+        assert!(!verifier.prepared(&mut forged));
+
+        // round 0 is settled by a checkpoint
+        let mut checkpoint = CheckpointCert::new(replicas[1..].iter()
+            .map(|me| from(me, 0, 0, PbftMsgKind::CHECKPOINT, vec![7; 32]))
+            .collect());
+        assert!(verifier.checkpoint(&mut checkpoint));
+        let mut short = CheckpointCert::new(checkpoint.votes_mut()[..2].to_vec());
+        assert!(!verifier.checkpoint(&mut short));
+
+        // round 4 prepared in view 0 and again with another request in view 1
+        let view_changes = vec![
+            ViewChange::new(Some(checkpoint), vec![cert.clone(), prepared(&replicas, 0, 4, b"old")]),
+            ViewChange::new(None, vec![prepared(&replicas, 1, 4, b"new")]),
+            ViewChange::new(None, vec![]),
+        ];
+        let (low, requests) = reissue(&view_changes);
+        assert_eq!(low, 1);
+        assert_eq!(requests, vec![vec![], b"req".to_vec(), vec![], b"new".to_vec()]);
+
+        // replica 2 is the primary of view 2
+        let vc_msgs: Vec<PbftMessage> = view_changes.iter().zip(replicas.iter())
+            .map(|(vc, me)| from(me, 2, 0, PbftMsgKind::VIEW_CHANGE, vc.into_payload().unwrap()))
+            .collect();
+        let pre_prepares: Vec<PbftMessage> = requests.iter().enumerate()
+            .map(|(n, request)| from(&replicas[2], 2, low + n as u32, PbftMsgKind::PRE_PREPARE, request.to_owned()))
+            .collect();
+
+        let new_view = NewView::new(vc_msgs.clone(), pre_prepares.clone());
+        let decoded = NewView::from_payload(&new_view.into_payload().unwrap()).unwrap();
+        let (stable, accepted) = verifier.new_view(2, decoded).unwrap();
+        assert_eq!(stable.unwrap().round(), 0);
+        assert_eq!(accepted[1].payload(), b"req");
+
+        // dropping the prepared request is caught
+        let mut dropped = pre_prepares.clone();
+        dropped[1] = from(&replicas[2], 2, 2, PbftMsgKind::PRE_PREPARE, vec![]);
+        assert!(verifier.new_view(2, NewView::new(vc_msgs.clone(), dropped)).is_none());
+
+        // so is a NEW-VIEW from the wrong primary or without a quorum
+        assert!(verifier.new_view(3, NewView::new(vc_msgs.clone(), pre_prepares.clone())).is_none());
+        assert!(verifier.new_view(2, NewView::new(vc_msgs[..2].to_vec(), pre_prepares)).is_none());
+
+        let stranger = identity();
+        let mut outsider = from(&stranger, 2, 0, PbftMsgKind::VIEW_CHANGE, ViewChange::new(None, vec![]).into_payload().unwrap());
+        assert!(verifier.view_change(&mut outsider).is_none());
+
+        // a prepared request below the checkpoint it carries is refused
+        let stale = ViewChange::new(latest_checkpoint(&view_changes), vec![prepared(&replicas, 0, 0, b"req")]);
+        let mut stale = from(&replicas[0], 2, 0, PbftMsgKind::VIEW_CHANGE, stale.into_payload().unwrap());
+        assert!(verifier.view_change(&mut stale).is_none());
+    }
+}
@@ -1,5 +1,117 @@
-pub const HEARTBEAT_INV: u128 = 100; // ms
-pub const ELECTION_INV_LOW: u128 = 150; // msg
-pub const ELECTION_INV_HIGH: u128 = 500; // msg
-pub const PAYLOAD_MAX: usize = 500; // bytes
-pub const CLIENT_TIMEOUT: usize = 500; // ms
\ No newline at end of file
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use yulong::config::{Config, invalid};
+use yulong::error::ConfigError;
+
+
+/// Tunables of a Raft node or client. All durations are in ms.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RaftConfig {
+    pub heartbeat_inv: u64,
+
+    // election timeout is picked from [election_inv_low, election_inv_high)
+    pub election_inv_low: u64,
+    pub election_inv_high: u64,
+
+    // seed of the randomized election timeouts, a random one if unset
+    pub election_seed: Option<u64>,
+
+    // size of generated test requests, bytes
+    pub payload_max: usize,
+
+    // a client waits this long for an answer before trying again,
+    // client_retry times at most
+    pub client_timeout: u64,
+    pub client_retry: u32,
+
+    // max entries carried by one AppendEntries
+    pub append_batch: usize,
+
+    // keep the log and vote in a write-ahead log here, in memory if unset
+    pub log_dir: Option<PathBuf>,
+
+    // bytes per wal segment file
+    pub wal_segment_size: u64,
+
+    // take a snapshot once this many entries are applied since the last one
+    pub snapshot_threshold: u64,
+
+    // bytes of snapshot carried by one InstallSnapshot
+    pub snapshot_chunk: usize,
+
+    // ask for pre-votes before an election, so a node that was cut off
+    // does not raise the term of the cluster when it comes back
+    pub pre_vote: bool,
+
+    // serve reads while a majority answered the leader within the last
+    // election_inv_low, instead of confirming leadership for each read.
+    // Relies on clocks of the nodes running at about the same rate.
+    pub lease_read: bool,
+}
+
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_inv: 100,
+            election_inv_low: 150,
+            election_inv_high: 500,
+            election_seed: None,
+            payload_max: 500,
+            client_timeout: 500,
+            client_retry: 5,
+            append_batch: 64,
+            log_dir: None,
+            wal_segment_size: 16 * 1024 * 1024,
+            snapshot_threshold: 10000,
+            snapshot_chunk: 64 * 1024,
+            pre_vote: true,
+            lease_read: false,
+        }
+    }
+}
+
+
+impl Config for RaftConfig {
+
+    const ENV_PREFIX: &'static str = "YULONG_RAFT";
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.heartbeat_inv == 0 || self.client_timeout == 0 || self.client_retry == 0 {
+            return Err(invalid("heartbeat_inv, client_timeout and client_retry should be positive"));
+        }
+        if self.wal_segment_size == 0 || self.append_batch == 0 {
+            return Err(invalid("wal_segment_size and append_batch should be positive"));
+        }
+        if self.snapshot_threshold == 0 || self.snapshot_chunk == 0 {
+            return Err(invalid("snapshot_threshold and snapshot_chunk should be positive"));
+        }
+        if self.election_inv_low >= self.election_inv_high {
+            return Err(invalid("election_inv_low should be less than election_inv_high"));
+        }
+        // followers must hear from the leader before they start an election
+        if self.heartbeat_inv >= self.election_inv_low {
+            return Err(invalid("heartbeat_inv should be less than election_inv_low"));
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn election_range() {
+        assert!(RaftConfig::default().validate().is_ok());
+
+        let env = vec![("YULONG_RAFT_ELECTION_INV_HIGH".to_owned(), "150".to_owned())];
+        assert!(RaftConfig::from_sources("", env).is_err());
+
+        assert!(RaftConfig::from_sources("heartbeat_inv = 200", vec![]).is_err());
+    }
+}
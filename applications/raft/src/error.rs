@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+
+
+/// Errors happened reading or writing the replicated log
+#[derive(Debug)]
+pub struct LogError {
+    describe: String,
+    boxed_error: Box<dyn Error>
+}
+
+
+impl LogError {
+    pub fn new<S: ToString>(des: S, err: impl Error + 'static) -> Self {
+        Self {
+            describe: des.to_string(),
+            boxed_error: Box::new(err)
+        }
+    }
+}
+
+
+impl Error for LogError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+
+impl Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Log error: {}", self.describe)
+    }
+}
+
+
+/// A membership change cannot be started
+#[derive(Debug)]
+pub struct MembershipError {
+    describe: String,
+    boxed_error: Box<dyn Error>
+}
+
+
+impl MembershipError {
+    pub fn new<S: ToString>(des: S, err: impl Error + 'static) -> Self {
+        Self {
+            describe: des.to_string(),
+            boxed_error: Box::new(err)
+        }
+    }
+}
+
+
+impl Error for MembershipError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+
+impl Display for MembershipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Membership error: {}", self.describe)
+    }
+}
+
+
+/// A client request got no answer
+#[derive(Debug)]
+pub struct ClientError {
+    describe: String,
+    boxed_error: Box<dyn Error>
+}
+
+
+impl ClientError {
+    pub fn new<S: ToString>(des: S, err: impl Error + 'static) -> Self {
+        Self {
+            describe: des.to_string(),
+            boxed_error: Box::new(err)
+        }
+    }
+}
+
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Client error: {}", self.describe)
+    }
+}
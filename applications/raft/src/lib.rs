@@ -1,8 +1,18 @@
 pub mod raft;
 pub mod raft_client;
+pub mod error;
 mod message;
 mod log_store;
 mod raft_timer;
 mod test;
-mod config;
-mod quorum;
\ No newline at end of file
+#[cfg(test)]
+mod test_util;
+pub mod config;
+pub mod state_machine;
+pub mod membership;
+mod quorum;
+mod session;
+
+mod raft_message {
+    include!(concat!(env!("OUT_DIR"), "/raft.rs"));
+}
@@ -0,0 +1,606 @@
+mod wal;
+
+pub use wal::WalLog;
+
+use yulong::error::{DeserializeError, DumbError};
+use yulong::utils::AsBytes;
+use yulong_network::identity::Peer;
+
+use crate::error::LogError;
+use crate::membership::Membership;
+use crate::raft_message;
+
+
+/// Storage of the Raft log, together with term and voted_for which must
+/// survive a restart as well.
+///
+/// Indexes start from 1, index 0 stands for the empty log and has term 0.
+/// Committed entries are never truncated. A snapshot replaces the entries
+/// up to its index, which are gone from the log afterwards.
+pub(crate) trait LogService: Send {
+
+    // leader shall accept log entries from clients and replicate
+    // them across the cluster, return the index of the new entry
+    fn client_new_entry(&mut self, entry: LogEntry) -> Result<u64, LogError>;
+
+    // follower stores entries following prev_idx
+    // an existing entry conflicting with a new one (same index, different term)
+    // is removed with all that follow it, matching entries are kept so a stale
+    // AppendEntries never shortens the log
+    // return the index of the last new entry
+    fn append_entry(&mut self, prev_idx: u64, entries: Vec<LogEntry>) -> Result<u64, LogError>;
+
+    // remove idx and all entries after it
+    fn truncate(&mut self, idx: u64) -> Result<(), LogError>;
+
+    // commit index only moves forward and stops at the last entry
+    fn commit(&mut self, idx: u64);
+
+    fn commit_idx(&self) -> u64;
+
+    fn last(&self) -> (u64, LogEntry);
+
+    fn get(&self, idx: u64) -> Option<LogEntry>;
+
+    fn term_at(&self, idx: u64) -> Option<u64>;
+
+    // at most max entries starting from idx, none if idx is compacted
+    fn entries_from(&self, idx: u64, max: usize) -> Vec<LogEntry>;
+
+    // replace the committed entries up to snapshot.index() with snapshot
+    fn compact(&mut self, snapshot: Snapshot) -> Result<(), LogError>;
+
+    // follower takes a snapshot from the leader, entries following it are
+    // kept if the log holds the snapshot's last entry, dropped otherwise
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> Result<(), LogError>;
+
+    fn snapshot(&self) -> Option<&Snapshot>;
+
+    fn save_state(&mut self, term: u64, voted_for: Option<&Peer>) -> Result<(), LogError>;
+
+    fn load_state(&self) -> (u64, Option<Peer>);
+
+
+    fn last_idx(&self) -> u64 {
+        self.last().0
+    }
+
+
+    fn snapshot_idx(&self) -> u64 {
+        self.snapshot().map_or(0, |s| s.index())
+    }
+
+
+    // log contains an entry at idx with term, compacted entries are
+    // committed and match any leader
+    fn matches(&self, idx: u64, term: u64) -> bool {
+        idx < self.snapshot_idx() || self.term_at(idx) == Some(term)
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    // goes to the state machine
+    Command,
+    // changes the voters, command holds a Membership
+    Membership,
+    // empty, a new leader commits it to learn the commit index
+    Noop,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    term: u64,
+    kind: EntryKind,
+    command: Vec<u8>,
+
+    // request the command comes from, 0 if not tracked
+    client_id: u64,
+    client_seq: u64,
+}
+
+impl LogEntry {
+    pub(crate) fn new(term: u64, command: Vec<u8>) -> Self {
+        Self::request(term, command, 0, 0)
+    }
+
+    /// Command of a client request, applied once per client_id and client_seq.
+    pub(crate) fn request(term: u64, command: Vec<u8>, client_id: u64, client_seq: u64) -> Self {
+        Self { term, kind: EntryKind::Command, command, client_id, client_seq }
+    }
+
+    pub(crate) fn membership(term: u64, membership: &Membership) -> Self {
+        Self {
+            term,
+            kind: EntryKind::Membership,
+            // encoding a Membership does not fail, safe unwrap
+            command: membership.into_bytes().unwrap(),
+            client_id: 0,
+            client_seq: 0,
+        }
+    }
+
+    pub(crate) fn noop(term: u64) -> Self {
+        Self { term, kind: EntryKind::Noop, command: vec![], client_id: 0, client_seq: 0 }
+    }
+
+    /// Get a reference to the log entry's kind.
+    pub(crate) fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    /// Decode the membership carried by a membership entry.
+    pub(crate) fn to_membership(&self) -> Option<Result<Membership, DeserializeError>> {
+        match self.kind {
+            EntryKind::Membership => Some(Membership::from_bytes(&self.command)),
+            EntryKind::Command | EntryKind::Noop => None,
+        }
+    }
+
+    /// Get a reference to the log entry's client id.
+    pub(crate) fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    /// Get a reference to the log entry's client seq.
+    pub(crate) fn client_seq(&self) -> u64 {
+        self.client_seq
+    }
+
+    /// Get a reference to the log entry's term.
+    pub(crate) fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Get a reference to the log entry's command.
+    pub(crate) fn command(&self) -> &[u8] {
+        self.command.as_ref()
+    }
+}
+
+
+impl From<&LogEntry> for raft_message::LogEntry {
+    fn from(entry: &LogEntry) -> Self {
+        Self {
+            term: entry.term,
+            command: entry.command.to_owned(),
+            kind: match entry.kind {
+                EntryKind::Command => 0,
+                EntryKind::Membership => 1,
+                EntryKind::Noop => 2,
+            },
+            client_id: entry.client_id,
+            client_seq: entry.client_seq,
+        }
+    }
+}
+
+
+impl From<raft_message::LogEntry> for LogEntry {
+    fn from(entry: raft_message::LogEntry) -> Self {
+        let kind = match entry.kind {
+            1 => EntryKind::Membership,
+            2 => EntryKind::Noop,
+            _ => EntryKind::Command,
+        };
+        Self {
+            term: entry.term,
+            kind,
+            command: entry.command,
+            client_id: entry.client_id,
+            client_seq: entry.client_seq,
+        }
+    }
+}
+
+
+/// State machine image covering the log up to index, whose entry was
+/// created in term, with the voters as of index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    index: u64,
+    term: u64,
+    membership: Membership,
+    data: Vec<u8>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(index: u64, term: u64, membership: Membership, data: Vec<u8>) -> Self {
+        Self { index, term, membership, data }
+    }
+
+    /// Get a reference to the snapshot's index.
+    pub(crate) fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Get a reference to the snapshot's term.
+    pub(crate) fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Get a reference to the snapshot's membership.
+    pub(crate) fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    /// Get a reference to the snapshot's data.
+    pub(crate) fn data(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+}
+
+
+// entries following a snapshot point, shared by the backends
+// entries[i] is at index base_idx + i + 1
+struct EntryBuf {
+    base_idx: u64,
+    base_term: u64,
+    entries: Vec<LogEntry>,
+}
+
+
+/// In-memory log, everything is lost on restart.
+pub struct ReplicatedLog {
+    buf: EntryBuf,
+    snapshot: Option<Snapshot>,
+    commit_idx: u64,
+
+    term: u64,
+    voted_for: Option<Peer>,
+}
+
+
+impl LogService for ReplicatedLog {
+
+    fn client_new_entry(&mut self, entry: LogEntry) -> Result<u64, LogError> {
+        self.buf.push(entry);
+        Ok(self.buf.last_idx())
+    }
+
+    fn append_entry(&mut self, prev_idx: u64, entries: Vec<LogEntry>) -> Result<u64, LogError> {
+        let last_new = prev_idx + entries.len() as u64;
+
+        let (conflict, skip) = plan_append(self, prev_idx, &entries)?;
+        if let Some(idx) = conflict {
+            self.truncate(idx)?;
+        }
+        self.buf.extend(entries.into_iter().skip(skip));
+
+        Ok(last_new)
+    }
+
+    fn truncate(&mut self, idx: u64) -> Result<(), LogError> {
+        check_truncate(idx, self.commit_idx)?;
+        self.buf.truncate(idx);
+        Ok(())
+    }
+
+    fn commit(&mut self, idx: u64) {
+        self.commit_idx = self.commit_idx.max(idx.min(self.buf.last_idx()));
+    }
+
+    fn commit_idx(&self) -> u64 {
+        self.commit_idx
+    }
+
+    fn last(&self) -> (u64, LogEntry) {
+        self.buf.last()
+    }
+
+    fn get(&self, idx: u64) -> Option<LogEntry> {
+        self.buf.get(idx)
+    }
+
+    fn term_at(&self, idx: u64) -> Option<u64> {
+        self.buf.term_at(idx)
+    }
+
+    fn entries_from(&self, idx: u64, max: usize) -> Vec<LogEntry> {
+        self.buf.slice(idx, max)
+    }
+
+    fn compact(&mut self, snapshot: Snapshot) -> Result<(), LogError> {
+        check_compact(&snapshot, self.buf.base_idx, self.commit_idx)?;
+        self.buf.compact(snapshot.index(), snapshot.term());
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> Result<(), LogError> {
+        check_install(&snapshot, self.buf.base_idx)?;
+        self.buf.install(snapshot.index(), snapshot.term());
+        self.commit_idx = self.commit_idx.max(snapshot.index());
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    fn save_state(&mut self, term: u64, voted_for: Option<&Peer>) -> Result<(), LogError> {
+        self.term = term;
+        self.voted_for = voted_for.cloned();
+        Ok(())
+    }
+
+    fn load_state(&self) -> (u64, Option<Peer>) {
+        (self.term, self.voted_for.clone())
+    }
+}
+
+
+impl ReplicatedLog {
+
+    pub fn new() -> Self {
+        Self {
+            buf: EntryBuf::new(0, 0),
+            snapshot: None,
+            commit_idx: 0,
+            term: 0,
+            voted_for: None,
+        }
+    }
+
+}
+
+
+impl EntryBuf {
+
+    fn new(base_idx: u64, base_term: u64) -> Self {
+        Self { base_idx, base_term, entries: Vec::new() }
+    }
+
+
+    fn last_idx(&self) -> u64 {
+        self.base_idx + self.entries.len() as u64
+    }
+
+
+    // an empty buffer ends at the snapshot point
+    fn last(&self) -> (u64, LogEntry) {
+        match self.entries.last() {
+            Some(entry) => (self.last_idx(), entry.to_owned()),
+            None => (self.base_idx, LogEntry::new(self.base_term, vec![])),
+        }
+    }
+
+
+    fn pos(&self, idx: u64) -> Option<usize> {
+        match idx > self.base_idx {
+            true => Some((idx - self.base_idx - 1) as usize),
+            false => None,
+        }
+    }
+
+
+    fn get(&self, idx: u64) -> Option<LogEntry> {
+        self.pos(idx).and_then(|pos| self.entries.get(pos)).cloned()
+    }
+
+
+    fn term_at(&self, idx: u64) -> Option<u64> {
+        if idx == self.base_idx {
+            return Some(self.base_term);
+        }
+        self.pos(idx).and_then(|pos| self.entries.get(pos)).map(|entry| entry.term())
+    }
+
+
+    fn slice(&self, idx: u64, max: usize) -> Vec<LogEntry> {
+        match self.pos(idx.max(1)) {
+            Some(start) => self.entries.iter().skip(start).take(max).cloned().collect(),
+            None => vec![],
+        }
+    }
+
+
+    // entries in [from, to) that are still in the buffer
+    fn range(&self, from: u64, to: u64) -> &[LogEntry] {
+        let lo = from.max(self.base_idx + 1);
+        let hi = to.min(self.last_idx() + 1).max(lo);
+        &self.entries[(lo - self.base_idx - 1) as usize .. (hi - self.base_idx - 1) as usize]
+    }
+
+
+    fn push(&mut self, entry: LogEntry) {
+        self.entries.push(entry);
+    }
+
+
+    fn extend<I: IntoIterator<Item = LogEntry>>(&mut self, entries: I) {
+        self.entries.extend(entries);
+    }
+
+
+    // remove idx and all entries after it
+    fn truncate(&mut self, idx: u64) {
+        if let Some(pos) = self.pos(idx) {
+            self.entries.truncate(pos);
+        }
+    }
+
+
+    // drop entries up to idx which now ends in term
+    fn compact(&mut self, idx: u64, term: u64) {
+        let drained = (idx.min(self.last_idx()) - self.base_idx) as usize;
+        self.entries.drain(..drained);
+        self.base_idx = idx;
+        self.base_term = term;
+    }
+
+
+    // keep the suffix only if it follows (idx, term), return whether it is kept
+    fn install(&mut self, idx: u64, term: u64) -> bool {
+        if self.term_at(idx) == Some(term) {
+            self.compact(idx, term);
+            return true;
+        }
+        self.entries.clear();
+        self.base_idx = idx;
+        self.base_term = term;
+        false
+    }
+}
+
+
+// where entries following prev_idx start to differ from log
+// return the index to truncate from, if any, and the # of entries already in log
+fn plan_append<L: LogService + ?Sized>(log: &L, prev_idx: u64, entries: &[LogEntry])
+    -> Result<(Option<u64>, usize), LogError>
+{
+    if prev_idx > log.last_idx() {
+        return Err(LogError::new(
+            format!("append after {} but the log ends at {}", prev_idx, log.last_idx()),
+            DumbError
+        ));
+    }
+
+    for (i, entry) in entries.iter().enumerate() {
+        let idx = prev_idx + 1 + i as u64;
+        if idx <= log.snapshot_idx() {
+            // compacted, hence committed and matching
+            continue;
+        }
+        match log.term_at(idx) {
+            Some(term) if term == entry.term() => continue,
+            Some(_) => {
+                if idx <= log.commit_idx() {
+                    return Err(LogError::new(
+                        format!("entry {} conflicts with a committed one", idx),
+                        DumbError
+                    ));
+                }
+                return Ok((Some(idx), i));
+            }
+            None => return Ok((None, i)),
+        }
+    }
+    Ok((None, entries.len()))
+}
+
+
+fn check_truncate(idx: u64, commit_idx: u64) -> Result<(), LogError> {
+    if idx == 0 || idx <= commit_idx {
+        return Err(LogError::new(
+            format!("cannot truncate from {}, committed up to {}", idx, commit_idx),
+            DumbError
+        ));
+    }
+    Ok(())
+}
+
+
+fn check_install(snapshot: &Snapshot, snapshot_idx: u64) -> Result<(), LogError> {
+    if snapshot.index() <= snapshot_idx {
+        return Err(LogError::new(
+            format!("snapshot at {} is older than the current one at {}", snapshot.index(), snapshot_idx),
+            DumbError
+        ));
+    }
+    Ok(())
+}
+
+
+fn check_compact(snapshot: &Snapshot, snapshot_idx: u64, commit_idx: u64) -> Result<(), LogError> {
+    if snapshot.index() <= snapshot_idx || snapshot.index() > commit_idx {
+        return Err(LogError::new(
+            format!("cannot compact up to {}, snapshot at {}, committed up to {}",
+                snapshot.index(), snapshot_idx, commit_idx),
+            DumbError
+        ));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(terms: &[u64]) -> Vec<LogEntry> {
+        terms.iter().map(|t| LogEntry::new(*t, vec![*t as u8])).collect()
+    }
+
+    fn snapshot(index: u64, term: u64) -> Snapshot {
+        Snapshot::new(index, term, Membership::Stable(vec![]), vec![index as u8])
+    }
+
+    fn terms<L: LogService>(log: &L) -> Vec<u64> {
+        log.entries_from(log.snapshot_idx() + 1, usize::MAX).iter().map(|e| e.term()).collect()
+    }
+
+    #[test]
+    fn append_and_conflict() {
+        let mut log = ReplicatedLog::new();
+        assert_eq!(log.last_idx(), 0);
+        assert!(log.matches(0, 0));
+
+        assert_eq!(log.append_entry(0, entries(&[1, 1, 2])).unwrap(), 3);
+        assert_eq!(log.client_new_entry(LogEntry::new(2, vec![])).unwrap(), 4);
+
+        // repeated and stale appends keep the log
+        assert_eq!(log.append_entry(1, entries(&[1])).unwrap(), 2);
+        assert_eq!(terms(&log), vec![1, 1, 2, 2]);
+
+        // a gap is refused
+        assert!(log.append_entry(5, entries(&[3])).is_err());
+
+        // conflict at 3 removes the suffix
+        log.commit(2);
+        assert_eq!(log.append_entry(2, entries(&[3])).unwrap(), 3);
+        assert_eq!(terms(&log), vec![1, 1, 3]);
+        assert_eq!(log.term_at(3), Some(3));
+        assert_eq!(log.get(4), None);
+
+        // committed entries stay
+        assert!(log.append_entry(0, entries(&[5])).is_err());
+        assert!(log.truncate(2).is_err());
+
+        log.commit(10);
+        assert_eq!(log.commit_idx(), 3);
+        log.commit(1);
+        assert_eq!(log.commit_idx(), 3);
+    }
+
+    #[test]
+    fn snapshot_compact_and_install() {
+        let mut log = ReplicatedLog::new();
+        log.append_entry(0, entries(&[1, 1, 2, 2, 3])).unwrap();
+
+        // only committed entries go into a snapshot
+        assert!(log.compact(snapshot(3, 2)).is_err());
+        log.commit(3);
+        log.compact(snapshot(3, 2)).unwrap();
+
+        assert_eq!(log.snapshot_idx(), 3);
+        assert_eq!(log.get(3), None);
+        assert_eq!(log.term_at(3), Some(2));
+        assert_eq!(log.term_at(2), None);
+        assert!(log.entries_from(2, 10).is_empty());
+        assert_eq!(terms(&log), vec![2, 3]);
+        assert_eq!(log.last(), (5, LogEntry::new(3, vec![3])));
+
+        // compacted entries match any leader and are skipped on append
+        assert!(log.matches(1, 9));
+        assert_eq!(log.append_entry(1, entries(&[1, 2, 2, 3, 3])).unwrap(), 6);
+        assert_eq!(log.last_idx(), 6);
+
+        // a snapshot agreeing with the log keeps what follows
+        log.install_snapshot(snapshot(4, 2)).unwrap();
+        assert_eq!(log.last_idx(), 6);
+        assert_eq!(log.commit_idx(), 4);
+
+        // otherwise the log is dropped
+        log.install_snapshot(snapshot(8, 4)).unwrap();
+        assert_eq!(log.last(), (8, LogEntry::new(4, vec![])));
+        assert_eq!(log.commit_idx(), 8);
+        assert_eq!(log.snapshot().unwrap().data(), &[8]);
+        assert!(log.install_snapshot(snapshot(7, 4)).is_err());
+
+        assert_eq!(log.client_new_entry(LogEntry::new(5, vec![])).unwrap(), 9);
+    }
+}
@@ -0,0 +1,730 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use prost::Message;
+
+use yulong::error::DumbError;
+use yulong_network::identity::Peer;
+
+use crate::error::LogError;
+use crate::membership::Membership;
+use crate::raft_message;
+
+use super::{EntryBuf, LogEntry, LogService, Snapshot};
+use super::{plan_append, check_truncate, check_compact, check_install};
+
+
+const HARD_STATE: &str = "hardstate";
+const SNAPSHOT: &str = "snapshot";
+
+// length and crc32 of a record, both little endian u32
+const FRAME_HEAD: usize = 8;
+
+
+struct Segment {
+    first_idx: u64,
+    path: PathBuf,
+    len: u64,
+}
+
+
+/// Durable log made of append-only segment files in one directory.
+///
+/// Each segment is named after the index of its first entry and holds
+/// length-prefixed, checksummed records; a new segment is started once the
+/// current one grows past segment_size. Every append is fsynced before it
+/// returns. term and voted_for, and the latest snapshot, are kept in
+/// separate files which are replaced atomically. Segments whose entries are
+/// all covered by the snapshot are removed.
+///
+/// On open, a torn record at the end of the last segment (a crash during
+/// append) is cut off, any other damage is reported as an error.
+pub struct WalLog {
+    dir: PathBuf,
+    segment_size: u64,
+
+    // log after the snapshot is cached
+    buf: EntryBuf,
+    snapshot: Option<Snapshot>,
+    commit_idx: u64,
+
+    term: u64,
+    voted_for: Option<Peer>,
+
+    // in index order, the last one takes appends
+    segments: Vec<Segment>,
+    active: File,
+}
+
+
+impl WalLog {
+
+    pub fn open<P: AsRef<Path>>(dir: P, segment_size: u64) -> Result<Self, LogError> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)
+            .map_err(|e| LogError::new(format!("Create {}", dir.display()), e))?;
+
+        let (term, voted_for) = Self::read_hard_state(&dir)?;
+        let snapshot = Self::read_snapshot(&dir)?;
+
+        let mut buf = match &snapshot {
+            Some(snapshot) => EntryBuf::new(snapshot.index(), snapshot.term()),
+            None => EntryBuf::new(0, 0),
+        };
+        let mut segments = Self::list_segments(&dir)?;
+
+        let mut stale = false;
+        let count = segments.len();
+        for (i, segment) in segments.iter_mut().enumerate() {
+            Self::recover_segment(segment, &mut buf, i + 1 == count, &mut stale)?;
+        }
+
+        let active = if stale {
+            // interrupted while installing a snapshot
+            warn!("WalLog::open drop entries not following the snapshot");
+            Self::reset_segments(&dir, &mut segments, buf.last_idx() + 1)?
+        }
+        else {
+            Self::remove_compacted(&dir, &mut segments, buf.base_idx)?;
+            match segments.last() {
+                Some(segment) => Self::open_append(&segment.path)?,
+                None => Self::reset_segments(&dir, &mut segments, buf.last_idx() + 1)?,
+            }
+        };
+
+        info!("WalLog::open entries up to {}, snapshot at {}, term {} from {}",
+            buf.last_idx(), buf.base_idx, term, dir.display());
+
+        Ok(Self {
+            dir,
+            segment_size,
+            // committed entries are in the snapshot, the rest is learnt
+            // again from the leader
+            commit_idx: buf.base_idx,
+            buf,
+            snapshot,
+            term,
+            voted_for,
+            segments,
+            active,
+        })
+    }
+
+
+    fn segment_path(dir: &Path, first_idx: u64) -> PathBuf {
+        dir.join(format!("wal-{:020}.log", first_idx))
+    }
+
+
+    fn list_segments(dir: &Path) -> Result<Vec<Segment>, LogError> {
+        let mut segments = Vec::new();
+
+        let read_dir = fs::read_dir(dir)
+            .map_err(|e| LogError::new(format!("Read {}", dir.display()), e))?;
+
+        for dir_entry in read_dir {
+            let path = dir_entry
+                .map_err(|e| LogError::new(format!("Read {}", dir.display()), e))?
+                .path();
+
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+
+            // left over by an interrupted rewrite, the original is intact
+            if name.ends_with(".tmp") {
+                warn!("WalLog::list_segments remove stale {}", name);
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+
+            let first_idx = name.strip_prefix("wal-")
+                .and_then(|n| n.strip_suffix(".log"))
+                .and_then(|n| n.parse::<u64>().ok());
+
+            if let Some(first_idx) = first_idx {
+                segments.push(Segment { first_idx, path, len: 0 });
+            }
+        }
+
+        segments.sort_by_key(|s| s.first_idx);
+        Ok(segments)
+    }
+
+
+    // read records of segment following the snapshot into log
+    // records after one that conflicts with the snapshot's last entry are stale
+    fn recover_segment(segment: &mut Segment, log: &mut EntryBuf, is_last: bool, stale: &mut bool)
+        -> Result<(), LogError>
+    {
+        let expected = log.last_idx() + 1;
+        if segment.first_idx > expected && !*stale {
+            return Err(LogError::new(
+                format!("{} should start at {}", segment.path.display(), expected),
+                DumbError
+            ));
+        }
+
+        let buf = fs::read(&segment.path)
+            .map_err(|e| LogError::new(format!("Read {}", segment.path.display()), e))?;
+
+        let mut offset = 0;
+        while offset < buf.len() {
+            let record = read_frame(&buf[offset..])
+                .and_then(|(payload, len)| {
+                    raft_message::WalRecord::decode(payload).ok().map(|r| (r, len))
+                });
+
+            match record {
+                Some((record, len)) if *stale || record.index <= log.base_idx => {
+                    let term = record.entry.map_or(0, |e| e.term);
+                    if record.index == log.base_idx && term != log.base_term {
+                        *stale = true;
+                    }
+                    offset += len;
+                }
+
+                Some((record, len)) if record.index == log.last_idx() + 1 => {
+                    log.push(record.entry.unwrap_or_default().into());
+                    offset += len;
+                }
+
+                Some((record, _)) => {
+                    return Err(LogError::new(
+                        format!("{} holds index {} out of order", segment.path.display(), record.index),
+                        DumbError
+                    ));
+                }
+
+                None if is_last => {
+                    // torn write, drop the tail
+                    warn!("WalLog::recover_segment cut {} bytes off {}",
+                        buf.len() - offset, segment.path.display());
+
+                    let file = OpenOptions::new().write(true).open(&segment.path)
+                        .map_err(|e| LogError::new(format!("Open {}", segment.path.display()), e))?;
+                    file.set_len(offset as u64)
+                        .and_then(|_| file.sync_all())
+                        .map_err(|e| LogError::new(format!("Truncate {}", segment.path.display()), e))?;
+                    break;
+                }
+
+                None => {
+                    return Err(LogError::new(
+                        format!("{} is corrupted at byte {}", segment.path.display(), offset),
+                        DumbError
+                    ));
+                }
+            }
+        }
+
+        segment.len = offset as u64;
+        Ok(())
+    }
+
+
+    fn read_hard_state(dir: &Path) -> Result<(u64, Option<Peer>), LogError> {
+        let path = dir.join(HARD_STATE);
+        if !path.exists() {
+            return Ok((0, None));
+        }
+
+        let buf = fs::read(&path)
+            .map_err(|e| LogError::new(format!("Read {}", path.display()), e))?;
+
+        // replaced atomically, so a bad one is real damage
+        let state = read_frame(&buf)
+            .and_then(|(payload, _)| raft_message::HardState::decode(payload).ok())
+            .ok_or_else(|| LogError::new(format!("{} is corrupted", path.display()), DumbError))?;
+
+        let voted_for = match state.voted_for.is_empty() {
+            true => None,
+            false => Some(Peer::try_from_id(&state.voted_for)
+                .map_err(|e| LogError::new("Bad voted_for in hard state", e))?),
+        };
+
+        Ok((state.term, voted_for))
+    }
+
+
+    fn read_snapshot(dir: &Path) -> Result<Option<Snapshot>, LogError> {
+        let path = dir.join(SNAPSHOT);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let buf = fs::read(&path)
+            .map_err(|e| LogError::new(format!("Read {}", path.display()), e))?;
+
+        let record = read_frame(&buf)
+            .and_then(|(payload, _)| raft_message::SnapshotRecord::decode(payload).ok())
+            .ok_or_else(|| LogError::new(format!("{} is corrupted", path.display()), DumbError))?;
+
+        let membership = Membership::from_snapshot(record.membership)
+            .map_err(|e| LogError::new(format!("Bad membership in {}", path.display()), e))?;
+
+        Ok(Some(Snapshot::new(record.index, record.term, membership, record.data)))
+    }
+
+
+    fn write_snapshot(&self, snapshot: &Snapshot) -> Result<(), LogError> {
+        let record = raft_message::SnapshotRecord {
+            index: snapshot.index(),
+            term: snapshot.term(),
+            data: snapshot.data().to_owned(),
+            membership: snapshot.membership().to_snapshot(),
+        };
+        replace_file(&self.dir, SNAPSHOT, &encode_frame(&encode_message(&record)))
+    }
+
+
+    fn create_segment(dir: &Path, first_idx: u64) -> Result<(Segment, File), LogError> {
+        let path = Self::segment_path(dir, first_idx);
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(&path)
+            .map_err(|e| LogError::new(format!("Create {}", path.display()), e))?;
+        sync_dir(dir)?;
+
+        Ok((Segment { first_idx, path, len: 0 }, file))
+    }
+
+
+    fn open_append(path: &Path) -> Result<File, LogError> {
+        OpenOptions::new().append(true).open(path)
+            .map_err(|e| LogError::new(format!("Open {}", path.display()), e))
+    }
+
+
+    // write entries starting at first_idx to disk
+    fn persist(&mut self, first_idx: u64, entries: &[LogEntry]) -> Result<(), LogError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        // safe unwrap, there is always an active segment
+        if self.segments.last().unwrap().len >= self.segment_size {
+            let (segment, file) = Self::create_segment(&self.dir, first_idx)?;
+            self.segments.push(segment);
+            self.active = file;
+        }
+
+        let buf = encode_records(first_idx, entries);
+
+        self.active.write_all(&buf)
+            .and_then(|_| self.active.sync_data())
+            .map_err(|e| LogError::new("Append to wal", e))?;
+
+        self.segments.last_mut().unwrap().len += buf.len() as u64;
+        Ok(())
+    }
+
+
+    // rewrite segment so that it ends before idx, compacted records are dropped
+    fn shorten_segment(&mut self, pos: usize, idx: u64) -> Result<(), LogError> {
+        let segment = &self.segments[pos];
+        let first_idx = segment.first_idx.max(self.buf.base_idx + 1);
+        let buf = encode_records(first_idx, self.buf.range(first_idx, idx));
+
+        let tmp = segment.path.with_extension("tmp");
+        let mut file = File::create(&tmp)
+            .map_err(|e| LogError::new(format!("Create {}", tmp.display()), e))?;
+        file.write_all(&buf)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| LogError::new(format!("Write {}", tmp.display()), e))?;
+
+        fs::rename(&tmp, &segment.path)
+            .map_err(|e| LogError::new(format!("Replace {}", segment.path.display()), e))?;
+        sync_dir(&self.dir)?;
+
+        self.segments[pos].len = buf.len() as u64;
+        Ok(())
+    }
+
+
+    // remove the oldest segments while the next one starts inside the snapshot
+    fn remove_compacted(dir: &Path, segments: &mut Vec<Segment>, snapshot_idx: u64)
+        -> Result<(), LogError>
+    {
+        while segments.len() > 1 && segments[1].first_idx <= snapshot_idx + 1 {
+            let segment = segments.remove(0);
+            fs::remove_file(&segment.path)
+                .map_err(|e| LogError::new(format!("Remove {}", segment.path.display()), e))?;
+        }
+        sync_dir(dir)
+    }
+
+
+    // replace every segment with an empty one starting at first_idx,
+    // return it for appending
+    fn reset_segments(dir: &Path, segments: &mut Vec<Segment>, first_idx: u64)
+        -> Result<File, LogError>
+    {
+        while let Some(segment) = segments.pop() {
+            fs::remove_file(&segment.path)
+                .map_err(|e| LogError::new(format!("Remove {}", segment.path.display()), e))?;
+        }
+        sync_dir(dir)?;
+
+        let (segment, file) = Self::create_segment(dir, first_idx)?;
+        segments.push(segment);
+        Ok(file)
+    }
+}
+
+
+impl LogService for WalLog {
+
+    fn client_new_entry(&mut self, entry: LogEntry) -> Result<u64, LogError> {
+        let idx = self.buf.last_idx() + 1;
+        self.persist(idx, std::slice::from_ref(&entry))?;
+        self.buf.push(entry);
+        Ok(idx)
+    }
+
+    fn append_entry(&mut self, prev_idx: u64, entries: Vec<LogEntry>) -> Result<u64, LogError> {
+        let last_new = prev_idx + entries.len() as u64;
+
+        let (conflict, skip) = plan_append(self, prev_idx, &entries)?;
+        if let Some(idx) = conflict {
+            self.truncate(idx)?;
+        }
+
+        let first_idx = self.buf.last_idx() + 1;
+        self.persist(first_idx, &entries[skip..])?;
+        self.buf.extend(entries.into_iter().skip(skip));
+
+        Ok(last_new)
+    }
+
+    fn truncate(&mut self, idx: u64) -> Result<(), LogError> {
+        check_truncate(idx, self.commit_idx)?;
+        if idx > self.buf.last_idx() {
+            return Ok(());
+        }
+
+        // drop whole segments from the newest one, so that a crash in between
+        // still leaves a contiguous prefix
+        while self.segments.last().map_or(false, |s| s.first_idx >= idx) {
+            let segment = self.segments.pop().unwrap();
+            fs::remove_file(&segment.path)
+                .map_err(|e| LogError::new(format!("Remove {}", segment.path.display()), e))?;
+        }
+        sync_dir(&self.dir)?;
+
+        match self.segments.len() {
+            0 => {
+                let (segment, file) = Self::create_segment(&self.dir, idx)?;
+                self.segments.push(segment);
+                self.active = file;
+            }
+            n => {
+                self.shorten_segment(n - 1, idx)?;
+                self.active = Self::open_append(&self.segments[n - 1].path)?;
+            }
+        }
+
+        self.buf.truncate(idx);
+        Ok(())
+    }
+
+    fn commit(&mut self, idx: u64) {
+        self.commit_idx = self.commit_idx.max(idx.min(self.buf.last_idx()));
+    }
+
+    fn commit_idx(&self) -> u64 {
+        self.commit_idx
+    }
+
+    fn last(&self) -> (u64, LogEntry) {
+        self.buf.last()
+    }
+
+    fn get(&self, idx: u64) -> Option<LogEntry> {
+        self.buf.get(idx)
+    }
+
+    fn term_at(&self, idx: u64) -> Option<u64> {
+        self.buf.term_at(idx)
+    }
+
+    fn entries_from(&self, idx: u64, max: usize) -> Vec<LogEntry> {
+        self.buf.slice(idx, max)
+    }
+
+    fn compact(&mut self, snapshot: Snapshot) -> Result<(), LogError> {
+        check_compact(&snapshot, self.buf.base_idx, self.commit_idx)?;
+
+        // snapshot first, so that a crash leaves the entries it covers
+        self.write_snapshot(&snapshot)?;
+        self.buf.compact(snapshot.index(), snapshot.term());
+        self.snapshot = Some(snapshot);
+
+        Self::remove_compacted(&self.dir, &mut self.segments, self.buf.base_idx)
+    }
+
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> Result<(), LogError> {
+        check_install(&snapshot, self.buf.base_idx)?;
+
+        // entries left behind by a crash are found stale on open
+        self.write_snapshot(&snapshot)?;
+        let kept = self.buf.install(snapshot.index(), snapshot.term());
+        self.commit_idx = self.commit_idx.max(snapshot.index());
+        self.snapshot = Some(snapshot);
+
+        if kept {
+            Self::remove_compacted(&self.dir, &mut self.segments, self.buf.base_idx)
+        }
+        else {
+            self.active = Self::reset_segments(&self.dir, &mut self.segments, self.buf.last_idx() + 1)?;
+            Ok(())
+        }
+    }
+
+    fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    fn save_state(&mut self, term: u64, voted_for: Option<&Peer>) -> Result<(), LogError> {
+        let state = raft_message::HardState {
+            term,
+            voted_for: voted_for.map_or(vec![], |p| p.get_id().to_vec()),
+        };
+        replace_file(&self.dir, HARD_STATE, &encode_frame(&encode_message(&state)))?;
+
+        self.term = term;
+        self.voted_for = voted_for.cloned();
+        Ok(())
+    }
+
+    fn load_state(&self) -> (u64, Option<Peer>) {
+        (self.term, self.voted_for.clone())
+    }
+}
+
+
+fn encode_records(first_idx: u64, entries: &[LogEntry]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let record = raft_message::WalRecord {
+            index: first_idx + i as u64,
+            entry: Some(entry.into()),
+        };
+        buf.extend(encode_frame(&encode_message(&record)));
+    }
+    buf
+}
+
+
+fn encode_message<M: Message>(msg: &M) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    // buf has enough capacity, safe unwrap
+    msg.encode(&mut buf).unwrap();
+    buf
+}
+
+
+fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEAD + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+
+// payload of the first frame in buf and the frame len, None if it is
+// incomplete or fails the checksum
+fn read_frame(buf: &[u8]) -> Option<(&[u8], usize)> {
+    if buf.len() < FRAME_HEAD {
+        return None;
+    }
+
+    let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+
+    let payload = buf.get(FRAME_HEAD..FRAME_HEAD + len)?;
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+    Some((payload, FRAME_HEAD + len))
+}
+
+
+// write dir/name through a temporary file, so it is either old or new
+fn replace_file(dir: &Path, name: &str, content: &[u8]) -> Result<(), LogError> {
+    let path = dir.join(name);
+    let tmp = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp)
+        .map_err(|e| LogError::new(format!("Create {}", tmp.display()), e))?;
+    file.write_all(content)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| LogError::new(format!("Write {}", tmp.display()), e))?;
+
+    fs::rename(&tmp, &path)
+        .map_err(|e| LogError::new(format!("Replace {}", path.display()), e))?;
+    sync_dir(dir)
+}
+
+
+// make renames and new files durable
+fn sync_dir(dir: &Path) -> Result<(), LogError> {
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .map_err(|e| LogError::new(format!("Sync {}", dir.display()), e))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("yulong-wal-{}-{}", name, rand::random::<u64>()))
+    }
+
+    fn snapshot(index: u64, term: u64) -> Snapshot {
+        let voters = vec![Peer::from_bytes(&[1]), Peer::from_bytes(&[2])];
+        Snapshot::new(index, term, Membership::Stable(voters), vec![index as u8])
+    }
+
+    fn entry(term: u64, byte: u8) -> LogEntry {
+        LogEntry::new(term, vec![byte; 40])
+    }
+
+    #[test]
+    fn wal_recovery() {
+        let dir = test_dir("recovery");
+        let voter = Peer::from_bytes(&[7]);
+
+        {
+            // small segments to force rolling
+            let mut log = WalLog::open(&dir, 100).unwrap();
+            for i in 0..10 {
+                log.client_new_entry(entry(1, i)).unwrap();
+            }
+            log.append_entry(10, vec![entry(2, 10), entry(2, 11)]).unwrap();
+            log.save_state(2, Some(&voter)).unwrap();
+
+            // conflict at 8 rewrites a segment and drops the newer ones
+            log.commit(5);
+            log.append_entry(7, vec![entry(3, 20)]).unwrap();
+            assert_eq!(log.last_idx(), 8);
+        }
+
+        let segments = WalLog::list_segments(&dir).unwrap();
+        assert!(segments.len() > 1);
+
+        let mut log = WalLog::open(&dir, 100).unwrap();
+        assert_eq!(log.last_idx(), 8);
+        assert_eq!(log.get(8), Some(entry(3, 20)));
+        assert_eq!(log.get(3), Some(entry(1, 2)));
+        assert_eq!(log.load_state(), (2, Some(voter.clone())));
+
+        // appends go on after recovery
+        assert_eq!(log.client_new_entry(entry(3, 21)).unwrap(), 9);
+        drop(log);
+
+        // a torn record at the tail is cut off
+        let last = WalLog::list_segments(&dir).unwrap().pop().unwrap();
+        let mut file = OpenOptions::new().append(true).open(&last.path).unwrap();
+        file.write_all(&[9, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let log = WalLog::open(&dir, 100).unwrap();
+        assert_eq!(log.last_idx(), 9);
+        assert_eq!(log.term_at(9), Some(3));
+        assert_eq!(log.load_state().0, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wal_damage() {
+        let dir = test_dir("damage");
+        {
+            let mut log = WalLog::open(&dir, 100).unwrap();
+            for i in 0..6 {
+                log.client_new_entry(entry(1, i)).unwrap();
+            }
+        }
+
+        // flip a byte in the first segment, which is not the last one
+        let first = WalLog::list_segments(&dir).unwrap().remove(0);
+        let mut buf = fs::read(&first.path).unwrap();
+        buf[FRAME_HEAD + 1] ^= 0xff;
+        fs::write(&first.path, buf).unwrap();
+
+        assert!(WalLog::open(&dir, 100).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wal_snapshot() {
+        let dir = test_dir("snapshot");
+        {
+            let mut log = WalLog::open(&dir, 100).unwrap();
+            for i in 0..10 {
+                log.client_new_entry(entry(1, i)).unwrap();
+            }
+            let before = WalLog::list_segments(&dir).unwrap().len();
+
+            log.commit(7);
+            log.compact(snapshot(7, 1)).unwrap();
+            assert!(WalLog::list_segments(&dir).unwrap().len() < before);
+
+            // rewriting a segment that starts inside the snapshot
+            log.append_entry(8, vec![entry(2, 20)]).unwrap();
+        }
+
+        let mut log = WalLog::open(&dir, 100).unwrap();
+        assert_eq!(log.snapshot(), Some(&snapshot(7, 1)));
+        assert_eq!(log.commit_idx(), 7);
+        assert_eq!(log.get(7), None);
+        assert_eq!(log.get(8), Some(entry(1, 7)));
+        assert_eq!(log.get(9), Some(entry(2, 20)));
+        assert_eq!(log.last_idx(), 9);
+
+        // conflicting snapshot replaces the whole log
+        log.install_snapshot(snapshot(12, 3)).unwrap();
+        assert_eq!(log.client_new_entry(entry(3, 13)).unwrap(), 13);
+        drop(log);
+
+        let log = WalLog::open(&dir, 100).unwrap();
+        assert_eq!(log.snapshot_idx(), 12);
+        assert_eq!(log.last_idx(), 13);
+        assert_eq!(log.term_at(12), Some(3));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wal_interrupted_install() {
+        let dir = test_dir("install");
+        {
+            let mut log = WalLog::open(&dir, 100).unwrap();
+            for i in 0..6 {
+                log.client_new_entry(entry(1, i)).unwrap();
+            }
+
+            // crash right after the snapshot is written
+            log.write_snapshot(&snapshot(4, 2)).unwrap();
+        }
+
+        // entry 4 conflicts with the snapshot, so 5 and 6 are stale
+        let mut log = WalLog::open(&dir, 100).unwrap();
+        assert_eq!(log.last_idx(), 4);
+        assert_eq!(log.term_at(4), Some(2));
+        assert_eq!(log.client_new_entry(entry(2, 5)).unwrap(), 5);
+        drop(log);
+
+        let log = WalLog::open(&dir, 100).unwrap();
+        assert_eq!(log.get(5), Some(entry(2, 5)));
+        assert_eq!(log.get(6), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+
+use prost::Message;
+
+use yulong::error::{SerializeError, DeserializeError, DumbError};
+use yulong::utils::AsBytes;
+use yulong_network::identity::Peer;
+
+use crate::raft_message;
+
+
+/// Voters of a Raft cluster.
+///
+/// Voters are changed through joint consensus: the cluster first moves to
+/// Joint(old, new), where elections and commits need a majority of both
+/// old and new, then to Stable(new). Each step is a log entry, and a node
+/// uses the latest one in its log whether it is committed or not.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Membership {
+    Stable(Vec<Peer>),
+    Joint(Vec<Peer>, Vec<Peer>),
+}
+
+
+impl Membership {
+
+    /// Every voter of the old and the new configuration.
+    pub fn members(&self) -> Vec<Peer> {
+        match self {
+            Membership::Stable(voters) => voters.to_owned(),
+            Membership::Joint(old, new) => {
+                let mut members = old.to_owned();
+                members.extend(new.iter().filter(|p| !old.contains(p)).cloned());
+                members
+            }
+        }
+    }
+
+
+    pub fn contains(&self, peer: &Peer) -> bool {
+        self.groups().iter().any(|group| group.contains(peer))
+    }
+
+
+    pub fn is_joint(&self) -> bool {
+        matches!(self, Membership::Joint(..))
+    }
+
+
+    // voter sets that must each reach a majority
+    pub(crate) fn groups(&self) -> Vec<Vec<Peer>> {
+        match self {
+            Membership::Stable(voters) => vec![voters.to_owned()],
+            Membership::Joint(old, new) => vec![old.to_owned(), new.to_owned()],
+        }
+    }
+
+
+    // first step of a change to voters, only from a stable configuration
+    pub(crate) fn enter(&self, voters: Vec<Peer>) -> Option<Membership> {
+        match self {
+            Membership::Stable(old) => Some(Membership::Joint(old.to_owned(), voters)),
+            Membership::Joint(..) => None,
+        }
+    }
+
+
+    // second step, the new configuration alone
+    pub(crate) fn leave(&self) -> Option<Membership> {
+        match self {
+            Membership::Joint(_, new) => Some(Membership::Stable(new.to_owned())),
+            Membership::Stable(_) => None,
+        }
+    }
+
+
+    // snapshots written before membership changes carry no voters
+    pub(crate) fn to_snapshot(&self) -> Option<raft_message::Membership> {
+        match self.members().is_empty() {
+            true => None,
+            false => Some(self.into()),
+        }
+    }
+
+
+    pub(crate) fn from_snapshot(m: Option<raft_message::Membership>) -> Result<Self, DeserializeError> {
+        m.map_or(Ok(Membership::Stable(vec![])), Membership::try_from)
+    }
+}
+
+
+/// Whether a voter is listed more than once, which would let it count twice
+/// toward a majority.
+pub(crate) fn has_duplicate(voters: &[Peer]) -> bool {
+    voters.iter().collect::<HashSet<_>>().len() != voters.len()
+}
+
+
+impl From<&Membership> for raft_message::Membership {
+    fn from(m: &Membership) -> Self {
+        let ids = |peers: &Vec<Peer>| peers.iter().map(|p| p.get_id().to_vec()).collect();
+
+        match m {
+            Membership::Stable(voters) => Self {
+                voters: ids(voters),
+                next_voters: vec![],
+                joint: false,
+            },
+            Membership::Joint(old, new) => Self {
+                voters: ids(old),
+                next_voters: ids(new),
+                joint: true,
+            },
+        }
+    }
+}
+
+
+impl TryFrom<raft_message::Membership> for Membership {
+    type Error = DeserializeError;
+
+    fn try_from(m: raft_message::Membership) -> Result<Self, Self::Error> {
+        let peers = |ids: Vec<Vec<u8>>| ids.iter()
+            .map(|id| Peer::try_from_id(id)
+                .map_err(|e| DeserializeError::new("Bad voter id in Membership", e)))
+            .collect::<Result<Vec<Peer>, DeserializeError>>();
+
+        let group = |ids: Vec<Vec<u8>>| {
+            let voters = peers(ids)?;
+            if voters.is_empty() {
+                return Err(DeserializeError::new("No voter in Membership", DumbError));
+            }
+            if has_duplicate(&voters) {
+                return Err(DeserializeError::new("Duplicate voter in Membership", DumbError));
+            }
+            Ok(voters)
+        };
+
+        match m.joint {
+            true => Ok(Membership::Joint(group(m.voters)?, group(m.next_voters)?)),
+            false => Ok(Membership::Stable(group(m.voters)?)),
+        }
+    }
+}
+
+
+impl AsBytes for Membership {
+
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        let proto_message = raft_message::Membership::from(self);
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        let m = raft_message::Membership::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize Membership", e))?;
+        Self::try_from(m)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn membership_steps() {
+        let peers: Vec<Peer> = (0..3).map(|_| Peer::from_random()).collect();
+        let stable = Membership::Stable(peers[0..2].to_vec());
+
+        let joint = stable.enter(peers[1..3].to_vec()).unwrap();
+        assert!(joint.is_joint());
+        assert_eq!(joint.members(), peers);
+        assert!(joint.enter(vec![]).is_none());
+        assert!(stable.leave().is_none());
+
+        let next = joint.leave().unwrap();
+        assert_eq!(next, Membership::Stable(peers[1..3].to_vec()));
+        assert!(!next.contains(&peers[0]));
+
+        for m in vec![stable, joint, next] {
+            assert_eq!(Membership::from_bytes(&m.into_bytes().unwrap()).unwrap(), m);
+        }
+
+        // an empty group or a voter listed twice is refused
+        let dup = Membership::Stable(vec![peers[0].clone(), peers[0].clone(), peers[1].clone()]);
+        assert!(Membership::from_bytes(&dup.into_bytes().unwrap()).is_err());
+        let empty = Membership::Joint(peers.clone(), vec![]);
+        assert!(Membership::from_bytes(&empty.into_bytes().unwrap()).is_err());
+
+        // unless it is a snapshot from before membership changes
+        let none = Membership::Stable(vec![]);
+        assert_eq!(Membership::from_snapshot(none.to_snapshot()).unwrap(), none);
+    }
+}
+
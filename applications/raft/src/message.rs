@@ -1,10 +1,15 @@
+use prost::Message;
+
 use yulong::utils::AsBytes;
-use yulong::error::{SerializeError, DeserializeError};
+use yulong::error::{SerializeError, DeserializeError, DumbError};
 use yulong_network::identity::Peer;
 
 use crate::log_store::LogEntry;
+use crate::membership::Membership;
+use crate::raft_message as proto;
+use crate::raft_message::raft_message::Msg;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct RaftMessage {
     seq: u32,
     sender: Peer,
@@ -12,36 +17,40 @@ pub struct RaftMessage {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum RaftMessageKind {
     RequestVote(RaftRequestVote),
     RequestVoteReply(RaftRequestVoteReply),
     AppendEntries(RaftAppendEntries),
     AppendEntriesReply(RaftAppendEntriesReply),
+    InstallSnapshot(RaftInstallSnapshot),
+    InstallSnapshotReply(RaftInstallSnapshotReply),
     ClientRequest(RaftClientRequest),
     ClientReply(RaftClientReply),
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct RaftRequestVote {
     term: u64,
     candidate_id: Peer,
     last_log_index: u64,
     last_log_term: u64,
+    pre_vote: bool,
 }
 
 
 impl RaftRequestVote {
 
     pub fn new(term: u64, candidate: Peer,
-        last_log_index: u64, last_log_term: u64) -> Self 
+        last_log_index: u64, last_log_term: u64, pre_vote: bool) -> Self 
     {
         Self {
             term,
             candidate_id: candidate.to_owned(),
             last_log_index,
-            last_log_term
+            last_log_term,
+            pre_vote
         }
     }
 
@@ -65,24 +74,31 @@ impl RaftRequestVote {
     pub fn last_log_term(&self) -> u64 {
         self.last_log_term
     }
+
+    /// Get a reference to the raft request vote's pre vote.
+    pub fn pre_vote(&self) -> bool {
+        self.pre_vote
+    }
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct RaftRequestVoteReply {
     ack: u32,
     term: u64,
     vote_granted: bool,
+    pre_vote: bool,
 }
 
 
 impl RaftRequestVoteReply {
 
-    pub fn new(ack: u32, term: u64, vote_granted: bool) -> Self {
+    pub fn new(ack: u32, term: u64, vote_granted: bool, pre_vote: bool) -> Self {
         Self {
             ack,
             term,
-            vote_granted
+            vote_granted,
+            pre_vote
         }
     }
 
@@ -101,10 +117,15 @@ impl RaftRequestVoteReply {
     pub fn vote_granted(&self) -> bool {
         self.vote_granted
     }
+
+    /// Get a reference to the raft request vote reply's pre vote.
+    pub fn pre_vote(&self) -> bool {
+        self.pre_vote
+    }
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct RaftAppendEntries {
     term: u64,
     leader_id: Peer,
@@ -170,7 +191,7 @@ impl RaftAppendEntries {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct RaftAppendEntriesReply {
     ack: u32,
     term: u64,
@@ -200,73 +221,389 @@ impl RaftAppendEntriesReply {
 }
 
 
-#[derive(Debug)]
+// one chunk of the leader's snapshot, data starts at offset of the snapshot
+#[derive(Debug, PartialEq)]
+pub struct RaftInstallSnapshot {
+    term: u64,
+    leader_id: Peer,
+
+    last_included_idx: u64,
+    last_included_term: u64,
+
+    offset: u64,
+    data: Vec<u8>,
+    done: bool,
+
+    membership: Membership,
+}
+
+
+impl RaftInstallSnapshot {
+
+    pub fn new(term: u64, leader_id: Peer, last_included_idx: u64,
+        last_included_term: u64, offset: u64, data: Vec<u8>, done: bool,
+        membership: Membership) -> Self
+    {
+        Self {
+            term, leader_id, last_included_idx, last_included_term,
+            offset, data, done, membership
+        }
+    }
+
+
+    /// Get a reference to the raft install snapshot's term.
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Get a reference to the raft install snapshot's leader id.
+    pub fn leader_id(&self) -> &Peer {
+        &self.leader_id
+    }
+
+    /// Get a reference to the raft install snapshot's last included idx.
+    pub fn last_included_idx(&self) -> u64 {
+        self.last_included_idx
+    }
+
+    /// Get a reference to the raft install snapshot's last included term.
+    pub fn last_included_term(&self) -> u64 {
+        self.last_included_term
+    }
+
+    /// Get a reference to the raft install snapshot's offset.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Get a reference to the raft install snapshot's data.
+    pub fn data(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+
+    /// Get a reference to the raft install snapshot's done.
+    pub fn done(&self) -> bool {
+        self.done
+    }
+
+    /// Get a reference to the raft install snapshot's membership.
+    pub fn membership(&self) -> &Membership {
+        &self.membership
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct RaftInstallSnapshotReply {
+    ack: u32,
+    term: u64,
+    success: bool,
+}
+
+
+impl RaftInstallSnapshotReply {
+    pub fn new(ack: u32, term: u64, success: bool) -> Self { Self { ack, term, success } }
+
+
+    /// Get a reference to the raft install snapshot reply's ack.
+    pub fn ack(&self) -> u32 {
+        self.ack
+    }
+
+    /// Get a reference to the raft install snapshot reply's term.
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Get a reference to the raft install snapshot reply's success.
+    pub fn success(&self) -> bool {
+        self.success
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct RaftClientRequest {
+    client_id: u64,
+    client_seq: u64,
     command: Vec<u8>,
+    read: bool,
 }
 
 
 impl AsBytes for RaftClientRequest {
     fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
-        todo!()
+        Ok(encode(&proto::ClientRequest::from(self)))
     }
 
     fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
-        todo!()
+        proto::ClientRequest::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize RaftClientRequest", e))
+            .map(Self::from)
+    }
+}
+
+
+impl From<&RaftClientRequest> for proto::ClientRequest {
+    fn from(m: &RaftClientRequest) -> Self {
+        Self {
+            command: m.command.to_owned(),
+            read: m.read,
+            client_id: m.client_id,
+            client_seq: m.client_seq,
+        }
+    }
+}
+
+
+impl From<proto::ClientRequest> for RaftClientRequest {
+    fn from(m: proto::ClientRequest) -> Self {
+        Self {
+            client_id: m.client_id,
+            client_seq: m.client_seq,
+            command: m.command,
+            read: m.read,
+        }
     }
 }
 
 
 impl RaftClientRequest {
-    pub fn new(command: Vec<u8>) -> Self { Self { command } }
+    /// client_seq numbers the writes of client_id, retries keep it.
+    pub fn new(client_id: u64, client_seq: u64, command: Vec<u8>) -> Self {
+        Self { client_id, client_seq, command, read: false }
+    }
 
+    /// A read leaves the state machine unchanged and is not logged.
+    pub fn read(command: Vec<u8>) -> Self {
+        Self { client_id: 0, client_seq: 0, command, read: true }
+    }
 
 
     /// Get a reference to the client request's command.
     pub fn command(&self) -> &[u8] {
         self.command.as_ref()
     }
+
+    pub fn is_read(&self) -> bool {
+        self.read
+    }
+
+    /// Get a reference to the client request's client id.
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    /// Get a reference to the client request's client seq.
+    pub fn client_seq(&self) -> u64 {
+        self.client_seq
+    }
 }
 
 
-#[derive(Debug)]
+// answer to the client request with seq ack, response is None if
+// the request is refused and should go to leader_id instead
+#[derive(Debug, PartialEq)]
 pub struct RaftClientReply {
+    ack: u32,
     leader_id: Peer,
+    response: Option<Vec<u8>>,
 }
 
 
 impl AsBytes for RaftClientReply {
     fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
-        todo!()
+        Ok(encode(&proto::ClientReply::from(self)))
     }
 
     fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
-        todo!()
+        let m = proto::ClientReply::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize RaftClientReply", e))?;
+        Self::try_from(m)
+    }
+}
+
+
+impl From<&RaftClientReply> for proto::ClientReply {
+    fn from(m: &RaftClientReply) -> Self {
+        Self {
+            ack: m.ack,
+            leader_id: m.leader_id.get_id().to_vec(),
+            applied: m.response.is_some(),
+            response: m.response.to_owned().unwrap_or_default(),
+        }
+    }
+}
+
+
+impl TryFrom<proto::ClientReply> for RaftClientReply {
+    type Error = DeserializeError;
+
+    fn try_from(m: proto::ClientReply) -> Result<Self, Self::Error> {
+        let response = match m.applied {
+            true => Some(m.response),
+            false => None,
+        };
+        Ok(Self::new(m.ack, peer_of(&m.leader_id)?, response))
     }
 }
 
 
 impl RaftClientReply {
-    pub fn new(leader_id: Peer) -> Self { Self { leader_id } }
+    pub fn new(ack: u32, leader_id: Peer, response: Option<Vec<u8>>) -> Self {
+        Self { ack, leader_id, response }
+    }
 
 
+    /// Get a reference to the raft client reply's ack.
+    pub fn ack(&self) -> u32 {
+        self.ack
+    }
 
     /// Get a reference to the raft client reply's leader id.
     pub fn leader_id(&self) -> &Peer {
         &self.leader_id
     }
+
+    /// Get a reference to the raft client reply's response.
+    pub fn response(&self) -> Option<&[u8]> {
+        self.response.as_deref()
+    }
 }
 
 impl AsBytes for RaftMessage {
 
 
     fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
-        todo!()
+        let msg = match &self.msg {
+            RaftMessageKind::RequestVote(m) => Msg::Req(proto::RequestVote {
+                term: m.term,
+                candidate_id: m.candidate_id.get_id().to_vec(),
+                last_log_index: m.last_log_index,
+                last_log_term: m.last_log_term,
+                pre_vote: m.pre_vote,
+            }),
+
+            RaftMessageKind::RequestVoteReply(m) => Msg::Reply(proto::RequestVoteReply {
+                ack: m.ack,
+                term: m.term,
+                vote_granted: m.vote_granted,
+                pre_vote: m.pre_vote,
+            }),
+
+            RaftMessageKind::AppendEntries(m) => Msg::App(proto::AppendEntries {
+                term: m.term,
+                leader_id: m.leader_id.get_id().to_vec(),
+                prev_log_idx: m.prev_log_idx,
+                prev_log_term: m.prev_log_term,
+                entries: m.entries.iter().map(|e| e.into()).collect(),
+                leader_commit: m.leader_commit,
+            }),
+
+            RaftMessageKind::AppendEntriesReply(m) => Msg::AppReply(proto::AppendEntriesReply {
+                ack: m.ack,
+                term: m.term,
+                success: m.success,
+            }),
+
+            RaftMessageKind::InstallSnapshot(m) => Msg::Snap(proto::InstallSnapshot {
+                term: m.term,
+                leader_id: m.leader_id.get_id().to_vec(),
+                last_included_idx: m.last_included_idx,
+                last_included_term: m.last_included_term,
+                offset: m.offset,
+                data: m.data.to_owned(),
+                done: m.done,
+                membership: m.membership.to_snapshot(),
+            }),
+
+            RaftMessageKind::InstallSnapshotReply(m) => Msg::SnapReply(proto::InstallSnapshotReply {
+                ack: m.ack,
+                term: m.term,
+                success: m.success,
+            }),
+
+            RaftMessageKind::ClientRequest(m) => Msg::ClientReq(m.into()),
+
+            RaftMessageKind::ClientReply(m) => Msg::ClientReply(m.into()),
+        };
+
+        let proto_message = proto::RaftMessage {
+            seq: self.seq,
+            sender: self.sender.get_id().to_vec(),
+            msg: Some(msg),
+        };
+
+        Ok(encode(&proto_message))
     }
 
 
     fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
-        todo!()
+        let m = proto::RaftMessage::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize RaftMessage", e))?;
+
+        let msg = match m.msg {
+            Some(Msg::Req(r)) => RaftMessageKind::RequestVote(RaftRequestVote::new(
+                r.term,
+                peer_of(&r.candidate_id)?,
+                r.last_log_index,
+                r.last_log_term,
+                r.pre_vote
+            )),
+
+            Some(Msg::Reply(r)) => RaftMessageKind::RequestVoteReply(RaftRequestVoteReply::new(
+                r.ack,
+                r.term,
+                r.vote_granted,
+                r.pre_vote
+            )),
+
+            Some(Msg::App(a)) => RaftMessageKind::AppendEntries(RaftAppendEntries::new(
+                a.term,
+                peer_of(&a.leader_id)?,
+                a.prev_log_idx,
+                a.prev_log_term,
+                a.entries.into_iter().map(LogEntry::from).collect(),
+                a.leader_commit
+            )),
+
+            Some(Msg::AppReply(a)) => RaftMessageKind::AppendEntriesReply(RaftAppendEntriesReply::new(
+                a.ack,
+                a.term,
+                a.success
+            )),
+
+            Some(Msg::Snap(i)) => RaftMessageKind::InstallSnapshot(RaftInstallSnapshot::new(
+                i.term,
+                peer_of(&i.leader_id)?,
+                i.last_included_idx,
+                i.last_included_term,
+                i.offset,
+                i.data,
+                i.done,
+                Membership::from_snapshot(i.membership)?
+            )),
+
+            Some(Msg::SnapReply(i)) => RaftMessageKind::InstallSnapshotReply(RaftInstallSnapshotReply::new(
+                i.ack,
+                i.term,
+                i.success
+            )),
+
+            Some(Msg::ClientReq(c)) => RaftMessageKind::ClientRequest(c.into()),
+
+            Some(Msg::ClientReply(c)) => RaftMessageKind::ClientReply(c.try_into()?),
+
+            None => {
+                return Err(DeserializeError::new("RaftMessage carries no msg", DumbError));
+            }
+        };
+
+        Ok(Self {
+            seq: m.seq,
+            sender: peer_of(&m.sender)?,
+            msg,
+        })
     }
 
 }
@@ -294,15 +631,17 @@ impl RaftMessage {
     }
 
 
-    // for now this option is meaningless, for future compatibility only
+    // client messages are not bound to a term
     pub fn term(&self) -> Option<u64> {
         match &self.msg {
             RaftMessageKind::RequestVote(m) => Some(m.term()),
             RaftMessageKind::RequestVoteReply(m) => Some(m.term()),
             RaftMessageKind::AppendEntries(m) => Some(m.term()),
             RaftMessageKind::AppendEntriesReply(m) => Some(m.term()),
-            RaftMessageKind::ClientRequest(_) => todo!(),
-            RaftMessageKind::ClientReply(_) => todo!(),
+            RaftMessageKind::InstallSnapshot(m) => Some(m.term()),
+            RaftMessageKind::InstallSnapshotReply(m) => Some(m.term()),
+            RaftMessageKind::ClientRequest(_) => None,
+            RaftMessageKind::ClientReply(_) => None,
         }
     }
 
@@ -310,4 +649,124 @@ impl RaftMessage {
     pub fn sender(&self) -> &Peer {
         &self.sender
     }
-}
\ No newline at end of file
+}
+
+
+fn encode<M: Message>(msg: &M) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    // buf has enough capacity, safe unwrap
+    msg.encode(&mut buf).unwrap();
+    buf
+}
+
+
+fn peer_of(id: &[u8]) -> Result<Peer, DeserializeError> {
+    Peer::try_from_id(id).map_err(|e| DeserializeError::new("Bad peer id in RaftMessage", e))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use proptest::collection::vec;
+
+    fn peer() -> impl Strategy<Value = Peer> {
+        any::<[u8; Peer::ID_SIZE]>().prop_map(|id| Peer::try_from_id(&id).unwrap())
+    }
+
+    fn bytes() -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..64)
+    }
+
+    fn membership() -> impl Strategy<Value = Membership> {
+        prop_oneof![
+            vec(peer(), 1..5).prop_map(Membership::Stable),
+            (vec(peer(), 1..5), vec(peer(), 1..5)).prop_map(|(o, n)| Membership::Joint(o, n)),
+        ]
+    }
+
+    fn entry() -> impl Strategy<Value = LogEntry> {
+        prop_oneof![
+            (any::<u64>(), bytes(), any::<u64>(), any::<u64>()).prop_map(|(term, command, id, seq)| {
+                LogEntry::request(term, command, id, seq)
+            }),
+            (any::<u64>(), membership()).prop_map(|(term, m)| LogEntry::membership(term, &m)),
+            any::<u64>().prop_map(LogEntry::noop),
+        ]
+    }
+
+    fn kind() -> impl Strategy<Value = RaftMessageKind> {
+        prop_oneof![
+            (any::<u64>(), peer(), any::<u64>(), any::<u64>(), any::<bool>()).prop_map(|(t, p, i, lt, pv)| {
+                RaftMessageKind::RequestVote(RaftRequestVote::new(t, p, i, lt, pv))
+            }),
+            (any::<u32>(), any::<u64>(), any::<bool>(), any::<bool>()).prop_map(|(a, t, g, pv)| {
+                RaftMessageKind::RequestVoteReply(RaftRequestVoteReply::new(a, t, g, pv))
+            }),
+            (any::<u64>(), peer(), any::<u64>(), any::<u64>(), vec(entry(), 0..8), any::<u64>())
+                .prop_map(|(t, p, pi, pt, e, c)| {
+                    RaftMessageKind::AppendEntries(RaftAppendEntries::new(t, p, pi, pt, e, c))
+                }),
+            (any::<u32>(), any::<u64>(), any::<bool>()).prop_map(|(a, t, s)| {
+                RaftMessageKind::AppendEntriesReply(RaftAppendEntriesReply::new(a, t, s))
+            }),
+            (any::<u64>(), peer(), any::<u64>(), any::<u64>(), any::<u64>(), bytes(), any::<bool>(),
+                membership())
+                .prop_map(|(t, p, i, it, o, d, done, m)| {
+                    RaftMessageKind::InstallSnapshot(RaftInstallSnapshot::new(t, p, i, it, o, d, done, m))
+                }),
+            (any::<u32>(), any::<u64>(), any::<bool>()).prop_map(|(a, t, s)| {
+                RaftMessageKind::InstallSnapshotReply(RaftInstallSnapshotReply::new(a, t, s))
+            }),
+            (any::<u64>(), any::<u64>(), bytes()).prop_map(|(id, s, c)| {
+                RaftMessageKind::ClientRequest(RaftClientRequest::new(id, s, c))
+            }),
+            bytes().prop_map(|c| RaftMessageKind::ClientRequest(RaftClientRequest::read(c))),
+            (any::<u32>(), peer(), proptest::option::of(bytes())).prop_map(|(a, p, r)| {
+                RaftMessageKind::ClientReply(RaftClientReply::new(a, p, r))
+            }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn message_roundtrip(msg in kind(), seq in any::<u32>(), sender in peer()) {
+            let raft_msg = RaftMessage::new(msg, seq, &sender);
+            let buf = raft_msg.into_bytes().unwrap();
+            prop_assert_eq!(RaftMessage::from_bytes(&buf).unwrap(), raft_msg);
+        }
+
+        #[test]
+        fn client_roundtrip(command in bytes(), ack in any::<u32>(), leader in peer(),
+            response in proptest::option::of(bytes()), client_id in any::<u64>())
+        {
+            let request = RaftClientRequest::new(client_id, 1, command);
+            let buf = request.into_bytes().unwrap();
+            prop_assert_eq!(RaftClientRequest::from_bytes(&buf).unwrap(), request);
+
+            // an empty response still tells applied from refused
+            let reply = RaftClientReply::new(ack, leader, response);
+            let buf = reply.into_bytes().unwrap();
+            prop_assert_eq!(RaftClientReply::from_bytes(&buf).unwrap(), reply);
+        }
+
+        #[test]
+        fn garbage_does_not_panic(buf in vec(any::<u8>(), 0..256)) {
+            let _ = RaftMessage::from_bytes(&buf);
+        }
+    }
+
+    #[test]
+    fn client_term() {
+        let sender = Peer::from_random();
+        let request = RaftMessage::new(
+            RaftMessageKind::ClientRequest(RaftClientRequest::new(1, 1, vec![1])), 1, &sender
+        );
+        assert_eq!(request.term(), None);
+
+        // an envelope without msg is refused
+        let empty = proto::RaftMessage { seq: 1, sender: sender.get_id().to_vec(), msg: None };
+        assert!(RaftMessage::from_bytes(&encode(&empty)).is_err());
+    }
+}
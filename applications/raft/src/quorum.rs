@@ -5,7 +5,11 @@ pub struct VoteBox {
     voter_n: usize,
     quorum_size: usize,
     vote_pos: HashSet<Peer>,
-    vote_neg: HashSet<Peer>
+    vote_neg: HashSet<Peer>,
+
+    // if set, votes are counted per group and each needs its own majority,
+    // instead of quorum_size votes from anyone
+    groups: Vec<HashSet<Peer>>,
 }
 
 #[derive(PartialEq)]
@@ -40,6 +44,7 @@ impl QuorumCollector for VoteBox {
             quorum_size: quorum,
             vote_pos: HashSet::new(),
             vote_neg: HashSet::new(),
+            groups: Vec::new(),
         }
     }
 
@@ -58,6 +63,10 @@ impl QuorumCollector for VoteBox {
     }
 
     fn result(&self) -> VoteResult {
+        if !self.groups.is_empty() {
+            return self.group_result();
+        }
+
         if self.vote_pos.len() >= self.quorum_size {
             return VoteResult::PASS;
         }
@@ -78,6 +87,62 @@ impl QuorumCollector for VoteBox {
 }
 
 
+impl VoteBox {
+
+    /// Count votes of each voter group separately, as in a joint
+    /// configuration. Votes already cast are kept.
+    pub fn set_groups(&mut self, groups: &[Vec<Peer>]) {
+        self.groups = groups.iter().map(|g| g.iter().cloned().collect()).collect();
+        self.voter_n = self.groups.iter().flatten().collect::<HashSet<_>>().len();
+    }
+
+
+    // pass once every group has a majority, fail once some group cannot
+    fn group_result(&self) -> VoteResult {
+        let count = |votes: &HashSet<Peer>, group: &HashSet<Peer>| {
+            votes.intersection(group).count()
+        };
+
+        if self.groups.iter().all(|g| count(&self.vote_pos, g) >= majority(g.len())) {
+            return VoteResult::PASS;
+        }
+        if self.groups.iter().any(|g| count(&self.vote_neg, g) > g.len().saturating_sub(majority(g.len()))) {
+            return VoteResult::FAIL;
+        }
+        VoteResult::PENDING
+    }
+}
+
+
+fn majority(n: usize) -> usize {
+    n / 2 + 1
+}
+
+
+/// Highest index stored on a majority of every group, given the index each
+/// voter has matched.
+pub fn quorum_idx<F: Fn(&Peer) -> u64>(groups: &[Vec<Peer>], matched: F) -> u64 {
+    quorum_value(groups, |p| Some(matched(p))).unwrap_or(0)
+}
+
+
+/// Highest value reached by a majority of every group, voters without a
+/// value count as the lowest.
+pub fn quorum_value<T: Ord + Copy, F: Fn(&Peer) -> Option<T>>(groups: &[Vec<Peer>], value: F)
+    -> Option<T>
+{
+    groups.iter()
+        .map(|group| {
+            let voters: HashSet<&Peer> = group.iter().collect();
+            let mut values: Vec<Option<T>> = voters.iter().map(|p| value(p)).collect();
+            values.sort_unstable_by(|a, b| b.cmp(a));
+            values.get(majority(voters.len()) - 1).copied().flatten()
+        })
+        .min()
+        .flatten()
+}
+
+
 pub struct VoteBoxes {
     voter_n: usize,
     quorum_size: usize,
@@ -147,4 +212,54 @@ impl VoteBoxes {
         }
     }
 
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn joint_quorum() {
+        let peers: Vec<Peer> = (0..5).map(|_| Peer::from_random()).collect();
+        let old = peers[0..3].to_vec();
+        let new = peers[2..5].to_vec();
+
+        let mut vote_box = VoteBox::new(3, 2);
+        vote_box.set_groups(&[old.clone(), new.clone()]);
+
+        // a majority of all voters is not enough without one of new
+        vote_box.vote(&peers[0], true);
+        vote_box.vote(&peers[1], true);
+        vote_box.vote(&peers[2], true);
+        assert!(vote_box.result() == VoteResult::PENDING);
+        vote_box.vote(&peers[3], true);
+        assert!(vote_box.result() == VoteResult::PASS);
+
+        vote_box.reset();
+        vote_box.vote(&peers[3], false);
+        vote_box.vote(&peers[4], false);
+        assert!(vote_box.result() == VoteResult::FAIL);
+
+        // old has 3 and 2 matched, new only 1
+        let matched = |p: &Peer| match peers.iter().position(|q| q == p).unwrap() {
+            0 => 5,
+            1 => 3,
+            2 => 1,
+            _ => 0,
+        };
+        assert_eq!(quorum_idx(&[old.clone()], matched), 3);
+        assert_eq!(quorum_idx(&[old.clone(), new], matched), 0);
+
+        // a voter listed twice still counts once
+        let twice = vec![peers[0].clone(), peers[0].clone(), peers[2].clone()];
+        assert_eq!(quorum_idx(&[twice], matched), 1);
+
+        // an empty group never passes nor fails
+        let mut vote_box = VoteBox::new(3, 2);
+        vote_box.set_groups(&[old, vec![]]);
+        vote_box.vote(&peers[0], false);
+        assert!(vote_box.result() == VoteResult::PENDING);
+    }
+}
+
@@ -1,11 +1,14 @@
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use log::debug;
 use log::warn;
 
 use yulong_network::identity::{Peer, Me};
 
+use yulong::error::{DeserializeError, DumbError};
 use yulong::utils::AsBytes;
 use yulong::utils::CasualTimer;
+use yulong::utils::{clock, runtime};
 
 use yulong_bdn::overlay::BDN;
 use yulong_bdn::message::OverlayMessage;
@@ -14,40 +17,55 @@ use yulong_bdn::route::AppLayerRouteUser;
 use yulong_network::transport::Transport;
 use yulong_bdn::route_inner::RelayCtl;
 
+use crate::log_store::EntryKind;
 use crate::log_store::LogEntry;
 use crate::log_store::LogService;
 use crate::log_store::ReplicatedLog;
+use crate::log_store::Snapshot;
+use crate::log_store::WalLog;
+use crate::error::LogError;
+use crate::error::MembershipError;
+use crate::membership::{Membership, has_duplicate};
 
 use crate::message::RaftClientReply;
 use crate::message::RaftClientRequest;
 use crate::message::{
     RaftAppendEntries,
     RaftAppendEntriesReply,
+    RaftInstallSnapshot,
+    RaftInstallSnapshotReply,
     RaftRequestVote,
     RaftRequestVoteReply
 };
 
 use crate::quorum::QuorumCollector;
 use crate::quorum::VoteBox;
+use crate::quorum::{quorum_idx, quorum_value};
+use crate::session::Sessions;
 
 use crate::message::{RaftMessage, RaftMessageKind};
 use crate::quorum::VoteResult;
-use crate::raft_timer::RaftTimer;
-use crate::raft_timer::WaitStateData::ApplyEntries;
+use crate::raft_timer::{RaftTimer, WaitState};
+use crate::config::RaftConfig;
+use crate::raft_timer::WaitStateData::{ApplyEntries, InstallSnapshot};
+use crate::state_machine::StateMachine;
 
 
 #[derive(Debug, PartialEq)]
 enum NodeState {
     Follower,
     Leader,
+    // asking for pre-votes, term unchanged
+    PreCandidate,
     Candidate,
 }
 
 
+// term and voted_for are cached here, log keeps the durable copy
 struct PersistentState {
     term: u64,
     voted_for: Option<Peer>,
-    log: ReplicatedLog,
+    log: Box<dyn LogService>,
 }
 
 
@@ -63,6 +81,34 @@ struct VolatileStateServer {
 }
 
 
+// snapshot being received from the leader chunk by chunk
+struct IncomingSnapshot {
+    index: u64,
+    term: u64,
+    membership: Membership,
+    data: Vec<u8>,
+}
+
+
+// client waiting for the entry it submitted to be applied
+struct PendingRequest {
+    term: u64,
+    client: Peer,
+    seq: u32,
+}
+
+
+// read served once leadership is confirmed after it arrived and
+// read_idx is applied
+struct PendingRead {
+    read_idx: u64,
+    since: Instant,
+    client: Peer,
+    seq: u32,
+    command: Vec<u8>,
+}
+
+
 pub struct RaftContext<T: Transport, R: RelayCtl> {
 
     state: NodeState,
@@ -71,6 +117,25 @@ pub struct RaftContext<T: Transport, R: RelayCtl> {
     vs: VolatileState,
     vss: VolatileStateServer,
 
+    state_machine: Box<dyn StateMachine>,
+    sessions: Sessions,
+
+    // log index -> client, leader only
+    pending: HashMap<u64, PendingRequest>,
+
+    // leader only
+    reads: Vec<PendingRead>,
+
+    // send time of the latest request each peer answered in this term,
+    // leader only
+    acked: HashMap<Peer, Instant>,
+
+    // index of the noop entry the leader started its term with
+    term_start_idx: u64,
+
+    incoming: Option<IncomingSnapshot>,
+
+    // other members of the current configuration
     peers: Vec<Peer>,
     leader: Option<Peer>,
 
@@ -80,11 +145,223 @@ pub struct RaftContext<T: Transport, R: RelayCtl> {
 
     timer: RaftTimer,
     election: VoteBox,
+
+    // latest configuration in the log, committed or not, and its index
+    membership: Membership,
+    membership_idx: u64,
+
+    // configuration given to new, in use until the log holds another
+    boot_membership: Membership,
+
+    config: RaftConfig,
 }
 
 
 impl<T: Transport, R: RelayCtl> RaftContext<T, R> {
 
+    /// The replica runs as the identity of network_handle, peers are the
+    /// other members of the cluster. They are the voters until a membership
+    /// change is logged.
+    /// Committed entries are applied to state_machine in log order.
+    ///
+    /// With config.log_dir set, the log, term and vote are recovered from
+    /// the write-ahead log there, and state_machine is restored from its
+    /// snapshot.
+    pub fn new(
+        network_handle: BDN<T, R>,
+        peers: Vec<Peer>,
+        state_machine: Box<dyn StateMachine>,
+        config: RaftConfig
+    ) -> Result<Self, LogError>
+    {
+        let local_id = network_handle.local_identity.clone();
+
+        // self included, each voter once
+        let mut voters: Vec<Peer> = Vec::new();
+        for peer in peers.iter().chain(std::iter::once(local_id.peer())) {
+            if !voters.contains(peer) {
+                voters.push(peer.to_owned());
+            }
+        }
+        let peers: Vec<Peer> = voters.iter().filter(|p| *p != local_id.peer()).cloned().collect();
+        let voter = voters.len();
+
+        // a follower waits for a leader before it runs for election
+        let mut timer = RaftTimer::new(&config);
+        timer.start_election_timer();
+
+        let log: Box<dyn LogService> = match &config.log_dir {
+            Some(dir) => Box::new(WalLog::open(dir, config.wal_segment_size)?),
+            None => Box::new(ReplicatedLog::new()),
+        };
+        let (term, voted_for) = log.load_state();
+
+        let mut state_machine = state_machine;
+        let sessions = match log.snapshot() {
+            Some(snapshot) => restore(state_machine.as_mut(), snapshot.data())
+                .map_err(|e| LogError::new("Restore snapshot", e))?,
+            None => Sessions::new(),
+        };
+        let applied = log.snapshot_idx();
+
+        let mut raft = Self {
+            state: NodeState::Follower,
+            ps: PersistentState {
+                term,
+                voted_for,
+                log,
+            },
+            vs: VolatileState {
+                commit_idx: applied,
+                last_applied: applied,
+            },
+            vss: VolatileStateServer {
+                next_idx: HashMap::new(),
+                match_idx: HashMap::new(),
+            },
+            state_machine,
+            sessions,
+            pending: HashMap::new(),
+            reads: Vec::new(),
+            acked: HashMap::new(),
+            term_start_idx: 0,
+            incoming: None,
+            peers,
+            leader: None,
+            network_handle,
+            local_id,
+            seq: 0,
+            timer,
+            election: VoteBox::new(voter, voter / 2 + 1),
+            membership: Membership::Stable(voters.clone()),
+            membership_idx: 0,
+            boot_membership: Membership::Stable(voters),
+            config,
+        };
+
+        // the log may have moved on from the boot configuration
+        let (idx, membership) = raft.membership_at(raft.ps.log.last_idx());
+        raft.adopt_membership(idx, membership);
+
+        Ok(raft)
+    }
+
+
+    /// Move the cluster to voters through joint consensus, leader only.
+    /// Returns the index of the joint configuration entry, the leader
+    /// appends the final one when it is committed. A change must finish
+    /// before the next one starts.
+    pub fn change_membership(&mut self, voters: Vec<Peer>) -> Result<u64, MembershipError> {
+        if self.state != NodeState::Leader {
+            return Err(MembershipError::new("Not the leader", DumbError));
+        }
+        if voters.is_empty() {
+            return Err(MembershipError::new("No voter in the new configuration", DumbError));
+        }
+        if has_duplicate(&voters) {
+            return Err(MembershipError::new("A voter is listed twice", DumbError));
+        }
+
+        let joint = match self.membership.enter(voters) {
+            Some(joint) if self.membership_idx <= self.vs.commit_idx => joint,
+            _ => return Err(MembershipError::new("A membership change is in progress", DumbError)),
+        };
+
+        self.append_membership(joint)
+    }
+
+
+    // a configuration takes effect as soon as it is in the log
+    fn append_membership(&mut self, membership: Membership) -> Result<u64, MembershipError> {
+        let entry = LogEntry::membership(self.ps.term, &membership);
+        let idx = self.ps.log.client_new_entry(entry)
+            .map_err(|e| MembershipError::new("Cannot store membership entry", e))?;
+
+        debug!("RaftContext::append_membership {:?} at {}", membership, idx);
+        self.adopt_membership(idx, membership);
+
+        self.broadcast_append_entry();
+        self.advance_commit();
+        Ok(idx)
+    }
+
+
+    // latest configuration in the log up to idx, and its index
+    fn membership_at(&self, idx: u64) -> (u64, Membership) {
+        let snapshot_idx = self.ps.log.snapshot_idx();
+
+        for i in (snapshot_idx + 1..=idx).rev() {
+            match self.ps.log.get(i).and_then(|entry| entry.to_membership()) {
+                Some(Ok(membership)) => return (i, membership),
+                Some(Err(error)) => warn!("RaftContext::membership_at bad entry {}: {}", i, error),
+                None => {}
+            }
+        }
+
+        // snapshots written before membership changes carry no voters
+        match self.ps.log.snapshot() {
+            Some(snapshot) if !snapshot.membership().members().is_empty() => {
+                (snapshot_idx, snapshot.membership().to_owned())
+            }
+            _ => (0, self.boot_membership.clone()),
+        }
+    }
+
+
+    fn adopt_membership(&mut self, idx: u64, membership: Membership) {
+        let me = self.local_id.peer().to_owned();
+        self.peers = membership.members().into_iter().filter(|p| *p != me).collect();
+
+        // progress of staying peers is kept, new ones start optimistic
+        let next_idx = self.ps.log.last_idx() + 1;
+        let vss = &self.vss;
+        let (next, matched) = self.peers.iter()
+            .map(|p| (
+                (p.to_owned(), vss.next_idx.get(p).copied().unwrap_or(next_idx)),
+                (p.to_owned(), vss.match_idx.get(p).copied().unwrap_or(0))
+            ))
+            .unzip();
+        self.vss.next_idx = next;
+        self.vss.match_idx = matched;
+
+        self.election.set_groups(&membership.groups());
+        self.membership = membership;
+        self.membership_idx = idx;
+    }
+
+
+    // follower picks up configurations the leader sent, or falls back when
+    // the entry holding the current one was overwritten
+    fn refresh_membership(&mut self) {
+        let (idx, membership) = self.membership_at(self.ps.log.last_idx());
+        if idx != self.membership_idx || membership != self.membership {
+            debug!("RaftContext::refresh_membership {:?} at {}", membership, idx);
+            self.adopt_membership(idx, membership);
+        }
+    }
+
+
+    // once its configuration is committed, the leader moves a joint one
+    // on to the new voters, or steps down if they do not include it
+    fn finish_membership_change(&mut self) {
+        if self.state != NodeState::Leader || self.membership_idx > self.vs.commit_idx {
+            return;
+        }
+
+        if let Some(stable) = self.membership.leave() {
+            if let Err(error) = self.append_membership(stable) {
+                warn!("RaftContext::finish_membership_change: {}", error);
+            }
+        }
+        else if !self.membership.contains(self.local_id.peer()) {
+            debug!("RaftContext::finish_membership_change {} removed, step down", self.local_id.peer());
+            self.state = NodeState::Follower;
+            self.leader = None;
+            self.timer.start_election_timer();
+        }
+    }
+
+
     fn raft_msg_dispatch(&mut self, raft_msg: RaftMessage) {
 
         match raft_msg.msg() {
@@ -93,11 +370,35 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R> {
                 self.request_vote_cb(msg, raft_msg.seq(), raft_msg.sender());
             }
 
-            RaftMessageKind::RequestVoteReply(_) => todo!(),
-            RaftMessageKind::AppendEntries(_) => todo!(),
-            RaftMessageKind::AppendEntriesReply(_) => todo!(),
-            RaftMessageKind::ClientRequest(_) => todo!(),
-            RaftMessageKind::ClientReply(_) => todo!(),
+            RaftMessageKind::RequestVoteReply(msg) => {
+                self.request_vote_reply_cb(msg, raft_msg.sender());
+            }
+
+            RaftMessageKind::AppendEntries(msg) => {
+                self.append_entry_cb(raft_msg.sender(), msg, raft_msg.seq());
+            }
+
+            RaftMessageKind::AppendEntriesReply(msg) => {
+                self.append_entry_reply_cb(raft_msg.sender(), msg);
+            }
+
+            RaftMessageKind::InstallSnapshot(msg) => {
+                self.install_snapshot_cb(raft_msg.sender(), msg, raft_msg.seq());
+            }
+
+            RaftMessageKind::InstallSnapshotReply(msg) => {
+                self.install_snapshot_reply_cb(raft_msg.sender(), msg);
+            }
+
+            RaftMessageKind::ClientRequest(msg) => {
+                self.request_cb(raft_msg.sender(), msg, raft_msg.seq());
+            }
+
+            // replies are for clients
+            RaftMessageKind::ClientReply(_) => {
+                warn!("RaftContext::raft_msg_dispatch unexpected client reply from {}",
+                    raft_msg.sender());
+            }
         }
 
     }
@@ -105,28 +406,57 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R> {
 
     fn request_cb(&mut self, sender: &Peer, msg: &RaftClientRequest, seq: u32) {
         if self.state == NodeState::Leader {
-            // serve it
-            
+            if msg.is_read() {
+                self.read_cb(sender, msg, seq);
+                return;
+            }
+
+            // a retried write that was applied is answered right away
+            if let Some(response) = self.sessions.applied(msg.client_id(), msg.client_seq()) {
+                self.reply_client(sender, seq, response);
+                return;
+            }
+
             // append entry
-            let new_entry = LogEntry::new(self.ps.term, msg.command().to_owned());
-            self.ps.log.client_new_entry(new_entry.clone());
+            let new_entry = LogEntry::request(
+                self.ps.term,
+                msg.command().to_owned(),
+                msg.client_id(),
+                msg.client_seq()
+            );
+            let idx = match self.ps.log.client_new_entry(new_entry) {
+                Ok(idx) => idx,
+                Err(error) => {
+                    warn!("RaftContext::request_cb cannot store entry: {}", error);
+                    return;
+                }
+            };
+
+            // reply once applied
+            self.pending.insert(idx, PendingRequest {
+                term: self.ps.term,
+                client: sender.to_owned(),
+                seq,
+            });
         
             // send append_entry to all followers
-            // todo 
-            self.broadcast_append_entry(vec![new_entry]);
+            self.broadcast_append_entry();
+
+            // a single node cluster commits right away
+            self.advance_commit();
 
         }
         else {
             // info leader address
             if let Some(leader) = &self.leader {
-                let refuse_msg = RaftClientReply::new(leader.to_owned());
+                let refuse_msg = RaftClientReply::new(seq, leader.to_owned(), None);
                 let raft_msg = RaftMessage::new(
                     RaftMessageKind::ClientReply(refuse_msg),
                     self.seq(),
                     self.local_id.peer()
                 );
 
-                async_std::task::block_on(
+                runtime::block_on(
                     self.send_to_direct(raft_msg, sender)
                 );
             }
@@ -134,73 +464,309 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R> {
     }
 
 
-    fn apply_append_entry() {
-        todo!()
+    // reads skip the log, they wait for the commit index as of their
+    // arrival to be applied and for a heartbeat round to confirm this node
+    // still leads, so no newer leader has committed more meanwhile
+    fn read_cb(&mut self, sender: &Peer, msg: &RaftClientRequest, seq: u32) {
+        self.reads.push(PendingRead {
+            // entries of earlier terms count once the noop is committed
+            read_idx: self.vs.commit_idx.max(self.term_start_idx),
+            since: clock::now(),
+            client: sender.to_owned(),
+            seq,
+            command: msg.command().to_owned(),
+        });
+
+        if !self.in_lease() {
+            self.send_heartbeat();
+        }
+        self.serve_reads();
+    }
+
+
+    fn serve_reads(&mut self) {
+        if self.reads.is_empty() {
+            return;
+        }
+
+        if self.state != NodeState::Leader {
+            // clients retry with the new leader
+            self.reads.clear();
+            return;
+        }
+
+        let confirmed = self.quorum_ack();
+        let lease = self.in_lease();
+        let last_applied = self.vs.last_applied;
+
+        let (ready, waiting): (Vec<PendingRead>, Vec<PendingRead>) = self.reads.drain(..)
+            .partition(|read| {
+                (lease || confirmed >= Some(read.since)) && read.read_idx <= last_applied
+            });
+        self.reads = waiting;
+
+        for read in ready {
+            let response = self.state_machine.query(&read.command);
+            self.reply_client(&read.client, read.seq, response);
+        }
     }
 
 
-    fn send_append_entry() {
-        todo!()
+    // latest time a quorum is known to have followed this leader
+    fn quorum_ack(&self) -> Option<Instant> {
+        let me = self.local_id.peer().to_owned();
+        let now = clock::now();
+
+        quorum_value(&self.membership.groups(), |p| match *p == me {
+            true => Some(now),
+            false => self.acked.get(p).copied(),
+        })
     }
-    
 
-    fn broadcast_append_entry(&mut self, entries: Vec<LogEntry>) {
-        // todo
+
+    // no other leader can be elected until election_inv_low after a quorum
+    // answered, as its members ignore candidates meanwhile
+    fn in_lease(&self) -> bool {
+        let lease = Duration::from_millis(self.config.election_inv_low);
+        self.config.lease_read && self.quorum_ack().map_or(false, |t| clock::elapsed(t) < lease)
     }
 
 
-    // empty append entry
-    fn send_heartbeat(&mut self) {
+    // follower checks that its log matches the leader's up to prev_log_idx,
+    // stores the entries and follows leader commit
+    fn apply_append_entry(&mut self, msg: &RaftAppendEntries) -> bool {
 
-        let (last_idx, last_log) = self.ps.log.last();
+        if !self.ps.log.matches(msg.prev_log_idx(), msg.prev_log_term()) {
+            // todo: conflicting entry optimization
+            return false;
+        }
 
-        let heartbeat_msg = RaftMessage::new(
+        match self.ps.log.append_entry(msg.prev_log_idx(), msg.entries().to_owned()) {
+            Ok(last_new) => {
+                let changed = self.membership_idx > msg.prev_log_idx() ||
+                    msg.entries().iter().any(|e| e.kind() == EntryKind::Membership);
+                if changed {
+                    self.refresh_membership();
+                }
+
+                // only entries known to match the leader may be committed
+                self.ps.log.commit(msg.leader_commit().min(last_new));
+                self.vs.commit_idx = self.ps.log.commit_idx();
+                self.apply();
+                true
+            }
+
+            Err(error) => {
+                warn!("RaftContext::apply_append_entry: {}", error);
+                false
+            }
+        }
+    }
+
+
+    // leader sends entries from next_idx on to follower, an up to date
+    // follower gets an empty one as heartbeat
+    fn send_append_entry(&mut self, follower: &Peer) {
+
+        let next_idx = match self.vss.next_idx.get(follower) {
+            Some(idx) => *idx,
+            None => {
+                warn!("RaftContext::send_append_entry {} is not a follower", follower);
+                return;
+            }
+        };
+
+        if next_idx <= self.ps.log.snapshot_idx() {
+            // entries are compacted, catch up from the snapshot unless
+            // a transfer is going on
+            let sending = self.timer.find_wait_data(|w| {
+                matches!(w, InstallSnapshot(f, ..) if f == follower)
+            });
+            if sending.is_none() {
+                self.send_snapshot(follower, 0);
+            }
+            return;
+        }
+
+        let prev_log_idx = next_idx - 1;
+        let prev_log_term = match self.ps.log.term_at(prev_log_idx) {
+            Some(term) => term,
+            None => {
+                warn!("RaftContext::send_append_entry no entry {} for {}", prev_log_idx, follower);
+                return;
+            }
+        };
+
+        let entries = self.ps.log.entries_from(next_idx, self.config.append_batch);
+        let entries_len = entries.len() as u64;
+
+        let seq = self.seq();
+        let append_entry_msg = RaftMessage::new(
             RaftMessageKind::AppendEntries(RaftAppendEntries::new(
                 self.ps.term,
-                self.local_id.peer().to_owned(),    // only leader send heartbeat
-                last_idx,
-                last_log.term(),
-                vec![],
+                self.local_id.peer().to_owned(),
+                prev_log_idx,
+                prev_log_term,
+                entries,
                 self.vs.commit_idx
             )),
-            self.seq(),
+            seq,
             self.local_id.peer()
         );
 
-        async_std::task::block_on(
-            self.broadcast(heartbeat_msg)
+        // an unanswered request is dropped, the next heartbeat covers it
+        self.timer.insert_wait_data(seq, &WaitState::new(
+            ApplyEntries(follower.to_owned(), next_idx, entries_len),
+            self.config.election_inv_low
+        ));
+
+        runtime::block_on(
+            self.send_to_direct(append_entry_msg, follower)
         );
     }
     
 
-    // receive empty append entry, term is already checked
-    fn heartbeat_cb(&mut self, msg: RaftAppendEntries, seq: u32) {
-        let reply_msg = RaftMessage::new(
-            RaftMessageKind::AppendEntriesReply(RaftAppendEntriesReply::new(
-                seq,
+    // leader sends the chunk of its snapshot starting at offset
+    fn send_snapshot(&mut self, follower: &Peer, offset: u64) {
+
+        let chunk = self.config.snapshot_chunk as u64;
+        let (index, term, membership, data, done) = match self.ps.log.snapshot() {
+            Some(snapshot) if offset <= snapshot.data().len() as u64 => {
+                let len = snapshot.data().len() as u64;
+                let end = (offset + chunk).min(len);
+                let data = snapshot.data()[offset as usize..end as usize].to_vec();
+                (snapshot.index(), snapshot.term(), snapshot.membership().to_owned(), data, end == len)
+            }
+            _ => {
+                warn!("RaftContext::send_snapshot no snapshot at {} for {}", offset, follower);
+                return;
+            }
+        };
+
+        let seq = self.seq();
+        let chunk_len = data.len() as u64;
+        let install_msg = RaftMessage::new(
+            RaftMessageKind::InstallSnapshot(RaftInstallSnapshot::new(
                 self.ps.term,
-                true
+                self.local_id.peer().to_owned(),
+                index,
+                term,
+                offset,
+                data,
+                done,
+                membership
             )),
-            self.seq(),
+            seq,
             self.local_id.peer()
         );
+
+        self.timer.insert_wait_data(seq, &WaitState::new(
+            InstallSnapshot(follower.to_owned(), index, offset, chunk_len),
+            self.config.election_inv_low
+        ));
+
+        runtime::block_on(
+            self.send_to_direct(install_msg, follower)
+        );
     }
-    
 
-    // follower heartbeat timeout, or election split vote timeout, start an election
+
+    fn broadcast_append_entry(&mut self) {
+        for follower in self.peers.clone() {
+            self.send_append_entry(&follower);
+        }
+    }
+
+
+    // leader heartbeat also carries entries to lagging followers
+    fn send_heartbeat(&mut self) {
+        self.timer.expire_wait_data();
+        self.broadcast_append_entry();
+        self.timer.start_heartbeat();
+    }
+
+
+    fn become_leader(&mut self) {
+        self.state = NodeState::Leader;
+        self.leader = Some(self.local_id.peer().to_owned());
+        self.acked.clear();
+        self.reads.clear();
+
+        // start from optimistic next_idx, mismatches walk it back
+        let next_idx = self.ps.log.last_idx() + 1;
+        for idx in self.vss.next_idx.values_mut() {
+            *idx = next_idx;
+        }
+        for idx in self.vss.match_idx.values_mut() {
+            *idx = 0;
+        }
+
+        // committing an entry of its own term tells the leader which
+        // entries are committed, reads depend on it
+        match self.ps.log.client_new_entry(LogEntry::noop(self.ps.term)) {
+            Ok(idx) => self.term_start_idx = idx,
+            Err(error) => warn!("RaftContext::become_leader cannot store noop: {}", error),
+        }
+
+        // assert leadership at once
+        self.send_heartbeat();
+
+        // a single node cluster commits right away
+        self.advance_commit();
+    }
+
+
+    /// Commit the highest index stored on a quorum. Only entries of the
+    /// current term are committed by counting, older ones follow them.
+    /// In a joint configuration both the old and the new voters must
+    /// have stored it.
+    fn advance_commit(&mut self) {
+        let me = self.local_id.peer().to_owned();
+        let last_idx = self.ps.log.last_idx();
+        let match_idx = &self.vss.match_idx;
+
+        // a leader outside the configuration does not count itself
+        let candidate = quorum_idx(&self.membership.groups(), |p| {
+            match *p == me {
+                true => last_idx,
+                false => match_idx.get(p).copied().unwrap_or(0),
+            }
+        });
+
+        if candidate > self.ps.log.commit_idx() &&
+            self.ps.log.term_at(candidate) == Some(self.ps.term)
+        {
+            self.ps.log.commit(candidate);
+            self.vs.commit_idx = self.ps.log.commit_idx();
+            debug!("RaftContext::advance_commit commit up to {}", self.vs.commit_idx);
+            self.apply();
+        }
+
+        self.finish_membership_change();
+    }
+
+
+    // follower heartbeat timeout, or election split vote timeout, start an
+    // election, first asking whether it could be won if pre_vote is set
     fn election_timeout_cb(&mut self) {
+        self.leader = None;
 
-        self.ps.term += 1;
-        self.state = NodeState::Candidate;
-        
-        // begin a new election
+        match self.config.pre_vote {
+            true => self.start_pre_vote(),
+            false => self.start_new_election(),
+        }
+    }
+
+
+    // terms stay as they are until a majority would vote for us
+    fn start_pre_vote(&mut self) {
+        self.state = NodeState::PreCandidate;
         self.timer.start_election_timer();
 
-        // vote for self
+        self.clear_election();
         self.vote(&self.local_id.peer().to_owned());
-        self.ps.voted_for = Some(self.local_id.peer().to_owned());
 
-        self.send_request_vote();
+        self.send_request_vote(self.ps.term + 1, true);
     }
 
 
@@ -208,51 +774,53 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R> {
         
         // increase term for a new round of election
         self.ps.term += 1;
+        self.state = NodeState::Candidate;
+        self.leader = None;
         self.timer.start_election_timer();
 
         // clear previous election and vote for self
         self.clear_election();
         self.vote(&self.local_id.peer().to_owned());
-        // vote_for is already set in previous round of election
+        self.ps.voted_for = Some(self.local_id.peer().to_owned());
+        self.persist_state();
 
-        self.send_request_vote();
+        self.send_request_vote(self.ps.term, false);
     }
 
 
-    fn send_request_vote(&mut self) {
+    fn send_request_vote(&mut self, term: u64, pre_vote: bool) {
 
         let (last_idx, last_log) = self.ps.log.last();
 
         let request_vote_msg = RaftMessage::new(
             RaftMessageKind::RequestVote(RaftRequestVote::new(
-                self.ps.term,
+                term,
                 self.local_id.peer().to_owned(),
                 last_idx,
-                last_log.term()
+                last_log.term(),
+                pre_vote
             )),
             self.seq(),
             self.local_id.peer()
         );
 
         // todo: broadcast in parallel
-        async_std::task::block_on(
+        runtime::block_on(
             self.broadcast(request_vote_msg)
         );
     }
 
 
-    fn append_entry_cb(&mut self, sender: &Peer, msg: RaftAppendEntries, seq: u32) {
+    fn append_entry_cb(&mut self, sender: &Peer, msg: &RaftAppendEntries, seq: u32) {
 
         if msg.term() < self.ps.term {
             // if message's term is smaller than current term, ignore it
             return;
         }
 
-        // refresh timer
-        self.timer.start_heartbeat();
-
-        // follow leader commit
-        self.vs.commit_idx = msg.leader_commit();
+        // leader is alive, postpone election
+        self.timer.start_election_timer();
+        self.timer.see_leader();
 
         // if remote term is higher, update term and clear vote_for
         if msg.term() != self.ps.term {
@@ -265,217 +833,365 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R> {
         // become follower
         self.state = NodeState::Follower;
 
-        if msg.is_empty() {
-            // empty append_entry is a heartbeat message
-            self.heartbeat_cb(msg, seq);
+        // empty append_entry is a heartbeat message, checked the same way
+        let success = self.apply_append_entry(msg);
+
+        let append_entry_apply = RaftMessage::new(
+            RaftMessageKind::AppendEntriesReply(RaftAppendEntriesReply::new(
+                seq,
+                self.ps.term,
+                success
+            )),
+            self.seq(),
+            self.local_id.peer()
+        );
+
+        runtime::block_on(
+            self.send_to_direct(append_entry_apply, sender)
+        );
+    }
+
+
+    fn append_entry_reply_cb(&mut self, sender: &Peer, msg: &RaftAppendEntriesReply) {
+
+        let waited = self.timer.take_wait_data_by_id(msg.ack());
+
+        if msg.term() > self.ps.term {
+            // become follower
+            self.update_term(msg.term(), None);
+            self.leader = None;
             return;
         }
 
-        // check log and reply
+        if self.state != NodeState::Leader || msg.term() < self.ps.term {
+            // reply to an earlier term
+            return;
+        }
 
-        let is_first_log = msg.prev_log_idx() == 0;
+        let (next_idx, entries_len, sent) = match waited.as_ref().map(|w| (&**w, w.sent())) {
+            Some((ApplyEntries(follower, next_idx, entries_len), sent)) if follower == sender => {
+                (*next_idx, *entries_len, sent)
+            }
+            _ => {
+                debug!("RaftContext::append_entry_reply_cb unknown or expired ack {}", msg.ack());
+                return;
+            }
+        };
 
-        let (last_idx, last_log) = self.ps.log.last();
+        // the follower is with us even if its log does not match yet
+        let acked = self.acked.entry(sender.to_owned()).or_insert(sent);
+        *acked = (*acked).max(sent);
+        self.serve_reads();
 
-        let log_matches = msg.prev_log_idx() == last_idx &&
-            msg.prev_log_term() == last_log.term();
+        if !msg.success() {
+            // log consistence check failed at next_idx - 1, step back and retry
+            // unless a later reply has moved next_idx already
+            match self.vss.next_idx.get_mut(sender) {
+                Some(idx) if *idx == next_idx && next_idx > 1 => *idx -= 1,
+                Some(_) => return,
+                None => {
+                    warn!("RaftContext::append_entry_reply_cb peer not in next_idx");
+                    return;
+                }
+            }
+            self.send_append_entry(sender);
+            return;
+        }
 
-        if is_first_log || log_matches {
-            // local log exactly matches leader log
-            self.ps.log.append_entry(msg.entries().to_owned());
-            
-            let append_entry_apply = RaftMessage::new(
-                RaftMessageKind::AppendEntriesReply(RaftAppendEntriesReply::new(
-                    seq,
-                    self.ps.term,
-                    true
-                )),
-                self.seq(),
-                self.local_id.peer()
-            );
+        // success, replies may arrive out of order so only move forward
+        let matched = next_idx + entries_len - 1;
 
-            async_std::task::block_on(
-                self.send_to_direct(append_entry_apply, sender)
-            );
-            
-            return;
+        if let Some(idx) = self.vss.match_idx.get_mut(sender) {
+            *idx = (*idx).max(matched);
         }
 
-        // previous log do not match, need to re-sync with leader
+        let lagging = match self.vss.next_idx.get_mut(sender) {
+            Some(idx) => {
+                *idx = (*idx).max(matched + 1);
+                *idx <= self.ps.log.last_idx()
+            }
+            None => false,
+        };
 
-        // todo: conflicting entry optimization
+        // update commit_idx
+        self.advance_commit();
 
-        let append_entry_apply = RaftMessage::new(
-            RaftMessageKind::AppendEntriesReply(RaftAppendEntriesReply::new(
+        // keep feeding a follower that is behind
+        if lagging && entries_len > 0 {
+            self.send_append_entry(sender);
+        }
+    }
+
+
+    fn install_snapshot_cb(&mut self, sender: &Peer, msg: &RaftInstallSnapshot, seq: u32) {
+
+        let success = if msg.term() < self.ps.term {
+            // stale leader, our term in the reply tells it to step down
+            false
+        }
+        else {
+            // leader is alive, postpone election
+            self.timer.start_election_timer();
+            self.timer.see_leader();
+            self.update_term(msg.term(), None);
+            self.leader = Some(msg.leader_id().to_owned());
+            self.state = NodeState::Follower;
+
+            self.receive_snapshot_chunk(msg)
+        };
+
+        let reply_msg = RaftMessage::new(
+            RaftMessageKind::InstallSnapshotReply(RaftInstallSnapshotReply::new(
                 seq,
                 self.ps.term,
-                false
+                success
             )),
             self.seq(),
             self.local_id.peer()
         );
 
-        async_std::task::block_on(
-            self.send_to_direct(append_entry_apply, sender)
+        runtime::block_on(
+            self.send_to_direct(reply_msg, sender)
         );
     }
 
 
-    fn append_entry_reply_cb(&mut self, sender: &Peer, msg: RaftAppendEntriesReply) {
-        
+    // chunks must arrive in order, a gap fails the transfer and the leader
+    // starts over
+    fn receive_snapshot_chunk(&mut self, msg: &RaftInstallSnapshot) -> bool {
+
+        if msg.offset() == 0 {
+            self.incoming = Some(IncomingSnapshot {
+                index: msg.last_included_idx(),
+                term: msg.last_included_term(),
+                membership: msg.membership().to_owned(),
+                data: Vec::new(),
+            });
+        }
+
+        match &mut self.incoming {
+            Some(incoming) if incoming.index == msg.last_included_idx() &&
+                incoming.data.len() as u64 == msg.offset() =>
+            {
+                incoming.data.extend_from_slice(msg.data());
+            }
+            _ => {
+                self.incoming = None;
+                return false;
+            }
+        }
+
+        match msg.done() {
+            // safe unwrap, filled above
+            true => {
+                let incoming = self.incoming.take().unwrap();
+                self.install_snapshot(incoming)
+            }
+            false => true,
+        }
+    }
+
+
+    fn install_snapshot(&mut self, incoming: IncomingSnapshot) -> bool {
+
+        if incoming.index <= self.vs.last_applied {
+            // state machine is ahead already
+            return true;
+        }
+
+        match restore(self.state_machine.as_mut(), &incoming.data) {
+            Ok(sessions) => self.sessions = sessions,
+            Err(error) => {
+                warn!("RaftContext::install_snapshot bad snapshot at {}: {}", incoming.index, error);
+                return false;
+            }
+        }
+
+        // state machine has moved to the snapshot, the log must follow
+        let snapshot = Snapshot::new(incoming.index, incoming.term, incoming.membership, incoming.data);
+        if let Err(error) = self.ps.log.install_snapshot(snapshot) {
+            panic!("RaftContext::install_snapshot: {}", error);
+        }
+        self.refresh_membership();
+
+        debug!("RaftContext::install_snapshot installed snapshot at {}", incoming.index);
+
+        self.vs.last_applied = incoming.index;
+        self.vs.commit_idx = self.ps.log.commit_idx();
+        self.apply();
+        true
+    }
+
+
+    fn install_snapshot_reply_cb(&mut self, sender: &Peer, msg: &RaftInstallSnapshotReply) {
+
+        let waited = self.timer.take_wait_data_by_id(msg.ack()).map(|w| (*w).clone());
+
         if msg.term() > self.ps.term {
             // become follower
-            self.state = NodeState::Follower;
+            self.update_term(msg.term(), None);
+            self.leader = None;
             return;
         }
 
+        if self.state != NodeState::Leader || msg.term() < self.ps.term || !msg.success() {
+            // a failed transfer restarts on the next heartbeat
+            return;
+        }
 
-        if !msg.success() {
-            // log consistence check failed
-
-            if let Some(idx) = self.vss.next_idx.get_mut(sender) {
-                *idx -= 1;
-                // todo: send append_entry again
+        let (index, offset, chunk_len) = match waited {
+            Some(InstallSnapshot(follower, index, offset, chunk_len)) if follower == *sender => {
+                (index, offset, chunk_len)
             }
-            else {
-                warn!("RaftContext::append_entry_reply_cb peer not in next_idx");
+            _ => {
+                debug!("RaftContext::install_snapshot_reply_cb unknown or expired ack {}", msg.ack());
+                return;
             }
+        };
+
+        let total = match self.ps.log.snapshot() {
+            Some(snapshot) => snapshot.data().len() as u64,
+            None => return,
+        };
+
+        if self.ps.log.snapshot_idx() != index {
+            // compacted again meanwhile, send the new snapshot
+            self.send_snapshot(sender, 0);
             return;
         }
 
-        // success
+        if offset + chunk_len < total {
+            self.send_snapshot(sender, offset + chunk_len);
+            return;
+        }
 
-        
-        if let Some(timed_data) = self.timer.get_wait_data_by_id(msg.ack()) {
-            match *timed_data {
-                ApplyEntries(next_idx, entries_len) => {
-                    
-                    if let Some(idx) = self.vss.next_idx.get_mut(sender) {
-                        *idx = next_idx + entries_len;
-                    }
+        // follower holds the log up to index
+        if let Some(idx) = self.vss.match_idx.get_mut(sender) {
+            *idx = (*idx).max(index);
+        }
+        if let Some(idx) = self.vss.next_idx.get_mut(sender) {
+            *idx = (*idx).max(index + 1);
+        }
+
+        self.advance_commit();
+        self.send_append_entry(sender);
+    }
 
-                    if let Some(idx) = self.vss.match_idx.get_mut(sender) {
-                        *idx = next_idx + entries_len - 1;
-                    }
 
-                },
-                _ => unreachable!()
-            }
+    fn request_vote_cb(&mut self, msg: &RaftRequestVote, seq: u32, from: &Peer) {
+
+        // a broadcast comes back to its sender down the relay tree, the
+        // candidate has voted for itself already and must stay one
+        if from == self.local_id.peer() {
+            return;
         }
 
-        // update commit_idx
-    
+        let granted = match msg.pre_vote() {
+            true => self.grant_pre_vote(msg),
+            false => self.grant_vote(msg, from),
+        };
+
+        // a granted pre-vote answers for the term asked about
+        let term = match msg.pre_vote() && granted {
+            true => msg.term(),
+            false => self.ps.term,
+        };
+
+        let vote_msg = RaftMessage::new(
+            RaftMessageKind::RequestVoteReply(RaftRequestVoteReply::new(
+                seq,
+                term,
+                granted,
+                msg.pre_vote()
+            )),
+            self.seq(),
+            self.local_id.peer()
+        );
+
+        runtime::block_on(
+            self.send_to_direct(vote_msg, from)
+        );
     }
 
 
-    fn request_vote_cb(&mut self, msg: &RaftRequestVote, seq: u32, from: &Peer) {
-        
-        let vote_msg: RaftMessage;
+    // a pre-vote changes nothing, it only tells whether the candidate
+    // would get this vote
+    fn grant_pre_vote(&self, msg: &RaftRequestVote) -> bool {
+        msg.term() > self.ps.term && !self.leader_alive() && self.log_up_to_date(msg)
+    }
 
-        if msg.term() < self.ps.term {
-            // if candidate's term is lower, reject immediately
-            vote_msg = RaftMessage::new(
-                RaftMessageKind::RequestVoteReply(RaftRequestVoteReply::new(
-                    seq,
-                    self.ps.term,
-                    false
-                )),
-                self.seq(),
-                self.local_id.peer()
-            );
 
-            async_std::task::block_on(
-                self.send_to_direct(vote_msg, from)
-            );
+    fn grant_vote(&mut self, msg: &RaftRequestVote, from: &Peer) -> bool {
 
-            return;
+        if msg.term() < self.ps.term {
+            return false;
+        }
+
+        // a candidate cannot unseat a leader that is still heard from,
+        // so it does not get to raise our term either
+        if msg.term() > self.ps.term && self.leader_alive() {
+            return false;
         }
 
         // if we receive a request_vote with higher term, update term
         // and become follower
-        if msg.term() > self.ps.term {
-            self.update_term(msg.last_log_term(), None);
-            self.state = NodeState::Follower;
-            // reset election timeout
+        self.update_term(msg.term(), None);
+
+        let free = match &self.ps.voted_for {
+            // already voted this candidate, vote again
+            Some(cand) => cand == from,
+            None => true,
+        };
+
+        if !free || !self.log_up_to_date(msg) {
+            return false;
         }
-        
-        // check log freshness
+
+        if self.ps.voted_for.is_none() {
+            self.ps.voted_for = Some(from.to_owned());
+            self.persist_state();
+        }
+        self.state = NodeState::Follower;
+        self.timer.start_election_timer();
+        true
+    }
+
+
+    // candidate's log is at least as recent as local: its last term is
+    // higher, or the same with at least as many entries
+    fn log_up_to_date(&self, msg: &RaftRequestVote) -> bool {
         let (last_idx, last_log) = self.ps.log.last();
-        
-        if msg.last_log_term() >= last_log.term() &&
-            msg.last_log_index() >= last_idx
-        {
-            // candidate's log is at least as recent as local, vote
-
-            match &self.ps.voted_for {
-                
-                Some(cand) => {
-                    if cand == from {
-                        // already voted this candidate, vote again
-                        vote_msg = RaftMessage::new(
-                            RaftMessageKind::RequestVoteReply(RaftRequestVoteReply::new(
-                                seq,
-                                self.ps.term,
-                                true
-                            )),
-                            self.seq(),
-                            self.local_id.peer()
-                        );
-                    }
-                    else {
-                        // already voted for another candidate, cannot vote
-                        vote_msg = RaftMessage::new(
-                            RaftMessageKind::RequestVoteReply(RaftRequestVoteReply::new(
-                                seq,
-                                self.ps.term,
-                                false
-                            )),
-                            self.seq(),
-                            self.local_id.peer()
-                        );
-                    }
-                },
 
-                None => {
-                    // have not vote yet
-                    vote_msg = RaftMessage::new(
-                        RaftMessageKind::RequestVoteReply(RaftRequestVoteReply::new(
-                            seq,
-                            self.ps.term,
-                            true
-                        )),
-                        self.seq(),
-                        self.local_id.peer()
-                    );
+        msg.last_log_term() > last_log.term() ||
+            (msg.last_log_term() == last_log.term() && msg.last_log_index() >= last_idx)
+    }
 
-                    self.ps.voted_for = Some(from.to_owned());
-                    self.state = NodeState::Follower;
-                    // todo: reset election timeout
 
-                },
-            }
+    // followers trust a leader they heard from lately, a leader trusts
+    // itself while it holds a lease
+    fn leader_alive(&self) -> bool {
+        match self.state {
+            NodeState::Leader => self.in_lease(),
+            _ => self.leader.is_some() && self.timer.leader_recent(),
         }
-        else {
-            // candidate's log is not fresher, reject
-            vote_msg = RaftMessage::new(
-                RaftMessageKind::RequestVoteReply(RaftRequestVoteReply::new(
-                    seq,
-                    self.ps.term,
-                    false
-                )),
-                self.seq(),
-                self.local_id.peer()
-            );
-        }
-        
-        async_std::task::block_on(
-            self.send_to_direct(vote_msg, from)
-        );
-
     }
 
 
-    fn request_vote_reply_cb(&mut self, msg: RaftRequestVoteReply, from: &Peer) {
+    fn request_vote_reply_cb(&mut self, msg: &RaftRequestVoteReply, from: &Peer) {
+
+        if msg.pre_vote() && msg.vote_granted() {
+            // granted for the term we would run in
+            if self.state == NodeState::PreCandidate && msg.term() == self.ps.term + 1 {
+                self.vote(from);
+                if self.election.result() == VoteResult::PASS {
+                    debug!("Peer {} gathered enough pre-votes for term {}",
+                        self.local_id.peer(), msg.term());
+
+                    self.start_new_election();
+                }
+            }
+            return;
+        }
 
         // if remote has higher term, back to follower
         if msg.term() > self.ps.term {
@@ -490,15 +1206,14 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R> {
             return;
         }
         
-        if msg.vote_granted() {
+        if msg.vote_granted() && !msg.pre_vote() && msg.term() == self.ps.term {
             self.vote(from);
-            if self.election.result() == VoteResult::PASS {
+            if self.state == NodeState::Candidate && self.election.result() == VoteResult::PASS {
                 // enough vote, become leader
-                self.state = NodeState::Leader;
-                
                 debug!("Peer {} gathered enough votes and is elected the leader of term {}",
                     self.local_id.peer(), self.ps.term);
-            
+
+                self.become_leader();
             }
             // not enough vote, wait for more
         }
@@ -526,9 +1241,127 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R> {
     /// log[lastApplied] to state machine
     fn apply(&mut self) {
         while self.vs.commit_idx > self.vs.last_applied {
-            self.vs.last_applied += 1;
-            // todo: apply log to state machine
-            debug!("Apply log {}", self.vs.last_applied);
+            let idx = self.vs.last_applied + 1;
+            let entry = match self.ps.log.get(idx) {
+                Some(entry) => entry,
+                None => {
+                    warn!("RaftContext::apply committed entry {} is missing", idx);
+                    break;
+                }
+            };
+
+            let response = match entry.kind() {
+                EntryKind::Command => self.apply_command(idx, &entry),
+                // voters changed when the entry was stored
+                EntryKind::Membership | EntryKind::Noop => Vec::new(),
+            };
+            self.vs.last_applied = idx;
+            debug!("Apply log {}", idx);
+
+            // an entry from another term took the index, its client retries
+            if let Some(pending) = self.pending.remove(&idx) {
+                if pending.term == entry.term() {
+                    self.reply_client(&pending.client, pending.seq, response);
+                }
+            }
+        }
+
+        self.serve_reads();
+        self.maybe_snapshot();
+    }
+
+
+    // a write the client retried may be in the log more than once, only
+    // the first copy reaches the state machine
+    fn apply_command(&mut self, idx: u64, entry: &LogEntry) -> Vec<u8> {
+        if let Some(response) = self.sessions.applied(entry.client_id(), entry.client_seq()) {
+            debug!("RaftContext::apply_command entry {} was applied before", idx);
+            return response;
+        }
+
+        let response = self.state_machine.apply(idx, entry.command());
+        self.sessions.record(entry.client_id(), entry.client_seq(), &response);
+        response
+    }
+
+
+    // snapshot the state machine and compact the log once enough entries
+    // are applied
+    fn maybe_snapshot(&mut self) {
+        let idx = self.vs.last_applied;
+        if idx - self.ps.log.snapshot_idx() < self.config.snapshot_threshold {
+            return;
+        }
+
+        let term = match self.ps.log.term_at(idx) {
+            Some(term) => term,
+            None => return,
+        };
+
+        let data = match self.state_machine.snapshot() {
+            Ok(state) => self.sessions.pack(state),
+            Err(error) => {
+                warn!("RaftContext::maybe_snapshot cannot snapshot state machine: {}", error);
+                return;
+            }
+        };
+
+        // the latest configuration is usually older than idx
+        let membership = match self.membership_idx <= idx {
+            true => self.membership.clone(),
+            false => self.membership_at(idx).1,
+        };
+
+        match self.ps.log.compact(Snapshot::new(idx, term, membership, data)) {
+            Ok(_) => debug!("RaftContext::maybe_snapshot compact log up to {}", idx),
+            Err(error) => warn!("RaftContext::maybe_snapshot: {}", error),
+        }
+    }
+
+
+    fn reply_client(&mut self, client: &Peer, seq: u32, response: Vec<u8>) {
+        let reply_msg = RaftMessage::new(
+            RaftMessageKind::ClientReply(RaftClientReply::new(
+                seq,
+                self.local_id.peer().to_owned(),
+                Some(response)
+            )),
+            self.seq(),
+            self.local_id.peer()
+        );
+
+        runtime::block_on(
+            self.send_to_direct(reply_msg, client)
+        );
+    }
+
+
+    // drive timers: leader keeps sending heartbeat, the others start
+    // an election when the leader is silent. A node that is not a voter
+    // never runs for election.
+    fn tick(&mut self) {
+        if self.state != NodeState::Leader && !self.membership.contains(self.local_id.peer()) {
+            return;
+        }
+
+        match self.state {
+            NodeState::Leader => {
+                if self.timer.is_heartbeat_timeout() {
+                    self.send_heartbeat();
+                }
+            }
+
+            NodeState::Follower => {
+                if self.timer.is_election_timeout() {
+                    self.election_timeout_cb();
+                }
+            }
+
+            NodeState::PreCandidate | NodeState::Candidate => {
+                if self.timer.is_election_timeout() {
+                    self.election_timeout_cb();
+                }
+            }
         }
     }
 
@@ -540,26 +1373,48 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R> {
             self.ps.term = new_term;
             self.state = NodeState::Follower;
             self.ps.voted_for = voted_for;
+            self.persist_state();
         }
     }
 
+
+    /// Store term and voted_for before acting on them. A node that cannot
+    /// remember its vote may vote twice in a term, so it stops here.
+    fn persist_state(&mut self) {
+        let voted_for = self.ps.voted_for.clone();
+        if let Err(error) = self.ps.log.save_state(self.ps.term, voted_for.as_ref()) {
+            panic!("RaftContext::persist_state: {}", error);
+        }
+    }
+
+}
+
+
+// restore state_machine from snapshot data, returning the sessions it holds
+fn restore(state_machine: &mut dyn StateMachine, data: &[u8]) -> Result<Sessions, DeserializeError> {
+    let (sessions, state) = Sessions::unpack(data)?;
+    state_machine.restore(&state)?;
+    Ok(sessions)
 }
 
 
+/// Each step runs due timers and handles at most one message, then
+/// yields the index of the last applied entry.
 impl<T: Transport, R: RelayCtl> Iterator for RaftContext<T, R> {
     
-    type Item = usize;
+    type Item = u64;
 
     fn next(&mut self) -> std::option::Option<<Self as Iterator>::Item> {
         
+        self.tick();
+
         let msg = self.network_handle.next();
         if let Some(msg) = msg {
             let raw_payload = msg.payload();
-            // todo
 
             match RaftMessage::from_bytes(&raw_payload) {
-                Ok(pbft_msg) => {
-                    self.raft_msg_dispatch(pbft_msg)
+                Ok(raft_msg) => {
+                    self.raft_msg_dispatch(raft_msg)
                 }
 
                 Err(error) => {
@@ -567,7 +1422,8 @@ impl<T: Transport, R: RelayCtl> Iterator for RaftContext<T, R> {
                 }
             }
         }
-        todo!()
+
+        Some(self.vs.last_applied)
     }
 }
 
@@ -582,7 +1438,7 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R>{
                 true,
                 RelayMethodKind::LOOKUP_TABLE_1,
                 1,
-                20
+                15
             ).unwrap();
     
             let mut overlay_msg = OverlayMessage::new(
@@ -608,7 +1464,7 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R>{
                 false,
                 RelayMethodKind::ALL,
                 1,
-                20
+                15
             ).unwrap();
     
             let mut overlay_msg = OverlayMessage::new(
@@ -618,7 +1474,8 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R>{
                 target,
                 &msg_buf);
     
-            self.network_handle.send_to(target, &mut overlay_msg).await;
+            // failures are logged by BDN
+            let _ = self.network_handle.send_to(target, &mut overlay_msg).await;
         }
         else {
             warn!("RaftMessage::send_to_direct ill-formed msg: {:?}", msg);
@@ -631,4 +1488,383 @@ impl<T: Transport, R: RelayCtl> RaftContext<T, R>{
         self.seq
     }
 
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::identity;
+    use yulong_bdn::config::BdnConfig;
+    use yulong_bdn::route_inner::impls::mlbt::MlbtRelayCtlContext;
+    use yulong_tcp::TcpContext;
+
+    use crate::state_machine::{KvCommand, KvStateMachine};
+
+    type TestContext = RaftContext<TcpContext, MlbtRelayCtlContext>;
+
+    // peers have no address, so every send fails at once
+    fn context(peers: Vec<Peer>) -> TestContext {
+        context_with(peers, RaftConfig::default())
+    }
+
+    fn context_with(peers: Vec<Peer>, config: RaftConfig) -> TestContext {
+        let bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new(identity(), BdnConfig::default());
+        RaftContext::new(bdn, peers, Box::new(KvStateMachine::new()), config).unwrap()
+    }
+
+    fn leader_context(peers: Vec<Peer>) -> TestContext {
+        let mut raft = context(peers);
+        raft.update_term(1, None);
+        raft.become_leader();
+        raft
+    }
+
+    // latest AppendEntries still waiting for a reply from peer
+    fn latest_wait(raft: &TestContext, from: &Peer) -> u32 {
+        (1..=raft.seq).rev()
+            .find(|id| matches!(
+                raft.timer.get_wait_data_by_id(*id).as_deref(),
+                Some(ApplyEntries(f, _, _)) if f == from
+            ))
+            .unwrap()
+    }
+
+    fn reply(raft: &mut TestContext, from: &Peer, success: bool) {
+        let ack = latest_wait(raft, from);
+        raft.append_entry_reply_cb(from, &RaftAppendEntriesReply::new(ack, 1, success));
+    }
+
+    fn state(raft: &TestContext) -> KvStateMachine {
+        let mut kv = KvStateMachine::new();
+        kv.restore(&raft.state_machine.snapshot().unwrap()).unwrap();
+        kv
+    }
+
+    fn put(term: u64, key: &[u8], value: &[u8]) -> LogEntry {
+        let command = KvCommand::Put { key: key.to_vec(), value: value.to_vec() };
+        LogEntry::new(term, command.into_bytes().unwrap())
+    }
+
+    #[test]
+    fn follower_apply() {
+        let leader = identity().peer().to_owned();
+        let mut raft = context(vec![leader.clone()]);
+        raft.update_term(1, None);
+
+        let entries = vec![put(1, b"k", b"1"), put(1, b"k", b"2"), put(1, b"j", b"3")];
+        let append = RaftAppendEntries::new(1, leader.clone(), 0, 0, entries, 2);
+        assert!(raft.apply_append_entry(&append));
+
+        // only committed entries are applied
+        assert_eq!(raft.vs.last_applied, 2);
+        let kv = state(&raft);
+        assert_eq!(kv.get(b"k"), Some(&b"2"[..]));
+        assert_eq!(kv.get(b"j"), None);
+
+        // heartbeat moves commit on
+        let heartbeat = RaftAppendEntries::new(1, leader, 3, 1, vec![], 3);
+        assert!(raft.apply_append_entry(&heartbeat));
+        assert_eq!(raft.vs.last_applied, 3);
+
+        let kv = state(&raft);
+        assert_eq!(kv.get(b"j"), Some(&b"3"[..]));
+        assert_eq!(kv.applied(), 3);
+    }
+
+    #[test]
+    fn snapshot_transfer() {
+        let leader = identity().peer().to_owned();
+        let config = RaftConfig { snapshot_threshold: 2, ..RaftConfig::default() };
+
+        // applying past the threshold compacts the log
+        let mut source = context_with(vec![leader.clone()], config);
+        let entries = vec![put(1, b"a", b"1"), put(1, b"b", b"2"), put(1, b"c", b"3")];
+        source.apply_append_entry(&RaftAppendEntries::new(1, leader.clone(), 0, 0, entries, 3));
+        assert_eq!(source.ps.log.snapshot_idx(), 3);
+
+        let data = source.ps.log.snapshot().unwrap().data().to_vec();
+        let chunk = |offset: usize, len: usize, done: bool| RaftInstallSnapshot::new(
+            1, leader.clone(), 3, 1, offset as u64, data[offset..offset + len].to_vec(), done,
+            source.membership.clone()
+        );
+
+        let mut raft = context(vec![leader.clone()]);
+        raft.apply_append_entry(&RaftAppendEntries::new(1, leader.clone(), 0, 0, vec![put(1, b"x", b"0")], 0));
+
+        // chunks out of order fail the transfer
+        assert!(raft.receive_snapshot_chunk(&chunk(0, 4, false)));
+        assert!(!raft.receive_snapshot_chunk(&chunk(5, 1, false)));
+        assert!(raft.incoming.is_none());
+
+        assert!(raft.receive_snapshot_chunk(&chunk(0, 4, false)));
+        assert!(raft.receive_snapshot_chunk(&chunk(4, data.len() - 4, true)));
+
+        assert_eq!(raft.vs.last_applied, 3);
+        assert_eq!(raft.ps.log.snapshot_idx(), 3);
+        assert_eq!(raft.ps.log.last_idx(), 3);
+        assert_eq!(state(&raft).get(b"c"), Some(&b"3"[..]));
+        assert_eq!(state(&raft).get(b"x"), None);
+
+        // the log goes on after the snapshot
+        let next = RaftAppendEntries::new(1, leader, 3, 1, vec![put(1, b"d", b"4")], 4);
+        assert!(raft.apply_append_entry(&next));
+        assert_eq!(state(&raft).get(b"d"), Some(&b"4"[..]));
+    }
+
+    #[test]
+    fn leader_replication() {
+        let (p1, p2) = (identity().peer().to_owned(), identity().peer().to_owned());
+        let mut raft = leader_context(vec![p1.clone(), p2.clone()]);
+        assert_eq!(raft.state, NodeState::Leader);
+
+        // the term starts with a noop
+        assert_eq!(raft.term_start_idx, 1);
+
+        let request = |key: &[u8]| RaftClientRequest::new(0, 0, put(1, key, b"v").command().to_vec());
+        raft.request_cb(&p1, &request(b"a"), 1);
+        raft.request_cb(&p1, &request(b"b"), 2);
+        assert_eq!(raft.vs.commit_idx, 0);
+        assert_eq!(raft.pending.len(), 2);
+
+        // one follower is enough for a quorum of 3
+        reply(&mut raft, &p1, true);
+        assert_eq!(raft.vss.match_idx[&p1], 3);
+        assert_eq!(raft.vss.next_idx[&p1], 4);
+        assert_eq!(raft.vs.commit_idx, 3);
+        assert_eq!(raft.vs.last_applied, 3);
+        assert!(raft.pending.is_empty());
+        assert_eq!(state(&raft).get(b"b"), Some(&b"v"[..]));
+
+        // a mismatch walks next_idx back and retries at once
+        raft.vss.next_idx.insert(p2.clone(), 4);
+        raft.send_append_entry(&p2);
+        reply(&mut raft, &p2, false);
+        assert_eq!(raft.vss.next_idx[&p2], 3);
+
+        let retry = raft.timer.get_wait_data_by_id(latest_wait(&raft, &p2)).unwrap();
+        assert_eq!(*retry, ApplyEntries(p2.clone(), 3, 1));
+
+        // unknown ack is ignored
+        raft.append_entry_reply_cb(&p2, &RaftAppendEntriesReply::new(u32::MAX, 1, true));
+        assert_eq!(raft.vss.match_idx[&p2], 0);
+
+        // higher term steps down
+        raft.append_entry_reply_cb(&p2, &RaftAppendEntriesReply::new(0, 2, false));
+        assert_eq!(raft.state, NodeState::Follower);
+        assert_eq!(raft.ps.term, 2);
+    }
+
+    #[test]
+    fn membership_change() {
+        let me = identity();
+        let (p1, p2, p3) = (identity().peer().to_owned(), identity().peer().to_owned(), identity().peer().to_owned());
+        let bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new(me.clone(), BdnConfig::default());
+        let mut raft = RaftContext::new(bdn, vec![p1.clone(), p2.clone()],
+            Box::new(KvStateMachine::new()), RaftConfig::default()).unwrap();
+        raft.update_term(1, None);
+        raft.become_leader();
+
+        let me = me.peer().to_owned();
+        let old = vec![p1.clone(), p2.clone(), me.clone()];
+        let new = vec![me.clone(), p1.clone(), p3.clone()];
+
+        // replace p2 with p3, the joint configuration is used at once
+        assert_eq!(raft.change_membership(new.clone()).unwrap(), 2);
+        assert_eq!(raft.membership, Membership::Joint(old, new.clone()));
+        assert_eq!(raft.vss.next_idx[&p3], 3);
+        assert!(raft.change_membership(vec![me.clone()]).is_err());
+        assert!(raft.change_membership(vec![me.clone(), p1.clone(), p1.clone()]).is_err());
+
+        // the new voters alone are not a quorum of the old ones
+        reply(&mut raft, &p3, true);
+        assert_eq!(raft.vs.commit_idx, 0);
+
+        // committing it moves on to the new voters
+        reply(&mut raft, &p1, true);
+        assert_eq!(raft.vs.commit_idx, 2);
+        assert_eq!(raft.membership, Membership::Stable(new.clone()));
+        assert_eq!(raft.membership_idx, 3);
+        assert!(!raft.vss.next_idx.contains_key(&p2));
+
+        reply(&mut raft, &p1, true);
+        assert_eq!(raft.vs.commit_idx, 3);
+
+        // a leader removing itself still replicates until the change is done
+        raft.change_membership(vec![p1.clone(), p3.clone()]).unwrap();
+        reply(&mut raft, &p1, true);
+        assert_eq!(raft.vs.commit_idx, 3);
+        reply(&mut raft, &p3, true);
+        assert_eq!(raft.vs.commit_idx, 4);
+        assert_eq!(raft.membership, Membership::Stable(vec![p1.clone(), p3.clone()]));
+
+        reply(&mut raft, &p1, true);
+        assert_eq!(raft.state, NodeState::Leader);
+        reply(&mut raft, &p3, true);
+        assert_eq!(raft.vs.commit_idx, 5);
+        assert_eq!(raft.state, NodeState::Follower);
+        assert_eq!(raft.leader, None);
+    }
+
+    #[test]
+    fn follower_membership() {
+        let (leader, other) = (identity().peer().to_owned(), identity().peer().to_owned());
+        let mut raft = context(vec![leader.clone()]);
+        raft.update_term(1, None);
+        let boot = raft.membership.clone();
+
+        let joint = boot.enter(vec![leader.clone(), other.clone()]).unwrap();
+        let entries = vec![put(1, b"a", b"1"), LogEntry::membership(1, &joint)];
+        assert!(raft.apply_append_entry(&RaftAppendEntries::new(1, leader.clone(), 0, 0, entries, 1)));
+
+        // taken before it is committed
+        assert_eq!(raft.membership, joint);
+        assert_eq!(raft.membership_idx, 2);
+        assert!(raft.peers.contains(&other));
+
+        // a new leader overwrites the entry, the boot voters are back
+        raft.update_term(2, None);
+        let overwrite = RaftAppendEntries::new(2, leader, 1, 1, vec![put(2, b"b", b"2")], 2);
+        assert!(raft.apply_append_entry(&overwrite));
+        assert_eq!(raft.membership, boot);
+        assert_eq!(raft.membership_idx, 0);
+        assert!(!raft.peers.contains(&other));
+    }
+
+    #[test]
+    fn pre_vote() {
+        let (p1, p2) = (identity().peer().to_owned(), identity().peer().to_owned());
+        let mut raft = context(vec![p1.clone(), p2.clone()]);
+        raft.update_term(1, None);
+
+        // a node that was cut off asks first, its term stays
+        raft.election_timeout_cb();
+        assert_eq!(raft.state, NodeState::PreCandidate);
+        assert_eq!(raft.ps.term, 1);
+
+        // pre-votes for another term do not count
+        raft.request_vote_reply_cb(&RaftRequestVoteReply::new(0, 1, true, true), &p1);
+        assert_eq!(raft.state, NodeState::PreCandidate);
+
+        // a majority of pre-votes starts the real election
+        raft.request_vote_reply_cb(&RaftRequestVoteReply::new(0, 2, true, true), &p1);
+        assert_eq!(raft.state, NodeState::Candidate);
+        assert_eq!(raft.ps.term, 2);
+        assert_eq!(raft.ps.voted_for.as_ref(), Some(raft.local_id.peer()));
+
+        // its own request relayed back changes nothing
+        let me = raft.local_id.peer().to_owned();
+        raft.request_vote_cb(&RaftRequestVote::new(2, me.clone(), 0, 0, false), 0, &me);
+        assert_eq!(raft.state, NodeState::Candidate);
+
+        raft.request_vote_reply_cb(&RaftRequestVoteReply::new(0, 2, true, false), &p2);
+        assert_eq!(raft.state, NodeState::Leader);
+    }
+
+    #[test]
+    fn vote_granting() {
+        let (p1, p2) = (identity().peer().to_owned(), identity().peer().to_owned());
+        let mut raft = context(vec![p1.clone(), p2.clone()]);
+        raft.update_term(1, None);
+        raft.ps.log.append_entry(0, vec![put(1, b"a", b"1"), put(1, b"b", b"2")]).unwrap();
+
+        let request = |term, last_idx, last_term, pre_vote| {
+            RaftRequestVote::new(term, p1.clone(), last_idx, last_term, pre_vote)
+        };
+
+        // a shorter log with the same last term, or an older last term, loses
+        assert!(!raft.grant_pre_vote(&request(2, 1, 1, true)));
+        assert!(!raft.grant_pre_vote(&request(2, 5, 0, true)));
+        assert!(raft.grant_pre_vote(&request(2, 1, 2, true)));
+        assert!(!raft.grant_pre_vote(&request(1, 2, 1, true)));
+        assert_eq!(raft.ps.term, 1);
+
+        // a follower hearing from its leader ignores candidates
+        raft.leader = Some(p2.clone());
+        raft.timer.see_leader();
+        assert!(!raft.grant_pre_vote(&request(2, 2, 1, true)));
+        assert!(!raft.grant_vote(&request(2, 2, 1, false), &p1));
+        assert_eq!(raft.ps.term, 1);
+
+        // the vote moves to the candidate's term, not its last log term
+        raft.leader = None;
+        assert!(raft.grant_vote(&request(3, 2, 1, false), &p1));
+        assert_eq!(raft.ps.term, 3);
+        assert_eq!(raft.ps.voted_for, Some(p1.clone()));
+        assert!(raft.grant_vote(&request(3, 2, 1, false), &p1));
+        assert!(!raft.grant_vote(&RaftRequestVote::new(3, p2.clone(), 2, 1, false), &p2));
+    }
+
+    #[test]
+    fn read_index() {
+        let (p1, p2, client) = (
+            identity().peer().to_owned(), identity().peer().to_owned(), identity().peer().to_owned()
+        );
+        let read = |key: &[u8]| {
+            RaftClientRequest::read(KvCommand::Get { key: key.to_vec() }.into_bytes().unwrap())
+        };
+        let mut raft = leader_context(vec![p1.clone(), p2.clone()]);
+
+        // until the noop commits the leader may not know the commit index
+        raft.request_cb(&client, &read(b"a"), 1);
+        assert_eq!(raft.reads.len(), 1);
+        assert_eq!(raft.reads[0].read_idx, 1);
+
+        // the heartbeat sent for it confirms leadership and commits the noop
+        reply(&mut raft, &p1, true);
+        assert_eq!(raft.vs.commit_idx, 1);
+        assert!(raft.reads.is_empty());
+        assert_eq!(raft.ps.log.last_idx(), 1);
+
+        // an answer to a request sent before the read does not confirm it
+        let earlier = latest_wait(&raft, &p2);
+        raft.request_cb(&client, &read(b"a"), 2);
+        raft.append_entry_reply_cb(&p2, &RaftAppendEntriesReply::new(earlier, 1, true));
+        assert_eq!(raft.reads.len(), 1);
+        reply(&mut raft, &p2, true);
+        assert!(raft.reads.is_empty());
+
+        // under a lease reads are answered at once, and candidates ignored
+        raft.config.lease_read = true;
+        raft.config.election_inv_low = 60_000;
+        raft.request_cb(&client, &read(b"a"), 3);
+        assert!(raft.reads.is_empty());
+        assert!(!raft.grant_pre_vote(&RaftRequestVote::new(5, p1.clone(), 9, 5, true)));
+
+        // a leader that stepped down drops its reads
+        raft.config.lease_read = false;
+        raft.request_cb(&client, &read(b"a"), 4);
+        raft.update_term(2, None);
+        raft.serve_reads();
+        assert!(raft.reads.is_empty());
+    }
+
+    #[test]
+    fn retried_write() {
+        let (p1, client) = (identity().peer().to_owned(), identity().peer().to_owned());
+        let mut raft = leader_context(vec![p1.clone()]);
+        let write = RaftClientRequest::new(7, 1, put(1, b"k", b"v").command().to_vec());
+
+        // a retry that reaches the log again is applied once
+        raft.request_cb(&client, &write, 1);
+        raft.request_cb(&client, &write, 2);
+        assert_eq!(raft.ps.log.last_idx(), 3);
+
+        reply(&mut raft, &p1, true);
+        assert_eq!(raft.vs.last_applied, 3);
+        assert_eq!(state(&raft).applied(), 2);
+        assert!(raft.pending.is_empty());
+
+        // once applied, a retry is answered without a new entry
+        raft.request_cb(&client, &write, 3);
+        assert_eq!(raft.ps.log.last_idx(), 3);
+
+        // sessions survive a snapshot
+        let data = raft.sessions.pack(raft.state_machine.snapshot().unwrap());
+        let mut kv = KvStateMachine::new();
+        let sessions = restore(&mut kv, &data).unwrap();
+        assert_eq!(sessions.applied(7, 1), raft.sessions.applied(7, 1));
+        assert_eq!(kv.get(b"k"), Some(&b"v"[..]));
+    }
+}
+
@@ -1,45 +1,230 @@
-use yulong::utils::AsBytes;
-use yulong::utils::CasualTimer;
+use std::time::Duration;
+
+use log::{debug, warn};
+use rand::Rng;
+
+use yulong::error::DumbError;
+use yulong::utils::{clock, AsBytes};
+use yulong_bdn::handle::{BdnHandle, PayloadStream};
 use yulong_bdn::msg_header::MsgTypeKind;
 use yulong_bdn::msg_header::RelayMethodKind;
 use yulong_network::identity::{Peer, Me};
-use yulong_bdn::overlay::BDN;
 use yulong_bdn::msg_header::MsgHeader;
 use yulong_bdn::message::OverlayMessage;
-use yulong_network::transport::Transport;
-use yulong_bdn::route_inner::RelayCtl;
 
+use crate::message::RaftClientReply;
 use crate::message::RaftClientRequest;
 use crate::message::RaftMessage;
 use crate::message::RaftMessageKind;
 
-use crate::config::{
-    PAYLOAD_MAX,
-    CLIENT_TIMEOUT
-};
+use crate::config::RaftConfig;
+use crate::error::ClientError;
+
+
+// what a reply means for the request waiting on it
+enum Outcome {
+    Done(Vec<u8>),
+    // redirected or unanswered, send again
+    Retry,
+    Closed,
+}
+
 
-pub struct RaftClientContext<T: Transport, R: RelayCtl> {
-    network_handle: BDN<T, R>,
+/// Client of a Raft cluster.
+///
+/// Requests go to the leader last heard of, or to the members in turn
+/// while it is unknown. A refusal naming the leader sends the request
+/// there, silence for client_timeout sends it to the next member.
+pub struct RaftClientContext {
+    network: BdnHandle,
+    payloads: PayloadStream,
     local_id: Me,
     seq: u32,
 
-    req_timer: CasualTimer,
+    // the cluster applies each client_seq of client_id once, retries
+    // included
+    client_id: u64,
+    client_seq: u64,
+
+    leader: Option<Peer>,
+    next_member: usize,
+
+    raft_cluster_member: Vec<Peer>,
 
-    raft_cluster_member: Vec<Peer>
+    config: RaftConfig,
 }
 
 
-impl<T: Transport, R: RelayCtl> RaftClientContext<T, R> {
-    
+impl RaftClientContext {
+
+    /// network and payloads come from BDN::spawn, replies arrive once the
+    /// BDN listens.
+    pub fn new(
+        network: BdnHandle,
+        payloads: PayloadStream,
+        local_id: Me,
+        raft_cluster_member: Vec<Peer>,
+        config: RaftConfig
+    ) -> Self {
+        Self {
+            network,
+            payloads,
+            local_id,
+            seq: 0,
+            // 0 marks requests that are not deduplicated
+            client_id: rand::thread_rng().gen_range(1..u64::MAX),
+            client_seq: 0,
+            leader: None,
+            next_member: 0,
+            raft_cluster_member,
+            config,
+        }
+    }
+
+
+    /// Apply command on the cluster and return the state machine's
+    /// response. It is applied once however many times it is sent.
+    pub async fn submit(&mut self, command: &[u8]) -> Result<Vec<u8>, ClientError> {
+        self.client_seq += 1;
+        let request = RaftClientRequest::new(self.client_id, self.client_seq, command.to_vec());
+        self.call(request).await
+    }
+
+
+    /// Answer a read-only command from the leader's state machine, without
+    /// going through the log.
+    pub async fn read(&mut self, command: &[u8]) -> Result<Vec<u8>, ClientError> {
+        self.call(RaftClientRequest::read(command.to_vec())).await
+    }
+
+
+    /// Leader of the cluster as last heard.
+    pub fn leader(&self) -> Option<&Peer> {
+        self.leader.as_ref()
+    }
+
+
+    /// Send command to a member without waiting for the outcome. It is
+    /// not deduplicated, sessions only keep the latest write of a client
+    /// and take it that the writes before were answered.
     pub async fn send_request(&mut self, recv_idx: usize, command: &[u8]) {
-        
-        let req = RaftClientRequest::new(
-            command.to_vec(),
-        );
+        let target = match self.raft_cluster_member.get(recv_idx) {
+            Some(member) => member.to_owned(),
+            None => {
+                warn!("RaftClientContext::send_request no member {}", recv_idx);
+                return;
+            }
+        };
+
+        let request = RaftClientRequest::new(0, 0, command.to_vec());
+        let seq = self.seq();
+        self.send_to(request, seq, &target).await;
+    }
+
+
+    async fn call(&mut self, request: RaftClientRequest) -> Result<Vec<u8>, ClientError> {
+        // a late reply to an earlier attempt answers the request as well
+        let mut attempts = Vec::new();
+
+        for _ in 0..self.config.client_retry {
+            let target = match self.target() {
+                Some(target) => target,
+                None => return Err(ClientError::new("No cluster member to ask", DumbError)),
+            };
+
+            let seq = self.seq();
+            attempts.push(seq);
+            self.send_to(request.clone(), seq, &target).await;
+
+            match self.wait_reply(&attempts).await {
+                Outcome::Done(response) => return Ok(response),
+                Outcome::Retry => continue,
+                Outcome::Closed => return Err(ClientError::new("BDN is stopped", DumbError)),
+            }
+        }
+
+        Err(ClientError::new(
+            format!("No answer after {} attempts", self.config.client_retry),
+            DumbError
+        ))
+    }
+
+
+    async fn wait_reply(&mut self, attempts: &[u32]) -> Outcome {
+        let deadline = clock::now() + Duration::from_millis(self.config.client_timeout);
+
+        loop {
+            let left = deadline.saturating_duration_since(clock::now());
+
+            let msg = match clock::timeout(left, self.payloads.recv()).await {
+                Ok(Ok(msg)) => msg,
+                Ok(Err(_)) => return Outcome::Closed,
+                Err(_) => {
+                    // leader may be gone, ask around
+                    debug!("RaftClientContext::wait_reply timeout");
+                    self.leader = None;
+                    return Outcome::Retry;
+                }
+            };
 
+            let raft_msg = match RaftMessage::from_bytes(&msg.payload()) {
+                Ok(raft_msg) => raft_msg,
+                Err(error) => {
+                    warn!("RaftClientContext::wait_reply Decode msg error: {}", error);
+                    continue;
+                }
+            };
+
+            match raft_msg.msg() {
+                RaftMessageKind::ClientReply(reply) if attempts.contains(&reply.ack()) => {
+                    return self.request_cb(raft_msg.sender(), reply);
+                }
+                _ => debug!("RaftClientContext::wait_reply drop message from {}", raft_msg.sender()),
+            }
+        }
+    }
+
+
+    // if peer is leader, it will log it and confirm,
+    // if not, it will info the client who is leader now
+    fn request_cb(&mut self, sender: &Peer, reply: &RaftClientReply) -> Outcome {
+        match reply.response() {
+            Some(response) => {
+                self.leader = Some(reply.leader_id().to_owned());
+                Outcome::Done(response.to_vec())
+            }
+
+            // a node naming itself does not know better
+            None if reply.leader_id() == sender => {
+                self.leader = None;
+                Outcome::Retry
+            }
+
+            None => {
+                debug!("RaftClientContext::request_cb {} redirects to {}", sender, reply.leader_id());
+                self.leader = Some(reply.leader_id().to_owned());
+                Outcome::Retry
+            }
+        }
+    }
+
+
+    // the leader if known, otherwise the members in turn
+    fn target(&mut self) -> Option<Peer> {
+        if let Some(leader) = &self.leader {
+            return Some(leader.to_owned());
+        }
+
+        let member = self.raft_cluster_member.get(self.next_member)?.to_owned();
+        self.next_member = (self.next_member + 1) % self.raft_cluster_member.len();
+        Some(member)
+    }
+
+
+    async fn send_to(&mut self, request: RaftClientRequest, seq: u32, target: &Peer) {
         let raft_message = RaftMessage::new(
-            RaftMessageKind::ClientRequest(req),
-            self.seq(),
+            RaftMessageKind::ClientRequest(request),
+            seq,
             self.local_id.peer()
         );
 
@@ -49,39 +234,30 @@ impl<T: Transport, R: RelayCtl> RaftClientContext<T, R> {
                 false,
                 RelayMethodKind::ALL,
                 1,
-                20
+                15
             ).unwrap();
 
-            let mut message = OverlayMessage::new(
+            let message = OverlayMessage::new(
                 header,
                 self.local_id.peer(),
                 self.local_id.peer(),
-                &self.raft_cluster_member[recv_idx],
+                target,
                 &msg_buf
             );
 
-            if recv_idx < self.raft_cluster_member.len() {
-                self.network_handle.send_to(
-                    &self.raft_cluster_member[recv_idx], 
-                    &mut message
-                ).await
-            }
+            self.network.send_to(target, message).await;
         }
     }
 
 
-    // if peer is leader, it will log it and confirm,
-    // if not, it will info the client who is leader now
-    pub fn request_cb(&mut self) {todo!();}
-
-
     pub async fn send_test_request(&mut self, recv_idx: usize) {
-        self.send_request(recv_idx, &Self::generate_test_request()).await
+        let command = self.generate_test_request();
+        self.send_request(recv_idx, &command).await
     }
 
 
-    fn generate_test_request() -> Vec<u8> {
-        (0..PAYLOAD_MAX).map(|_| { rand::random::<u8>() }).collect()
+    fn generate_test_request(&self) -> Vec<u8> {
+        (0..self.config.payload_max).map(|_| { rand::random::<u8>() }).collect()
     }
 
 
@@ -90,4 +266,50 @@ impl<T: Transport, R: RelayCtl> RaftClientContext<T, R> {
         self.seq
     }
 
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::identity;
+    use yulong_bdn::config::BdnConfig;
+    use yulong_bdn::overlay::BDN;
+    use yulong_bdn::route_inner::impls::mlbt::MlbtRelayCtlContext;
+    use yulong_tcp::TcpContext;
+
+    #[async_std::test]
+    async fn client_retry_and_redirect() {
+        let me = identity();
+        let bdn = BDN::<TcpContext, MlbtRelayCtlContext>::new(me.clone(), BdnConfig::default());
+        let (network, payloads) = bdn.spawn();
+
+        // members have no address, nothing ever answers
+        let members = vec![identity().peer().to_owned(), identity().peer().to_owned()];
+        let config = RaftConfig { client_timeout: 20, client_retry: 3, ..RaftConfig::default() };
+        let mut client = RaftClientContext::new(network, payloads, me, members.clone(), config);
+
+        client.send_request(members.len(), b"x").await;
+        // writes not waited for stay out of the session
+        client.send_request(0, b"x").await;
+        assert!(client.submit(b"x").await.is_err());
+        assert_eq!(client.client_seq, 1);
+        assert_eq!(client.next_member, 1);
+        assert_eq!(client.leader(), None);
+
+        // a refusal points at the leader, the answer confirms it
+        let leader = members[1].clone();
+        let redirect = RaftClientReply::new(1, leader.clone(), None);
+        assert!(matches!(client.request_cb(&members[0], &redirect), Outcome::Retry));
+        assert_eq!(client.target(), Some(leader.clone()));
+
+        let answer = RaftClientReply::new(2, leader.clone(), Some(b"ok".to_vec()));
+        assert!(matches!(client.request_cb(&leader, &answer), Outcome::Done(r) if r == b"ok"));
+        assert_eq!(client.leader(), Some(&leader));
+
+        // a node that names itself knows no leader
+        let unknown = RaftClientReply::new(3, leader.clone(), None);
+        assert!(matches!(client.request_cb(&leader, &unknown), Outcome::Retry));
+        assert_eq!(client.leader(), None);
+    }
+}
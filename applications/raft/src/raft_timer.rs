@@ -1,16 +1,19 @@
 use std::collections::HashMap;
 use std::ops::Deref;
+use std::time::{Duration, Instant};
 
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 
 use yulong::utils::CasualTimer;
-use crate::config::HEARTBEAT_INV;
-use crate::config::ELECTION_INV_LOW;
-use crate::config::ELECTION_INV_HIGH;
+use yulong::utils::clock;
+use yulong_network::identity::Peer;
+use crate::config::RaftConfig;
 
 #[derive(Clone)]
 pub struct WaitState {
     wait_timer: CasualTimer,
+    sent: Instant,
     inner: WaitStateData,
 }
 
@@ -26,31 +29,60 @@ impl Deref for WaitState {
 
 
 impl WaitState {
+
+    pub fn new(inner: WaitStateData, timeout: u64) -> Self {
+        let mut wait_timer = CasualTimer::new(timeout as u128);
+        wait_timer.set_now();
+        Self { wait_timer, sent: clock::now(), inner }
+    }
+
+
+    // when the request was sent
+    pub fn sent(&self) -> Instant {
+        self.sent
+    }
+
+
     fn is_timeout(&self) -> bool {
         self.wait_timer.is_timeout()
     }
 }
 
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum WaitStateData {
-    ApplyEntries(u64, u64),     // next_idx, len of entries
+    ApplyEntries(Peer, u64, u64),     // follower, next_idx, len of entries
+    InstallSnapshot(Peer, u64, u64, u64),   // follower, snapshot idx, offset, len of chunk
 }
 
 pub struct RaftTimer {
     heartbeat_timer: CasualTimer,
     election_timer: Option<CasualTimer>,
 
-    replys: HashMap<u32, WaitState>
+    replys: HashMap<u32, WaitState>,
+
+    // last time a leader was heard from
+    leader_seen: Option<Instant>,
+
+    election_inv_low: u128,
+    election_inv_high: u128,
+    election_rng: StdRng,
 }
 
 impl RaftTimer {
 
-    pub fn new() -> Self {
+    pub fn new(config: &RaftConfig) -> Self {
         Self {
-            heartbeat_timer: CasualTimer::new(HEARTBEAT_INV),
+            heartbeat_timer: CasualTimer::new(config.heartbeat_inv as u128),
             election_timer: None,
-            replys: HashMap::new()
+            replys: HashMap::new(),
+            leader_seen: None,
+            election_inv_low: config.election_inv_low as u128,
+            election_inv_high: config.election_inv_high as u128,
+            election_rng: match config.election_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
         }
     }
 
@@ -62,8 +94,7 @@ impl RaftTimer {
 
     pub fn start_election_timer(&mut self) {
 
-        let mut rng = rand::thread_rng();
-        let randomized_timeout = rng.gen_range(ELECTION_INV_LOW..ELECTION_INV_HIGH);
+        let randomized_timeout = self.election_rng.gen_range(self.election_inv_low..self.election_inv_high);
 
         let mut election_timer = CasualTimer::new(randomized_timeout);
         election_timer.set_now();
@@ -72,6 +103,19 @@ impl RaftTimer {
     }
 
 
+    pub fn see_leader(&mut self) {
+        self.leader_seen = Some(clock::now());
+    }
+
+
+    // heard from a leader within the shortest election timeout, so no
+    // election may be needed yet
+    pub fn leader_recent(&self) -> bool {
+        let timeout = Duration::from_millis(self.election_inv_low as u64);
+        self.leader_seen.map_or(false, |seen| clock::elapsed(seen) < timeout)
+    }
+
+
     pub fn is_election_timeout(&mut self) -> bool {
         if let Some(election_timer) = &self.election_timer {
             election_timer.is_timeout()
@@ -91,4 +135,27 @@ impl RaftTimer {
         self.replys.insert(id, data.to_owned())
     }
 
+
+    // id of some outstanding request matching pred
+    pub fn find_wait_data<F: Fn(&WaitStateData) -> bool>(&self, pred: F) -> Option<u32> {
+        self.replys.iter().find(|(_, state)| pred(state)).map(|(id, _)| *id)
+    }
+
+
+    // a reply arrived, stop waiting for it
+    pub fn take_wait_data_by_id(&mut self, id: u32) -> Option<WaitState> {
+        self.replys.remove(&id)
+    }
+
+
+    // forget requests that were not answered in time
+    pub fn expire_wait_data(&mut self) -> Vec<WaitState> {
+        let expired: Vec<u32> = self.replys.iter()
+            .filter(|(_, state)| state.is_timeout())
+            .map(|(id, _)| *id)
+            .collect();
+
+        expired.iter().filter_map(|id| self.replys.remove(id)).collect()
+    }
+
 }
\ No newline at end of file
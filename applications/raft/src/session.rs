@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+
+use prost::Message;
+
+use yulong::error::DeserializeError;
+
+use crate::raft_message;
+
+
+// latest write applied for a client
+struct Session {
+    seq: u64,
+    response: Vec<u8>,
+}
+
+
+/// Latest write applied for each client, so that a retried write is
+/// answered from here instead of being applied again. Sessions are part of
+/// the replicated state and go into snapshots with the state machine.
+pub(crate) struct Sessions {
+    sessions: HashMap<u64, Session>,
+}
+
+
+impl Sessions {
+
+    pub(crate) fn new() -> Self {
+        Self { sessions: HashMap::new() }
+    }
+
+
+    /// Response of a write that was applied already. A client waits for
+    /// each write before the next, so only the latest response is kept,
+    /// older retries get an empty one.
+    pub(crate) fn applied(&self, client_id: u64, seq: u64) -> Option<Vec<u8>> {
+        match self.sessions.get(&client_id) {
+            Some(session) if client_id != 0 && seq == session.seq => Some(session.response.clone()),
+            Some(session) if client_id != 0 && seq < session.seq => Some(Vec::new()),
+            _ => None,
+        }
+    }
+
+
+    pub(crate) fn record(&mut self, client_id: u64, seq: u64, response: &[u8]) {
+        if client_id != 0 {
+            self.sessions.insert(client_id, Session { seq, response: response.to_vec() });
+        }
+    }
+
+
+    /// Snapshot data made of the state machine snapshot and the sessions.
+    pub(crate) fn pack(&self, state: Vec<u8>) -> Vec<u8> {
+        let proto_message = raft_message::SnapshotData {
+            state,
+            sessions: self.sessions.iter().map(|(id, session)| raft_message::ClientSession {
+                client_id: *id,
+                client_seq: session.seq,
+                response: session.response.to_owned(),
+            }).collect(),
+        };
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        // buf has enough capacity, safe unwrap
+        proto_message.encode(&mut buf).unwrap();
+        buf
+    }
+
+
+    /// Split snapshot data into the sessions and the state machine snapshot.
+    pub(crate) fn unpack(data: &[u8]) -> Result<(Self, Vec<u8>), DeserializeError> {
+        let m = raft_message::SnapshotData::decode(data)
+            .map_err(|e| DeserializeError::new("Deserialize SnapshotData", e))?;
+
+        let sessions = m.sessions.into_iter()
+            .map(|s| (s.client_id, Session { seq: s.client_seq, response: s.response }))
+            .collect();
+
+        Ok((Self { sessions }, m.state))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn session_dedup_and_pack() {
+        let mut sessions = Sessions::new();
+        assert_eq!(sessions.applied(7, 1), None);
+
+        sessions.record(7, 1, b"one");
+        sessions.record(7, 2, b"two");
+        assert_eq!(sessions.applied(7, 2), Some(b"two".to_vec()));
+        assert_eq!(sessions.applied(7, 1), Some(vec![]));
+        assert_eq!(sessions.applied(7, 3), None);
+
+        // anonymous writes are not tracked
+        sessions.record(0, 1, b"x");
+        assert_eq!(sessions.applied(0, 1), None);
+
+        let (restored, state) = Sessions::unpack(&sessions.pack(b"state".to_vec())).unwrap();
+        assert_eq!(state, b"state");
+        assert_eq!(restored.applied(7, 2), Some(b"two".to_vec()));
+        assert!(Sessions::unpack(&[0xff]).is_err());
+    }
+}
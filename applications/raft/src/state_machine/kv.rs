@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+
+use log::warn;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::{FromPrimitive, ToPrimitive};
+use prost::Message;
+
+use yulong::error::{SerializeError, DeserializeError, DumbError};
+use yulong::utils::AsBytes;
+
+use crate::raft_message;
+
+use super::StateMachine;
+
+
+#[derive(FromPrimitive, ToPrimitive, PartialEq, Debug, Clone, Copy)]
+enum KvOp {
+    Put = 0,
+    Get = 1,
+    Delete = 2,
+}
+
+
+/// Command of the key-value state machine, submitted by clients as bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvCommand {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Get { key: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+
+/// Result of a KvCommand: the current value for get, the replaced or
+/// removed one for put and delete.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KvResponse {
+    value: Option<Vec<u8>>,
+}
+
+
+/// Ordered in-memory key-value store.
+pub struct KvStateMachine {
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+    applied: u64,
+}
+
+
+impl AsBytes for KvCommand {
+
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        let (op, key, value) = match self {
+            KvCommand::Put { key, value } => (KvOp::Put, key, value.to_owned()),
+            KvCommand::Get { key } => (KvOp::Get, key, vec![]),
+            KvCommand::Delete { key } => (KvOp::Delete, key, vec![]),
+        };
+
+        let proto_message = raft_message::KvCommand {
+            op: ToPrimitive::to_u32(&op).unwrap(),
+            key: key.to_owned(),
+            value,
+        };
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        let m = raft_message::KvCommand::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize KvCommand", e))?;
+
+        match FromPrimitive::from_u32(m.op) {
+            Some(KvOp::Put) => Ok(KvCommand::Put { key: m.key, value: m.value }),
+            Some(KvOp::Get) => Ok(KvCommand::Get { key: m.key }),
+            Some(KvOp::Delete) => Ok(KvCommand::Delete { key: m.key }),
+            None => Err(DeserializeError::new(format!("Unknown KvCommand op {}", m.op), DumbError)),
+        }
+    }
+}
+
+
+impl AsBytes for KvResponse {
+
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        let proto_message = raft_message::KvResponse {
+            found: self.value.is_some(),
+            value: self.value.to_owned().unwrap_or_default(),
+        };
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        raft_message::KvResponse::decode(buf)
+            .map_err(|e| DeserializeError::new("Deserialize KvResponse", e))
+            .map(|m| Self {
+                value: if m.found { Some(m.value) } else { None },
+            })
+    }
+}
+
+
+impl KvResponse {
+
+    pub fn new(value: Option<Vec<u8>>) -> Self { Self { value } }
+
+
+    /// Get a reference to the kv response's value.
+    pub fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+}
+
+
+impl KvStateMachine {
+
+    pub fn new() -> Self {
+        Self {
+            data: BTreeMap::new(),
+            applied: 0,
+        }
+    }
+
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.data.get(key).map(|v| v.as_slice())
+    }
+
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+
+    /// Index of the last applied command.
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+
+    fn execute(&mut self, command: KvCommand) -> KvResponse {
+        let value = match command {
+            KvCommand::Put { key, value } => self.data.insert(key, value),
+            KvCommand::Get { key } => self.data.get(&key).cloned(),
+            KvCommand::Delete { key } => self.data.remove(&key),
+        };
+        KvResponse::new(value)
+    }
+}
+
+
+impl StateMachine for KvStateMachine {
+
+    fn apply(&mut self, index: u64, command: &[u8]) -> Vec<u8> {
+        self.applied = index;
+
+        // a malformed command is committed like any other, every node
+        // skips it the same way
+        let response = match KvCommand::from_bytes(command) {
+            Ok(command) => self.execute(command),
+            Err(error) => {
+                warn!("KvStateMachine::apply entry {}: {}", index, error);
+                KvResponse::new(None)
+            }
+        };
+
+        response.into_bytes().unwrap()
+    }
+
+
+    fn query(&self, command: &[u8]) -> Vec<u8> {
+        let value = match KvCommand::from_bytes(command) {
+            Ok(KvCommand::Get { key }) => self.data.get(&key).cloned(),
+            Ok(command) => {
+                warn!("KvStateMachine::query {:?} is not a read", command);
+                None
+            }
+            Err(error) => {
+                warn!("KvStateMachine::query: {}", error);
+                None
+            }
+        };
+
+        KvResponse::new(value).into_bytes().unwrap()
+    }
+
+
+    fn snapshot(&self) -> Result<Vec<u8>, SerializeError> {
+        let proto_message = raft_message::KvSnapshot {
+            applied: self.applied,
+            pairs: self.data.iter().map(|(key, value)| raft_message::KvPair {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            }).collect(),
+        };
+
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf)
+            .map_err(|e| SerializeError::new("Serialize KvSnapshot", e))?;
+        Ok(buf)
+    }
+
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), DeserializeError> {
+        let m = raft_message::KvSnapshot::decode(snapshot)
+            .map_err(|e| DeserializeError::new("Deserialize KvSnapshot", e))?;
+
+        self.applied = m.applied;
+        self.data = m.pairs.into_iter().map(|pair| (pair.key, pair.value)).collect();
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn put(key: &[u8], value: &[u8]) -> Vec<u8> {
+        KvCommand::Put { key: key.to_vec(), value: value.to_vec() }.into_bytes().unwrap()
+    }
+
+    fn response(buf: &[u8]) -> Option<Vec<u8>> {
+        KvResponse::from_bytes(buf).unwrap().value().map(|v| v.to_vec())
+    }
+
+    #[test]
+    fn kv_apply_and_snapshot() {
+        let mut kv = KvStateMachine::new();
+
+        assert_eq!(response(&kv.apply(1, &put(b"a", b"1"))), None);
+        assert_eq!(response(&kv.apply(2, &put(b"a", b"2"))), Some(b"1".to_vec()));
+        assert_eq!(response(&kv.apply(3, &put(b"b", b"3"))), None);
+
+        let get = KvCommand::Get { key: b"a".to_vec() }.into_bytes().unwrap();
+        assert_eq!(response(&kv.apply(4, &get)), Some(b"2".to_vec()));
+        assert_eq!(response(&kv.query(&get)), Some(b"2".to_vec()));
+
+        // writes are not served as reads
+        assert_eq!(response(&kv.query(&put(b"a", b"9"))), None);
+        assert_eq!(kv.get(b"a"), Some(&b"2"[..]));
+
+        let delete = KvCommand::Delete { key: b"b".to_vec() }.into_bytes().unwrap();
+        assert_eq!(response(&kv.apply(5, &delete)), Some(b"3".to_vec()));
+
+        // garbage is applied as a no-op
+        assert_eq!(response(&kv.apply(6, &[0xff, 0xff])), None);
+        assert_eq!(kv.len(), 1);
+        assert_eq!(kv.applied(), 6);
+
+        let snapshot = kv.snapshot().unwrap();
+        let mut restored = KvStateMachine::new();
+        restored.apply(1, &put(b"c", b"4"));
+        restored.restore(&snapshot).unwrap();
+
+        assert_eq!(restored.get(b"a"), Some(&b"2"[..]));
+        assert_eq!(restored.get(b"c"), None);
+        assert_eq!(restored.applied(), 6);
+        assert!(restored.restore(&[0xff]).is_err());
+    }
+}
@@ -0,0 +1,28 @@
+mod kv;
+
+pub use kv::{KvCommand, KvResponse, KvStateMachine};
+
+use yulong::error::{SerializeError, DeserializeError};
+
+
+/// Application state replicated by Raft.
+///
+/// Every node applies the same committed commands in the same order, so
+/// apply must be deterministic: its result may only depend on the state
+/// and the command.
+pub trait StateMachine: Send {
+
+    /// Apply the command committed at index, the returned bytes are sent
+    /// back to the client that submitted it.
+    fn apply(&mut self, index: u64, command: &[u8]) -> Vec<u8>;
+
+    /// Answer a read-only command against the current state, which must
+    /// not change.
+    fn query(&self, command: &[u8]) -> Vec<u8>;
+
+    /// Serialize the whole state, covering every command applied so far.
+    fn snapshot(&self) -> Result<Vec<u8>, SerializeError>;
+
+    /// Replace the state with one produced by snapshot.
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), DeserializeError>;
+}
@@ -3,4 +3,75 @@ mod test {
 
     use super::*;
     use yulong::log;
-}
\ No newline at end of file
+
+    use std::time::Duration;
+
+    use yulong::utils::AsBytes;
+    use yulong_bdn::sim::{drive, listening, sim_node, Turn};
+    use yulong_network::identity::{Me, Peer};
+    use yulong_sim::{seed_from_env, LinkConfig, SimNetwork};
+
+    use crate::config::RaftConfig;
+    use crate::raft::RaftContext;
+    use crate::raft_client::RaftClientContext;
+    use crate::state_machine::{KvCommand, KvResponse, KvStateMachine};
+
+    fn put(key: &[u8], value: &[u8]) -> Vec<u8> {
+        KvCommand::Put { key: key.to_vec(), value: value.to_vec() }.into_bytes().unwrap()
+    }
+
+    // value replaced by a put
+    fn replaced(response: Vec<u8>) -> Option<Vec<u8>> {
+        KvResponse::from_bytes(&response).unwrap().value().map(|value| value.to_vec())
+    }
+
+    #[test]
+    fn sim_cluster_replicates() {
+        let net = SimNetwork::new(seed_from_env());
+        net.set_default_link(LinkConfig {
+            latency: Duration::from_millis(2),
+            jitter: Duration::from_millis(3),
+            ..LinkConfig::default()
+        });
+
+        let members: Vec<Me> = (0..3).map(|_| net.identity()).collect();
+        let client_id = net.identity();
+        let everyone = [&members[..], std::slice::from_ref(&client_id)].concat();
+        for id in everyone.iter() {
+            net.add_node(id);
+        }
+        let peers: Vec<Peer> = members.iter().map(|id| id.peer().to_owned()).collect();
+
+        net.block_on(async {
+            let mut nodes: Vec<Turn<_>> = members.iter().enumerate().map(|(n, id)| {
+                let bdn = sim_node(&net, id, &members, &everyone);
+                let others = peers.iter().filter(|peer| *peer != id.peer()).cloned().collect();
+                let inbox = bdn.msg_sender.clone();
+
+                // elections replay along with the network
+                let config = RaftConfig { election_seed: Some(net.seed().wrapping_add(n as u64)), ..RaftConfig::default() };
+                let mut raft = RaftContext::new(bdn, others, Box::new(KvStateMachine::new()), config).unwrap();
+                (inbox, Box::new(move || { raft.next(); }) as Box<dyn FnMut()>)
+            }).collect();
+
+            let (network, payloads) = sim_node(&net, &client_id, &members, &everyone).spawn();
+            let config = RaftConfig { client_timeout: 1000, client_retry: 20, ..RaftConfig::default() };
+            let mut client = RaftClientContext::new(network, payloads, client_id.clone(), peers.clone(), config);
+            listening(&net, &everyone).await;
+
+            drive(&net, &mut nodes, async {
+                assert_eq!(replaced(client.submit(&put(b"k", b"1")).await.unwrap()), None);
+                assert_eq!(replaced(client.submit(&put(b"k", b"2")).await.unwrap()), Some(b"1".to_vec()));
+            }).await;
+
+            // a majority goes on without a follower, the root of the tree stays
+            let leader = client.leader().cloned().unwrap();
+            let follower = peers.iter().skip(1).find(|peer| **peer != leader).cloned().unwrap();
+            net.partition(&[vec![follower]]);
+
+            drive(&net, &mut nodes, async {
+                assert_eq!(replaced(client.submit(&put(b"k", b"3")).await.unwrap()), Some(b"2".to_vec()));
+            }).await;
+        });
+    }
+}
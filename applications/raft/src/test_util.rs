@@ -0,0 +1,11 @@
+//! Fixtures shared by the tests of this crate.
+
+use yulong_network::identity::Me;
+use yulong_network::identity::crypto::{PublicKey, PrivateKey, Signer};
+use yulong_network::identity::crypto::ed25519_signer::Ed25519Signer;
+
+
+pub(crate) fn identity() -> Me {
+    let (pk, sk) = Ed25519Signer::new().keygen();
+    Me::from_keypair(PublicKey::Ed25519(pk), PrivateKey::Ed25519(sk))
+}
@@ -1,5 +1,5 @@
 use futures::{AsyncWriteExt};
-use yulong_network::{transport::{Transport}};
+use yulong_network::{transport::{Transport}, identity::Me};
 use yulong_tcp::TcpContext;
 use yulong_quic::QuicContext;
 use std::{error::Error, net::SocketAddr, str::FromStr};
@@ -14,8 +14,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
 async fn client<T: Transport>() {
     
-    let mut stream = T::connect(
-        &SocketAddr::from_str("127.0.0.1:9001").ok().unwrap()
+    let local = Me::new();
+    let (mut stream, _) = T::connect(
+        &SocketAddr::from_str("127.0.0.1:9001").ok().unwrap(),
+        &local
     ).await.ok().unwrap();
 
     let buf :[u8; 5] = [1,2,3,4,5];
@@ -1,6 +1,6 @@
 use std::{error::Error, net::SocketAddr, str::FromStr};
 use futures::{AsyncReadExt};
-use yulong_network::{transport::{IngressStream, Transport}};
+use yulong_network::{transport::{IngressStream, Transport}, identity::Me};
 use yulong_tcp::TcpContext;
 use yulong_quic::QuicContext;
 
@@ -19,11 +19,19 @@ async fn server<T: Transport>() {
             &SocketAddr::from_str("0.0.0.0:9001").ok().unwrap()
         ).await.ok().unwrap();
 
+    let local = Me::new();
+
     loop {
-        match T::accept(&mut listener).await {
-            Ok(stream) => {
+        match T::accept(&mut listener, &local).await {
+            Ok(incoming) => {
+                let local = local.clone();
                 task::spawn(
-                    async move {connection::<T>(stream).await}
+                    async move {
+                        match T::upgrade(incoming, &local).await.ok() {
+                            Some(stream) => connection::<T>(stream).await,
+                            None => println!("Handshake error."),
+                        }
+                    }
                 );
             }
             Err(_) => {println!("Connection error.");}
@@ -35,7 +43,7 @@ async fn connection<'a, T: Transport> (stream: IngressStream<<T as Transport>::S
     
     let mut accepted_stream = stream.stream;
     let remote_addr = stream.remote_addr;
-    println!("Connected by {:?}", remote_addr);
+    println!("Connected by {:?}, {}", remote_addr, stream.remote_peer);
 
     let mut buf = [0u8; 2048];
 
@@ -63,4 +63,35 @@ impl Display for TryfromSliceError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "TryfromSliceError error: {}", self.describe)
     }
-}
\ No newline at end of file
+}
+
+/// Errors happened loading or saving a keystore
+#[derive(Debug)]
+pub struct KeystoreError {
+    describe: String,
+    boxed_error: Box<dyn Error>
+}
+
+
+impl KeystoreError {
+    pub fn new<S: ToString>(des: S, err: impl Error + 'static) -> Self {
+        Self {
+            describe: des.to_string(),
+            boxed_error: Box::new(err)
+        }
+    }
+}
+
+
+impl Error for KeystoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+
+impl Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Keystore error: {}", self.describe)
+    }
+}
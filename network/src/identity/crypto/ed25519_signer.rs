@@ -0,0 +1,167 @@
+use std::convert::TryFrom;
+
+use ed25519_dalek;
+use rand::Rng;
+use yulong::utils::AsBytes;
+use yulong::error::{DumbError, SerializeError, DeserializeError};
+use crate::identity::crypto::Signer;
+
+use super::{GenericSigner, PrivateKey, PublicKey};
+
+pub struct Ed25519Signer {}
+
+impl Ed25519Signer {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+#[derive(Clone)]
+pub struct Ed25519PubKey {
+    pk: ed25519_dalek::PublicKey
+}
+
+pub struct Ed25519SecKey {
+    sk: ed25519_dalek::SecretKey
+}
+
+pub struct Ed25519Sig {
+    sig: ed25519_dalek::Signature
+}
+
+// dalek does not clone secret keys on purpose
+impl Clone for Ed25519SecKey {
+    fn clone(&self) -> Self {
+        Self {
+            // bytes come from a valid key, safe unwrap
+            sk: ed25519_dalek::SecretKey::from_bytes(self.sk.as_bytes()).unwrap()
+        }
+    }
+}
+
+impl AsBytes for Ed25519PubKey {
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        Ok(self.pk.to_bytes().to_vec())
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        ed25519_dalek::PublicKey::from_bytes(buf)
+            .map(|pk| Self{pk})
+            .map_err(|e| DeserializeError::new("Load ed25519 pubkey error", e))
+    }
+}
+
+impl AsBytes for Ed25519SecKey {
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        Ok(self.sk.to_bytes().to_vec())
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        ed25519_dalek::SecretKey::from_bytes(buf)
+            .map(|sk| Self{sk})
+            .map_err(|e| DeserializeError::new("Load ed25519 seckey error", e))
+    }
+}
+
+impl AsBytes for Ed25519Sig {
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        Ok(self.sig.to_bytes().to_vec())
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        match ed25519_dalek::Signature::try_from(buf) {
+            Ok(sig) => {
+                Ok(Self {sig})
+            }
+
+            Err(_) => {
+                Err(DeserializeError::new("Load ed25519 signature failed", DumbError))
+            }
+        }
+    }
+}
+
+impl Ed25519SecKey {
+    pub fn public_key(&self) -> Ed25519PubKey {
+        Ed25519PubKey {
+            pk: ed25519_dalek::PublicKey::from(&self.sk)
+        }
+    }
+}
+
+impl Signer for Ed25519Signer {
+
+    type PK = Ed25519PubKey;
+
+    type SK = Ed25519SecKey;
+
+    type SIG = Ed25519Sig;
+
+    fn keygen(&self) -> (Self::PK, Self::SK) {
+        // dalek wants an older rand_core, feed it random bytes instead
+        let seed = rand::thread_rng().gen::<[u8; ed25519_dalek::SECRET_KEY_LENGTH]>();
+        let sk = Self::SK {
+            sk: ed25519_dalek::SecretKey::from_bytes(&seed).unwrap()
+        };
+        (sk.public_key(), sk)
+    }
+
+    fn sign(&self, msg: &[u8], sk: &Self::SK, pk: &Self::PK) -> Self::SIG {
+        let expanded = ed25519_dalek::ExpandedSecretKey::from(&sk.sk);
+        Self::SIG {
+            sig: expanded.sign(msg, &pk.pk)
+        }
+    }
+
+    fn verify(&self, msg: &[u8], pk: &Self::PK, sig: &Self::SIG) -> bool {
+        pk.pk.verify_strict(msg, &sig.sig).is_ok()
+    }
+}
+
+
+impl GenericSigner for Ed25519Signer {
+    fn sign(&self, msg: &[u8], sk: &PrivateKey, pk: &PublicKey) -> Option<Self::SIG> {
+        match (sk, pk) {
+            (PrivateKey::Ed25519(sk), PublicKey::Ed25519(pk)) => {
+                Some(Signer::sign(self, msg, sk, pk))
+            }
+            _ => None
+        }
+    }
+
+    fn verify(&self, msg: &[u8], pk: &PublicKey, sig: &Self::SIG) -> bool {
+        match pk {
+            PublicKey::Ed25519(pk) => Signer::verify(self, msg, pk, sig),
+            _ => false
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ed25519_sign_verify() {
+        let signer = Ed25519Signer::new();
+        let (pk, sk) = signer.keygen();
+
+        let sig = Signer::sign(&signer, b"msg", &sk, &pk);
+        assert!(Signer::verify(&signer, b"msg", &pk, &sig));
+        assert!(!Signer::verify(&signer, b"msg2", &pk, &sig));
+
+        let sig = Ed25519Sig::from_bytes(&sig.into_bytes().unwrap()).unwrap();
+        let pk = Ed25519PubKey::from_bytes(&pk.into_bytes().unwrap()).unwrap();
+        assert!(Signer::verify(&signer, b"msg", &pk, &sig));
+    }
+
+    #[test]
+    fn ed25519_key_serde() {
+        let signer = Ed25519Signer::new();
+        let (pk, sk) = signer.keygen();
+
+        let sk2 = Ed25519SecKey::from_bytes(&sk.into_bytes().unwrap()).unwrap();
+        assert_eq!(sk2.public_key().into_bytes().unwrap(), pk.into_bytes().unwrap());
+    }
+}
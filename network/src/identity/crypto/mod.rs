@@ -1,11 +1,15 @@
 #![allow(unused_variables)]
 pub mod sm_signer;
+pub mod sm_cipher;
+pub mod ed25519_signer;
+pub mod secp256k1_signer;
 
-use libsm::sm2::signature::Pubkey;
 use yulong::error::{DeserializeError, SerializeError, DumbError};
 use yulong::utils::AsBytes;
 
-use sm_signer::{SmPubKey, SmSecKey};
+use sm_signer::{SmPubKey, SmSecKey, SmSig, SmSigner};
+use ed25519_signer::{Ed25519PubKey, Ed25519SecKey, Ed25519Sig, Ed25519Signer};
+use secp256k1_signer::{Secp256k1PubKey, Secp256k1SecKey, Secp256k1Sig, Secp256k1Signer};
 use crate::peer_id;
 use prost::Message;
 
@@ -24,14 +28,20 @@ pub trait Signer: 'static + Send {
 }
 
 
+/// Signer working on the enum-level keys carried by Me and Peer.
+///
+/// sign returns None if the keys do not belong to this signer, verify 
+/// returns false in that case.
 pub trait GenericSigner: Signer {
-    fn sign(&self, msg: &[u8], sk: &PrivateKey, pk: &PublicKey) -> Self::SIG;
-    fn verify(&self, msg: &[u8], pk: &Pubkey, sig: &Self::SIG) -> bool;
+    fn sign(&self, msg: &[u8], sk: &PrivateKey, pk: &PublicKey) -> Option<Self::SIG>;
+    fn verify(&self, msg: &[u8], pk: &PublicKey, sig: &Self::SIG) -> bool;
 }
 
 #[derive(Clone)]
 pub enum PublicKey {
     SM2(SmPubKey),
+    Ed25519(Ed25519PubKey),
+    Secp256k1(Secp256k1PubKey),
     NoKey
 }
 
@@ -49,6 +59,20 @@ impl AsBytes for PublicKey {
                 }
             }
 
+            PublicKey::Ed25519(key) => {
+                peer_id::PublicKey {
+                    r#type: peer_id::CryptoType::Ed25519 as i32,
+                    data: key.into_bytes().unwrap()
+                }
+            }
+
+            PublicKey::Secp256k1(key) => {
+                peer_id::PublicKey {
+                    r#type: peer_id::CryptoType::Secp256k1 as i32,
+                    data: key.into_bytes().unwrap()
+                }
+            }
+
             PublicKey::NoKey => {
                 peer_id::PublicKey {
                     r#type: peer_id::CryptoType::Unknown as i32,
@@ -83,6 +107,14 @@ impl AsBytes for PublicKey {
                 }
             }
 
+            peer_id::CryptoType::Ed25519 => {
+                Ed25519PubKey::from_bytes(&key.data).map(PublicKey::Ed25519)
+            }
+
+            peer_id::CryptoType::Secp256k1 => {
+                Secp256k1PubKey::from_bytes(&key.data).map(PublicKey::Secp256k1)
+            }
+
             peer_id::CryptoType::Unknown => {
                 Ok(PublicKey::NoKey)
             }
@@ -91,13 +123,137 @@ impl AsBytes for PublicKey {
 
 }
 
+
+impl PublicKey {
+
+    /// Verify sig with the signer matching this key type. NoKey never verifies.
+    pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
+        match self {
+            PublicKey::SM2(_) => {
+                SmSig::from_bytes(sig)
+                    .map(|sig| GenericSigner::verify(&SmSigner::new(), msg, self, &sig))
+                    .unwrap_or(false)
+            }
+
+            PublicKey::Ed25519(_) => {
+                Ed25519Sig::from_bytes(sig)
+                    .map(|sig| GenericSigner::verify(&Ed25519Signer::new(), msg, self, &sig))
+                    .unwrap_or(false)
+            }
+
+            PublicKey::Secp256k1(_) => {
+                Secp256k1Sig::from_bytes(sig)
+                    .map(|sig| GenericSigner::verify(&Secp256k1Signer::new(), msg, self, &sig))
+                    .unwrap_or(false)
+            }
+
+            PublicKey::NoKey => false
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum PrivateKey {
     SM2(SmSecKey),
+    Ed25519(Ed25519SecKey),
+    Secp256k1(Secp256k1SecKey),
     NoKey
 }
 
-impl PrivateKey {}
+impl AsBytes for PrivateKey {
+
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        let proto_message = match self {
+
+            PrivateKey::SM2(key) => {
+                peer_id::PrivateKey {
+                    r#type: peer_id::CryptoType::Sm2 as i32,
+                    data: key.into_bytes()?
+                }
+            }
+
+            PrivateKey::Ed25519(key) => {
+                peer_id::PrivateKey {
+                    r#type: peer_id::CryptoType::Ed25519 as i32,
+                    data: key.into_bytes()?
+                }
+            }
+
+            PrivateKey::Secp256k1(key) => {
+                peer_id::PrivateKey {
+                    r#type: peer_id::CryptoType::Secp256k1 as i32,
+                    data: key.into_bytes()?
+                }
+            }
+
+            PrivateKey::NoKey => {
+                peer_id::PrivateKey {
+                    r#type: peer_id::CryptoType::Unknown as i32,
+                    data: vec![]
+                }
+            }
+        };
+        let mut buf = Vec::with_capacity(proto_message.encoded_len());
+        proto_message.encode(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+
+        let key = peer_id::PrivateKey::decode(bytes)
+            .map_err(|e| DeserializeError::new("Deserialize private key", e))?;
+
+        let key_type = peer_id::CryptoType::from_i32(key.r#type).ok_or_else(
+            || DeserializeError::new("Deserialize private key, unknown type", DumbError)
+        )?;
+
+        match key_type {
+            peer_id::CryptoType::Sm2 => {
+                SmSecKey::from_bytes(&key.data).map(PrivateKey::SM2)
+            }
+
+            peer_id::CryptoType::Ed25519 => {
+                Ed25519SecKey::from_bytes(&key.data).map(PrivateKey::Ed25519)
+            }
+
+            peer_id::CryptoType::Secp256k1 => {
+                Secp256k1SecKey::from_bytes(&key.data).map(PrivateKey::Secp256k1)
+            }
+
+            peer_id::CryptoType::Unknown => {
+                Ok(PrivateKey::NoKey)
+            }
+        }
+    }
+
+}
+
+
+impl PrivateKey {
+
+    /// Sign with the signer matching this key type, the encoded signature is
+    /// returned. None if pk is not the same key type.
+    pub fn sign(&self, msg: &[u8], pk: &PublicKey) -> Option<Vec<u8>> {
+        match self {
+            PrivateKey::SM2(_) => {
+                GenericSigner::sign(&SmSigner::new(), msg, self, pk)
+                    .map(|sig| sig.into_bytes().unwrap())
+            }
+
+            PrivateKey::Ed25519(_) => {
+                GenericSigner::sign(&Ed25519Signer::new(), msg, self, pk)
+                    .map(|sig| sig.into_bytes().unwrap())
+            }
+
+            PrivateKey::Secp256k1(_) => {
+                GenericSigner::sign(&Secp256k1Signer::new(), msg, self, pk)
+                    .map(|sig| sig.into_bytes().unwrap())
+            }
+
+            PrivateKey::NoKey => None
+        }
+    }
+}
 
 // placeholder Signer, do nothing and cannot be called
 // Todo: find a better way to express Optional Generics
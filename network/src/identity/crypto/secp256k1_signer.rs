@@ -0,0 +1,151 @@
+use std::convert::TryFrom;
+
+use k256::ecdsa::{self, signature::{Signer as _, Verifier as _}};
+use yulong::utils::AsBytes;
+use yulong::error::{SerializeError, DeserializeError};
+use crate::identity::crypto::Signer;
+
+use super::{GenericSigner, PrivateKey, PublicKey};
+
+/// ECDSA over secp256k1 with SHA-256, signatures are the fixed 64 bytes r || s
+pub struct Secp256k1Signer {}
+
+impl Secp256k1Signer {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+#[derive(Clone)]
+pub struct Secp256k1PubKey {
+    pk: ecdsa::VerifyingKey
+}
+
+#[derive(Clone)]
+pub struct Secp256k1SecKey {
+    sk: ecdsa::SigningKey
+}
+
+pub struct Secp256k1Sig {
+    sig: ecdsa::Signature
+}
+
+// public keys are stored as compressed sec1 points
+impl AsBytes for Secp256k1PubKey {
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        Ok(self.pk.to_bytes().to_vec())
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        ecdsa::VerifyingKey::from_sec1_bytes(buf)
+            .map(|pk| Self{pk})
+            .map_err(|e| DeserializeError::new("Load secp256k1 pubkey error", e))
+    }
+}
+
+impl AsBytes for Secp256k1SecKey {
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        Ok(self.sk.to_bytes().to_vec())
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        ecdsa::SigningKey::from_bytes(buf)
+            .map(|sk| Self{sk})
+            .map_err(|e| DeserializeError::new("Load secp256k1 seckey error", e))
+    }
+}
+
+impl AsBytes for Secp256k1Sig {
+    fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
+        Ok(self.sig.as_ref().to_vec())
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DeserializeError> {
+        ecdsa::Signature::try_from(buf)
+            .map(|sig| Self{sig})
+            .map_err(|e| DeserializeError::new("Load secp256k1 signature failed", e))
+    }
+}
+
+impl Secp256k1SecKey {
+    pub fn public_key(&self) -> Secp256k1PubKey {
+        Secp256k1PubKey {
+            pk: self.sk.verify_key()
+        }
+    }
+}
+
+impl Signer for Secp256k1Signer {
+
+    type PK = Secp256k1PubKey;
+
+    type SK = Secp256k1SecKey;
+
+    type SIG = Secp256k1Sig;
+
+    fn keygen(&self) -> (Self::PK, Self::SK) {
+        let sk = Self::SK {
+            sk: ecdsa::SigningKey::random(rand::rngs::OsRng)
+        };
+        (sk.public_key(), sk)
+    }
+
+    fn sign(&self, msg: &[u8], sk: &Self::SK, pk: &Self::PK) -> Self::SIG {
+        Self::SIG {
+            sig: sk.sk.sign(msg)
+        }
+    }
+
+    fn verify(&self, msg: &[u8], pk: &Self::PK, sig: &Self::SIG) -> bool {
+        pk.pk.verify(msg, &sig.sig).is_ok()
+    }
+}
+
+
+impl GenericSigner for Secp256k1Signer {
+    fn sign(&self, msg: &[u8], sk: &PrivateKey, pk: &PublicKey) -> Option<Self::SIG> {
+        match (sk, pk) {
+            (PrivateKey::Secp256k1(sk), PublicKey::Secp256k1(pk)) => {
+                Some(Signer::sign(self, msg, sk, pk))
+            }
+            _ => None
+        }
+    }
+
+    fn verify(&self, msg: &[u8], pk: &PublicKey, sig: &Self::SIG) -> bool {
+        match pk {
+            PublicKey::Secp256k1(pk) => Signer::verify(self, msg, pk, sig),
+            _ => false
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secp256k1_sign_verify() {
+        let signer = Secp256k1Signer::new();
+        let (pk, sk) = signer.keygen();
+
+        let sig = Signer::sign(&signer, b"msg", &sk, &pk);
+        assert!(Signer::verify(&signer, b"msg", &pk, &sig));
+        assert!(!Signer::verify(&signer, b"msg2", &pk, &sig));
+
+        let sig = Secp256k1Sig::from_bytes(&sig.into_bytes().unwrap()).unwrap();
+        let pk = Secp256k1PubKey::from_bytes(&pk.into_bytes().unwrap()).unwrap();
+        assert!(Signer::verify(&signer, b"msg", &pk, &sig));
+    }
+
+    #[test]
+    fn secp256k1_key_serde() {
+        let signer = Secp256k1Signer::new();
+        let (pk, sk) = signer.keygen();
+
+        let sk2 = Secp256k1SecKey::from_bytes(&sk.into_bytes().unwrap()).unwrap();
+        assert_eq!(sk2.public_key().into_bytes().unwrap(), pk.into_bytes().unwrap());
+        assert_eq!(pk.into_bytes().unwrap().len(), 33);
+    }
+}
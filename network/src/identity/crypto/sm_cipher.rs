@@ -0,0 +1,112 @@
+use libsm::{sm3, sm4};
+
+
+pub const SM4_KEY_SIZE: usize = 16;
+pub const SM4_BLOCK_SIZE: usize = 16;
+pub const SM3_HASH_SIZE: usize = 32;
+
+// hmac block size of sm3
+const SM3_BLOCK_SIZE: usize = 64;
+
+
+pub fn sm3_hash(data: &[u8]) -> [u8; SM3_HASH_SIZE] {
+    sm3::hash::Sm3Hash::new(data).get_hash()
+}
+
+
+/// HMAC (RFC 2104) instantiated with SM3.
+pub fn hmac_sm3(key: &[u8], data: &[u8]) -> [u8; SM3_HASH_SIZE] {
+    let mut block_key = [0u8; SM3_BLOCK_SIZE];
+    if key.len() > SM3_BLOCK_SIZE {
+        block_key[..SM3_HASH_SIZE].copy_from_slice(&sm3_hash(key));
+    }
+    else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Vec::with_capacity(SM3_BLOCK_SIZE + data.len());
+    inner.extend(block_key.iter().map(|b| b ^ 0x36));
+    inner.extend_from_slice(data);
+
+    let mut outer = Vec::with_capacity(SM3_BLOCK_SIZE + SM3_HASH_SIZE);
+    outer.extend(block_key.iter().map(|b| b ^ 0x5c));
+    outer.extend_from_slice(&sm3_hash(&inner));
+
+    sm3_hash(&outer)
+}
+
+
+/// PBKDF2 (RFC 8018) with HMAC-SM3 as the PRF, derives out_len bytes from
+/// a password. rounds should be large enough to make guessing expensive.
+pub fn pbkdf2_sm3(password: &[u8], salt: &[u8], rounds: u32, out_len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(out_len);
+    let mut block_idx: u32 = 1;
+
+    while out.len() < out_len {
+        let mut salted = salt.to_vec();
+        salted.extend_from_slice(&block_idx.to_be_bytes());
+
+        let mut u = hmac_sm3(password, &salted);
+        let mut t = u;
+        for _ in 1..rounds {
+            u = hmac_sm3(password, &u);
+            t.iter_mut().zip(u.iter()).for_each(|(a, b)| *a ^= b);
+        }
+
+        let take = std::cmp::min(SM3_HASH_SIZE, out_len - out.len());
+        out.extend_from_slice(&t[..take]);
+        block_idx += 1;
+    }
+    out
+}
+
+
+/// SM4 in counter mode, encryption and decryption are the same operation.
+/// A (key, iv) pair must never be used twice.
+pub fn sm4_ctr(key: &[u8; SM4_KEY_SIZE], iv: &[u8; SM4_BLOCK_SIZE], data: &[u8]) -> Vec<u8> {
+    let cipher = sm4::Cipher::new(key, sm4::Mode::Ctr);
+    cipher.encrypt(data, iv)
+}
+
+
+/// Compare two tags without an early exit.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ctr_roundtrip() {
+        let key = [7u8; SM4_KEY_SIZE];
+        let iv = [1u8; SM4_BLOCK_SIZE];
+        let plain = b"the quick brown fox jumps over the lazy dog".to_vec();
+
+        let cipher = sm4_ctr(&key, &iv, &plain);
+        assert_ne!(cipher, plain);
+        assert_eq!(sm4_ctr(&key, &iv, &cipher), plain);
+    }
+
+    #[test]
+    fn hmac_key_sensitive() {
+        let t1 = hmac_sm3(b"key1", b"data");
+        let t2 = hmac_sm3(b"key2", b"data");
+        assert!(!constant_time_eq(&t1, &t2));
+        assert!(constant_time_eq(&t1, &hmac_sm3(b"key1", b"data")));
+    }
+
+    #[test]
+    fn pbkdf2_output() {
+        let k1 = pbkdf2_sm3(b"password", b"salt", 16, 48);
+        assert_eq!(k1.len(), 48);
+        assert_eq!(k1, pbkdf2_sm3(b"password", b"salt", 16, 48));
+        assert_ne!(k1, pbkdf2_sm3(b"password", b"pepper", 16, 48));
+        assert_ne!(k1, pbkdf2_sm3(b"password", b"salt", 17, 48));
+    }
+}
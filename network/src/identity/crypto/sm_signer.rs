@@ -3,7 +3,7 @@ use yulong::utils::AsBytes;
 use yulong::error::{DumbError, SerializeError, DeserializeError};
 use crate::identity::crypto::Signer;
 
-use super::GenericSigner;
+use super::{GenericSigner, PrivateKey, PublicKey};
 
 pub struct SmSigner {
     ctx: sm2::signature::SigCtx
@@ -31,6 +31,18 @@ pub struct SmSig {
     sig: sm2::signature::Signature
 }
 
+
+impl SmSecKey {
+    /// Static Diffie-Hellman on the SM2 curve, returns the uncompressed
+    /// shared point. Both sides get the same bytes from their own secret key
+    /// and the other side's public key; hash it before use as a key.
+    pub fn key_agreement(&self, remote: &SmPubKey) -> Vec<u8> {
+        let ecc = sm2::ecc::EccCtx::new();
+        let shared = ecc.mul(&self.sk, &remote.pk);
+        ecc.point_to_bytes(&shared, false)
+    }
+}
+
 impl AsBytes for SmPubKey {
     fn into_bytes(&self) -> Result<Vec<u8>, SerializeError> {
         let cx = sm2::signature::SigCtx::new();
@@ -113,11 +125,19 @@ impl Signer for SmSigner {
 
 
 impl GenericSigner for SmSigner {
-    fn sign(&self, msg: &[u8], sk: &super::PrivateKey, pk: &super::PublicKey) -> Self::SIG {
-        todo!()
+    fn sign(&self, msg: &[u8], sk: &PrivateKey, pk: &PublicKey) -> Option<Self::SIG> {
+        match (sk, pk) {
+            (PrivateKey::SM2(sk), PublicKey::SM2(pk)) => {
+                Some(Signer::sign(self, msg, sk, pk))
+            }
+            _ => None
+        }
     }
 
-    fn verify(&self, msg: &[u8], pk: &sm2::signature::Pubkey, sig: &Self::SIG) -> bool {
-        todo!()
+    fn verify(&self, msg: &[u8], pk: &PublicKey, sig: &Self::SIG) -> bool {
+        match pk {
+            PublicKey::SM2(pk) => Signer::verify(self, msg, pk, sig),
+            _ => false
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,311 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use prost::Message;
+use rand::Rng;
+
+use yulong::utils::AsBytes;
+use yulong::error::DumbError;
+
+use crate::error::KeystoreError;
+use crate::peer_id::Keystore;
+use super::Me;
+use super::crypto::{PublicKey, PrivateKey};
+use super::crypto::sm_cipher::{self, SM4_KEY_SIZE, SM4_BLOCK_SIZE, SM3_HASH_SIZE};
+
+
+const KEYSTORE_VERSION: u32 = 1;
+
+const KDF_ROUNDS: u32 = 65536;
+const SALT_SIZE: usize = 16;
+
+// rounds accepted on load, a file could ask for a kdf that never ends
+const KDF_ROUNDS_MIN: u32 = 1 << 14;
+const KDF_ROUNDS_MAX: u32 = 1 << 22;
+
+// message signed on load to make sure the two keys belong together
+const KEYPAIR_CHECK_MSG: &[u8] = b"yulong-keystore-check";
+
+
+// encryption key followed by mac key
+fn derive_keys(password: &[u8], salt: &[u8], rounds: u32) -> ([u8; SM4_KEY_SIZE], Vec<u8>) {
+    let okm = sm_cipher::pbkdf2_sm3(password, salt, rounds, SM4_KEY_SIZE + SM3_HASH_SIZE);
+    let mut enc = [0u8; SM4_KEY_SIZE];
+    enc.copy_from_slice(&okm[..SM4_KEY_SIZE]);
+    (enc, okm[SM4_KEY_SIZE..].to_vec())
+}
+
+
+// over every field but the mac, those of variable length prefixed with it
+fn keystore_mac(mac_key: &[u8], store: &Keystore) -> [u8; SM3_HASH_SIZE] {
+    let mut data = store.version.to_le_bytes().to_vec();
+    data.extend_from_slice(&store.kdf_rounds.to_le_bytes());
+    for field in [&store.salt, &store.public_key, &store.secret_key] {
+        data.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        data.extend_from_slice(field);
+    }
+    sm_cipher::hmac_sm3(mac_key, &data)
+}
+
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+
+impl Me {
+
+    /// Load the identity stored at path, or generate a new one and save it
+    /// there if the file does not exist yet.
+    pub fn load_or_generate<P: AsRef<Path>>(path: P, password: Option<&[u8]>)
+        -> Result<Me, KeystoreError>
+    {
+        let path = path.as_ref();
+        if path.exists() {
+            return Me::load(path, password);
+        }
+
+        let me = Me::new();
+        me.save(path, password)?;
+        info!("Me::load_or_generate new identity saved to {}", path.display());
+        Ok(me)
+    }
+
+
+    /// Read a keystore written by Me::save. password must be given if the
+    /// secret key was saved encrypted.
+    pub fn load<P: AsRef<Path>>(path: P, password: Option<&[u8]>) -> Result<Me, KeystoreError> {
+        let path = path.as_ref();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Ok(meta) = fs::metadata(path) {
+                if meta.permissions().mode() & 0o077 != 0 {
+                    warn!("Me::load keystore {} is accessible by other users", path.display());
+                }
+            }
+        }
+
+        let buf = fs::read(path)
+            .map_err(|e| KeystoreError::new("Read keystore", e))?;
+
+        let store = Keystore::decode(buf.as_slice())
+            .map_err(|e| KeystoreError::new("Decode keystore", e))?;
+
+        if store.version != KEYSTORE_VERSION {
+            return Err(KeystoreError::new(
+                format!("Unsupported keystore version {}", store.version), DumbError));
+        }
+
+        let secret_key = if store.salt.is_empty() {
+            if password.is_some() {
+                warn!("Me::load keystore is not encrypted, password ignored");
+            }
+            store.secret_key
+        }
+        else {
+            let password = password.ok_or_else(
+                || KeystoreError::new("Keystore is encrypted, password required", DumbError)
+            )?;
+
+            if store.salt.len() != SALT_SIZE {
+                return Err(KeystoreError::new(
+                    format!("Bad keystore salt of {} bytes", store.salt.len()), DumbError));
+            }
+            if !(KDF_ROUNDS_MIN..=KDF_ROUNDS_MAX).contains(&store.kdf_rounds) {
+                return Err(KeystoreError::new(
+                    format!("Keystore kdf rounds {} out of range", store.kdf_rounds), DumbError));
+            }
+
+            let (enc_key, mac_key) = derive_keys(password, &store.salt, store.kdf_rounds);
+            let mac = keystore_mac(&mac_key, &store);
+            if !sm_cipher::constant_time_eq(&mac, &store.mac) {
+                return Err(KeystoreError::new("Wrong password or corrupted keystore", DumbError));
+            }
+
+            // every save uses a fresh salt, so a zero iv is never reused with the same key
+            sm_cipher::sm4_ctr(&enc_key, &[0u8; SM4_BLOCK_SIZE], &store.secret_key)
+        };
+
+        let pk = PublicKey::from_bytes(&store.public_key)
+            .map_err(|e| KeystoreError::new("Decode public key", e))?;
+        let sk = PrivateKey::from_bytes(&secret_key)
+            .map_err(|e| KeystoreError::new("Decode private key", e))?;
+
+        let matches = sk.sign(KEYPAIR_CHECK_MSG, &pk)
+            .map(|sig| pk.verify(KEYPAIR_CHECK_MSG, &sig))
+            .unwrap_or(false);
+        if !matches {
+            return Err(KeystoreError::new("Public key does not match private key", DumbError));
+        }
+
+        Ok(Me::from_keypair(pk, sk))
+    }
+
+
+    /// Write the keypair to path. The secret key is encrypted if a password is
+    /// given. The file is only readable by the owner on unix, and is replaced
+    /// atomically so a crash never leaves a half written keystore.
+    pub fn save<P: AsRef<Path>>(&self, path: P, password: Option<&[u8]>) -> Result<(), KeystoreError> {
+        let path = path.as_ref();
+
+        let public_key = self.public_key().into_bytes()
+            .map_err(|e| KeystoreError::new("Encode public key", e))?;
+        let secret_key = self.private_key().into_bytes()
+            .map_err(|e| KeystoreError::new("Encode private key", e))?;
+
+        let mut store = Keystore {
+            version: KEYSTORE_VERSION,
+            public_key,
+            secret_key,
+            salt: vec![],
+            kdf_rounds: 0,
+            mac: vec![],
+        };
+
+        if let Some(password) = password {
+            let salt = rand::thread_rng().gen::<[u8; SALT_SIZE]>().to_vec();
+            let (enc_key, mac_key) = derive_keys(password, &salt, KDF_ROUNDS);
+
+            store.secret_key = sm_cipher::sm4_ctr(&enc_key, &[0u8; SM4_BLOCK_SIZE], &store.secret_key);
+            store.salt = salt;
+            store.kdf_rounds = KDF_ROUNDS;
+            store.mac = keystore_mac(&mac_key, &store).to_vec();
+        }
+
+        let mut buf = Vec::with_capacity(store.encoded_len());
+        store.encode(&mut buf).unwrap();
+
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)
+                    .map_err(|e| KeystoreError::new("Create keystore directory", e))?;
+            }
+        }
+
+        let tmp = tmp_path(path);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(&tmp)
+            .map_err(|e| KeystoreError::new("Create keystore", e))?;
+
+        // mode only applies to newly created files, fix up a leftover tmp file
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))
+                .map_err(|e| KeystoreError::new("Set keystore permissions", e))?;
+        }
+
+        file.write_all(&buf)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| KeystoreError::new("Write keystore", e))?;
+
+        fs::rename(&tmp, path)
+            .map_err(|e| KeystoreError::new("Replace keystore", e))?;
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::identity::crypto::Signer;
+    use crate::identity::crypto::ed25519_signer::Ed25519Signer;
+
+    fn test_path(name: &str) -> PathBuf {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("yulong-keystore-{}-{}", name, rand::random::<u64>()));
+        dir.push("id.key");
+        dir
+    }
+
+    fn test_identity() -> Me {
+        let (pk, sk) = Ed25519Signer::new().keygen();
+        Me::from_keypair(PublicKey::Ed25519(pk), PrivateKey::Ed25519(sk))
+    }
+
+    #[test]
+    fn keystore_roundtrip() {
+        let path = test_path("plain");
+        let me = test_identity();
+
+        me.save(&path, None).unwrap();
+        let loaded = Me::load(&path, None).unwrap();
+        assert_eq!(loaded.peer(), me.peer());
+
+        // same id on every restart
+        let again = Me::load_or_generate(&path, None).unwrap();
+        assert_eq!(again.peer(), me.peer());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn keystore_password() {
+        let path = test_path("password");
+        let me = test_identity();
+
+        me.save(&path, Some(b"hunter2")).unwrap();
+
+        // secret key is not stored in plain text
+        let raw = fs::read(&path).unwrap();
+        let sk = me.private_key().into_bytes().unwrap();
+        assert!(!raw.windows(sk.len()).any(|w| w == sk.as_slice()));
+
+        let loaded = Me::load(&path, Some(b"hunter2")).unwrap();
+        assert_eq!(loaded.peer(), me.peer());
+
+        assert!(Me::load(&path, Some(b"hunter3")).is_err());
+        assert!(Me::load(&path, None).is_err());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn keystore_tampered() {
+        let path = test_path("tampered");
+        let me = test_identity();
+        me.save(&path, Some(b"hunter2")).unwrap();
+        let saved = Keystore::decode(fs::read(&path).unwrap().as_slice()).unwrap();
+
+        let load_with = |change: &dyn Fn(&mut Keystore)| {
+            let mut store = saved.clone();
+            change(&mut store);
+            let mut buf = Vec::new();
+            store.encode(&mut buf).unwrap();
+            fs::write(&path, buf).unwrap();
+            Me::load(&path, Some(b"hunter2"))
+        };
+
+        assert!(load_with(&|_| ()).is_ok());
+        assert!(load_with(&|store| store.kdf_rounds = u32::MAX).is_err());
+        assert!(load_with(&|store| store.kdf_rounds = 1).is_err());
+        assert!(load_with(&|store| store.kdf_rounds += 1).is_err());
+        assert!(load_with(&|store| store.salt.push(0)).is_err());
+        assert!(load_with(&|store| store.salt[0] ^= 1).is_err());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
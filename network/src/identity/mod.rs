@@ -1,4 +1,5 @@
 pub mod crypto;
+mod keystore;
 
 use libsm::sm3;
 use rand;
@@ -211,4 +212,76 @@ impl Peer {
     pub fn set_pubkey(&mut self, pubkey: &PublicKey) {
         self.pubkey = pubkey.to_owned();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::ed25519_signer::Ed25519Signer;
+    use crypto::secp256k1_signer::Secp256k1Signer;
+
+    fn assert_stable_id(pk: PublicKey) {
+        let id = Peer::from_public_key(&pk).get_id();
+
+        let decoded = PublicKey::from_bytes(&pk.into_bytes().unwrap()).unwrap();
+        assert_eq!(Peer::from_public_key(&decoded).get_id(), id);
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn stable_peer_id() {
+        let (pk, _) = Ed25519Signer::new().keygen();
+        assert_stable_id(PublicKey::Ed25519(pk));
+
+        let (pk, _) = Secp256k1Signer::new().keygen();
+        assert_stable_id(PublicKey::Secp256k1(pk));
+    }
+
+    // ids of fixed keys must never change, they are the sm3 hash of the key
+    // encoded with its type
+    #[test]
+    fn golden_peer_id() {
+        let vectors = [
+            // RFC 8032 test 1
+            (
+                "08021220",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "2aa4dacfddce35b416d725b57742a2c7e45c2224a7163ea9dc183a2d4bb41fe9",
+            ),
+            // secp256k1 generator, the key of secret 1
+            (
+                "08031221",
+                "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+                "0042fe92d822ae8b1f1aa6194150fa0ae8ee74352a87a44ef5f896a58e3d8acd",
+            ),
+        ];
+
+        for (header, key, id) in vectors {
+            let encoded = unhex(&[header, key].concat());
+            let pk = PublicKey::from_bytes(&encoded).unwrap();
+            assert_eq!(pk.into_bytes().unwrap(), encoded);
+            assert_eq!(Peer::from_public_key(&pk).get_id().to_vec(), unhex(id));
+        }
+    }
+
+    #[test]
+    fn generic_sign_verify() {
+        let (pk, sk) = Ed25519Signer::new().keygen();
+        let me = Me::from_keypair(PublicKey::Ed25519(pk), PrivateKey::Ed25519(sk));
+
+        let sig = me.private_key().sign(b"msg", me.public_key()).unwrap();
+        assert!(me.public_key().verify(b"msg", &sig));
+        assert!(!me.public_key().verify(b"other", &sig));
+
+        // key type mismatch
+        let (pk, _) = Secp256k1Signer::new().keygen();
+        let other = PublicKey::Secp256k1(pk);
+        assert!(me.private_key().sign(b"msg", &other).is_none());
+        assert!(!other.verify(b"msg", &sig));
+    }
+}
@@ -0,0 +1,235 @@
+use std::time::Duration;
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use log::warn;
+use prost::Message;
+use rand::Rng;
+
+use yulong::utils::{clock, AsBytes};
+use yulong::error::{DumbError, DeserializeError};
+
+use crate::error::TransportError;
+use crate::identity::{Me, Peer};
+use crate::identity::crypto::PublicKey;
+use crate::peer_id::{HandshakeHello, HandshakeProof};
+
+
+const CHALLENGE_SIZE: usize = 32;
+
+// both frames are tiny, anything larger is garbage
+const HANDSHAKE_MAXLEN: usize = 4096;
+
+const HANDSHAKE_DOMAIN: &[u8] = b"yulong-handshake";
+
+/// How long a peer may take to complete the handshake, a silent one would
+/// otherwise hold the stream (and a serial accept loop) forever.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// Mutually authenticate a freshly established stream.
+///
+/// Both sides send their public key (encoded as peer_id.PublicKey) together
+/// with a random challenge, then sign the challenge they received. The signed
+/// content also binds the signer's own key so a proof cannot be replayed
+/// under another identity. The verified remote Peer is returned. The whole
+/// exchange fails after HANDSHAKE_TIMEOUT.
+pub async fn handshake<S>(stream: &mut S, local: &Me) -> Result<Peer, TransportError>
+    where S: AsyncRead + AsyncWrite + Unpin + Send
+{
+    handshake_within(stream, local, HANDSHAKE_TIMEOUT).await
+}
+
+
+async fn handshake_within<S>(stream: &mut S, local: &Me, timeout: Duration)
+    -> Result<Peer, TransportError>
+    where S: AsyncRead + AsyncWrite + Unpin + Send
+{
+    clock::timeout(timeout, exchange(stream, local)).await
+        .map_err(|e| TransportError::new("Handshake timed out", e))?
+}
+
+
+async fn exchange<S>(stream: &mut S, local: &Me) -> Result<Peer, TransportError>
+    where S: AsyncRead + AsyncWrite + Unpin + Send
+{
+    let local_pk = local.public_key().into_bytes()
+        .map_err(|e| TransportError::new("Handshake encode local key", e))?;
+
+    let challenge = rand::thread_rng().gen::<[u8; CHALLENGE_SIZE]>();
+
+    write_frame(stream, &HandshakeHello {
+        public_key: local_pk.clone(),
+        challenge: challenge.to_vec(),
+    }).await?;
+
+    let remote_hello: HandshakeHello = read_frame(stream).await?;
+    if remote_hello.challenge.len() != CHALLENGE_SIZE {
+        warn!("handshake: bad challenge size {}", remote_hello.challenge.len());
+        return Err(TransportError::new("Handshake bad challenge", DumbError));
+    }
+
+    let remote_pk = PublicKey::from_bytes(&remote_hello.public_key)
+        .map_err(|e| TransportError::new("Handshake decode remote key", e))?;
+
+    let proof = sign_challenge(local, &remote_hello.challenge, &local_pk)?;
+    write_frame(stream, &HandshakeProof {signature: proof}).await?;
+
+    let remote_proof: HandshakeProof = read_frame(stream).await?;
+    if !verify_challenge(
+        &remote_pk,
+        &challenge,
+        &remote_hello.public_key,
+        &remote_proof.signature)
+    {
+        warn!("handshake: remote proof is invalid");
+        return Err(TransportError::new("Handshake remote proof is invalid", DumbError));
+    }
+
+    Ok(Peer::from_public_key(&remote_pk))
+}
+
+
+fn signed_content(challenge: &[u8], signer_pk: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(
+        HANDSHAKE_DOMAIN.len() + challenge.len() + signer_pk.len());
+    buf.extend_from_slice(HANDSHAKE_DOMAIN);
+    buf.extend_from_slice(challenge);
+    buf.extend_from_slice(signer_pk);
+    buf
+}
+
+
+fn sign_challenge(local: &Me, challenge: &[u8], local_pk: &[u8])
+    -> Result<Vec<u8>, TransportError>
+{
+    local.private_key()
+        .sign(&signed_content(challenge, local_pk), local.public_key())
+        .ok_or_else(|| TransportError::new("Handshake local identity has no usable key", DumbError))
+}
+
+
+fn verify_challenge(remote_pk: &PublicKey, challenge: &[u8], remote_pk_bytes: &[u8], sig: &[u8])
+    -> bool
+{
+    remote_pk.verify(&signed_content(challenge, remote_pk_bytes), sig)
+}
+
+
+async fn write_frame<S, M>(stream: &mut S, msg: &M) -> Result<(), TransportError>
+    where S: AsyncWrite + Unpin + Send, M: Message
+{
+    let mut buf = Vec::with_capacity(4 + msg.encoded_len());
+    buf.extend_from_slice(&(msg.encoded_len() as u32).to_be_bytes());
+    msg.encode(&mut buf).unwrap();
+
+    stream.write_all(&buf).await
+        .map_err(|e| TransportError::new("Handshake write", e))?;
+    stream.flush().await
+        .map_err(|e| TransportError::new("Handshake flush", e))
+}
+
+
+async fn read_frame<S, M>(stream: &mut S) -> Result<M, TransportError>
+    where S: AsyncRead + Unpin + Send, M: Message + Default
+{
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf).await
+        .map_err(|e| TransportError::new("Handshake read length", e))?;
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > HANDSHAKE_MAXLEN {
+        return Err(TransportError::new("Handshake frame too long", DumbError));
+    }
+
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await
+        .map_err(|e| TransportError::new("Handshake read frame", e))?;
+
+    M::decode(buf.as_slice()).map_err(|e| TransportError::new(
+        "Handshake decode frame",
+        DeserializeError::new("Handshake decode frame", e)))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use async_std::os::unix::net::UnixStream;
+    use futures::io::Cursor;
+    use futures::executor::block_on;
+    use yulong::utils::AsBytes;
+
+    use crate::identity::Me;
+    use crate::identity::crypto::{PublicKey, PrivateKey, Signer};
+    use crate::identity::crypto::ed25519_signer::Ed25519Signer;
+    use crate::peer_id::{HandshakeHello, HandshakeProof};
+    use super::{handshake, handshake_within, read_frame, write_frame, HANDSHAKE_MAXLEN, CHALLENGE_SIZE};
+
+    fn identity() -> Me {
+        let (pk, sk) = Ed25519Signer::new().keygen();
+        Me::from_keypair(PublicKey::Ed25519(pk), PrivateKey::Ed25519(sk))
+    }
+
+    #[test]
+    fn frame_serde() {
+        let hello = HandshakeHello {
+            public_key: vec![1, 2, 3],
+            challenge: vec![42; 32],
+        };
+
+        let mut stream = Cursor::new(Vec::new());
+        block_on(write_frame(&mut stream, &hello)).unwrap();
+        stream.set_position(0);
+
+        let recv: HandshakeHello = block_on(read_frame(&mut stream)).unwrap();
+        assert_eq!(recv, hello);
+    }
+
+    #[test]
+    fn frame_too_long() {
+        let mut buf = ((HANDSHAKE_MAXLEN + 1) as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(&[0u8; 8]);
+
+        let mut stream = Cursor::new(buf);
+        let recv: Result<HandshakeProof, _> = block_on(read_frame(&mut stream));
+        assert!(recv.is_err());
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let (a, b) = (identity(), identity());
+        let (mut sa, mut sb) = UnixStream::pair().unwrap();
+
+        let (pa, pb) = block_on(futures::future::join(handshake(&mut sa, &a), handshake(&mut sb, &b)));
+        assert_eq!(pa.unwrap(), *b.peer());
+        assert_eq!(pb.unwrap(), *a.peer());
+    }
+
+    #[test]
+    fn handshake_bad_signature() {
+        let (a, b) = (identity(), identity());
+        let (mut sa, mut sb) = UnixStream::pair().unwrap();
+
+        // b announces its key but cannot sign the challenge
+        let forger = async {
+            write_frame(&mut sb, &HandshakeHello {
+                public_key: b.public_key().into_bytes().unwrap(),
+                challenge: vec![7; CHALLENGE_SIZE],
+            }).await.unwrap();
+            let _: HandshakeHello = read_frame(&mut sb).await.unwrap();
+            write_frame(&mut sb, &HandshakeProof {signature: vec![0; 64]}).await.unwrap();
+        };
+
+        let (pa, _) = block_on(futures::future::join(handshake(&mut sa, &a), forger));
+        assert!(pa.is_err());
+    }
+
+    #[test]
+    fn handshake_silent_peer() {
+        let a = identity();
+        let (mut sa, _sb) = UnixStream::pair().unwrap();
+
+        let pa = block_on(handshake_within(&mut sa, &a, Duration::from_millis(50)));
+        assert!(pa.is_err());
+    }
+}
@@ -1,16 +1,29 @@
+mod handshake;
+
 use std::{fmt::Debug, net::SocketAddr};
 use crate::error::TransportError;
 use futures::{AsyncRead, AsyncWrite};
 use async_trait::async_trait;
-use crate::identity::crypto;
+use crate::identity::{crypto, Me, Peer};
+
+pub use handshake::{handshake, HANDSHAKE_TIMEOUT};
 
 pub struct IngressStream<S: AsyncRead + AsyncWrite + Send + Unpin + Debug> 
 {
     pub remote_addr: SocketAddr,
     pub stream: S,
     pub remote_pk: crypto::PublicKey,
+    pub remote_peer: Peer,
 }
 
+/// Streams returned by connect and upgrade have already finished the
+/// authenticated handshake (see handshake::handshake), the Peer returned 
+/// alongside is the verified identity of the other side.
+///
+/// accept only takes the next connection off the listener, nothing is read
+/// from it yet. upgrade runs the handshake, a listener serving each
+/// connection on a task of its own does it there, so that a peer stalling
+/// the handshake holds no one else up.
 #[async_trait]
 pub trait Transport: 'static + Clone + Copy + Unpin + Send
 {
@@ -19,10 +32,16 @@ pub trait Transport: 'static + Clone + Copy + Unpin + Send
 
     type Listener: Send + Unpin;
 
+    /// An accepted connection before the handshake.
+    type Incoming: Send;
+
     async fn listen(_: &SocketAddr) -> Result<Self::Listener, TransportError>;
 
-    async fn connect(_: &SocketAddr) -> Result<Self::Stream, TransportError>;
+    async fn connect(_: &SocketAddr, local: &Me) -> Result<(Self::Stream, Peer), TransportError>;
 
-    async fn accept(_: &mut Self::Listener) -> 
+    async fn accept(_: &mut Self::Listener, local: &Me) -> 
+        Result<Self::Incoming, TransportError>;
+
+    async fn upgrade(_: Self::Incoming, local: &Me) ->
         Result<IngressStream<Self::Stream>, TransportError>;
-}
\ No newline at end of file
+}
@@ -0,0 +1,170 @@
+use std::fs;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use toml::Value;
+
+use crate::error::{ConfigError, DumbError};
+
+
+/// Typed config of a subsystem, loaded from a toml file and then overridden
+/// by environment variables.
+///
+/// Fields missing from the file keep their Default value. An environment
+/// variable named ENV_PREFIX_KEY overrides key, nested tables are separated
+/// by a double underscore, e.g. YULONG_BDN_MLBT__MERGE_PRE_TO sets
+/// merge_pre_to in the [mlbt] table of the bdn config.
+pub trait Config: DeserializeOwned + Default {
+
+    const ENV_PREFIX: &'static str;
+
+    /// Check relations between values that serde can not express.
+    fn validate(&self) -> Result<(), ConfigError> {
+        Ok(())
+    }
+
+
+    /// Load from path, or from defaults if path is None, then apply
+    /// the process environment.
+    fn load<P: AsRef<Path>>(path: Option<P>) -> Result<Self, ConfigError> {
+        let text = match path {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| ConfigError::new("Read config file", e))?,
+            None => String::new(),
+        };
+        Self::from_sources(&text, std::env::vars())
+    }
+
+
+    fn from_sources<I>(toml_text: &str, env: I) -> Result<Self, ConfigError>
+        where I: IntoIterator<Item = (String, String)>
+    {
+        let mut value = toml_text.parse::<Value>()
+            .map_err(|e| ConfigError::new("Parse config file", e))?;
+
+        apply_env(&mut value, Self::ENV_PREFIX, env)?;
+
+        let config: Self = value.try_into()
+            .map_err(|e| ConfigError::new(format!("Invalid config: {}", e), e))?;
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+
+/// Shorthand for a validation failure.
+pub fn invalid<S: ToString>(des: S) -> ConfigError {
+    ConfigError::new(des, DumbError)
+}
+
+
+fn apply_env<I>(value: &mut Value, prefix: &str, env: I) -> Result<(), ConfigError>
+    where I: IntoIterator<Item = (String, String)>
+{
+    let prefix = format!("{}_", prefix);
+
+    for (name, raw) in env {
+        let key_path = match name.strip_prefix(&prefix) {
+            Some(key_path) if !key_path.is_empty() => key_path.to_lowercase(),
+            _ => continue,
+        };
+
+        let keys: Vec<&str> = key_path.split("__").collect();
+        let (last, tables) = keys.split_last().unwrap();
+
+        let mut table = value.as_table_mut()
+            .ok_or_else(|| invalid("Config root is not a table"))?;
+
+        for key in tables {
+            table = table.entry(key.to_string())
+                .or_insert_with(|| Value::Table(Default::default()))
+                .as_table_mut()
+                .ok_or_else(|| invalid(format!("{} is not a table", key)))?;
+        }
+
+        table.insert(last.to_string(), parse_env_value(&raw));
+    }
+    Ok(())
+}
+
+
+// numbers and booleans are typed as in toml, anything else is a string
+fn parse_env_value(raw: &str) -> Value {
+    match format!("v = {}", raw).parse::<Value>() {
+        Ok(Value::Table(mut t)) => t.remove("v").unwrap_or_else(|| Value::String(raw.to_owned())),
+        _ => Value::String(raw.to_owned()),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(default, deny_unknown_fields)]
+    struct Inner {
+        timeout: u64,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(default, deny_unknown_fields)]
+    struct Outer {
+        port: u16,
+        name: String,
+        inner: Inner,
+    }
+
+    impl Default for Inner {
+        fn default() -> Self {
+            Self { timeout: 10 }
+        }
+    }
+
+    impl Default for Outer {
+        fn default() -> Self {
+            Self { port: 1, name: "a".to_owned(), inner: Inner::default() }
+        }
+    }
+
+    impl Config for Outer {
+        const ENV_PREFIX: &'static str = "TEST";
+
+        fn validate(&self) -> Result<(), ConfigError> {
+            if self.inner.timeout == 0 {
+                return Err(invalid("timeout must not be zero"));
+            }
+            Ok(())
+        }
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn file_and_env() {
+        let c = Outer::from_sources("", env(&[])).unwrap();
+        assert_eq!(c, Outer::default());
+
+        let c = Outer::from_sources("port = 2\n[inner]\ntimeout = 20", env(&[])).unwrap();
+        assert_eq!(c.port, 2);
+        assert_eq!(c.inner.timeout, 20);
+
+        let c = Outer::from_sources(
+            "port = 2",
+            env(&[("TEST_PORT", "3"), ("TEST_NAME", "b"), ("TEST_INNER__TIMEOUT", "30"), ("OTHER_PORT", "4")])
+        ).unwrap();
+        assert_eq!(c, Outer { port: 3, name: "b".to_owned(), inner: Inner { timeout: 30 } });
+    }
+
+    #[test]
+    fn rejected() {
+        assert!(Outer::from_sources("prot = 2", env(&[])).is_err());
+        assert!(Outer::from_sources("port = \"x\"", env(&[])).is_err());
+        assert!(Outer::from_sources("", env(&[("TEST_PORT", "70000")])).is_err());
+        assert!(Outer::from_sources("", env(&[("TEST_INNER__TIMEOUT", "0")])).is_err());
+    }
+}
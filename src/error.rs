@@ -82,4 +82,36 @@ impl Display for SerializeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Serialize error: {}", self.describe)
     }
-}
\ No newline at end of file
+}
+
+
+/// Errors happened loading or validating a config
+#[derive(Debug)]
+pub struct ConfigError {
+    describe: String,
+    boxed_error: Box<dyn Error>
+}
+
+
+impl ConfigError {
+    pub fn new<S: ToString>(des: S, err: impl Error + 'static) -> Self {
+        Self {
+            describe: des.to_string(),
+            boxed_error: Box::new(err)
+        }
+    }
+}
+
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Config error: {}", self.describe)
+    }
+}
@@ -3,5 +3,6 @@
 pub mod utils;
 pub mod log;
 pub mod error;
+pub mod config;
 
 mod yulong {}
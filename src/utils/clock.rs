@@ -0,0 +1,183 @@
+//! Time as protocol timers see it.
+//!
+//! It is the wall clock, unless a runtime runs the caller (see
+//! runtime::Runtime), then it is the time of that runtime, the same for
+//! every task it runs.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use futures::Stream;
+
+use super::runtime;
+
+
+pub fn now() -> Instant {
+    match runtime::current() {
+        Some(runtime) => runtime.now(),
+        None => Instant::now(),
+    }
+}
+
+
+/// Time passed since earlier, as now sees it.
+pub fn elapsed(earlier: Instant) -> Duration {
+    now().saturating_duration_since(earlier)
+}
+
+
+/// Wait for duration to pass.
+pub fn sleep(duration: Duration) -> Sleep {
+    match runtime::current() {
+        Some(runtime) => Sleep { deadline: runtime.now() + duration, wall: None },
+        None => Sleep {
+            deadline: Instant::now() + duration,
+            wall: Some(Box::pin(async_std::task::sleep(duration))),
+        },
+    }
+}
+
+
+/// Wait for future for at most duration.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, TimeoutError> {
+    // a future done by the deadline wins over the timer
+    match future::select(Box::pin(future), sleep(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(TimeoutError),
+    }
+}
+
+
+/// Stream of a tick every period, the first one a period from now.
+pub fn interval(period: Duration) -> Interval {
+    Interval { period, sleep: sleep(period) }
+}
+
+
+/// Future of sleep.
+pub struct Sleep {
+    deadline: Instant,
+
+    // timer of async-std, None on a runtime, which wakes it itself
+    wall: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+
+        if let Some(wall) = this.wall.as_mut() {
+            return wall.as_mut().poll(cx);
+        }
+
+        match runtime::current() {
+            Some(runtime) if runtime.now() < this.deadline => {
+                runtime.wake_at(this.deadline, cx.waker().clone());
+                Poll::Pending
+            }
+
+            // due, or the runtime is not there to wait on any more
+            _ => Poll::Ready(()),
+        }
+    }
+}
+
+
+/// Stream of interval.
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+
+impl Stream for Interval {
+    type Item = ();
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<()>> {
+        let this = self.get_mut();
+        futures::ready!(Pin::new(&mut this.sleep).poll(cx));
+
+        this.sleep = sleep(this.period);
+        Poll::Ready(Some(()))
+    }
+}
+
+
+/// The future of timeout was not done in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError;
+
+
+impl Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "future timed out")
+    }
+}
+
+
+impl Error for TimeoutError {}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::task::Waker;
+    use crate::utils::runtime::{Runtime, Task};
+
+    // time moved by hand, timers are only noted
+    #[derive(Default)]
+    struct Manual {
+        time: Cell<Duration>,
+        timers: RefCell<Vec<Instant>>,
+        epoch: Cell<Option<Instant>>,
+    }
+
+    impl Runtime for Manual {
+        fn now(&self) -> Instant {
+            self.epoch.get().unwrap() + self.time.get()
+        }
+
+        fn wake_at(&self, at: Instant, _: Waker) {
+            self.timers.borrow_mut().push(at);
+        }
+
+        fn spawn(&self, _: Task) {}
+
+        fn run_until(&self, _: &dyn Fn() -> bool) {}
+    }
+
+    #[test]
+    fn follows_runtime() {
+        let manual = Rc::new(Manual::default());
+        manual.epoch.set(Some(Instant::now()));
+
+        runtime::enter(manual.clone(), || {
+            let start = now();
+            manual.time.set(Duration::from_millis(5));
+            assert_eq!(elapsed(start), Duration::from_millis(5));
+
+            let mut sleep = sleep(Duration::from_millis(10));
+            let waker = futures::task::noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+            assert_eq!(*manual.timers.borrow(), vec![start + Duration::from_millis(15)]);
+
+            manual.time.set(Duration::from_millis(15));
+            assert!(Pin::new(&mut sleep).poll(&mut cx).is_ready());
+        });
+
+        // the wall clock again
+        let start = now();
+        assert!(elapsed(start) < Duration::from_secs(1));
+    }
+}
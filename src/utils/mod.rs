@@ -1,7 +1,9 @@
 pub mod bidirct_hashmap;
 pub mod type_alias;
+pub mod clock;
+pub mod runtime;
 
-use std::time::SystemTime;
+use std::time::Instant;
 
 use log::warn;
 
@@ -15,9 +17,10 @@ pub trait AsBytes: Sized {
 
 /// count down timer
 /// set a count-down value, start the timer, and query whether the count-down value reaches 0
+/// time is read from clock::now
 #[derive(Clone)]
 pub struct CasualTimer {
-    last_seen: Option<SystemTime>,
+    last_seen: Option<Instant>,
     counter: u128,
 }
 
@@ -33,7 +36,7 @@ impl CasualTimer {
 
 
     pub fn set_now(&mut self) {
-        self.last_seen = Some(SystemTime::now());
+        self.last_seen = Some(clock::now());
     }
 
 
@@ -41,15 +44,20 @@ impl CasualTimer {
         self.last_seen = None;
     }
 
+
+    /// Whether the timer runs, i.e. set_now since the last reset.
+    pub fn is_set(&self) -> bool {
+        self.last_seen.is_some()
+    }
+
     
     pub fn is_timeout(&self) -> bool {
         match self.last_seen {
 
             Some(earlier) => {
-                let escaped = SystemTime::now().duration_since(earlier);
-                if escaped.is_err() {
-                    warn!("CasualTimer::is_timeout clock go backwards {}", 
-                        escaped.unwrap_err());
+                let escaped = clock::now().checked_duration_since(earlier);
+                if escaped.is_none() {
+                    warn!("CasualTimer::is_timeout clock go backwards");
                     return false;
                 }
                 let escaped = escaped.unwrap();
@@ -68,10 +76,9 @@ impl CasualTimer {
         match self.last_seen {
 
             Some(earlier) => {
-                let escaped = SystemTime::now().duration_since(earlier);
-                if escaped.is_err() {
-                    warn!("CasualTimer::escaped clock go backwards {}", 
-                        escaped.unwrap_err());
+                let escaped = clock::now().checked_duration_since(earlier);
+                if escaped.is_none() {
+                    warn!("CasualTimer::escaped clock go backwards");
                     return None;
                 }
                 Some(escaped.unwrap().as_millis())
@@ -0,0 +1,116 @@
+//! Where tasks run.
+//!
+//! Tasks run on async-std and their timers follow the wall clock, unless a
+//! Runtime runs them, e.g. a simulation. Code that may run either way
+//! spawns and blocks through here and keeps time with utils::clock.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Instant;
+
+
+/// A task handed over to a runtime.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+
+/// Runs tasks in place of async-std, on a time of its own.
+///
+/// It drives every task it runs from the thread that runs it, and is
+/// entered there for as long as it does, see enter. What those tasks spawn
+/// then becomes a task of the same runtime, blocking on a future runs the
+/// runtime until the future is done, and their timers follow its time.
+pub trait Runtime {
+
+    fn now(&self) -> Instant;
+
+    /// Wake waker once now reaches at.
+    fn wake_at(&self, at: Instant, waker: Waker);
+
+    fn spawn(&self, task: Task);
+
+    /// Run tasks, moving time on as needed, until done holds.
+    fn run_until(&self, done: &dyn Fn() -> bool);
+}
+
+
+thread_local! {
+    // the runtime running the current thread, if any
+    static CURRENT: RefCell<Option<Rc<dyn Runtime>>> = RefCell::new(None);
+}
+
+
+/// Run f as a task of runtime would run, a runtime enters itself around
+/// running its tasks. Entering again nests.
+pub fn enter<F, U>(runtime: Rc<dyn Runtime>, f: F) -> U
+    where F: FnOnce() -> U
+{
+    // restored even if f panics
+    struct Leave(Option<Rc<dyn Runtime>>);
+
+    impl Drop for Leave {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            CURRENT.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+
+    let _leave = Leave(CURRENT.with(|current| current.replace(Some(runtime))));
+    f()
+}
+
+
+pub(crate) fn current() -> Option<Rc<dyn Runtime>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+
+/// Run task in the background, on the runtime of the caller if any.
+pub fn spawn<F>(task: F)
+    where F: Future<Output = ()> + Send + 'static
+{
+    match current() {
+        Some(runtime) => runtime.spawn(Box::pin(task)),
+        None => {
+            async_std::task::spawn(task);
+        }
+    }
+}
+
+
+/// Block the caller until future is done. On a runtime the other tasks
+/// run meanwhile, and time moves on if they all wait for it.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let runtime = match current() {
+        Some(runtime) => runtime,
+        None => return async_std::task::block_on(future),
+    };
+
+    let woken = Arc::new(Woken(AtomicBool::new(true)));
+    let waker = Waker::from(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+
+    loop {
+        if woken.0.swap(false, Ordering::SeqCst) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+        runtime.run_until(&|| woken.0.load(Ordering::SeqCst));
+    }
+}
+
+
+struct Woken(AtomicBool);
+
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
@@ -1,11 +1,14 @@
 use yulong_quic::QuicContext;
 use yulong_network::transport::Transport;
+use yulong_network::identity::Me;
 use futures::{AsyncReadExt};
 
 #[tokio::main]
 async fn main() {
+    let local = Me::new();
     if let Ok(mut listener) = QuicContext::listen(&"0.0.0.0:4433".parse().unwrap()).await {
-        if let Ok(mut stream) = QuicContext::accept(&mut listener).await {
+        if let Ok(incoming) = QuicContext::accept(&mut listener, &local).await {
+            let mut stream = QuicContext::upgrade(incoming, &local).await.unwrap();
             let mut buf = [0u8; 1024];
             while let Ok(len) = stream.stream.read(&mut buf).await {
                 println!("recv {:?}", &buf[..len]);
@@ -10,9 +10,9 @@ use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::{fs, io};
 use yulong_network::error::TransportError;
-use yulong_network::identity::crypto::PublicKey;
-use yulong_network::transport::{IngressStream, Transport};
-use log::{info};
+use yulong_network::identity::{Me, Peer};
+use yulong_network::transport::{IngressStream, Transport, handshake};
+use log::{info, warn};
 
 #[derive(Clone, Copy)]
 pub struct QuicContext {}
@@ -62,6 +62,7 @@ impl Transport for QuicContext {
     type Stream = QuicStream;
 
     type Listener = quinn::Incoming;
+    type Incoming = quinn::Connecting;
 
     async fn listen(addr: &std::net::SocketAddr) -> Result<Self::Listener, TransportError> {
         let mut transport_config = quinn::TransportConfig::default();
@@ -108,7 +109,10 @@ impl Transport for QuicContext {
         Ok(incoming)
     }
 
-    async fn connect(addr: &std::net::SocketAddr) -> Result<Self::Stream, TransportError> {
+    async fn connect(
+        addr: &std::net::SocketAddr,
+        local: &Me,
+    ) -> Result<(Self::Stream, Peer), TransportError> {
         let mut endpoint = quinn::Endpoint::builder();
         let mut client_config = quinn::ClientConfigBuilder::default();
         client_config.protocols(&[b"hq-29"]);
@@ -137,46 +141,72 @@ impl Transport for QuicContext {
 
         let (send, recv) = conn.clone().open_bi().await.unwrap();
 
-        Ok(QuicStream {
+        let mut stream = QuicStream {
             send_stream: send,
             recv_stream: recv,
-        })
+        };
+
+        // the opener speaks first, which is also what makes the stream
+        // visible to the remote accept
+        match handshake(&mut stream, local).await {
+            Ok(remote_peer) => Ok((stream, remote_peer)),
+            Err(err) => {
+                warn!("Handshake with {} failed", addr);
+                Err(err)
+            }
+        }
     }
 
     async fn accept(
         listener: &mut Self::Listener,
-    ) -> Result<IngressStream<Self::Stream>, TransportError> {
+        _local: &Me,
+    ) -> Result<Self::Incoming, TransportError> {
         if let Some(conn) = listener.next().await {
-            let quinn::NewConnection {
-                connection,
-                mut bi_streams,
-                ..
-            } = conn.await.unwrap();
-            let remote_addr = connection.remote_address();
-
-            if let Some(stream) = bi_streams.next().await {
-                let (send, recv) = match stream {
-                    Err(quinn::ConnectionError::ApplicationClosed(_)) => {
-                        return Err(TransportError::new("Application Closed", DumbError));
-                    }
-                    Err(err) => {
-                        return Err(TransportError::new("Other error", err));
-                    }
-                    Ok(s) => s,
-                };
-
-                return Ok(IngressStream {
-                    remote_addr,
-                    stream: QuicStream {
-                        send_stream: send,
-                        recv_stream: recv,
-                    },
-                    // Todo: retrieve pk
-                    remote_pk: PublicKey::NoKey,
-                });
-            }
+            return Ok(conn);
         }
 
         unreachable!()
     }
+
+    async fn upgrade(
+        incoming: Self::Incoming,
+        local: &Me,
+    ) -> Result<IngressStream<Self::Stream>, TransportError> {
+        let quinn::NewConnection {
+            connection,
+            mut bi_streams,
+            ..
+        } = incoming.await.map_err(|err| TransportError::new("Connection failed", err))?;
+        let remote_addr = connection.remote_address();
+
+        let (send, recv) = match bi_streams.next().await {
+            Some(Err(quinn::ConnectionError::ApplicationClosed(_))) | None => {
+                return Err(TransportError::new("Application Closed", DumbError));
+            }
+            Some(Err(err)) => {
+                return Err(TransportError::new("Other error", err));
+            }
+            Some(Ok(s)) => s,
+        };
+
+        let mut stream = QuicStream {
+            send_stream: send,
+            recv_stream: recv,
+        };
+
+        let remote_peer = match handshake(&mut stream, local).await {
+            Ok(peer) => peer,
+            Err(err) => {
+                warn!("Handshake with {} failed", remote_addr);
+                return Err(err);
+            }
+        };
+
+        Ok(IngressStream {
+            remote_addr,
+            stream,
+            remote_pk: remote_peer.pubkey().to_owned(),
+            remote_peer,
+        })
+    }
 }
@@ -0,0 +1,550 @@
+use std::fmt::{self, Debug};
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use futures::{ready, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use log::{info, warn};
+use rand::Rng;
+
+use yulong::error::DumbError;
+use yulong_network::error::TransportError;
+use yulong_network::identity::{Me, Peer};
+use yulong_network::identity::crypto::{PrivateKey, PublicKey};
+use yulong_network::identity::crypto::sm_cipher::{
+    constant_time_eq, hmac_sm3, sm4_ctr, SM4_BLOCK_SIZE, SM4_KEY_SIZE, SM3_HASH_SIZE,
+};
+use yulong_network::transport::{IngressStream, Transport};
+
+
+const NONCE_SIZE: usize = 32;
+
+// truncated hmac-sm3
+const TAG_SIZE: usize = 16;
+
+const LEN_SIZE: usize = 4;
+
+// max plaintext carried by one frame
+const MAX_FRAME: usize = 64 * 1024;
+
+const READ_CHUNK: usize = 16 * 1024;
+
+const SESSION_DOMAIN: &[u8] = b"yulong-session";
+
+
+/// Wrap any Transport into an encrypted one.
+///
+/// Right after the inner transport finishes its authenticated handshake both
+/// sides exchange a fresh nonce and derive the session keys from an SM2
+/// key agreement between their identities. Every frame is encrypted with
+/// SM4-CTR and authenticated with HMAC-SM3 (encrypt-then-mac), each direction
+/// having its own keys and frame counter.
+#[derive(Clone, Copy)]
+pub struct SecureContext<T: Transport> {
+    _inner: PhantomData<T>,
+}
+
+
+#[derive(Clone)]
+struct DirectionKeys {
+    enc: [u8; SM4_KEY_SIZE],
+    mac: [u8; SM3_HASH_SIZE],
+    seq: u64,
+}
+
+
+impl DirectionKeys {
+
+    fn derive(prk: &[u8], label: &[u8]) -> Self {
+        let mut enc_info = label.to_vec();
+        enc_info.extend_from_slice(b"enc");
+        let mut mac_info = label.to_vec();
+        mac_info.extend_from_slice(b"mac");
+
+        let mut enc = [0u8; SM4_KEY_SIZE];
+        enc.copy_from_slice(&hmac_sm3(prk, &enc_info)[..SM4_KEY_SIZE]);
+
+        Self {
+            enc,
+            mac: hmac_sm3(prk, &mac_info),
+            seq: 0,
+        }
+    }
+
+
+    // iv = seq || 0, the low half is left to the ctr block counter
+    fn iv(&self) -> [u8; SM4_BLOCK_SIZE] {
+        let mut iv = [0u8; SM4_BLOCK_SIZE];
+        iv[..8].copy_from_slice(&self.seq.to_be_bytes());
+        iv
+    }
+
+
+    fn tag(&self, len: &[u8], cipher_text: &[u8]) -> [u8; TAG_SIZE] {
+        let mut content = Vec::with_capacity(8 + LEN_SIZE + cipher_text.len());
+        content.extend_from_slice(&self.seq.to_be_bytes());
+        content.extend_from_slice(len);
+        content.extend_from_slice(cipher_text);
+
+        let mut tag = [0u8; TAG_SIZE];
+        tag.copy_from_slice(&hmac_sm3(&self.mac, &content)[..TAG_SIZE]);
+        tag
+    }
+}
+
+
+pub struct SecureStream<S> {
+    inner: S,
+
+    tx: DirectionKeys,
+    rx: DirectionKeys,
+
+    // sealed frame waiting to be written
+    out_buf: Vec<u8>,
+    out_pos: usize,
+
+    // raw bytes not yet forming a full frame
+    in_buf: Vec<u8>,
+
+    // opened plaintext not yet consumed
+    plain: Vec<u8>,
+    plain_pos: usize,
+}
+
+
+impl<S: Debug> Debug for SecureStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the keys
+        f.debug_struct("SecureStream").field("inner", &self.inner).finish()
+    }
+}
+
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> SecureStream<S> {
+
+    fn new(inner: S, tx: DirectionKeys, rx: DirectionKeys) -> Self {
+        Self {
+            inner,
+            tx,
+            rx,
+            out_buf: Vec::new(),
+            out_pos: 0,
+            in_buf: Vec::new(),
+            plain: Vec::new(),
+            plain_pos: 0,
+        }
+    }
+
+
+    /// Run the key agreement over an authenticated stream.
+    ///
+    /// *initiator* is true on the connecting side, it only decides the
+    /// order of nonces and which direction keys are used for sending.
+    async fn establish(mut inner: S, local: &Me, remote: &Peer, initiator: bool)
+        -> Result<Self, TransportError>
+    {
+        let sk = match local.private_key() {
+            PrivateKey::SM2(sk) => sk,
+            _ => {
+                return Err(TransportError::new(
+                    "SecureStream::establish local identity is not SM2", DumbError));
+            }
+        };
+
+        let remote_pk = match remote.pubkey() {
+            PublicKey::SM2(pk) => pk,
+            _ => {
+                return Err(TransportError::new(
+                    "SecureStream::establish remote identity is not SM2", DumbError));
+            }
+        };
+
+        let local_nonce = rand::thread_rng().gen::<[u8; NONCE_SIZE]>();
+        inner.write_all(&local_nonce).await
+            .map_err(|e| TransportError::new("SecureStream::establish write nonce", e))?;
+        inner.flush().await
+            .map_err(|e| TransportError::new("SecureStream::establish flush nonce", e))?;
+
+        let mut remote_nonce = [0u8; NONCE_SIZE];
+        inner.read_exact(&mut remote_nonce).await
+            .map_err(|e| TransportError::new("SecureStream::establish read nonce", e))?;
+
+        let (nonce_i, nonce_r) = if initiator {
+            (local_nonce, remote_nonce)
+        }
+        else {
+            (remote_nonce, local_nonce)
+        };
+
+        let mut salt = Vec::with_capacity(SESSION_DOMAIN.len() + 2 * NONCE_SIZE);
+        salt.extend_from_slice(SESSION_DOMAIN);
+        salt.extend_from_slice(&nonce_i);
+        salt.extend_from_slice(&nonce_r);
+
+        let prk = hmac_sm3(&sk.key_agreement(remote_pk), &salt);
+
+        let i2r = DirectionKeys::derive(&prk, b"i2r");
+        let r2i = DirectionKeys::derive(&prk, b"r2i");
+
+        if initiator {
+            Ok(Self::new(inner, i2r, r2i))
+        }
+        else {
+            Ok(Self::new(inner, r2i, i2r))
+        }
+    }
+
+
+    fn seal(&mut self, plain: &[u8]) -> Vec<u8> {
+        let cipher_text = sm4_ctr(&self.tx.enc, &self.tx.iv(), plain);
+        let len = (cipher_text.len() as u32).to_be_bytes();
+        let tag = self.tx.tag(&len, &cipher_text);
+        self.tx.seq += 1;
+
+        let mut frame = Vec::with_capacity(LEN_SIZE + cipher_text.len() + TAG_SIZE);
+        frame.extend_from_slice(&len);
+        frame.extend_from_slice(&cipher_text);
+        frame.extend_from_slice(&tag);
+        frame
+    }
+
+
+    // open one frame from in_buf if it is complete
+    fn open(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.in_buf.len() < LEN_SIZE {
+            return Ok(None);
+        }
+
+        let mut len = [0u8; LEN_SIZE];
+        len.copy_from_slice(&self.in_buf[..LEN_SIZE]);
+        let body_len = u32::from_be_bytes(len) as usize;
+
+        if body_len > MAX_FRAME {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "SecureStream frame too long"));
+        }
+
+        let frame_len = LEN_SIZE + body_len + TAG_SIZE;
+        if self.in_buf.len() < frame_len {
+            return Ok(None);
+        }
+
+        let cipher_text = &self.in_buf[LEN_SIZE..LEN_SIZE + body_len];
+        let tag = &self.in_buf[LEN_SIZE + body_len..frame_len];
+
+        if !constant_time_eq(&self.rx.tag(&len, cipher_text), tag) {
+            warn!("SecureStream::open frame authentication failed");
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "SecureStream bad frame tag"));
+        }
+
+        let plain = sm4_ctr(&self.rx.enc, &self.rx.iv(), cipher_text);
+        self.rx.seq += 1;
+        self.in_buf.drain(..frame_len);
+
+        Ok(Some(plain))
+    }
+
+
+    fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.out_pos < self.out_buf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out_buf[self.out_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out_pos += n;
+        }
+        self.out_buf.clear();
+        self.out_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncRead for SecureStream<S> {
+
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            // serve buffered plaintext first
+            if this.plain_pos < this.plain.len() {
+                let n = buf.len().min(this.plain.len() - this.plain_pos);
+                buf[..n].copy_from_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+
+            if let Some(plain) = this.open()? {
+                this.plain = plain;
+                this.plain_pos = 0;
+                continue;
+            }
+
+            let mut chunk = [0u8; READ_CHUNK];
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if n == 0 {
+                if this.in_buf.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.in_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncWrite for SecureStream<S> {
+
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // only one sealed frame is kept pending
+        ready!(this.poll_write_out(cx))?;
+
+        let n = buf.len().min(MAX_FRAME);
+        this.out_buf = this.seal(&buf[..n]);
+        this.out_pos = 0;
+
+        // the frame is accepted now, a pending write is finished by later calls
+        if let Poll::Ready(Err(e)) = this.poll_write_out(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+
+#[async_trait]
+impl<T: Transport> Transport for SecureContext<T> {
+
+    type Stream = SecureStream<T::Stream>;
+    type Listener = T::Listener;
+    type Incoming = T::Incoming;
+
+    async fn listen(addr: &SocketAddr) -> Result<Self::Listener, TransportError> {
+        T::listen(addr).await
+    }
+
+
+    async fn connect(addr: &SocketAddr, local: &Me) -> Result<(Self::Stream, Peer), TransportError> {
+        let (stream, remote_peer) = T::connect(addr, local).await?;
+
+        match SecureStream::establish(stream, local, &remote_peer, true).await {
+            Ok(stream) => {
+                info!("Secure session with {} established", addr);
+                Ok((stream, remote_peer))
+            }
+            Err(error) => {
+                warn!("Secure session with {} failed", addr);
+                Err(error)
+            }
+        }
+    }
+
+
+    async fn accept(listener: &mut Self::Listener, local: &Me)
+        -> Result<Self::Incoming, TransportError>
+    {
+        T::accept(listener, local).await
+    }
+
+
+    async fn upgrade(incoming: Self::Incoming, local: &Me)
+        -> Result<IngressStream<Self::Stream>, TransportError>
+    {
+        let istream = T::upgrade(incoming, local).await?;
+        let remote_addr = istream.remote_addr;
+
+        match SecureStream::establish(istream.stream, local, &istream.remote_peer, false).await {
+            Ok(stream) => {
+                info!("Secure session with {} established", remote_addr);
+                Ok(IngressStream {
+                    remote_addr,
+                    stream,
+                    remote_pk: istream.remote_pk,
+                    remote_peer: istream.remote_peer,
+                })
+            }
+            Err(error) => {
+                warn!("Secure session with {} failed", remote_addr);
+                Err(error)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use async_std::os::unix::net::UnixStream;
+    use futures::executor::block_on;
+    use futures::io::Cursor;
+    use yulong_network::identity::crypto::Signer;
+    use yulong_network::identity::crypto::ed25519_signer::Ed25519Signer;
+    use yulong_network::identity::crypto::sm_signer::SmSigner;
+    use yulong_sim::{SimContext, SimNetwork};
+
+    type SecureSim = SecureContext<SimContext>;
+
+    const PORT: u16 = 9001;
+
+    fn identity() -> Me {
+        let (pk, sk) = SmSigner::new().keygen();
+        Me::from_keypair(PublicKey::SM2(pk), PrivateKey::SM2(sk))
+    }
+
+    fn shared_secret(local: &Me, remote: &Me) -> Vec<u8> {
+        match (local.private_key(), remote.public_key()) {
+            (PrivateKey::SM2(sk), PublicKey::SM2(pk)) => sk.key_agreement(pk),
+            _ => unreachable!(),
+        }
+    }
+
+    // a message each way between the two ends of a session
+    async fn ping_pong<S, R>(mut initiator: S, mut responder: R)
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+            R: AsyncRead + AsyncWrite + Unpin
+    {
+        let mut buf = [0u8; 4];
+        initiator.write_all(b"ping").await.unwrap();
+        initiator.flush().await.unwrap();
+        responder.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        responder.write_all(b"pong").await.unwrap();
+        responder.flush().await.unwrap();
+        initiator.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    fn keys(label: &[u8]) -> DirectionKeys {
+        DirectionKeys::derive(&[42u8; SM3_HASH_SIZE], label)
+    }
+
+    fn sealed(msgs: &[&[u8]]) -> Vec<u8> {
+        let mut writer = SecureStream::new(Cursor::new(Vec::new()), keys(b"i2r"), keys(b"r2i"));
+        block_on(async {
+            for msg in msgs {
+                writer.write_all(msg).await.unwrap();
+            }
+            writer.flush().await.unwrap();
+        });
+        writer.inner.into_inner()
+    }
+
+    #[test]
+    fn stream_roundtrip() {
+        let large = vec![7u8; MAX_FRAME + 100];
+        let wire = sealed(&[b"hello", &large, b"world"]);
+
+        let mut reader = SecureStream::new(Cursor::new(wire), keys(b"r2i"), keys(b"i2r"));
+        let mut recv = Vec::new();
+        block_on(reader.read_to_end(&mut recv)).unwrap();
+
+        let mut expected = b"hello".to_vec();
+        expected.extend_from_slice(&large);
+        expected.extend_from_slice(b"world");
+        assert_eq!(recv, expected);
+    }
+
+    #[test]
+    fn tampered_frame() {
+        let mut wire = sealed(&[b"hello world"]);
+        wire[LEN_SIZE + 1] ^= 1;
+
+        let mut reader = SecureStream::new(Cursor::new(wire), keys(b"r2i"), keys(b"i2r"));
+        let mut recv = Vec::new();
+        assert!(block_on(reader.read_to_end(&mut recv)).is_err());
+    }
+
+    #[test]
+    fn replayed_frame() {
+        // the same frame twice is rejected since seq is part of the tag
+        let frame = sealed(&[b"once"]);
+        let mut wire = frame.clone();
+        wire.extend_from_slice(&frame);
+
+        let mut reader = SecureStream::new(Cursor::new(wire), keys(b"r2i"), keys(b"i2r"));
+        let mut recv = Vec::new();
+        assert!(block_on(reader.read_to_end(&mut recv)).is_err());
+    }
+
+    #[test]
+    fn key_agreement_symmetry() {
+        let (a, b, c) = (identity(), identity(), identity());
+        assert_eq!(shared_secret(&a, &b), shared_secret(&b, &a));
+        assert_ne!(shared_secret(&a, &b), shared_secret(&a, &c));
+    }
+
+    #[test]
+    fn establish_round_trip() {
+        let (a, b) = (identity(), identity());
+        let (sa, sb) = UnixStream::pair().unwrap();
+
+        let (ea, eb) = block_on(futures::future::join(
+            SecureStream::establish(sa, &a, b.peer(), true),
+            SecureStream::establish(sb, &b, a.peer(), false),
+        ));
+        block_on(ping_pong(ea.unwrap(), eb.unwrap()));
+    }
+
+    #[test]
+    fn establish_needs_sm2() {
+        let (pk, sk) = Ed25519Signer::new().keygen();
+        let other = Me::from_keypair(PublicKey::Ed25519(pk), PrivateKey::Ed25519(sk));
+        let (sa, _sb) = UnixStream::pair().unwrap();
+
+        assert!(block_on(SecureStream::establish(sa, &identity(), other.peer(), true)).is_err());
+    }
+
+    #[test]
+    fn context_connect_accept() {
+        let net = SimNetwork::new(1);
+        net.block_on(async {
+            let (a, b) = (identity(), identity());
+            let addr = SocketAddr::new(net.add_node(&a), PORT);
+            net.add_node(&b);
+
+            let any = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), PORT);
+            let mut listener = SecureSim::listen(&any).await.unwrap();
+            let accept = async {
+                let incoming = SecureSim::accept(&mut listener, &a).await.unwrap();
+                SecureSim::upgrade(incoming, &a).await.unwrap()
+            };
+            let (accepted, opened) = futures::join!(accept, SecureSim::connect(&addr, &b));
+            let (stream, remote_peer) = opened.unwrap();
+            assert_eq!(&remote_peer, a.peer());
+            assert_eq!(&accepted.remote_peer, b.peer());
+
+            ping_pong(stream, accepted.stream).await;
+        });
+    }
+}
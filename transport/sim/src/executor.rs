@@ -0,0 +1,164 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Wake, Waker};
+
+
+pub(crate) type LocalTask = Pin<Box<dyn Future<Output = ()>>>;
+
+
+/// Tasks of a simulated network and the timers they wait on. Tasks run one
+/// at a time, in the order they were woken, so the same wakes run the same
+/// way every time.
+#[derive(Default)]
+pub(crate) struct Executor {
+    // None while the task is being polled
+    tasks: RefCell<HashMap<u64, Option<LocalTask>>>,
+    next_task: Cell<u64>,
+
+    // shared with the wakers
+    ready: Arc<Mutex<Ready>>,
+
+    // woken while being polled further up the stack, by a task blocking
+    // on a future, queued again once that poll is over
+    rewoken: RefCell<HashSet<u64>>,
+
+    // by (virtual time, order of arrival)
+    timers: RefCell<BTreeMap<(u64, u64), Waker>>,
+    next_timer: Cell<u64>,
+}
+
+
+#[derive(Default)]
+struct Ready {
+    queue: VecDeque<u64>,
+    queued: HashSet<u64>,
+}
+
+
+impl Ready {
+
+    fn push(&mut self, id: u64) {
+        if self.queued.insert(id) {
+            self.queue.push_back(id);
+        }
+    }
+
+
+    fn pop(&mut self) -> Option<u64> {
+        let id = self.queue.pop_front()?;
+        self.queued.remove(&id);
+        Some(id)
+    }
+}
+
+
+struct TaskWaker {
+    id: u64,
+    ready: Arc<Mutex<Ready>>,
+}
+
+
+impl Wake for TaskWaker {
+
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        lock(&self.ready).push(self.id);
+    }
+}
+
+
+// a task panicking on another thread leaves the queue consistent
+fn lock(ready: &Mutex<Ready>) -> std::sync::MutexGuard<'_, Ready> {
+    ready.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+
+impl Executor {
+
+    pub(crate) fn spawn(&self, task: LocalTask) {
+        let id = self.next_task.get();
+        self.next_task.set(id + 1);
+
+        self.tasks.borrow_mut().insert(id, Some(task));
+        lock(&self.ready).push(id);
+    }
+
+
+    pub(crate) fn ready_count(&self) -> usize {
+        lock(&self.ready).queue.len()
+    }
+
+
+    /// Poll the task woken first, false if none is.
+    pub(crate) fn run_one(&self) -> bool {
+        let id = match lock(&self.ready).pop() {
+            Some(id) => id,
+            None => return false,
+        };
+
+        let task = match self.tasks.borrow_mut().get_mut(&id) {
+            Some(slot) => slot.take(),
+            // done already
+            None => return true,
+        };
+
+        let mut task = match task {
+            Some(task) => task,
+            None => {
+                self.rewoken.borrow_mut().insert(id);
+                return true;
+            }
+        };
+
+        let waker = Waker::from(Arc::new(TaskWaker { id, ready: self.ready.clone() }));
+        let done = task.as_mut().poll(&mut Context::from_waker(&waker)).is_ready();
+
+        if done {
+            self.tasks.borrow_mut().remove(&id);
+        }
+        else {
+            self.tasks.borrow_mut().insert(id, Some(task));
+            if self.rewoken.borrow_mut().remove(&id) {
+                lock(&self.ready).push(id);
+            }
+        }
+        true
+    }
+
+
+    /// Wake waker at virtual time at, once.
+    pub(crate) fn add_timer(&self, at: u64, waker: Waker) {
+        let mut timers = self.timers.borrow_mut();
+
+        // a sleep polled again asks again
+        if timers.range((at, 0)..(at + 1, 0)).any(|(_, other)| other.will_wake(&waker)) {
+            return;
+        }
+
+        let order = self.next_timer.get();
+        self.next_timer.set(order + 1);
+        timers.insert((at, order), waker);
+    }
+
+
+    pub(crate) fn next_timer(&self) -> Option<u64> {
+        self.timers.borrow().keys().next().map(|(at, _)| *at)
+    }
+
+
+    /// Timers due at now, removed, as (time, order, waker) in order.
+    pub(crate) fn due_timers(&self, now: u64) -> Vec<(u64, u64, Waker)> {
+        let mut timers = self.timers.borrow_mut();
+        let later = timers.split_off(&(now + 1, 0));
+        let due = std::mem::replace(&mut *timers, later);
+
+        due.into_iter().map(|((at, order), waker)| (at, order, waker)).collect()
+    }
+}
@@ -0,0 +1,436 @@
+//! In-process Transport over a simulated network, for running many nodes
+//! in one test. See SimNetwork.
+
+mod executor;
+mod network;
+
+pub use network::{seed_from_env, LinkConfig, LinkStats, SimNetwork};
+
+use yulong_network::transport::{Transport, IngressStream, handshake};
+use yulong_network::error::TransportError;
+use futures::{AsyncRead, AsyncWrite};
+use std::pin::Pin;
+use std::task::Poll;
+use async_trait::async_trait;
+use yulong_network::identity::{Me, Peer};
+use log::{warn, info};
+
+#[derive(Clone, Copy)]
+pub struct SimContext {}
+
+
+#[derive(Debug)]
+pub struct SimStream {
+    net: u32,
+    rx: u64,
+    tx: u64,
+}
+
+
+/// Stream accepted by a SimListener, the handshake still to run.
+pub struct SimIncoming {
+    stream: SimStream,
+    remote_addr: std::net::SocketAddr,
+}
+
+
+/// Listener on a port of whichever node accepts on it first.
+pub struct SimListener {
+    port: u16,
+    node: Option<(u32, usize)>,
+}
+
+
+impl AsyncRead for SimStream {
+
+    fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+
+        network::read(self.net, self.rx, cx, buf)
+    }
+}
+
+
+impl AsyncWrite for SimStream {
+
+    // never blocks, the link decides when the message arrives
+    fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+
+        Poll::Ready(network::write(self.net, self.tx, buf))
+    }
+
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        network::shutdown(self.net, self.tx);
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        network::release(self.net, self.rx, self.tx);
+    }
+}
+
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        if let Some((net, node)) = self.node {
+            network::unbind(net, node, self.port);
+        }
+    }
+}
+
+
+/// Nodes are attached to a network with SimNetwork::add_node beforehand,
+/// connect and accept find theirs by the identity they run as. The
+/// handshake is not subject to link conditions.
+#[async_trait]
+impl Transport for SimContext {
+
+    type Stream = SimStream;
+    type Listener = SimListener;
+    type Incoming = SimIncoming;
+
+    // the node is not known before the first accept
+    async fn listen(addr: &std::net::SocketAddr) -> Result<Self::Listener, TransportError> {
+        Ok(SimListener { port: addr.port(), node: None })
+    }
+
+
+    async fn connect(addr: &std::net::SocketAddr, local: &Me)
+        -> Result<(Self::Stream, Peer), TransportError> {
+
+        match network::connect(local.peer(), addr) {
+            Ok((net, rx, tx)) => {
+                info!("Connected to: {}", addr);
+                let mut stream = SimStream { net, rx, tx };
+                match handshake(&mut stream, local).await {
+                    Ok(remote_peer) => {
+                        network::establish(net, tx);
+                        Ok((stream, remote_peer))
+                    }
+                    Err(error) => {
+                        warn!("Handshake with: {} failed", addr);
+                        Err(error)
+                    }
+                }
+            }
+            Err(error) => {
+                warn!("Connect to: {} failed", addr);
+                Err(TransportError::new(
+                format!("Transport Error happens when connecting to {:?}", addr), error))
+            }
+        }
+    }
+
+
+    // accept on a node outside any network, or of a dropped one, stalls
+    // rather than fail over and over in BDN::listen
+    async fn accept(listener: &mut Self::Listener, local: &Me)
+        -> Result<Self::Incoming, TransportError> {
+
+        if listener.node.is_none() {
+            listener.node = network::bind(local.peer(), listener.port);
+        }
+
+        let (net, node) = match listener.node {
+            Some(node) => node,
+            None => {
+                warn!("{} is not a node of any SimNetwork, accept nothing", local.peer());
+                return stall().await;
+            }
+        };
+
+        let port = listener.port;
+        let incoming = match futures::future::poll_fn(|cx| network::poll_accept(net, node, port, cx)).await {
+            Some(incoming) => incoming,
+            None => {
+                warn!("SimNetwork of {} is gone, accept nothing", local.peer());
+                return stall().await;
+            }
+        };
+
+        info!("Accept connection from {}", incoming.remote_addr);
+        Ok(SimIncoming {
+            stream: SimStream { net, rx: incoming.rx, tx: incoming.tx },
+            remote_addr: incoming.remote_addr,
+        })
+    }
+
+
+    async fn upgrade(incoming: Self::Incoming, local: &Me)
+        -> Result<IngressStream<Self::Stream>, TransportError> {
+
+        let SimIncoming { mut stream, remote_addr } = incoming;
+
+        let remote_peer = handshake(&mut stream, local).await;
+        if remote_peer.is_err() {
+            warn!("Handshake with: {} failed", remote_addr);
+            return Err(remote_peer.err().unwrap());
+        }
+        let remote_peer = remote_peer.unwrap();
+        network::establish(stream.net, stream.tx);
+
+        Ok(IngressStream{
+            remote_addr,
+            stream,
+            remote_pk: remote_peer.pubkey().to_owned(),
+            remote_peer,
+        })
+    }
+}
+
+
+async fn stall<T>() -> T {
+    loop {
+        futures::future::pending::<()>().await;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use futures::{AsyncReadExt, AsyncWriteExt, FutureExt};
+    use yulong::utils::{clock, runtime};
+
+    const PORT: u16 = 9001;
+
+    // a stream between two new nodes, as accepted by a and as opened by b
+    async fn pair(net: &SimNetwork) -> (Me, Me, SimStream, SimStream) {
+        let (a, b) = (net.identity(), net.identity());
+        let ip = net.add_node(&a);
+        net.add_node(&b);
+
+        let any = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), PORT);
+        let mut listener = SimContext::listen(&any).await.unwrap();
+        let addr = SocketAddr::new(ip, PORT);
+
+        // accept binds before connect is first polled
+        let accept = async {
+            let incoming = SimContext::accept(&mut listener, &a).await.unwrap();
+            SimContext::upgrade(incoming, &a).await
+        };
+        let (accepted, opened) = futures::join!(accept, SimContext::connect(&addr, &b));
+        let (accepted, (opened, remote_peer)) = (accepted.unwrap(), opened.unwrap());
+        assert_eq!(&remote_peer, a.peer());
+        assert_eq!(&accepted.remote_peer, b.peer());
+        (a, b, accepted.stream, opened)
+    }
+
+    // a peer silent after connecting holds up its own handshake only
+    #[test]
+    fn accept_leaves_handshake_to_upgrade() {
+        let net = SimNetwork::new(5);
+        net.block_on(async {
+            let (a, b, c) = (net.identity(), net.identity(), net.identity());
+            let addr = SocketAddr::new(net.add_node(&a), PORT);
+            net.add_node(&b);
+            net.add_node(&c);
+
+            let any = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), PORT);
+            let mut listener = SimContext::listen(&any).await.unwrap();
+            // the first accept binds the port
+            assert!(SimContext::accept(&mut listener, &a).now_or_never().is_none());
+
+            let _silent = network::connect(b.peer(), &addr).unwrap();
+            let stalled = SimContext::accept(&mut listener, &a).await.unwrap();
+
+            let accept = async {
+                let incoming = SimContext::accept(&mut listener, &a).await.unwrap();
+                SimContext::upgrade(incoming, &a).await
+            };
+            let (accepted, opened) = futures::join!(accept, SimContext::connect(&addr, &c));
+            assert_eq!(&accepted.unwrap().remote_peer, c.peer());
+            assert_eq!(&opened.unwrap().1, a.peer());
+
+            assert!(SimContext::upgrade(stalled, &a).await.is_err());
+        });
+    }
+
+    #[test]
+    fn link_delays_messages() {
+        let net = SimNetwork::new(1);
+        net.block_on(async {
+            let (a, b, mut a_stream, mut b_stream) = pair(&net).await;
+
+            // 100 bytes take 100ms to send at 1000 bytes per second
+            net.set_link(b.peer(), a.peer(), LinkConfig {
+                latency: Duration::from_millis(10),
+                bandwidth: 1000,
+                ..LinkConfig::default()
+            });
+
+            b_stream.write_all(&[1; 100]).await.unwrap();
+            b_stream.write_all(&[2; 100]).await.unwrap();
+
+            let mut buf = [0; 1024];
+            assert!(a_stream.read(&mut buf).now_or_never().is_none());
+
+            net.advance(Duration::from_millis(109));
+            assert!(a_stream.read(&mut buf).now_or_never().is_none());
+
+            net.advance(Duration::from_millis(1));
+            assert_eq!(a_stream.read(&mut buf).now_or_never().unwrap().unwrap(), 100);
+            assert_eq!(buf[0], 1);
+
+            // the second one waited for the first to leave
+            assert!(a_stream.read(&mut buf).now_or_never().is_none());
+            net.advance(Duration::from_millis(100));
+            assert_eq!(a_stream.read(&mut buf).now_or_never().unwrap().unwrap(), 100);
+            assert_eq!(buf[0], 2);
+
+            assert_eq!(net.now(), Duration::from_millis(210));
+            assert_eq!(net.link_stats(b.peer(), a.peer()), LinkStats { sent: 2, dropped: 0, bytes: 200 });
+        });
+    }
+
+    // messages of b that reach a over a lossy link
+    fn delivered(seed: u64) -> Vec<u8> {
+        let net = SimNetwork::new(seed);
+        net.block_on(async {
+            let (a, b, mut a_stream, mut b_stream) = pair(&net).await;
+
+            net.set_link(b.peer(), a.peer(), LinkConfig {
+                jitter: Duration::from_millis(5),
+                loss: 0.5,
+                ..LinkConfig::default()
+            });
+
+            for i in 0..100 {
+                b_stream.write_all(&[i]).await.unwrap();
+            }
+            b_stream.close().await.unwrap();
+
+            let mut got = Vec::new();
+            a_stream.read_to_end(&mut got).await.unwrap();
+
+            assert_eq!(net.link_stats(b.peer(), a.peer()).dropped, 100 - got.len() as u64);
+            got
+        })
+    }
+
+    #[test]
+    fn loss_replays_from_seed() {
+        let got = delivered(7);
+        assert!(!got.is_empty() && got.len() < 100);
+
+        // jitter does not reorder a stream
+        assert!(got.windows(2).all(|w| w[0] < w[1]));
+
+        assert_eq!(delivered(7), got);
+    }
+
+    #[test]
+    fn partition_and_heal() {
+        let net = SimNetwork::new(2);
+        net.block_on(async {
+            let (a, b, mut a_stream, mut b_stream) = pair(&net).await;
+            let addr = SocketAddr::new(net.add_node(&a), PORT);
+
+            net.partition(&[vec![a.peer().to_owned()]]);
+
+            // the stream stays open, what is written is lost
+            b_stream.write_all(b"lost").await.unwrap();
+            let mut buf = [0; 16];
+            assert!(a_stream.read(&mut buf).now_or_never().is_none());
+            assert!(SimContext::connect(&addr, &b).await.is_err());
+            assert_eq!(net.link_stats(b.peer(), a.peer()).dropped, 1);
+
+            net.heal();
+            b_stream.write_all(b"found").await.unwrap();
+            assert_eq!(a_stream.read(&mut buf).await.unwrap(), 5);
+            assert_eq!(&buf[..5], b"found");
+
+            // a is gone
+            drop(a_stream);
+            assert!(b_stream.write_all(b"late").await.is_err());
+            assert_eq!(b_stream.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
+    // two readers on tasks of their own get what falls due in one step in
+    // order of delivery, whichever task was spawned first
+    #[test]
+    fn step_hands_over_in_order() {
+        let net = SimNetwork::new(3);
+        let got = Arc::new(Mutex::new(Vec::new()));
+
+        let mut writers = Vec::new();
+        for (tag, latency) in [(1, 3), (2, 1)] {
+            let (a, b, mut a_stream, b_stream) = net.block_on(pair(&net));
+            net.set_link(b.peer(), a.peer(), LinkConfig {
+                latency: Duration::from_millis(latency),
+                ..LinkConfig::default()
+            });
+
+            let got = got.clone();
+            net.spawn(async move {
+                let mut buf = [0; 1];
+                while a_stream.read(&mut buf).await.unwrap_or(0) > 0 {
+                    got.lock().unwrap().push(buf[0]);
+                }
+            });
+            writers.push((tag, b_stream));
+        }
+
+        // both readers block
+        net.step(Duration::ZERO);
+
+        for (tag, stream) in writers.iter_mut() {
+            stream.write_all(&[*tag]).now_or_never().unwrap().unwrap();
+        }
+        net.step(Duration::from_millis(5));
+        assert_eq!(*got.lock().unwrap(), vec![2, 1]);
+        assert_eq!(net.now(), Duration::from_millis(5));
+    }
+
+    // timers of every task follow the virtual time of the network, which
+    // jumps to the next one once all tasks wait
+    #[test]
+    fn tasks_sleep_on_virtual_time() {
+        let net = SimNetwork::new(4);
+        let woke = Arc::new(Mutex::new(Vec::new()));
+
+        net.block_on(async {
+            let start = clock::now();
+            for (tag, wait) in [(1, 30), (2, 10), (3, 20)] {
+                let woke = woke.clone();
+                runtime::spawn(async move {
+                    clock::sleep(Duration::from_millis(wait)).await;
+                    woke.lock().unwrap().push((tag, clock::elapsed(start)));
+                });
+            }
+
+            assert!(clock::timeout(Duration::from_millis(15), clock::sleep(Duration::from_millis(40))).await.is_err());
+            assert_eq!(net.now(), Duration::from_millis(15));
+
+            // blocking runs the other tasks meanwhile
+            runtime::block_on(clock::sleep(Duration::from_millis(35)));
+        });
+
+        assert_eq!(net.now(), Duration::from_millis(50));
+        assert_eq!(*woke.lock().unwrap(), vec![
+            (2, Duration::from_millis(10)),
+            (3, Duration::from_millis(20)),
+            (1, Duration::from_millis(30)),
+        ]);
+    }
+}
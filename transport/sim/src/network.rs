@@ -0,0 +1,822 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use once_cell::sync::Lazy;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use yulong::utils::AsBytes;
+use yulong::utils::runtime::{self, Runtime, Task};
+use yulong_network::identity::{Me, Peer};
+use yulong_network::identity::crypto::{PrivateKey, PublicKey};
+use yulong_network::identity::crypto::ed25519_signer::Ed25519SecKey;
+
+use crate::executor::Executor;
+
+
+// every simulated network of the process, SimContext has no state of its own
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry::default()));
+
+// first port of outgoing streams, as seen by the accepting side
+const EPHEMERAL_PORT: u16 = 40000;
+
+const SEED_VAR: &str = "YULONG_SIM_SEED";
+
+
+/// Conditions of the link from one node to another, the default one
+/// delivers every message at once.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkConfig {
+    pub latency: Duration,
+
+    // extra delay of a message, uniform from 0 to jitter
+    pub jitter: Duration,
+
+    // bytes per second, 0 for unlimited, messages queue behind each other
+    pub bandwidth: u64,
+
+    // chance that a message is lost, from 0 to 1
+    pub loss: f64,
+}
+
+
+/// Messages written over a link once streams were set up, the dropped ones
+/// were lost or hit a partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LinkStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub bytes: u64,
+}
+
+
+#[derive(Default)]
+struct Registry {
+    next_net: u32,
+    nets: HashMap<u32, Net>,
+
+    // (network, node index) of every node
+    by_ip: HashMap<IpAddr, (u32, usize)>,
+    by_peer: HashMap<Peer, (u32, usize)>,
+}
+
+
+struct Net {
+    seed: u64,
+
+    // virtual time in microseconds
+    now: u64,
+
+    nodes: Vec<Node>,
+
+    default_link: LinkConfig,
+    // by (from, to) node index
+    links: HashMap<(usize, usize), Link>,
+
+    // partition group of each node, nodes only reach their own group
+    groups: Vec<usize>,
+
+    pipes: HashMap<u64, Pipe>,
+    next_pipe: u64,
+
+    // of identity
+    keys: ChaCha8Rng,
+}
+
+
+struct Node {
+    peer: Peer,
+    ip: IpAddr,
+
+    // streams waiting to be accepted, by listening port
+    listeners: HashMap<u16, Backlog>,
+    next_port: u16,
+}
+
+
+#[derive(Default)]
+struct Backlog {
+    incoming: VecDeque<Incoming>,
+    waker: Option<Waker>,
+}
+
+
+/// A connection on its way to an accept call.
+pub(crate) struct Incoming {
+    pub(crate) rx: u64,
+    pub(crate) tx: u64,
+    pub(crate) remote_addr: SocketAddr,
+}
+
+
+struct Link {
+    // None follows the default link of the network
+    config: Option<LinkConfig>,
+
+    // seeded per link, so the fate of the n-th message over a link does not
+    // depend on traffic elsewhere
+    rng: ChaCha8Rng,
+
+    // the last message leaves the sender by then
+    busy_until: u64,
+
+    stats: LinkStats,
+}
+
+
+// one direction of a stream, each write is a chunk readable from its
+// delivery time on
+struct Pipe {
+    from: usize,
+    to: usize,
+
+    chunks: VecDeque<(u64, Vec<u8>)>,
+    // read so far of the front chunk
+    offset: usize,
+    last_at: u64,
+
+    // the handshake is done, link conditions apply from now on
+    established: bool,
+
+    writer_closed: bool,
+    reader_closed: bool,
+
+    // of the reader
+    waker: Option<Waker>,
+}
+
+
+impl Pipe {
+
+    // the reader can make progress at now
+    fn due(&self, now: u64) -> bool {
+        match self.chunks.front() {
+            Some((at, _)) => *at <= now,
+            None => self.writer_closed,
+        }
+    }
+}
+
+
+impl Net {
+
+    fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            now: 0,
+            nodes: Vec::new(),
+            default_link: LinkConfig::default(),
+            links: HashMap::new(),
+            groups: Vec::new(),
+            pipes: HashMap::new(),
+            next_pipe: 0,
+            keys: {
+                let mut rng = ChaCha8Rng::seed_from_u64(seed);
+                // apart from the links, which take (from, to) as stream
+                rng.set_stream(u64::MAX);
+                rng
+            },
+        }
+    }
+
+
+    fn link(&mut self, from: usize, to: usize) -> &mut Link {
+        let seed = self.seed;
+        self.links.entry((from, to)).or_insert_with(|| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            rng.set_stream(((from as u64) << 32) | to as u64);
+            Link {
+                config: None,
+                rng,
+                busy_until: 0,
+                stats: LinkStats::default(),
+            }
+        })
+    }
+
+
+    fn reachable(&self, from: usize, to: usize) -> bool {
+        self.groups[from] == self.groups[to]
+    }
+
+
+    fn open_pipe(&mut self, from: usize, to: usize) -> u64 {
+        let id = self.next_pipe;
+        self.next_pipe += 1;
+
+        self.pipes.insert(id, Pipe {
+            from,
+            to,
+            chunks: VecDeque::new(),
+            offset: 0,
+            last_at: 0,
+            established: false,
+            writer_closed: false,
+            reader_closed: false,
+            waker: None,
+        });
+        id
+    }
+
+
+    // blocked readers that can make progress at the current time, as
+    // (delivery time, stream, waker) in order of delivery
+    fn due_wakers(&mut self) -> Vec<(u64, u64, Waker)> {
+        let now = self.now;
+        let mut due: Vec<(u64, u64, Waker)> = self.pipes.iter_mut()
+            .filter(|(_, pipe)| pipe.waker.is_some() && pipe.due(now))
+            .filter_map(|(id, pipe)| {
+                let at = pipe.chunks.front().map_or(now, |(at, _)| *at);
+                pipe.waker.take().map(|waker| (at, *id, waker))
+            })
+            .collect();
+
+        due.sort_by_key(|(at, id, _)| (*at, *id));
+        due
+    }
+
+
+    // when the next message falls due for a blocked reader
+    fn next_delivery(&self) -> Option<u64> {
+        self.pipes.values()
+            .filter(|pipe| pipe.waker.is_some())
+            .filter_map(|pipe| pipe.chunks.front().map(|(at, _)| *at))
+            .min()
+    }
+
+
+    fn all_wakers(&mut self) -> Vec<Waker> {
+        let pipes = self.pipes.values_mut().filter_map(|pipe| pipe.waker.take());
+        let backlogs = self.nodes.iter_mut()
+            .flat_map(|node| node.listeners.values_mut())
+            .filter_map(|backlog| backlog.waker.take());
+
+        pipes.chain(backlogs).collect()
+    }
+}
+
+
+/// A simulated network of in-process nodes that talk through SimContext.
+///
+/// Every write to a stream is one message. Once the handshake is done it is
+/// delayed, queued behind earlier ones and possibly lost as configured for
+/// its link, or dropped if a partition separates the nodes. The random
+/// choices come from seed, per link and in the order of the messages sent
+/// over it, so a run can be replayed from its seed.
+///
+/// The network runs the nodes as well, on a runtime of its own (see
+/// yulong::utils::runtime) driven from the thread that calls block_on or
+/// step. Whatever its tasks spawn runs on it, and their timers follow its
+/// virtual time, which only moves once every task waits. Tasks run one at
+/// a time in the order they were woken, messages falling due at the same
+/// time wake their readers in order of delivery, so a run is the same out
+/// of the same seed and the same identities, see identity.
+///
+/// Nodes share the listening port and are told apart by address, the
+/// network owns 10.x.y.0/24 with x.y unique in the process. It is torn down
+/// when dropped, its streams then end and its tasks are dropped.
+pub struct SimNetwork {
+    id: u32,
+    seed: u64,
+    runtime: Rc<SimRuntime>,
+}
+
+
+impl SimNetwork {
+
+    pub fn new(seed: u64) -> Self {
+        let mut registry = registry();
+
+        let id = loop {
+            let id = registry.next_net;
+            registry.next_net = (id + 1) % (1 << 16);
+            if !registry.nets.contains_key(&id) {
+                break id;
+            }
+        };
+        registry.nets.insert(id, Net::new(seed));
+
+        debug!("SimNetwork::new network {} with seed {}", id, seed);
+        let runtime = Rc::new(SimRuntime { id, epoch: Instant::now(), tasks: Executor::default() });
+        Self { id, seed, runtime }
+    }
+
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+
+    /// A new Ed25519 identity drawn from the seed, the n-th one is the same
+    /// in every run.
+    pub fn identity(&self) -> Me {
+        let secret: [u8; 32] = self.with_net(|net| net.keys.gen());
+
+        // any 32 bytes make a key, safe unwrap
+        let sk = Ed25519SecKey::from_bytes(&secret).unwrap();
+        Me::from_keypair(PublicKey::Ed25519(sk.public_key()), PrivateKey::Ed25519(sk))
+    }
+
+
+    /// Attach me to the network and return its address, me can then listen
+    /// and connect through SimContext. A peer belongs to one network at a
+    /// time, the one it was last added to.
+    pub fn add_node(&self, me: &Me) -> IpAddr {
+        let mut registry = registry();
+        let registry = &mut *registry;
+        let net = registry.nets.get_mut(&self.id).unwrap();
+
+        if let Some(node) = net.nodes.iter().find(|node| node.peer == *me.peer()) {
+            return node.ip;
+        }
+
+        let idx = net.nodes.len();
+        assert!(idx < 254, "SimNetwork holds up to 254 nodes");
+
+        let ip = IpAddr::V4(Ipv4Addr::new(10, (self.id >> 8) as u8, self.id as u8, idx as u8 + 1));
+        net.nodes.push(Node {
+            peer: me.peer().to_owned(),
+            ip,
+            listeners: HashMap::new(),
+            next_port: EPHEMERAL_PORT,
+        });
+        net.groups.push(0);
+
+        registry.by_ip.insert(ip, (self.id, idx));
+        registry.by_peer.insert(me.peer().to_owned(), (self.id, idx));
+        ip
+    }
+
+
+    /// Conditions of the links without one of their own.
+    pub fn set_default_link(&self, config: LinkConfig) {
+        self.with_net(|net| net.default_link = config);
+    }
+
+
+    /// Conditions of the link from one node to another, the way back is
+    /// configured separately.
+    pub fn set_link(&self, from: &Peer, to: &Peer, config: LinkConfig) {
+        let (from, to) = (self.node(from), self.node(to));
+        self.with_net(|net| net.link(from, to).config = Some(config));
+    }
+
+
+    /// Split the nodes into groups that cannot reach each other, nodes left
+    /// out of every group form one more. Streams across groups stay open
+    /// but lose whatever is written to them.
+    pub fn partition(&self, groups: &[Vec<Peer>]) {
+        let assigned: Vec<(usize, usize)> = groups.iter().enumerate()
+            .flat_map(|(group, peers)| peers.iter().map(move |peer| (group, peer)))
+            .map(|(group, peer)| (self.node(peer), group))
+            .collect();
+
+        self.with_net(|net| {
+            net.groups = vec![groups.len(); net.nodes.len()];
+            for (idx, group) in assigned {
+                net.groups[idx] = group;
+            }
+        });
+    }
+
+
+    pub fn heal(&self) {
+        self.with_net(|net| net.groups = vec![0; net.nodes.len()]);
+    }
+
+
+    pub fn link_stats(&self, from: &Peer, to: &Peer) -> LinkStats {
+        let (from, to) = (self.node(from), self.node(to));
+        self.with_net(|net| net.links.get(&(from, to)).map(|link| link.stats).unwrap_or_default())
+    }
+
+
+    /// Whether peer accepts connections, i.e. SimContext::accept was called
+    /// with it.
+    pub fn listening(&self, peer: &Peer) -> bool {
+        let idx = self.node(peer);
+        self.with_net(|net| !net.nodes[idx].listeners.is_empty())
+    }
+
+
+    /// Virtual time since the network was created.
+    pub fn now(&self) -> Duration {
+        Duration::from_micros(self.with_net(|net| net.now))
+    }
+
+
+    /// Move the virtual clock forward, waking readers of what falls due and
+    /// timers, without running anything.
+    pub fn advance(&self, by: Duration) {
+        let now = self.with_net(|net| net.now);
+        self.runtime.move_to(now + by.as_micros() as u64);
+    }
+
+
+    /// Run the tasks of the network as time moves forward by, until they
+    /// all wait again each time something falls due on the way.
+    pub fn step(&self, by: Duration) {
+        let runtime = self.runtime.clone();
+        runtime::enter(runtime.clone(), || {
+            let until = self.with_net(|net| net.now) + by.as_micros() as u64;
+
+            loop {
+                while runtime.tasks.run_one() {}
+                match runtime.next_event() {
+                    Some(at) if at <= until => runtime.move_to(at),
+                    _ => break,
+                }
+            }
+
+            runtime.move_to(until);
+            while runtime.tasks.run_one() {}
+        });
+    }
+
+
+    /// Run future on the network until it is done, along with the tasks of
+    /// the network, time moving on whenever they all wait. It panics once
+    /// nothing is left that could wake them.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        runtime::enter(self.runtime.clone(), || runtime::block_on(future))
+    }
+
+
+    /// Run task on the network, from the next block_on or step on.
+    pub fn spawn<F>(&self, task: F)
+        where F: Future<Output = ()> + 'static
+    {
+        self.runtime.tasks.spawn(Box::pin(task));
+    }
+
+
+    fn with_net<F, U>(&self, f: F) -> U
+        where F: FnOnce(&mut Net) -> U
+    {
+        // the network lives as long as self
+        f(registry().nets.get_mut(&self.id).unwrap())
+    }
+
+
+    fn node(&self, peer: &Peer) -> usize {
+        match registry().by_peer.get(peer) {
+            Some((net, idx)) if *net == self.id => *idx,
+            _ => panic!("{} is not a node of this SimNetwork", peer),
+        }
+    }
+}
+
+
+impl Drop for SimNetwork {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut registry = registry();
+            let id = self.id;
+
+            registry.by_ip.retain(|_, (net, _)| *net != id);
+            registry.by_peer.retain(|_, (net, _)| *net != id);
+
+            match registry.nets.remove(&id) {
+                Some(mut net) => net.all_wakers(),
+                None => Vec::new(),
+            }
+        };
+        wake(wakers);
+    }
+}
+
+
+// the runtime of a network, time is that of the network
+struct SimRuntime {
+    id: u32,
+
+    // where virtual time starts as an Instant
+    epoch: Instant,
+
+    tasks: Executor,
+}
+
+
+impl SimRuntime {
+
+    fn with_net<F, U>(&self, f: F) -> Option<U>
+        where F: FnOnce(&mut Net) -> U
+    {
+        registry().nets.get_mut(&self.id).map(f)
+    }
+
+
+    // the next time a blocked reader or a timer is due
+    fn next_event(&self) -> Option<u64> {
+        let delivery = self.with_net(|net| net.next_delivery()).flatten();
+        match (delivery, self.tasks.next_timer()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+
+    // move time on to at, waking what falls due by then in order of time,
+    // readers of a message before timers of the same time
+    fn move_to(&self, at: u64) {
+        let (now, readers) = match self.with_net(|net| {
+            net.now = net.now.max(at);
+            (net.now, net.due_wakers())
+        }) {
+            Some(due) => due,
+            None => return,
+        };
+
+        let mut due: Vec<(u64, u8, u64, Waker)> = readers.into_iter()
+            .map(|(at, id, waker)| (at, 0, id, waker))
+            .chain(self.tasks.due_timers(now).into_iter().map(|(at, order, waker)| (at, 1, order, waker)))
+            .collect();
+        due.sort_by_key(|(at, kind, key, _)| (*at, *kind, *key));
+
+        wake(due.into_iter().map(|(_, _, _, waker)| waker).collect());
+    }
+}
+
+
+impl Runtime for SimRuntime {
+
+    fn now(&self) -> Instant {
+        self.epoch + Duration::from_micros(self.with_net(|net| net.now).unwrap_or(0))
+    }
+
+
+    fn wake_at(&self, at: Instant, waker: Waker) {
+        // rounded up, the timer does not fire early
+        let nanos = at.saturating_duration_since(self.epoch).as_nanos();
+        self.tasks.add_timer(nanos.div_ceil(1000) as u64, waker);
+    }
+
+
+    fn spawn(&self, task: Task) {
+        self.tasks.spawn(task);
+    }
+
+
+    fn run_until(&self, done: &dyn Fn() -> bool) {
+        // what is ready gets a turn first, a caller that keeps waking
+        // itself does not starve the rest
+        for _ in 0..self.tasks.ready_count() {
+            self.tasks.run_one();
+        }
+
+        while !done() {
+            if self.tasks.run_one() {
+                continue;
+            }
+
+            match self.next_event() {
+                Some(at) => self.move_to(at),
+                None => panic!("SimNetwork {}: every task waits and nothing is due", self.id),
+            }
+        }
+    }
+}
+
+
+/// Seed of a simulation run, YULONG_SIM_SEED if set or else a random one.
+/// It is logged to replay the run with.
+pub fn seed_from_env() -> u64 {
+    let seed = std::env::var(SEED_VAR).ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(rand::random);
+
+    info!("simulation seed {}, replay with {}={}", seed, SEED_VAR, seed);
+    seed
+}
+
+
+fn registry() -> MutexGuard<'static, Registry> {
+    // a test panicking elsewhere leaves the registry consistent
+    REGISTRY.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+
+fn wake(wakers: Vec<Waker>) {
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+
+/// Start listening on port as the node of local, None if local was never
+/// added to a network.
+pub(crate) fn bind(local: &Peer, port: u16) -> Option<(u32, usize)> {
+    let mut registry = registry();
+    let (id, idx) = *registry.by_peer.get(local)?;
+
+    let node = &mut registry.nets.get_mut(&id)?.nodes[idx];
+    node.listeners.entry(port).or_default();
+
+    info!("Simulated node {} listening on {}:{}", local, node.ip, port);
+    Some((id, idx))
+}
+
+
+pub(crate) fn unbind(id: u32, idx: usize, port: u16) {
+    if let Some(net) = registry().nets.get_mut(&id) {
+        net.nodes[idx].listeners.remove(&port);
+    }
+}
+
+
+/// Next stream to accept on port, None once the network is gone.
+pub(crate) fn poll_accept(id: u32, idx: usize, port: u16, cx: &mut Context<'_>) -> Poll<Option<Incoming>> {
+    let mut registry = registry();
+    let backlog = match registry.nets.get_mut(&id) {
+        Some(net) => net.nodes[idx].listeners.entry(port).or_default(),
+        None => return Poll::Ready(None),
+    };
+
+    match backlog.incoming.pop_front() {
+        Some(incoming) => Poll::Ready(Some(incoming)),
+        None => {
+            backlog.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+
+/// Open a stream from local to addr, returned as (network, rx, tx) pipes.
+pub(crate) fn connect(local: &Peer, addr: &SocketAddr) -> io::Result<(u32, u64, u64)> {
+    let mut registry = registry();
+
+    let (id, from) = *registry.by_peer.get(local)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "not a simulated node"))?;
+    let to = match registry.by_ip.get(&addr.ip()) {
+        Some((net, to)) if *net == id => *to,
+        _ => return Err(io::ErrorKind::ConnectionRefused.into()),
+    };
+
+    let net = registry.nets.get_mut(&id)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+
+    if !net.reachable(from, to) {
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+    if !net.nodes[to].listeners.contains_key(&addr.port()) {
+        return Err(io::ErrorKind::ConnectionRefused.into());
+    }
+
+    let tx = net.open_pipe(from, to);
+    let rx = net.open_pipe(to, from);
+
+    let source = &mut net.nodes[from];
+    let remote_addr = SocketAddr::new(source.ip, source.next_port);
+    source.next_port = source.next_port.checked_add(1).unwrap_or(EPHEMERAL_PORT);
+
+    // checked above, safe unwrap
+    let backlog = net.nodes[to].listeners.get_mut(&addr.port()).unwrap();
+    backlog.incoming.push_back(Incoming { rx: tx, tx: rx, remote_addr });
+    let waker = backlog.waker.take();
+
+    drop(registry);
+    wake(waker.into_iter().collect());
+
+    Ok((id, rx, tx))
+}
+
+
+/// Writes to tx are subject to link conditions from now on.
+pub(crate) fn establish(id: u32, tx: u64) {
+    if let Some(pipe) = registry().nets.get_mut(&id).and_then(|net| net.pipes.get_mut(&tx)) {
+        pipe.established = true;
+    }
+}
+
+
+pub(crate) fn write(id: u32, tx: u64, buf: &[u8]) -> io::Result<usize> {
+    let mut registry = registry();
+    let net = registry.nets.get_mut(&id)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+    let (from, to, established) = match net.pipes.get(&tx) {
+        Some(pipe) if !pipe.reader_closed && !pipe.writer_closed => (pipe.from, pipe.to, pipe.established),
+        _ => return Err(io::ErrorKind::BrokenPipe.into()),
+    };
+
+    let now = net.now;
+    let reachable = net.reachable(from, to);
+    let default_link = net.default_link;
+
+    let at = if established {
+        let link = net.link(from, to);
+        let config = link.config.unwrap_or(default_link);
+
+        // two draws per message whatever the outcome, keeps the sequence
+        // of a link stable
+        let lost = link.rng.gen::<f64>() < config.loss;
+        let jitter = link.rng.gen_range(0..=config.jitter.as_micros() as u64);
+
+        link.stats.sent += 1;
+        link.stats.bytes += buf.len() as u64;
+
+        if lost || !reachable {
+            link.stats.dropped += 1;
+            return Ok(buf.len());
+        }
+
+        let transmit = match config.bandwidth {
+            0 => 0,
+            bandwidth => buf.len() as u64 * 1_000_000 / bandwidth,
+        };
+        link.busy_until = link.busy_until.max(now) + transmit;
+        link.busy_until + config.latency.as_micros() as u64 + jitter
+    }
+    else {
+        now
+    };
+
+    // checked above, safe unwrap
+    let pipe = net.pipes.get_mut(&tx).unwrap();
+
+    // a stream keeps its order whatever the jitter
+    let at = at.max(pipe.last_at);
+    pipe.last_at = at;
+    pipe.chunks.push_back((at, buf.to_vec()));
+
+    let waker = if at <= now { pipe.waker.take() } else { None };
+
+    drop(registry);
+    wake(waker.into_iter().collect());
+
+    Ok(buf.len())
+}
+
+
+pub(crate) fn read(id: u32, rx: u64, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+    let mut registry = registry();
+    let net = match registry.nets.get_mut(&id) {
+        Some(net) => net,
+        None => return Poll::Ready(Ok(0)),
+    };
+
+    let now = net.now;
+    let pipe = match net.pipes.get_mut(&rx) {
+        Some(pipe) => pipe,
+        None => return Poll::Ready(Ok(0)),
+    };
+
+    match pipe.chunks.front() {
+        Some((at, chunk)) if *at <= now => {
+            // one chunk at most, a message is read on its own
+            let n = buf.len().min(chunk.len() - pipe.offset);
+            buf[..n].copy_from_slice(&chunk[pipe.offset..pipe.offset + n]);
+
+            pipe.offset += n;
+            if pipe.offset == chunk.len() {
+                pipe.chunks.pop_front();
+                pipe.offset = 0;
+            }
+            Poll::Ready(Ok(n))
+        }
+
+        None if pipe.writer_closed => Poll::Ready(Ok(0)),
+
+        _ => {
+            pipe.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+
+/// No more writes to tx, the reader gets EOF once it has read the rest.
+pub(crate) fn shutdown(id: u32, tx: u64) {
+    let waker = registry().nets.get_mut(&id)
+        .and_then(|net| net.pipes.get_mut(&tx))
+        .and_then(|pipe| {
+            pipe.writer_closed = true;
+            pipe.waker.take()
+        });
+    wake(waker.into_iter().collect());
+}
+
+
+/// Both ends of a stream are dropped, writes from the other side fail.
+pub(crate) fn release(id: u32, rx: u64, tx: u64) {
+    shutdown(id, tx);
+
+    let mut registry = registry();
+    let net = match registry.nets.get_mut(&id) {
+        Some(net) => net,
+        None => return,
+    };
+
+    if let Some(pipe) = net.pipes.get_mut(&rx) {
+        pipe.reader_closed = true;
+    }
+    net.pipes.retain(|_, pipe| !(pipe.reader_closed && pipe.writer_closed));
+}
@@ -1,10 +1,10 @@
-use yulong_network::transport::{Transport, IngressStream};
+use yulong_network::transport::{Transport, IngressStream, handshake};
 use yulong_network::error::TransportError;
 use futures::{AsyncRead, AsyncWrite};
 use std::{pin::Pin};
 use async_trait::async_trait;
 use tokio;
-use yulong_network::identity::crypto::{PublicKey};
+use yulong_network::identity::{Me, Peer};
 use log::{warn, info};
 
 #[derive(Clone, Copy)]
@@ -81,6 +81,7 @@ impl Transport for TcpContext {
 
     type Stream = TcpStream;
     type Listener = tokio::net::TcpListener;
+    type Incoming = (TcpStream, std::net::SocketAddr);
 
     async fn listen(addr: &std::net::SocketAddr) -> Result<Self::Listener, TransportError> {
         match tokio::net::TcpListener::bind(addr).await {
@@ -98,12 +99,22 @@ impl Transport for TcpContext {
     }
 
 
-    async fn connect(addr: &std::net::SocketAddr) -> Result<Self::Stream, TransportError> {
+    async fn connect(addr: &std::net::SocketAddr, local: &Me) 
+        -> Result<(Self::Stream, Peer), TransportError> {
 
         match tokio::net::TcpStream::connect(addr).await {
             Ok(std_stream) => {
                 info!("Connected to: {}", addr);
-                Ok(Self::Stream::from(std_stream))
+                let mut stream = Self::Stream::from(std_stream);
+                match handshake(&mut stream, local).await {
+                    Ok(remote_peer) => {
+                        Ok((stream, remote_peer))
+                    }
+                    Err(error) => {
+                        warn!("Handshake with: {} failed", addr);
+                        Err(error)
+                    }
+                }
             }
             Err(error) => {
                 warn!("Connect to: {} failed", addr);
@@ -114,18 +125,13 @@ impl Transport for TcpContext {
     }
 
 
-    async fn accept(listener: &mut Self::Listener) 
-        -> Result<IngressStream<Self::Stream>, TransportError> {
+    async fn accept(listener: &mut Self::Listener, _local: &Me)
+        -> Result<Self::Incoming, TransportError> {
 
         match listener.accept().await {
             Ok((stream, remote_addr)) => {
-                let stream = Self::Stream::from(stream);
                 info!("Accept connection from {}", remote_addr);
-                Ok(IngressStream{
-                    remote_addr: remote_addr, 
-                    stream: stream, 
-                    remote_pk: PublicKey::NoKey,
-                })
+                Ok((Self::Stream::from(stream), remote_addr))
             }
             Err(error) => {
                 Err(TransportError::new(
@@ -134,4 +140,23 @@ impl Transport for TcpContext {
             }
         }
     }
+
+
+    async fn upgrade(incoming: Self::Incoming, local: &Me)
+        -> Result<IngressStream<Self::Stream>, TransportError> {
+
+        let (mut stream, remote_addr) = incoming;
+        let remote_peer = handshake(&mut stream, local).await;
+        if remote_peer.is_err() {
+            warn!("Handshake with: {} failed", remote_addr);
+            return Err(remote_peer.err().unwrap());
+        }
+        let remote_peer = remote_peer.unwrap();
+        Ok(IngressStream{
+            remote_addr: remote_addr,
+            stream: stream,
+            remote_pk: remote_peer.pubkey().to_owned(),
+            remote_peer: remote_peer,
+        })
+    }
 }